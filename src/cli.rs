@@ -0,0 +1,241 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+pub const USAGE: &str = "\
+Usage: weekend-raytracer-wgpu [OPTIONS] [SCENE]
+
+Arguments:
+  [SCENE]                  RON or glTF scene file [default: assets/scenes/default.ron]
+
+Options:
+      --headless           Render without a window and write the result to --output
+  -o, --output <PATH>      Output image, .png (tonemapped), .exr or .hdr (linear) [default: render.png]
+  -r, --resolution <WxH>   Output resolution [default: 1280x720]
+  -s, --spp <N>            Samples per pixel, overrides the scene file
+  -b, --bounces <N>        Maximum number of bounces, overrides the scene file
+      --software           Use a software adapter such as lavapipe, even if a GPU is available
+  -h, --help               Print this help";
+
+const DEFAULT_SCENE_PATH: &str = "assets/scenes/default.ron";
+
+const DEFAULT_OUTPUT_PATH: &str = "render.png";
+
+const DEFAULT_RESOLUTION: (u32, u32) = (1280_u32, 720_u32);
+
+pub enum Command {
+    Help,
+    Run(Options),
+}
+
+pub struct Options {
+    pub scene_path: PathBuf,
+    /// Rendering without a window is requested by `--headless`. The other output flags are only
+    /// valid in combination with it.
+    pub headless: Option<HeadlessOptions>,
+}
+
+pub struct HeadlessOptions {
+    pub output_path: PathBuf,
+    pub resolution: (u32, u32),
+    pub samples_per_pixel: Option<u32>,
+    pub num_bounces: Option<u32>,
+    pub force_software_adapter: bool,
+}
+
+/// Parses the command line arguments, without the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, CliError> {
+    let mut args = args.into_iter();
+
+    let mut scene_path = None;
+
+    let mut headless = false;
+
+    let mut headless_options = HeadlessOptions {
+        output_path: PathBuf::from(DEFAULT_OUTPUT_PATH),
+        resolution: DEFAULT_RESOLUTION,
+        samples_per_pixel: None,
+        num_bounces: None,
+        force_software_adapter: false,
+    };
+
+    // The first flag which only makes sense for headless rendering, reported if `--headless` is
+    // missing.
+    let mut headless_flag = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| CliError::MissingValue(flag.to_owned()))
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--headless" => headless = true,
+            "-o" | "--output" => {
+                headless_options.output_path = PathBuf::from(value(&arg)?);
+            }
+            "-r" | "--resolution" => {
+                headless_options.resolution = parse_resolution(&arg, &value(&arg)?)?;
+            }
+            "-s" | "--spp" => {
+                headless_options.samples_per_pixel = Some(parse_positive(&arg, &value(&arg)?)?);
+            }
+            "-b" | "--bounces" => {
+                headless_options.num_bounces = Some(parse_positive(&arg, &value(&arg)?)?);
+            }
+            "--software" => headless_options.force_software_adapter = true,
+            flag if flag.starts_with('-') => return Err(CliError::UnknownFlag(arg)),
+            _ if scene_path.is_none() => {
+                scene_path = Some(PathBuf::from(arg));
+
+                continue;
+            }
+            _ => return Err(CliError::UnexpectedArgument(arg)),
+        }
+
+        if arg != "--headless" && headless_flag.is_none() {
+            headless_flag = Some(arg);
+        }
+    }
+
+    if let (false, Some(flag)) = (headless, headless_flag) {
+        return Err(CliError::RequiresHeadless(flag));
+    }
+
+    Ok(Command::Run(Options {
+        scene_path: scene_path.unwrap_or_else(|| PathBuf::from(DEFAULT_SCENE_PATH)),
+        headless: headless.then_some(headless_options),
+    }))
+}
+
+fn parse_resolution(
+    flag: &str,
+    value: &str,
+) -> Result<(u32, u32), CliError> {
+    let invalid = || CliError::InvalidValue {
+        flag: flag.to_owned(),
+        value: value.to_owned(),
+        expected: "WIDTHxHEIGHT, e.g. 1920x1080",
+    };
+
+    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(width), Ok(height)) if width > 0_u32 && height > 0_u32 => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_positive(
+    flag: &str,
+    value: &str,
+) -> Result<u32, CliError> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0_u32 => Ok(n),
+        _ => Err(CliError::InvalidValue {
+            flag: flag.to_owned(),
+            value: value.to_owned(),
+            expected: "a positive integer",
+        }),
+    }
+}
+
+#[derive(Error, Debug)]
+
+pub enum CliError {
+    #[error("unknown flag `{0}`")]
+    UnknownFlag(String),
+    #[error("unexpected argument `{0}`, only one scene file can be given")]
+    UnexpectedArgument(String),
+    #[error("`{0}` requires a value")]
+    MissingValue(String),
+    #[error("invalid value `{value}` for `{flag}`, expected {expected}")]
+    InvalidValue {
+        flag: String,
+        value: String,
+        expected: &'static str,
+    },
+    #[error("`{0}` can only be used together with `--headless`")]
+    RequiresHeadless(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, CliError> {
+        parse(args.iter().map(|arg| (*arg).to_owned()))
+    }
+
+    #[test]
+    fn headless_flags_are_parsed() {
+        let Ok(Command::Run(options)) = parse_args(&[
+            "--headless",
+            "scene.ron",
+            "-r",
+            "640x480",
+            "--spp",
+            "64",
+            "--bounces",
+            "4",
+            "-o",
+            "out.exr",
+        ]) else {
+            panic!("the arguments should be valid");
+        };
+
+        assert_eq!(options.scene_path, PathBuf::from("scene.ron"));
+
+        let headless = options.headless.expect("--headless was given");
+
+        assert_eq!(headless.resolution, (640_u32, 480_u32));
+
+        assert_eq!(headless.samples_per_pixel, Some(64_u32));
+
+        assert_eq!(headless.num_bounces, Some(4_u32));
+
+        assert_eq!(headless.output_path, PathBuf::from("out.exr"));
+
+        assert!(!headless.force_software_adapter);
+    }
+
+    #[test]
+    fn interactive_mode_is_the_default() {
+        let Ok(Command::Run(options)) = parse_args(&[]) else {
+            panic!("no arguments should be valid");
+        };
+
+        assert_eq!(options.scene_path, PathBuf::from(DEFAULT_SCENE_PATH));
+
+        assert!(options.headless.is_none());
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(matches!(
+            parse_args(&["--headless", "-r", "640"]),
+            Err(CliError::InvalidValue { .. })
+        ));
+
+        assert!(matches!(
+            parse_args(&["--headless", "--spp", "0"]),
+            Err(CliError::InvalidValue { .. })
+        ));
+
+        assert!(matches!(
+            parse_args(&["--headless", "--output"]),
+            Err(CliError::MissingValue(_))
+        ));
+
+        assert!(matches!(
+            parse_args(&["--spp", "64"]),
+            Err(CliError::RequiresHeadless(_))
+        ));
+
+        assert!(matches!(
+            parse_args(&["a.ron", "b.ron"]),
+            Err(CliError::UnexpectedArgument(_))
+        ));
+    }
+}
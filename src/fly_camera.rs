@@ -1,6 +1,6 @@
 use winit::event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
 
-use crate::raytracer::{Angle, Camera};
+use crate::raytracer::{Angle, Camera, CameraPose};
 
 pub struct FlyCameraController {
     pub position: glm::Vec3,
@@ -9,6 +9,9 @@ pub struct FlyCameraController {
     pub vfov_degrees: f32,
     pub aperture: f32,
     pub focus_distance: f32,
+    pub motion: Option<CameraMotion>,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
 
     pub forward_pressed: bool,
     pub backward_pressed: bool,
@@ -36,6 +39,9 @@ impl Default for FlyCameraController {
             vfov_degrees: 30.0,
             aperture: 0.8,
             focus_distance,
+            motion: None,
+            shutter_open: 0_f32,
+            shutter_close: 1_f32,
             forward_pressed: false,
             backward_pressed: false,
             left_pressed: false,
@@ -49,10 +55,33 @@ impl Default for FlyCameraController {
     }
 }
 
+/// How far the camera moves and turns from the time 0 to the time 1 of the scene. The motion is
+/// kept as the camera is flown around, it starts wherever the camera is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+
+pub struct CameraMotion {
+    pub translation: glm::Vec3,
+    pub yaw: Angle,
+    pub pitch: Angle,
+}
+
 impl FlyCameraController {
     pub fn renderer_camera(&self) -> Camera {
         let orientation = camera_orientation(self);
 
+        let end_pose = self.motion.map(|motion| {
+            let orientation = yaw_pitch_orientation(
+                self.yaw + motion.yaw,
+                (self.pitch + motion.pitch).clamp(Angle::degrees(-89.0), Angle::degrees(89.0)),
+            );
+
+            CameraPose {
+                eye_pos: self.position + motion.translation,
+                eye_dir: orientation.forward,
+                up: orientation.up,
+            }
+        });
+
         Camera {
             eye_pos: self.position,
             eye_dir: orientation.forward,
@@ -60,6 +89,9 @@ impl FlyCameraController {
             vfov: Angle::degrees(self.vfov_degrees),
             aperture: self.aperture,
             focus_distance: self.focus_distance,
+            end_pose,
+            shutter_open: self.shutter_open,
+            shutter_close: self.shutter_close,
         }
     }
 
@@ -225,10 +257,17 @@ pub struct Orientation {
 }
 
 pub fn camera_orientation(camera: &FlyCameraController) -> Orientation {
+    yaw_pitch_orientation(camera.yaw, camera.pitch)
+}
+
+fn yaw_pitch_orientation(
+    yaw: Angle,
+    pitch: Angle,
+) -> Orientation {
     let forward = glm::normalize(&glm::vec3(
-        camera.yaw.as_radians().cos() * camera.pitch.as_radians().cos(),
-        camera.pitch.as_radians().sin(),
-        camera.yaw.as_radians().sin() * camera.pitch.as_radians().cos(),
+        yaw.as_radians().cos() * pitch.as_radians().cos(),
+        pitch.as_radians().sin(),
+        yaw.as_radians().sin() * pitch.as_radians().cos(),
     ));
 
     let world_up = glm::vec3(0.0, 1.0, 0.0);
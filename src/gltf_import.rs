@@ -0,0 +1,886 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use base64::Engine;
+use gltf::{
+    camera::Projection,
+    image::Source,
+    khr_lights_punctual::Kind,
+    material::AlphaMode,
+    mesh::Mode,
+    texture::{MagFilter, MinFilter, WrappingMode},
+};
+use thiserror::Error;
+
+use crate::{
+    fly_camera::{camera_orientation, FlyCameraController},
+    raytracer::{
+        Angle, ColorSpace, FilterMode, Instance, InstancedObject, Material, Mesh, NormalMap,
+        Sphere, Texture, TextureError, TextureSampler, WrapMode,
+    },
+};
+
+/// The extensions whose data is imported, the others are reported and ignored.
+const SUPPORTED_EXTENSIONS: [&str; 3] = [
+    "KHR_lights_punctual",
+    "KHR_materials_emissive_strength",
+    "KHR_materials_ior",
+];
+
+/// The radius of the emissive spheres which point and spot lights become. glTF lights are
+/// infinitely small, which the path tracer could never hit.
+const POINT_LIGHT_RADIUS: f32 = 0.05_f32;
+
+/// The contents of a glTF file, converted to the renderer's representations.
+pub struct GltfScene {
+    pub meshes: Vec<Mesh>,
+    /// The copies of the meshes which several nodes share.
+    pub instances: Vec<Instance>,
+    /// The point and spot lights, as small emissive spheres.
+    pub spheres: Vec<Sphere>,
+    pub materials: Vec<Material>,
+    /// The first perspective camera in the scene, without its roll. Scenes without a camera are
+    /// framed from the default camera's direction.
+    pub camera: FlyCameraController,
+    /// The direction towards the first directional light, which the sun is placed in. The light's
+    /// color and intensity are not used.
+    pub sun_direction: Option<glm::Vec3>,
+}
+
+/// Loads the default scene of a `.gltf` or `.glb` file, with its external or embedded buffers
+/// and images. The node transforms are applied to the meshes, or meshes which several nodes share
+/// are imported once and placed by instances. The materials are converted to
+/// `Material::Principled`. Features the renderer doesn't support, such as animations or alpha
+/// blending, are reported as warnings and skipped.
+///
+/// Light intensities are used as they are, in the renderer's units.
+pub fn load_gltf(path: &Path) -> Result<GltfScene, GltfImportError> {
+    let gltf = gltf::Gltf::open(path).map_err(|source| GltfImportError::Load { source })?;
+
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let buffers = load_buffers(&gltf, base_dir)?;
+
+    let mut importer = Importer {
+        path,
+        base_dir,
+        buffers: &buffers,
+        meshes: Vec::new(),
+        instances: Vec::new(),
+        mesh_uses: HashMap::new(),
+        instanced_meshes: HashMap::new(),
+        spheres: Vec::new(),
+        materials: Vec::new(),
+        material_indices: HashMap::new(),
+        camera: None,
+        sun_direction: None,
+    };
+
+    for extension in gltf.extensions_used() {
+        if !SUPPORTED_EXTENSIONS.contains(&extension) {
+            importer.warn(format!(
+                "extension {extension} is not supported and is ignored"
+            ));
+        }
+    }
+
+    if gltf.animations().next().is_some() || gltf.skins().next().is_some() {
+        importer.warn("animations and skins are not supported, the meshes are in their rest pose");
+    }
+
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or(GltfImportError::NoScene)?;
+
+    for node in scene.nodes() {
+        count_mesh_uses(&node, &mut importer.mesh_uses);
+    }
+
+    for node in scene.nodes() {
+        importer.import_node(&node, &glm::Mat4::identity())?;
+    }
+
+    let camera = match importer.camera {
+        Some(camera) => camera,
+        None => framing_camera(&importer.meshes, &importer.instances),
+    };
+
+    Ok(GltfScene {
+        meshes: importer.meshes,
+        instances: importer.instances,
+        spheres: importer.spheres,
+        materials: importer.materials,
+        camera,
+        sun_direction: importer.sun_direction,
+    })
+}
+
+struct Importer<'a> {
+    path: &'a Path,
+    base_dir: &'a Path,
+    buffers: &'a [Vec<u8>],
+    meshes: Vec<Mesh>,
+    instances: Vec<Instance>,
+    /// How many nodes of the scene use each glTF mesh.
+    mesh_uses: HashMap<usize, u32>,
+    /// The meshes each shared glTF mesh was imported into, in its own space.
+    instanced_meshes: HashMap<usize, Range<u32>>,
+    spheres: Vec<Sphere>,
+    materials: Vec<Material>,
+    /// Maps the glTF material index, or `None` for the default material, to the scene's.
+    material_indices: HashMap<Option<usize>, u32>,
+    camera: Option<FlyCameraController>,
+    sun_direction: Option<glm::Vec3>,
+}
+
+impl Importer<'_> {
+    fn warn(
+        &self,
+        message: impl Display,
+    ) {
+        eprintln!("Warning: {}: {message}", self.path.display());
+    }
+
+    fn import_node(
+        &mut self,
+        node: &gltf::Node<'_>,
+        parent_transform: &glm::Mat4,
+    ) -> Result<(), GltfImportError> {
+        let transform = parent_transform * glm::make_mat4(&node.transform().matrix().concat());
+
+        if let Some(mesh) = node.mesh() {
+            if self.mesh_uses.get(&mesh.index()).copied().unwrap_or(0_u32) > 1_u32 {
+                self.instance_mesh(&mesh, &transform)?;
+            } else {
+                self.import_mesh(&mesh, &transform)?;
+            }
+        }
+
+        if let Some(camera) = node.camera() {
+            if self.camera.is_none() {
+                self.import_camera(&camera, &transform);
+            }
+        }
+
+        if let Some(light) = node.light() {
+            self.import_light(&light, &transform);
+        }
+
+        for child in node.children() {
+            self.import_node(&child, &transform)?;
+        }
+
+        Ok(())
+    }
+
+    /// Imports a mesh which several nodes share the first time, and places it with an instance.
+    fn instance_mesh(
+        &mut self,
+        mesh: &gltf::Mesh<'_>,
+        transform: &glm::Mat4,
+    ) -> Result<(), GltfImportError> {
+        let meshes = match self.instanced_meshes.get(&mesh.index()) {
+            Some(meshes) => meshes.clone(),
+            None => {
+                let first_mesh = self.meshes.len() as u32;
+
+                self.import_mesh(mesh, &glm::Mat4::identity())?;
+
+                let meshes = first_mesh..self.meshes.len() as u32;
+
+                self.instanced_meshes.insert(mesh.index(), meshes.clone());
+
+                meshes
+            }
+        };
+
+        // A node scaled to nothing is invisible.
+        if let Some(instance) = Instance::new(InstancedObject::Meshes(meshes), *transform) {
+            self.instances.push(instance);
+        }
+
+        Ok(())
+    }
+
+    fn import_mesh(
+        &mut self,
+        mesh: &gltf::Mesh<'_>,
+        transform: &glm::Mat4,
+    ) -> Result<(), GltfImportError> {
+        let linear = glm::mat4_to_mat3(transform);
+
+        let normal_transform = glm::transpose(&glm::inverse(&linear));
+
+        // A mirroring transform turns the triangles inside out.
+        let flip_winding = glm::determinant(&linear) < 0_f32;
+
+        let mesh_name = mesh.name().unwrap_or("unnamed");
+
+        let buffers = self.buffers;
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                self.warn(format_args!(
+                    "mesh `{mesh_name}`: {:?} primitives are not supported and are skipped",
+                    primitive.mode()
+                ));
+
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+
+            let Some(positions) = reader.read_positions() else {
+                self.warn(format_args!(
+                    "mesh `{mesh_name}`: a primitive without positions is skipped"
+                ));
+
+                continue;
+            };
+
+            let positions: Vec<glm::Vec3> = positions
+                .map(|p| (transform * glm::vec4(p[0], p[1], p[2], 1_f32)).xyz())
+                .collect();
+
+            let normals: Vec<glm::Vec3> = reader
+                .read_normals()
+                .map(|normals| {
+                    normals
+                        .map(|n| glm::normalize(&(normal_transform * glm::make_vec3(&n))))
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            // glTF's uv origin is in the top left corner, the renderer's in the bottom left.
+            let uvs: Vec<glm::Vec2> = reader
+                .read_tex_coords(0)
+                .map(|uvs| {
+                    uvs.into_f32()
+                        .map(|[u, v]| glm::vec2(u, 1_f32 - v))
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+
+            // Malformed files can index past the vertices, or give other attributes for them.
+            if let Some(index) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+                self.warn(format_args!(
+                    "mesh `{mesh_name}`: a primitive whose index {index} is out of range of its {} \
+                     vertices is skipped",
+                    positions.len()
+                ));
+
+                continue;
+            }
+
+            if [normals.len(), uvs.len()]
+                .iter()
+                .any(|&len| len != 0 && len != positions.len())
+            {
+                self.warn(format_args!(
+                    "mesh `{mesh_name}`: a primitive whose normals or uvs don't match its \
+                     positions is skipped"
+                ));
+
+                continue;
+            }
+
+            let triangles = indices
+                .chunks_exact(3)
+                .map(|t| {
+                    if flip_winding {
+                        [t[0], t[2], t[1]]
+                    } else {
+                        [t[0], t[1], t[2]]
+                    }
+                })
+                .collect();
+
+            let material_idx = self.material_index(&primitive.material())?;
+
+            self.meshes
+                .push(Mesh::new(positions, normals, uvs, triangles, material_idx));
+        }
+
+        Ok(())
+    }
+
+    fn import_camera(
+        &mut self,
+        camera: &gltf::Camera<'_>,
+        transform: &glm::Mat4,
+    ) {
+        match camera.projection() {
+            Projection::Perspective(perspective) => {
+                // glTF cameras look down their local -z axis.
+                let forward =
+                    glm::normalize(&(transform * glm::vec4(0_f32, 0_f32, -1_f32, 0_f32)).xyz());
+
+                self.camera = Some(FlyCameraController {
+                    position: transform.column(3).xyz(),
+                    yaw: Angle::radians(forward.z.atan2(forward.x)),
+                    pitch: Angle::radians(forward.y.clamp(-1_f32, 1_f32).asin()),
+                    vfov_degrees: perspective.yfov().to_degrees(),
+                    aperture: 0_f32,
+                    ..Default::default()
+                });
+            }
+            Projection::Orthographic(_) => {
+                self.warn("orthographic cameras are not supported and are skipped");
+            }
+        }
+    }
+
+    fn import_light(
+        &mut self,
+        light: &gltf::khr_lights_punctual::Light<'_>,
+        transform: &glm::Mat4,
+    ) {
+        let position = transform.column(3).xyz();
+
+        match light.kind() {
+            Kind::Directional => {
+                if self.sun_direction.is_some() {
+                    self.warn("only the first directional light is used as the sun");
+
+                    return;
+                }
+
+                // The light shines down its local -z axis.
+                let towards_light = (transform * glm::vec4(0_f32, 0_f32, 1_f32, 0_f32)).xyz();
+
+                self.sun_direction = Some(glm::normalize(&towards_light));
+
+                self.warn(format_args!(
+                    "the directional light's color {:?} and intensity {} are ignored, the sun's \
+                     follow from the sky's turbidity and the sun's elevation",
+                    light.color(),
+                    light.intensity()
+                ));
+            }
+            Kind::Point => self.add_point_light(position, light.color(), light.intensity()),
+            Kind::Spot { .. } => {
+                self.warn(
+                    "spot light cones are not supported, spot lights shine in all directions",
+                );
+
+                self.add_point_light(position, light.color(), light.intensity());
+            }
+        }
+    }
+
+    /// A sphere whose radiance makes it as bright, seen from afar, as a light of the given
+    /// intensity.
+    fn add_point_light(
+        &mut self,
+        position: glm::Vec3,
+        color: [f32; 3],
+        intensity: f32,
+    ) {
+        self.materials.push(Material::Emissive {
+            radiance: Texture::new_from_color(glm::make_vec3(&color)),
+            strength: intensity / (std::f32::consts::PI * POINT_LIGHT_RADIUS * POINT_LIGHT_RADIUS),
+        });
+
+        self.spheres.push(Sphere::new(
+            position,
+            POINT_LIGHT_RADIUS,
+            self.materials.len() as u32 - 1_u32,
+        ));
+    }
+
+    fn material_index(
+        &mut self,
+        material: &gltf::Material<'_>,
+    ) -> Result<u32, GltfImportError> {
+        if let Some(&idx) = self.material_indices.get(&material.index()) {
+            return Ok(idx);
+        }
+
+        let converted = self.import_material(material)?;
+
+        self.materials.push(converted);
+
+        let idx = self.materials.len() as u32 - 1_u32;
+
+        self.material_indices.insert(material.index(), idx);
+
+        Ok(idx)
+    }
+
+    fn import_material(
+        &self,
+        material: &gltf::Material<'_>,
+    ) -> Result<Material, GltfImportError> {
+        let name = material.name().unwrap_or("unnamed");
+
+        if material.alpha_mode() != AlphaMode::Opaque {
+            self.warn(format_args!(
+                "material `{name}`: alpha mode {:?} is not supported, the material is opaque",
+                material.alpha_mode()
+            ));
+        }
+
+        // The occlusion texture is left out, the path tracer finds the occlusion itself.
+        let pbr = material.pbr_metallic_roughness();
+
+        let [r, g, b, _] = pbr.base_color_factor();
+
+        let base_color = match pbr.base_color_texture() {
+            Some(info) => self
+                .load_texture(name, &info.texture(), info.tex_coord(), ColorSpace::Srgb)?
+                .scaled([r, g, b]),
+            None => Texture::new_from_color(glm::vec3(r, g, b)),
+        };
+
+        let metallic_roughness = match pbr.metallic_roughness_texture() {
+            Some(info) => Some(self.load_texture(
+                name,
+                &info.texture(),
+                info.tex_coord(),
+                ColorSpace::Data,
+            )?),
+            None => None,
+        };
+
+        let normal_map = match material.normal_texture() {
+            Some(normal) => Some(NormalMap::Normal {
+                texture: self.load_texture(
+                    name,
+                    &normal.texture(),
+                    normal.tex_coord(),
+                    ColorSpace::Data,
+                )?,
+                scale: normal.scale(),
+            }),
+            None => None,
+        };
+
+        let strength = material.emissive_strength().unwrap_or(1_f32);
+
+        let emissive_factor = material.emissive_factor().map(|c| c * strength);
+
+        let emission = if emissive_factor.iter().any(|&c| c > 0_f32) {
+            Some(match material.emissive_texture() {
+                Some(info) => self
+                    .load_texture(name, &info.texture(), info.tex_coord(), ColorSpace::Srgb)?
+                    .scaled(emissive_factor),
+                None => Texture::new_from_color(glm::make_vec3(&emissive_factor)),
+            })
+        } else {
+            None
+        };
+
+        Ok(Material::Principled {
+            base_color,
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            metallic_roughness,
+            ior: material.ior().unwrap_or(1.5_f32),
+            emission,
+            normal_map,
+        })
+    }
+
+    fn load_texture(
+        &self,
+        material_name: &str,
+        texture: &gltf::Texture<'_>,
+        tex_coord: u32,
+        color_space: ColorSpace,
+    ) -> Result<Texture, GltfImportError> {
+        if tex_coord != 0 {
+            self.warn(format_args!(
+                "material `{material_name}`: only the first uv set is supported"
+            ));
+        }
+
+        let loaded = match texture.source().source() {
+            Source::View { view, .. } => {
+                let bytes = self.buffers[view.buffer().index()]
+                    .get(view.offset()..view.offset() + view.length())
+                    .ok_or(GltfImportError::BufferLength {
+                        index: view.buffer().index(),
+                    })?;
+
+                Texture::new_from_memory(bytes, color_space)
+            }
+            Source::Uri { uri, .. } => match data_uri_bytes(uri)? {
+                Some(bytes) => Texture::new_from_memory(&bytes, color_space),
+                None => Texture::new_from_image(self.base_dir.join(uri_path(uri)), color_space),
+            },
+        }
+        .map_err(|source| GltfImportError::Texture {
+            material: material_name.to_owned(),
+            source,
+        })?;
+
+        let sampler = texture.sampler();
+
+        if sampler.wrap_s() != sampler.wrap_t() {
+            self.warn(format_args!(
+                "material `{material_name}`: a texture wraps differently in u and v, the u mode is used"
+            ));
+        }
+
+        Ok(loaded.with_sampler(TextureSampler {
+            wrap: match sampler.wrap_s() {
+                WrappingMode::ClampToEdge => WrapMode::Clamp,
+                WrappingMode::MirroredRepeat => WrapMode::Mirror,
+                WrappingMode::Repeat => WrapMode::Repeat,
+            },
+            filter: match sampler.mag_filter() {
+                Some(MagFilter::Nearest) => FilterMode::Nearest,
+                _ => FilterMode::Bilinear,
+            },
+            mipmaps: !matches!(
+                sampler.min_filter(),
+                Some(MinFilter::Nearest | MinFilter::Linear)
+            ),
+        }))
+    }
+}
+
+/// Reads the binary chunk of a `.glb` file, and the data URIs and files of the other buffers.
+fn load_buffers(
+    gltf: &gltf::Gltf,
+    base_dir: &Path,
+) -> Result<Vec<Vec<u8>>, GltfImportError> {
+    let mut buffers = Vec::new();
+
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf.blob.clone().unwrap_or_default(),
+            gltf::buffer::Source::Uri(uri) => match data_uri_bytes(uri)? {
+                Some(bytes) => bytes,
+                None => {
+                    let path = base_dir.join(uri_path(uri));
+
+                    std::fs::read(&path).map_err(|source| GltfImportError::Io { path, source })?
+                }
+            },
+        };
+
+        if data.len() < buffer.length() {
+            return Err(GltfImportError::BufferLength {
+                index: buffer.index(),
+            });
+        }
+
+        buffers.push(data);
+    }
+
+    Ok(buffers)
+}
+
+/// Decodes the contents of a `data:` URI, or returns `None` for any other URI.
+fn data_uri_bytes(uri: &str) -> Result<Option<Vec<u8>>, GltfImportError> {
+    let Some(data) = uri.strip_prefix("data:") else {
+        return Ok(None);
+    };
+
+    let (_, encoded) = data
+        .split_once(";base64,")
+        .ok_or(GltfImportError::InvalidDataUri)?;
+
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map(Some)
+        .map_err(|_| GltfImportError::InvalidDataUri)
+}
+
+/// URIs of files are relative paths, with special characters percent encoded.
+fn uri_path(uri: &str) -> PathBuf {
+    match urlencoding::decode(uri) {
+        Ok(decoded) => PathBuf::from(decoded.into_owned()),
+        Err(_) => PathBuf::from(uri),
+    }
+}
+
+/// Counts the nodes which use each mesh, in the node and its descendants.
+fn count_mesh_uses(
+    node: &gltf::Node<'_>,
+    mesh_uses: &mut HashMap<usize, u32>,
+) {
+    if let Some(mesh) = node.mesh() {
+        *mesh_uses.entry(mesh.index()).or_insert(0_u32) += 1_u32;
+    }
+
+    for child in node.children() {
+        count_mesh_uses(&child, mesh_uses);
+    }
+}
+
+/// Looks at the bounds of the meshes from the default camera's direction, so that all of them
+/// are in view.
+fn framing_camera(
+    meshes: &[Mesh],
+    instances: &[Instance],
+) -> FlyCameraController {
+    let mut is_instanced = vec![false; meshes.len()];
+
+    let mut positions = Vec::new();
+
+    // Instanced meshes are in their own space, they are framed where their instances place them.
+    for instance in instances {
+        if let InstancedObject::Meshes(range) = &instance.object {
+            let range = range.start as usize..range.end as usize;
+
+            is_instanced[range.clone()].fill(true);
+
+            positions.extend(meshes[range].iter().flat_map(|mesh| {
+                mesh.positions
+                    .iter()
+                    .map(|p| (instance.transform() * glm::vec4(p.x, p.y, p.z, 1_f32)).xyz())
+            }));
+        }
+    }
+
+    positions.extend(
+        meshes
+            .iter()
+            .zip(is_instanced)
+            .filter(|(_, is_instanced)| !is_instanced)
+            .flat_map(|(mesh, _)| mesh.positions.iter().copied()),
+    );
+
+    let Some(first) = positions.first() else {
+        return FlyCameraController::default();
+    };
+
+    let (min, max) = positions.iter().fold((*first, *first), |(min, max), p| {
+        (glm::min2(&min, p), glm::max2(&max, p))
+    });
+
+    let center = 0.5_f32 * (min + max);
+
+    let radius = (0.5_f32 * glm::distance(&min, &max)).max(1e-3_f32);
+
+    let camera = FlyCameraController {
+        aperture: 0_f32,
+        ..Default::default()
+    };
+
+    let distance = radius
+        / Angle::degrees(0.5_f32 * camera.vfov_degrees)
+            .as_radians()
+            .sin();
+
+    FlyCameraController {
+        position: center - distance * camera_orientation(&camera).forward,
+        focus_distance: distance,
+        ..camera
+    }
+}
+
+#[derive(Error, Debug)]
+
+pub enum GltfImportError {
+    #[error("{source}")]
+    Load { source: gltf::Error },
+    #[error("{}: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("buffer {index} is shorter than the data it holds")]
+    BufferLength { index: usize },
+    #[error("invalid data URI, only base64 encoded data is supported")]
+    InvalidDataUri,
+    #[error("material `{material}`: failed to load texture {source}")]
+    Texture {
+        material: String,
+        source: TextureError,
+    },
+    #[error("the file has no scenes")]
+    NoScene,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where a test writes its glTF file, apart from other tests and from other test runs.
+    fn test_path(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "gltf_import_test_{}_{test_name}",
+            std::process::id()
+        ));
+
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir.join("scene.gltf")
+    }
+
+    /// A triangle moved up by its node, under a point light, seen by a camera.
+    fn triangle_gltf() -> String {
+        let positions: [f32; 9] = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+
+        let data = base64::engine::general_purpose::STANDARD
+            .encode(bytemuck::cast_slice::<f32, u8>(&positions));
+
+        format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "extensionsUsed": ["KHR_lights_punctual"],
+                "extensions": {{
+                    "KHR_lights_punctual": {{
+                        "lights": [{{ "type": "point", "color": [1.0, 0.5, 0.25], "intensity": 2.0 }}]
+                    }}
+                }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0, 1, 2] }}],
+                "nodes": [
+                    {{ "mesh": 0, "translation": [0.0, 2.0, 0.0] }},
+                    {{ "camera": 0, "translation": [0.0, 0.0, 5.0] }},
+                    {{ "extensions": {{ "KHR_lights_punctual": {{ "light": 0 }} }}, "translation": [1.0, 3.0, 0.0] }}
+                ],
+                "cameras": [{{ "type": "perspective", "perspective": {{ "yfov": 0.5, "znear": 0.1 }} }}],
+                "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "material": 0 }}] }}],
+                "materials": [{{
+                    "pbrMetallicRoughness": {{ "baseColorFactor": [0.5, 0.25, 1.0, 1.0], "metallicFactor": 0.0 }}
+                }}],
+                "accessors": [{{
+                    "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                    "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+                }}],
+                "bufferViews": [{{ "buffer": 0, "byteLength": 36 }}],
+                "buffers": [{{ "byteLength": 36, "uri": "data:application/octet-stream;base64,{data}" }}]
+            }}"#
+        )
+    }
+
+    #[test]
+    fn test_load_gltf_scene() {
+        let path = test_path("triangle");
+
+        std::fs::write(&path, triangle_gltf()).unwrap();
+
+        let scene = load_gltf(&path).unwrap();
+
+        assert_eq!(scene.meshes.len(), 1);
+
+        assert_eq!(scene.meshes[0].positions[2], glm::vec3(0_f32, 3_f32, 0_f32));
+
+        // The mesh node is visited before the light node, so the mesh's material comes first and
+        // the light's emissive material second.
+        assert_eq!(scene.materials.len(), 2);
+
+        assert_eq!(scene.meshes[0].material_idx, 0);
+
+        assert!(matches!(scene.materials[1], Material::Emissive { .. }));
+
+        assert_eq!(scene.spheres.len(), 1);
+
+        match &scene.materials[scene.meshes[0].material_idx as usize] {
+            Material::Principled {
+                base_color,
+                metallic,
+                roughness,
+                ..
+            } => {
+                assert_eq!(base_color.as_slice()[0], [0.5_f32, 0.25_f32, 1_f32]);
+
+                assert_eq!((*metallic, *roughness), (0_f32, 1_f32));
+            }
+            _ => panic!("expected a principled material"),
+        }
+
+        // The camera looks down -z from its position.
+        assert_eq!(scene.camera.position, glm::vec3(0_f32, 0_f32, 5_f32));
+
+        assert!((scene.camera.yaw.as_degrees() + 90_f32).abs() < 1e-4_f32);
+
+        assert!((scene.camera.vfov_degrees - 0.5_f32.to_degrees()).abs() < 1e-4_f32);
+    }
+
+    #[test]
+    fn test_out_of_range_indices_are_skipped() {
+        let path = test_path("out_of_range_indices");
+
+        // The positions are followed by the indices 0, 1 and 5, and two bytes of padding.
+        let indices = [0_u16, 1_u16, 5_u16, 0_u16];
+
+        let mut bytes = bytemuck::cast_slice::<f32, u8>(&[0_f32; 9]).to_vec();
+
+        bytes.extend(bytemuck::cast_slice::<u16, u8>(&indices));
+
+        let data = base64::engine::general_purpose::STANDARD.encode(bytes);
+
+        let gltf = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scenes": [{{ "nodes": [0] }}],
+                "nodes": [{{ "mesh": 0 }}],
+                "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1 }}] }}],
+                "accessors": [
+                    {{
+                        "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                        "min": [0.0, 0.0, 0.0], "max": [0.0, 0.0, 0.0]
+                    }},
+                    {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+                ],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }}
+                ],
+                "buffers": [{{ "byteLength": 44, "uri": "data:application/octet-stream;base64,{data}" }}]
+            }}"#
+        );
+
+        std::fs::write(&path, gltf).unwrap();
+
+        let scene = load_gltf(&path).unwrap();
+
+        assert!(scene.meshes.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_data_uri() {
+        assert!(matches!(
+            data_uri_bytes("data:text/plain,hello"),
+            Err(GltfImportError::InvalidDataUri)
+        ));
+
+        assert!(matches!(data_uri_bytes("buffer.bin"), Ok(None)));
+    }
+
+    #[test]
+    fn test_shared_mesh_is_instanced() {
+        let path = test_path("instances");
+
+        let gltf = triangle_gltf()
+            .replace(r#""nodes": [0, 1, 2]"#, r#""nodes": [0, 1, 2, 3]"#)
+            .replace(
+                r#""translation": [1.0, 3.0, 0.0] }"#,
+                r#""translation": [1.0, 3.0, 0.0] },
+                    { "mesh": 0, "translation": [4.0, 0.0, 0.0] }"#,
+            );
+
+        std::fs::write(&path, gltf).unwrap();
+
+        let scene = load_gltf(&path).unwrap();
+
+        // The mesh is imported once in its own space, and placed by one instance per node.
+        assert_eq!(scene.meshes.len(), 1);
+
+        assert_eq!(scene.meshes[0].positions[2], glm::vec3(0_f32, 1_f32, 0_f32));
+
+        assert_eq!(scene.instances.len(), 2);
+
+        assert_eq!(scene.instances[0].object, InstancedObject::Meshes(0..1));
+
+        let origin = scene.instances[1].transform() * glm::vec4(0_f32, 0_f32, 0_f32, 1_f32);
+
+        assert_eq!(origin, glm::vec4(4_f32, 0_f32, 0_f32, 1_f32));
+    }
+}
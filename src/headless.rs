@@ -0,0 +1,170 @@
+use std::time::Instant;
+
+use thiserror::Error;
+
+use crate::{
+    cli::HeadlessOptions,
+    raytracer::{DeviceLimitsError, ImageExportError, Raytracer, RaytracerError, RenderParams},
+    scene_file::LoadedScene,
+};
+
+/// Nothing is displayed, but the raytracer always creates a display pipeline for some format.
+const TARGET_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Renders the scene without a window until `max_samples_per_pixel` samples have been
+/// accumulated, and writes the image to the output path.
+pub fn render(
+    options: &HeadlessOptions,
+    loaded_scene: LoadedScene,
+) -> Result<(), HeadlessError> {
+    let LoadedScene {
+        scene,
+        camera,
+        sky,
+        mut sampling,
+        display,
+    } = loaded_scene;
+
+    if let Some(samples_per_pixel) = options.samples_per_pixel {
+        sampling.max_samples_per_pixel = samples_per_pixel;
+    }
+
+    if let Some(num_bounces) = options.num_bounces {
+        sampling.num_bounces = num_bounces;
+    }
+
+    // Take as many samples per frame as the scene asks for, as long as they add up to exactly
+    // max_samples_per_pixel.
+    sampling.num_samples_per_pixel = (1_u32..=sampling.num_samples_per_pixel)
+        .rev()
+        .find(|n| sampling.max_samples_per_pixel % n == 0_u32)
+        .unwrap_or(1_u32);
+
+    let (device, queue, adapter_info) =
+        pollster::block_on(request_device(options.force_software_adapter))?;
+
+    eprintln!(
+        "Using adapter: {} ({:?}, {:?})",
+        adapter_info.name, adapter_info.device_type, adapter_info.backend
+    );
+
+    let (width, height) = options.resolution;
+
+    {
+        let max_dimension = device.limits().max_texture_dimension_2d;
+
+        if width > max_dimension || height > max_dimension {
+            return Err(HeadlessError::ResolutionTooLarge(width, height));
+        }
+    }
+
+    let render_params = RenderParams {
+        camera: camera.renderer_camera(),
+        sky,
+        sampling,
+        display,
+        viewport_size: options.resolution,
+    };
+
+    let mut raytracer = Raytracer::new(&device, &queue, TARGET_FORMAT, &scene, &render_params)?;
+
+    let start_time = Instant::now();
+
+    let num_frames = sampling.max_samples_per_pixel / sampling.num_samples_per_pixel;
+
+    for _ in 0..num_frames {
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+        raytracer.trace_frame(&queue, &mut encoder);
+
+        queue.submit(Some(encoder.finish()));
+
+        // Wait for every frame, so that a slow software adapter never has more than one frame of
+        // work queued up, and the progress below is accurate.
+        device.poll(wgpu::Maintain::Wait);
+
+        eprint!("\rRendering: {:.1} %", raytracer.progress() * 100.0);
+    }
+
+    eprintln!(" ({:.2} s)", start_time.elapsed().as_secs_f32());
+
+    if display.auto_exposure.enabled {
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+        // Without adapting over time, the exposure is metered from the finished image alone.
+        raytracer.update_exposure(&device, &queue, &mut encoder, f32::INFINITY);
+
+        queue.submit(Some(encoder.finish()));
+    }
+
+    let image = raytracer.read_image(&device, &queue)?;
+
+    image.save(&options.output_path)?;
+
+    eprintln!("Wrote {}", options.output_path.display());
+
+    Ok(())
+}
+
+async fn request_device(
+    force_software_adapter: bool
+) -> Result<(wgpu::Device, wgpu::Queue, wgpu::AdapterInfo), HeadlessError> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..Default::default()
+    });
+
+    let adapter_options = |force_fallback_adapter| wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        force_fallback_adapter,
+        compatible_surface: None,
+    };
+
+    // On machines without a GPU, software adapters such as lavapipe may only be returned when
+    // explicitly asking for a fallback adapter.
+    let adapter = match instance
+        .request_adapter(&adapter_options(force_software_adapter))
+        .await
+    {
+        Some(adapter) => adapter,
+        None if !force_software_adapter => instance
+            .request_adapter(&adapter_options(true))
+            .await
+            .ok_or(HeadlessError::NoAdapter)?,
+        None => return Err(HeadlessError::NoAdapter),
+    };
+
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                features: wgpu::Features::empty(),
+                limits: Raytracer::device_limits(&adapter.limits())?,
+                label: None,
+            },
+            None,
+        )
+        .await?;
+
+    Ok((device, queue, adapter.get_info()))
+}
+
+#[derive(Error, Debug)]
+
+pub enum HeadlessError {
+    #[error("no graphics adapter found, install a Vulkan driver or a software one such as lavapipe")]
+    NoAdapter,
+    #[error("failed to create the device: {0}")]
+    RequestDevice(#[from] wgpu::RequestDeviceError),
+    #[error(transparent)]
+    DeviceLimits(#[from] DeviceLimitsError),
+    #[error("the resolution {0}x{1} exceeds the adapter's limits")]
+    ResolutionTooLarge(u32, u32),
+    #[error(transparent)]
+    Raytracer(#[from] RaytracerError),
+    #[error("failed to read back the image: {0}")]
+    Readback(#[from] wgpu::BufferAsyncError),
+    #[error(transparent)]
+    Export(#[from] ImageExportError),
+}
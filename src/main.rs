@@ -5,22 +5,72 @@
     nonstandard_style
 )]
 
+mod cli;
 mod fly_camera;
+mod gltf_import;
+mod headless;
+mod obj_import;
 mod raytracer;
+mod scene_file;
 pub extern crate nalgebra_glm as glm;
 
-use fly_camera::FlyCameraController;
-use raytracer::{
-    Layer, Material, Raytracer, RenderParams, SamplingParams, Scene, SkyParams, Sphere, Texture,
+use cli::Command;
+use raytracer::{DeviceLimitsError, Layer, Metering, Raytracer, RenderParams, SkyMode, Tonemapper};
+use scene_file::LoadedScene;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Instant, SystemTime},
 };
-use std::{collections::VecDeque, time::Instant};
+use thiserror::Error;
 use winit::{
-    event::{Event, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::{Window, WindowBuilder},
 };
 
 fn main() {
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+
+            return;
+        }
+        Err(e) => {
+            eprintln!("Error: {e}\n\n{}", cli::USAGE);
+
+            std::process::exit(2);
+        }
+    };
+
+    let loaded_scene = match scene_file::load(&options.scene_path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error loading scene: {e}");
+
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(headless_options) = &options.headless {
+        if let Err(e) = headless::render(headless_options, loaded_scene) {
+            eprintln!("Error rendering headless: {e}");
+
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let LoadedScene {
+        scene,
+        camera: mut fly_camera_controller,
+        sky,
+        sampling,
+        display,
+    } = loaded_scene;
+
     let event_loop = EventLoop::new();
 
     let window = WindowBuilder::new()
@@ -29,43 +79,42 @@ fn main() {
         .build(&event_loop)
         .unwrap();
 
-    let mut context = pollster::block_on(GpuContext::new(&window));
+    let mut context = match pollster::block_on(GpuContext::new(&window)) {
+        Ok(context) => context,
+        Err(e) => {
+            eprintln!("Error creating the device: {e}");
 
-    let viewport_size = {
-        let viewport = window.inner_size();
-
-        (viewport.width, viewport.height)
+            std::process::exit(1);
+        }
     };
 
-    let max_viewport_resolution = window
-        .available_monitors()
-        .map(|monitor| -> u32 {
-            let size = monitor.size();
-
-            size.width * size.height
-        })
-        .max()
-        .expect("There should be at least one monitor available");
-
-    let scene = scene();
+    // The image is traced at a fraction of the window's resolution, and scaled up to fill it.
+    let mut resolution_scale = 1_f32;
 
-    let mut fly_camera_controller = FlyCameraController::default();
+    let viewport_size = render_resolution(window.inner_size(), resolution_scale);
 
     let mut render_params = RenderParams {
         camera: fly_camera_controller.renderer_camera(),
-        sky: SkyParams::default(),
-        sampling: SamplingParams::default(),
+        sky,
+        sampling,
+        display,
         viewport_size,
     };
 
-    let mut raytracer = Raytracer::new(
+    let mut raytracer = match Raytracer::new(
         &context.device,
-        &context.surface_config,
+        &context.queue,
+        context.surface_config.format,
         &scene,
         &render_params,
-        max_viewport_resolution,
-    )
-    .expect("The default values should be selected correctly");
+    ) {
+        Ok(raytracer) => raytracer,
+        Err(e) => {
+            eprintln!("Error creating the raytracer: {e}");
+
+            std::process::exit(1);
+        }
+    };
 
     let mut imgui = imgui::Context::create();
 
@@ -110,18 +159,38 @@ fn main() {
 
     let mut last_time = Instant::now();
 
+    // The duration of the latest frame, which the exposure adapts over when it is redrawn.
+    let mut frame_dt = 0_f32;
+
     let mut fps_counter = FpsCounter::new();
 
+    let mut save_image_requested = false;
+
+    let mut save_image_status: Option<String> = None;
+
     // HACK: imgui layers
 
     let mut layer = Layer::new(
         [viewport_size.0 as f32, viewport_size.1 as f32],
         &render_params,
+        &scene,
+        &context.device.limits(),
     );
 
-    if layer.set_global_data() {
-        layer.set_data(&render_params);
-        layer.register_texture(&context.device, &context.queue, &mut imgui_renderer);
+    // The path tracer doesn't need the preview, so it is left out if its textures don't load.
+    let mut layer = match layer.set_global_data() {
+        Ok(true) => {
+            layer.set_data(&render_params);
+            layer.register_texture(&context.device, &context.queue, &mut imgui_renderer);
+
+            Some(layer)
+        }
+        Ok(false) => Some(layer),
+        Err(e) => {
+            eprintln!("Error loading the preview's textures, disabling the preview: {e}");
+
+            None
+        }
     };
 
     event_loop.run(move |event, _, _control_flow| {
@@ -136,10 +205,22 @@ fn main() {
                         *_control_flow = ControlFlow::Exit;
                     }
 
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::F12),
+                                ..
+                            },
+                        ..
+                    } => {
+                        save_image_requested = true;
+                    }
+
                     WindowEvent::Resized(physical_size) => {
                         if physical_size.width > 0 && physical_size.height > 0 {
                             render_params.viewport_size =
-                                (physical_size.width, physical_size.height);
+                                render_resolution(physical_size, resolution_scale);
 
                             context.surface_config.width = physical_size.width;
 
@@ -156,7 +237,7 @@ fn main() {
                     WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                         if new_inner_size.width > 0 && new_inner_size.height > 0 {
                             render_params.viewport_size =
-                                (new_inner_size.width, new_inner_size.height);
+                                render_resolution(*new_inner_size, resolution_scale);
 
                             context.surface_config.width = new_inner_size.width;
 
@@ -182,6 +263,8 @@ fn main() {
 
                     fps_counter.update(dt);
 
+                    frame_dt = dt;
+
                     fly_camera_controller.after_events(render_params.viewport_size, 2.0 * dt);
 
                     imgui.io_mut().update_delta_time(now - last_time);
@@ -191,12 +274,14 @@ fn main() {
 
                 render_params.camera = fly_camera_controller.renderer_camera();
 
-                match raytracer.set_render_params(&context.queue, &render_params) {
+                match raytracer.set_render_params(&context.device, &context.queue, &render_params) {
                     Err(e) => {
                         eprintln!("Error setting render params: {e}")
                     }
                     _ => {
-                        layer.update_camera(&render_params);
+                        if let Some(layer) = layer.as_mut() {
+                            layer.update_camera(&render_params);
+                        }
                     }
                 }
 
@@ -205,10 +290,12 @@ fn main() {
                         .prepare_frame(imgui.io_mut(), &window)
                         .expect("WinitPlatform::prepare_frame failed");
 
+                    let window_size = window.inner_size();
+
                     let ui = imgui.frame();
 
                     // HACK:
-                    {
+                    if let Some(layer) = layer.as_mut() {
                         layer.render_draw_list(ui, &render_params);
                     }
 
@@ -224,6 +311,16 @@ fn main() {
                                     raytracer.progress() * 100.0
                                 ));
 
+                                if ui.button("Save image (F12)") {
+                                    save_image_requested = true;
+                                }
+
+                                if let Some(status) = &save_image_status {
+                                    ui.same_line();
+
+                                    ui.text(status);
+                                }
+
                                 ui.separator();
 
                                 ui.text("Sampling parameters");
@@ -287,6 +384,21 @@ fn main() {
                                     &mut render_params.sampling.num_bounces,
                                 );
 
+                                if ui.slider(
+                                    "resolution scale",
+                                    0.25_f32,
+                                    1_f32,
+                                    &mut resolution_scale,
+                                ) {
+                                    render_params.viewport_size =
+                                        render_resolution(window_size, resolution_scale);
+                                }
+
+                                ui.text(format!(
+                                    "render resolution: {}x{}",
+                                    render_params.viewport_size.0, render_params.viewport_size.1
+                                ));
+
                                 ui.separator();
 
                                 ui.text("Camera parameters");
@@ -312,32 +424,214 @@ fn main() {
                                     &mut fly_camera_controller.focus_distance,
                                 );
 
-                                ui.separator();
-
-                                ui.text("Sky parameters");
-
                                 ui.slider(
-                                    "azimuth",
-                                    0_f32,
-                                    360_f32,
-                                    &mut render_params.sky.azimuth_degrees,
+                                    "shutter open",
+                                    0.0,
+                                    1.0,
+                                    &mut fly_camera_controller.shutter_open,
                                 );
 
                                 ui.slider(
-                                    "inclination",
-                                    0_f32,
-                                    90_f32,
-                                    &mut render_params.sky.zenith_degrees,
+                                    "shutter close",
+                                    0.0,
+                                    1.0,
+                                    &mut fly_camera_controller.shutter_close,
                                 );
 
+                                // The shutter can't close before it opens.
+                                fly_camera_controller.shutter_close = fly_camera_controller
+                                    .shutter_close
+                                    .max(fly_camera_controller.shutter_open);
+
+                                ui.separator();
+
+                                ui.text("Display parameters");
+
+                                for tonemapper in Tonemapper::ALL {
+                                    ui.radio_button(
+                                        tonemapper.name(),
+                                        &mut render_params.display.tonemapper,
+                                        tonemapper,
+                                    );
+                                }
+
                                 ui.slider(
-                                    "turbidity",
-                                    1_f32,
-                                    10_f32,
-                                    &mut render_params.sky.turbidity,
+                                    "exposure (EV)",
+                                    -8_f32,
+                                    8_f32,
+                                    &mut render_params.display.exposure_ev,
                                 );
 
-                                ui.color_edit3("albedo", &mut render_params.sky.albedo);
+                                if matches!(
+                                    render_params.display.tonemapper,
+                                    Tonemapper::ReinhardExtended | Tonemapper::Uncharted2
+                                ) {
+                                    ui.slider(
+                                        "white point",
+                                        1_f32,
+                                        20_f32,
+                                        &mut render_params.display.white_point,
+                                    );
+                                }
+
+                                let auto_exposure = &mut render_params.display.auto_exposure;
+
+                                ui.checkbox("auto exposure", &mut auto_exposure.enabled);
+
+                                if auto_exposure.enabled {
+                                    ui.radio_button(
+                                        "average",
+                                        &mut auto_exposure.metering,
+                                        Metering::Average,
+                                    );
+
+                                    ui.same_line();
+
+                                    ui.radio_button(
+                                        "percentile",
+                                        &mut auto_exposure.metering,
+                                        Metering::Percentile,
+                                    );
+
+                                    if auto_exposure.metering == Metering::Percentile {
+                                        ui.slider(
+                                            "metered percentile",
+                                            0_f32,
+                                            1_f32,
+                                            &mut auto_exposure.percentile,
+                                        );
+                                    }
+
+                                    ui.slider(
+                                        "adaptation rate",
+                                        0_f32,
+                                        10_f32,
+                                        &mut auto_exposure.adaptation_rate,
+                                    );
+
+                                    if let Some(stats) = raytracer.exposure_stats() {
+                                        let histogram = stats.histogram.map(|count| count as f32);
+
+                                        ui.plot_histogram("log luminance", &histogram)
+                                            .graph_size([0_f32, 60_f32])
+                                            .build();
+
+                                        ui.text(format!(
+                                            "auto exposure: {:.2} EV",
+                                            stats.exposure_ev()
+                                        ));
+
+                                        // Keeps the current look as a fixed exposure.
+                                        if ui.button("Lock exposure") {
+                                            render_params.display.exposure_ev +=
+                                                stats.exposure_ev();
+
+                                            render_params.display.auto_exposure.enabled = false;
+                                        }
+                                    }
+                                }
+
+                                ui.separator();
+
+                                ui.text("Sky parameters");
+
+                                if raytracer.has_environment_map() {
+                                    ui.radio_button(
+                                        "Hosek-Wilkie sky",
+                                        &mut render_params.sky.mode,
+                                        SkyMode::HosekWilkie,
+                                    );
+
+                                    ui.same_line();
+
+                                    ui.radio_button(
+                                        "Environment map",
+                                        &mut render_params.sky.mode,
+                                        SkyMode::EnvironmentMap,
+                                    );
+                                }
+
+                                match render_params.sky.mode {
+                                    SkyMode::HosekWilkie => {
+                                        ui.slider(
+                                            "azimuth",
+                                            0_f32,
+                                            360_f32,
+                                            &mut render_params.sky.azimuth_degrees,
+                                        );
+
+                                        ui.slider(
+                                            "inclination",
+                                            0_f32,
+                                            90_f32,
+                                            &mut render_params.sky.zenith_degrees,
+                                        );
+
+                                        ui.slider(
+                                            "turbidity",
+                                            1_f32,
+                                            10_f32,
+                                            &mut render_params.sky.turbidity,
+                                        );
+
+                                        ui.color_edit3("albedo", &mut render_params.sky.albedo);
+                                    }
+                                    SkyMode::EnvironmentMap => {
+                                        ui.slider(
+                                            "rotation",
+                                            0_f32,
+                                            360_f32,
+                                            &mut render_params.sky.environment_rotation_degrees,
+                                        );
+
+                                        ui.slider(
+                                            "intensity",
+                                            0_f32,
+                                            4_f32,
+                                            &mut render_params.sky.environment_intensity,
+                                        );
+                                    }
+                                }
+
+                                let fog = &mut render_params.sky.fog;
+
+                                ui.slider("fog density", 0_f32, 0.5_f32, &mut fog.density);
+
+                                if fog.density > 0_f32 {
+                                    ui.slider(
+                                        "fog height falloff",
+                                        0.1_f32,
+                                        50_f32,
+                                        &mut fog.height_falloff,
+                                    );
+
+                                    ui.slider(
+                                        "fog anisotropy",
+                                        -0.95_f32,
+                                        0.95_f32,
+                                        &mut fog.anisotropy,
+                                    );
+
+                                    ui.color_edit3("fog albedo", &mut fog.albedo);
+                                }
+
+                                ui.separator();
+
+                                ui.text("Scene statistics");
+
+                                let bvh_stats = raytracer.bvh_stats();
+
+                                ui.text(format!("primitives: {}", bvh_stats.primitive_count));
+
+                                ui.text(format!(
+                                    "BVH nodes: {}, leaves: {}, depth: {}",
+                                    bvh_stats.node_count, bvh_stats.leaf_count, bvh_stats.depth
+                                ));
+
+                                ui.text(format!(
+                                    "BVH build time: {:.2} ms",
+                                    bvh_stats.build_time.as_secs_f64() * 1000.0
+                                ));
                             });
                     }
 
@@ -369,6 +663,10 @@ fn main() {
                     .device
                     .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
+                raytracer.trace_frame(&context.queue, &mut encoder);
+
+                raytracer.update_exposure(&context.device, &context.queue, &mut encoder, frame_dt);
+
                 {
                     let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                         color_attachments: &[Some(wgpu::RenderPassColorAttachment {
@@ -388,7 +686,7 @@ fn main() {
                         label: None,
                     });
 
-                    raytracer.render_frame(&context.queue, &mut render_pass);
+                    raytracer.render_frame(&mut render_pass);
 
                     match imgui_renderer.render(
                         imgui.render(),
@@ -403,6 +701,12 @@ fn main() {
 
                 context.queue.submit(Some(encoder.finish()));
 
+                if save_image_requested {
+                    save_image_requested = false;
+
+                    save_image_status = Some(save_image(&context, &raytracer));
+                }
+
                 frame.present();
             }
 
@@ -419,17 +723,13 @@ pub struct GpuContext {
 }
 
 impl GpuContext {
-    async fn new(window: &Window) -> Self {
+    async fn new(window: &Window) -> Result<Self, GpuContextError> {
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
             backends: wgpu::Backends::all(),
             ..Default::default()
         });
 
-        let surface = unsafe {
-            instance
-                .create_surface(window)
-                .expect("Surface creation should succeed on desktop")
-        };
+        let surface = unsafe { instance.create_surface(window)? };
 
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
@@ -438,22 +738,18 @@ impl GpuContext {
                 compatible_surface: Some(&surface),
             })
             .await
-            .expect("Adapter should be available on desktop");
+            .ok_or(GpuContextError::NoAdapter)?;
 
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     features: wgpu::Features::empty(),
-                    limits: wgpu::Limits {
-                        max_storage_buffer_binding_size: 512_u32 << 20,
-                        ..Default::default()
-                    },
+                    limits: Raytracer::device_limits(&adapter.limits())?,
                     label: None,
                 },
                 None,
             )
-            .await
-            .expect("Device should be available on desktop");
+            .await?;
 
         let window_size = window.inner_size();
 
@@ -472,15 +768,77 @@ impl GpuContext {
 
         surface.configure(&device, &surface_config);
 
-        Self {
+        Ok(Self {
             device,
             queue,
             surface,
             surface_config,
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+
+pub enum GpuContextError {
+    #[error("failed to create the window's surface: {0}")]
+    CreateSurface(#[from] wgpu::CreateSurfaceError),
+    #[error("no graphics adapter can draw to the window")]
+    NoAdapter,
+    #[error("failed to create the device: {0}")]
+    RequestDevice(#[from] wgpu::RequestDeviceError),
+    #[error(transparent)]
+    DeviceLimits(#[from] DeviceLimitsError),
+}
+
+/// Saves the samples accumulated so far as a tonemapped PNG and a linear EXR in the working
+/// directory, and returns a status message for the UI.
+fn save_image(
+    context: &GpuContext,
+    raytracer: &Raytracer,
+) -> String {
+    let image = match raytracer.read_image(&context.device, &context.queue) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Error reading back the image: {e}");
+
+            return "Saving failed".to_owned();
         }
+    };
+
+    // In milliseconds, so that saving twice within a second doesn't overwrite the first image.
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0_u128, |duration| duration.as_millis());
+
+    let mut saved_paths = Vec::new();
+
+    for extension in ["png", "exr"] {
+        let path = PathBuf::from(format!("render-{timestamp}.{extension}"));
+
+        match image.save(&path) {
+            Ok(()) => saved_paths.push(path.display().to_string()),
+            Err(e) => eprintln!("Error saving image: {e}"),
+        }
+    }
+
+    if saved_paths.is_empty() {
+        "Saving failed".to_owned()
+    } else {
+        println!("Saved {}", saved_paths.join(", "));
+
+        format!("Saved {}", saved_paths.join(", "))
     }
 }
 
+fn render_resolution(
+    window_size: winit::dpi::PhysicalSize<u32>,
+    scale: f32,
+) -> (u32, u32) {
+    let scaled = |size: u32| ((size as f32 * scale).round() as u32).max(1_u32);
+
+    (scaled(window_size.width), scaled(window_size.height))
+}
+
 struct FpsCounter {
     frame_times: VecDeque<f32>,
 }
@@ -512,40 +870,6 @@ impl FpsCounter {
     }
 }
 
-fn scene() -> Scene {
-    let materials = vec![
-        Material::Checkerboard {
-            even: Texture::new_from_color(glm::vec3(0.5_f32, 0.7_f32, 0.8_f32)),
-            odd: Texture::new_from_color(glm::vec3(0.9_f32, 0.9_f32, 0.9_f32)),
-        },
-        Material::Lambertian {
-            albedo: Texture::new_from_image("assets/moon.jpeg")
-                .expect("Hardcoded path should be valid"),
-        },
-        Material::Metal {
-            albedo: Texture::new_from_color(glm::vec3(1_f32, 0.85_f32, 0.57_f32)),
-            fuzz: 0.4_f32,
-        },
-        Material::Dielectric {
-            refraction_index: 1.5_f32,
-        },
-        Material::Lambertian {
-            albedo: Texture::new_from_image("assets/earthmap.jpeg")
-                .expect("Hardcoded path should be valid"),
-        },
-    ];
-
-    let spheres = vec![
-        Sphere::new(glm::vec3(0.0, -500.0, -1.0), 500.0, 0_u32),
-        Sphere::new(glm::vec3(0.0, 1.0, 0.0), 1.0, 3_u32),
-        Sphere::new(glm::vec3(-5.0, 1.0, 0.0), 1.0, 2_u32),
-        Sphere::new(glm::vec3(5.0, 0.8, 1.5), 0.8, 1_u32),
-        Sphere::new(glm::vec3(5.0, 1.2, -1.5), 1.2, 4_u32),
-    ];
-
-    Scene { spheres, materials }
-}
-
 pub fn create_empty_texels(
     width: usize,
     height: usize,
@@ -577,6 +901,8 @@ pub fn render_view_in_imgui(
         .device
         .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
+    raytracer.trace_frame(&context.queue, &mut encoder);
+
     {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
@@ -596,7 +922,7 @@ pub fn render_view_in_imgui(
             label: None,
         });
 
-        raytracer.render_frame(&context.queue, &mut render_pass);
+        raytracer.render_frame(&mut render_pass);
     }
 
     context.queue.submit(Some(encoder.finish()));
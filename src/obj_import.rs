@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::raytracer::{ColorSpace, Material, Mesh, Texture, TextureError};
+
+/// Loads the meshes of a Wavefront OBJ file. The materials of the accompanying MTL file are
+/// appended to `materials`, unless `material_override` is given, in which case every mesh uses
+/// that material instead.
+pub fn load_obj(
+    path: &Path,
+    material_override: Option<u32>,
+    materials: &mut Vec<Material>,
+) -> Result<Vec<Mesh>, ObjImportError> {
+    let (models, mtl_materials) =
+        tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS).map_err(|source| ObjImportError::Load {
+            path: path.to_owned(),
+            source,
+        })?;
+
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    // Maps the MTL material index to the scene material index.
+    let mut material_indices = Vec::new();
+
+    if material_override.is_none() {
+        match mtl_materials {
+            Ok(mtl_materials) => {
+                for mtl in &mtl_materials {
+                    material_indices.push(materials.len() as u32);
+
+                    materials.push(material_from_mtl(mtl, base_dir)?);
+                }
+            }
+            Err(err) => {
+                eprintln!(
+                    "Warning: {}: failed to load materials ({err}), using a default material",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    let mut default_material_idx = None;
+
+    let mut meshes = Vec::with_capacity(models.len());
+
+    for model in models {
+        let mesh = model.mesh;
+
+        let material_idx = match (material_override, mesh.material_id) {
+            (Some(idx), _) => idx,
+            (None, Some(id)) if id < material_indices.len() => material_indices[id],
+            (None, _) => *default_material_idx.get_or_insert_with(|| {
+                materials.push(Material::Lambertian {
+                    albedo: Texture::new_from_color(glm::vec3(0.8_f32, 0.8_f32, 0.8_f32)),
+                    normal_map: None,
+                });
+
+                materials.len() as u32 - 1_u32
+            }),
+        };
+
+        let positions = mesh
+            .positions
+            .chunks_exact(3)
+            .map(glm::make_vec3)
+            .collect();
+
+        let normals = mesh.normals.chunks_exact(3).map(glm::make_vec3).collect();
+
+        let uvs = mesh.texcoords.chunks_exact(2).map(glm::make_vec2).collect();
+
+        let triangles = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+
+        meshes.push(Mesh::new(positions, normals, uvs, triangles, material_idx));
+    }
+
+    Ok(meshes)
+}
+
+/// Maps an MTL material onto the closest `Material` variant. Materials with an emissive color
+/// become emitters, transparent materials become dielectrics, materials with a stronger specular
+/// than diffuse color become metals, and everything else is lambertian.
+fn material_from_mtl(
+    mtl: &tobj::Material,
+    base_dir: &Path,
+) -> Result<Material, ObjImportError> {
+    let max_component = |c: [f32; 3]| c[0].max(c[1]).max(c[2]);
+
+    // tobj does not know the emissive color, so it ends up among the unknown parameters.
+    let emissive = mtl
+        .unknown_param
+        .get("Ke")
+        .and_then(|value| parse_color(value));
+
+    if let Some(emissive) = emissive.filter(|c| max_component(*c) > 0_f32) {
+        return Ok(Material::Emissive {
+            radiance: Texture::new_from_color(glm::make_vec3(&emissive)),
+            strength: 1_f32,
+        });
+    }
+
+    let dissolve = mtl.dissolve.unwrap_or(1_f32);
+
+    if dissolve < 1_f32 {
+        return Ok(Material::Dielectric {
+            refraction_index: mtl.optical_density.unwrap_or(1.5_f32),
+            attenuation_color: None,
+            attenuation_distance: 1_f32,
+            abbe_number: None,
+            normal_map: None,
+        });
+    }
+
+    let diffuse = mtl.diffuse.unwrap_or([0.8_f32; 3]);
+
+    let specular = mtl.specular.unwrap_or([0_f32; 3]);
+
+    if max_component(specular) > max_component(diffuse) {
+        // Convert the Blinn-Phong exponent to a roughness-like value.
+        let shininess = mtl.shininess.unwrap_or(0_f32).max(0_f32);
+
+        let fuzz = (2_f32 / (shininess + 2_f32)).sqrt().clamp(0_f32, 1_f32);
+
+        return Ok(Material::Metal {
+            albedo: Texture::new_from_color(glm::make_vec3(&specular)),
+            fuzz,
+            normal_map: None,
+        });
+    }
+
+    let albedo = match &mtl.diffuse_texture {
+        Some(texture) => {
+            // MTL files don't say how textures are encoded, diffuse maps are practically always
+            // sRGB.
+            Texture::new_from_image(base_dir.join(texture), ColorSpace::Srgb).map_err(|source| {
+                ObjImportError::Texture {
+                    material: mtl.name.clone(),
+                    source,
+                }
+            })?
+        }
+        None => Texture::new_from_color(glm::make_vec3(&diffuse)),
+    };
+
+    Ok(Material::Lambertian {
+        albedo,
+        normal_map: None,
+    })
+}
+
+fn parse_color(value: &str) -> Option<[f32; 3]> {
+    let mut components = value.split_whitespace().map(str::parse::<f32>);
+
+    match (components.next(), components.next(), components.next()) {
+        (Some(Ok(r)), Some(Ok(g)), Some(Ok(b))) => Some([r, g, b]),
+        _ => None,
+    }
+}
+
+#[derive(Error, Debug)]
+
+pub enum ObjImportError {
+    #[error("{}: {source}", .path.display())]
+    Load {
+        path: PathBuf,
+        source: tobj::LoadError,
+    },
+    #[error("material `{material}`: failed to load texture {source}")]
+    Texture {
+        material: String,
+        source: TextureError,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary directory for one test of this process, which parallel runs of the tests
+    /// don't write into.
+    fn test_dir(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "obj_import_test_{}_{test_name}",
+            std::process::id()
+        ));
+
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    const TRIANGLE_OBJ: &str = "mtllib triangle.mtl
+usemtl red
+v 0 0 0
+v 1 0 0
+v 0 1 0
+f 1 2 3
+";
+
+    #[test]
+    fn test_material_from_mtl() {
+        let mtl = |diffuse, specular| tobj::Material {
+            diffuse: Some(diffuse),
+            specular: Some(specular),
+            shininess: Some(2_f32),
+            ..Default::default()
+        };
+        let base_dir = Path::new("");
+
+        let mut emissive = mtl([0.5_f32; 3], [0_f32; 3]);
+        emissive
+            .unknown_param
+            .insert("Ke".to_owned(), "1.0 0.5 0.0".to_owned());
+        assert!(matches!(
+            material_from_mtl(&emissive, base_dir).unwrap(),
+            Material::Emissive { strength, .. } if strength == 1_f32
+        ));
+
+        // A black emissive color doesn't make an emitter.
+        emissive
+            .unknown_param
+            .insert("Ke".to_owned(), "0 0 0".to_owned());
+        assert!(matches!(
+            material_from_mtl(&emissive, base_dir).unwrap(),
+            Material::Lambertian { .. }
+        ));
+
+        let transparent = tobj::Material {
+            dissolve: Some(0.5_f32),
+            optical_density: Some(1.33_f32),
+            ..mtl([0.5_f32; 3], [1_f32; 3])
+        };
+        assert!(matches!(
+            material_from_mtl(&transparent, base_dir).unwrap(),
+            Material::Dielectric { refraction_index, .. } if refraction_index == 1.33_f32
+        ));
+
+        // Ns = 2 gives a fuzz of sqrt(2 / (2 + 2)).
+        match material_from_mtl(&mtl([0.2_f32; 3], [0.9_f32; 3]), base_dir).unwrap() {
+            Material::Metal { fuzz, .. } => assert!((fuzz - 0.5_f32.sqrt()).abs() < 1e-6_f32),
+            _ => panic!("expected a metal material"),
+        }
+
+        assert!(matches!(
+            material_from_mtl(&mtl([0.9_f32; 3], [0.2_f32; 3]), base_dir).unwrap(),
+            Material::Lambertian { .. }
+        ));
+    }
+
+    #[test]
+    fn test_missing_mtl_uses_a_default_material() {
+        let path = test_dir("missing_mtl").join("triangle.obj");
+        std::fs::write(&path, TRIANGLE_OBJ).unwrap();
+
+        let mut materials = vec![Material::Lambertian {
+            albedo: Texture::new_from_color(glm::vec3(1_f32, 1_f32, 1_f32)),
+            normal_map: None,
+        }];
+        let meshes = load_obj(&path, None, &mut materials).unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].material_idx, 1);
+        assert_eq!(materials.len(), 2);
+        assert!(matches!(materials[1], Material::Lambertian { .. }));
+    }
+
+    #[test]
+    fn test_material_override_skips_the_mtl() {
+        let dir = test_dir("material_override");
+        let path = dir.join("triangle.obj");
+        std::fs::write(&path, TRIANGLE_OBJ).unwrap();
+        std::fs::write(dir.join("triangle.mtl"), "newmtl red\nKd 1 0 0\n").unwrap();
+
+        let mut materials = Vec::new();
+        let meshes = load_obj(&path, None, &mut materials).unwrap();
+        assert_eq!(meshes[0].material_idx, 0);
+        assert_eq!(materials.len(), 1);
+
+        let meshes = load_obj(&path, Some(0), &mut materials).unwrap();
+        assert_eq!(meshes[0].material_idx, 0);
+        assert_eq!(materials.len(), 1);
+    }
+
+    #[test]
+    fn test_face_index_out_of_range() {
+        let path = test_dir("face_index").join("triangle.obj");
+        std::fs::write(&path, TRIANGLE_OBJ.replace("f 1 2 3", "f 1 2 9")).unwrap();
+
+        assert!(matches!(
+            load_obj(&path, Some(0), &mut Vec::new()),
+            Err(ObjImportError::Load {
+                source: tobj::LoadError::FaceVertexOutOfBounds,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,353 @@
+use wgpu::util::DeviceExt;
+
+use super::gpu_buffer::UniformBuffer;
+
+/// The width and height of the tiles traced by a single dispatch, in pixels.
+const TILE_SIZE: u32 = 256_u32;
+
+/// Must match `@workgroup_size` of `csMain`.
+const WORKGROUP_SIZE: u32 = 8_u32;
+
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+
+/// The sum of all the samples of every pixel. Read-write storage textures are not portable, so
+/// two textures take turns: each frame reads the previous sums from one of them and writes the new
+/// sums to the other.
+pub struct AccumulationImage {
+    size: (u32, u32),
+    textures: [wgpu::Texture; 2],
+    tiles: Vec<Tile>,
+    /// Reads from `textures[i]` and writes to the other texture.
+    trace_bind_groups: [wgpu::BindGroup; 2],
+    /// Reads from `textures[i]`.
+    display_bind_groups: [wgpu::BindGroup; 2],
+    ping_pong: PingPong,
+}
+
+struct Tile {
+    /// The offset of the tile's origin in the tile buffer.
+    offset: wgpu::DynamicOffset,
+    num_workgroups: (u32, u32),
+}
+
+/// Which of the two textures holds the latest sums. A new image starts from the first texture,
+/// resetting the accumulation only clears the sums in the shader.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+
+struct PingPong {
+    current: usize,
+}
+
+impl PingPong {
+    /// The texture the next dispatch reads the previous sums from, also the one displayed.
+    fn read(self) -> usize {
+        self.current
+    }
+
+    /// The texture the next dispatch writes the new sums to.
+    fn write(self) -> usize {
+        1 - self.current
+    }
+
+    fn swap(&mut self) {
+        self.current = self.write();
+    }
+}
+
+impl AccumulationImage {
+    pub fn trace_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &Self::trace_layout_entries(),
+            label: Some("accumulation trace layout"),
+        })
+    }
+
+    /// The frame data, the tile origin, the texture holding the previous sums and the one the new
+    /// sums are written to.
+    pub fn trace_layout_entries() -> [wgpu::BindGroupLayoutEntry; 4] {
+        [
+            wgpu::BindGroupLayoutEntry {
+                binding: 0_u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1_u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(16_u64),
+                },
+                count: None,
+            },
+            texture_layout(2_u32, wgpu::ShaderStages::COMPUTE),
+            wgpu::BindGroupLayoutEntry {
+                binding: 3_u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: FORMAT,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            },
+        ]
+    }
+
+    /// Also used by the exposure passes, which meter the image.
+    pub fn display_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0_u32,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_layout(
+                    1_u32,
+                    wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                ),
+            ],
+            label: Some("accumulation display layout"),
+        })
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        size: (u32, u32),
+        trace_layout: &wgpu::BindGroupLayout,
+        display_layout: &wgpu::BindGroupLayout,
+        frame_data_buffer: &UniformBuffer,
+        display_params_buffer: &UniformBuffer,
+    ) -> Self {
+        let create_texture = || {
+            device.create_texture(&wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width: size.0,
+                    height: size.1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: FORMAT,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::STORAGE_BINDING
+                    | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+                label: Some("accumulation texture"),
+            })
+        };
+
+        let textures = [create_texture(), create_texture()];
+
+        let views = textures
+            .each_ref()
+            .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        let (tiles, tile_buffer) = {
+            let (tiles, tile_data) =
+                tiles(size, device.limits().min_uniform_buffer_offset_alignment);
+
+            let tile_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                contents: &tile_data,
+                usage: wgpu::BufferUsages::UNIFORM,
+                label: Some("tile buffer"),
+            });
+
+            (tiles, tile_buffer)
+        };
+
+        let trace_bind_groups = [0_usize, 1_usize].map(|idx| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: trace_layout,
+                entries: &[
+                    frame_data_buffer.binding(),
+                    wgpu::BindGroupEntry {
+                        binding: 1_u32,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: &tile_buffer,
+                            offset: 0_u64,
+                            size: wgpu::BufferSize::new(16_u64),
+                        }),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2_u32,
+                        resource: wgpu::BindingResource::TextureView(&views[idx]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3_u32,
+                        resource: wgpu::BindingResource::TextureView(&views[1 - idx]),
+                    },
+                ],
+                label: Some("accumulation trace bind group"),
+            })
+        });
+
+        let display_bind_groups = [0_usize, 1_usize].map(|idx| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: display_layout,
+                entries: &[
+                    display_params_buffer.binding(),
+                    wgpu::BindGroupEntry {
+                        binding: 1_u32,
+                        resource: wgpu::BindingResource::TextureView(&views[idx]),
+                    },
+                ],
+                label: Some("accumulation display bind group"),
+            })
+        });
+
+        Self {
+            size,
+            textures,
+            tiles,
+            trace_bind_groups,
+            display_bind_groups,
+            ping_pong: PingPong::default(),
+        }
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// Dispatches the trace pipeline once per tile. The other bind groups and the pipeline must
+    /// already be set. Call `swap` once the pass has ended.
+    pub fn dispatch<'a>(
+        &'a self,
+        compute_pass: &mut wgpu::ComputePass<'a>,
+    ) {
+        for tile in &self.tiles {
+            compute_pass.set_bind_group(
+                0,
+                &self.trace_bind_groups[self.ping_pong.read()],
+                &[tile.offset],
+            );
+
+            compute_pass.dispatch_workgroups(tile.num_workgroups.0, tile.num_workgroups.1, 1);
+        }
+    }
+
+    /// Makes the texture written by the last `dispatch` the current one.
+    pub fn swap(&mut self) {
+        self.ping_pong.swap();
+    }
+
+    pub fn display_bind_group(&self) -> &wgpu::BindGroup {
+        &self.display_bind_groups[self.ping_pong.read()]
+    }
+
+    pub fn current_texture(&self) -> &wgpu::Texture {
+        &self.textures[self.ping_pong.read()]
+    }
+}
+
+/// Splits the image into tiles, and lays out their origins in the tile buffer `stride` bytes
+/// apart, as dynamic offsets have to be aligned.
+fn tiles(
+    size: (u32, u32),
+    stride: u32,
+) -> (Vec<Tile>, Vec<u8>) {
+    let mut tiles = Vec::new();
+
+    let mut tile_data: Vec<u8> = Vec::new();
+
+    for y in (0_u32..size.1).step_by(TILE_SIZE as usize) {
+        for x in (0_u32..size.0).step_by(TILE_SIZE as usize) {
+            let tile_width = TILE_SIZE.min(size.0 - x);
+
+            let tile_height = TILE_SIZE.min(size.1 - y);
+
+            tiles.push(Tile {
+                offset: tile_data.len() as wgpu::DynamicOffset,
+                num_workgroups: (
+                    tile_width.div_ceil(WORKGROUP_SIZE),
+                    tile_height.div_ceil(WORKGROUP_SIZE),
+                ),
+            });
+
+            tile_data.extend_from_slice(bytemuck::cast_slice(&[x, y, 0_u32, 0_u32]));
+
+            tile_data.resize(tiles.len() * stride as usize, 0_u8);
+        }
+    }
+
+    (tiles, tile_data)
+}
+
+fn texture_layout(
+    binding: u32,
+    visibility: wgpu::ShaderStages,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_the_image() {
+        assert_eq!(tiles((256_u32, 256_u32), 256_u32).0.len(), 1);
+        assert_eq!(tiles((257_u32, 1_u32), 256_u32).0.len(), 2);
+
+        let (tiles, tile_data) = tiles((600_u32, 300_u32), 256_u32);
+        assert_eq!(tiles.len(), 6);
+        // The last tile is the 88 x 44 pixels left over.
+        assert_eq!(tiles[5].num_workgroups, (11_u32, 6_u32));
+        assert_eq!(tiles[0].num_workgroups, (32_u32, 32_u32));
+        assert_eq!(tile_data.len(), 6 * 256);
+    }
+
+    #[test]
+    fn test_tile_offsets_are_aligned() {
+        for stride in [64_u32, 256_u32] {
+            let (tiles, tile_data) = tiles((1000_u32, 700_u32), stride);
+            for (idx, tile) in tiles.iter().enumerate() {
+                assert_eq!(tile.offset, idx as u32 * stride);
+                let offset = tile.offset as usize;
+                let origin: [u32; 2] = bytemuck::pod_read_unaligned(&tile_data[offset..offset + 8]);
+                let expected = [(idx % 4) as u32 * TILE_SIZE, (idx / 4) as u32 * TILE_SIZE];
+                assert_eq!(origin, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let mut ping_pong = PingPong::default();
+        assert_eq!((ping_pong.read(), ping_pong.write()), (0, 1));
+
+        for frame in 1..=5 {
+            let written = ping_pong.write();
+            ping_pong.swap();
+            // Each frame reads the sums the previous one wrote.
+            assert_eq!(ping_pong.read(), written);
+            assert_eq!(ping_pong.read(), frame % 2);
+        }
+
+        // A resized image starts over from the first texture.
+        assert_eq!(PingPong::default().read(), 0);
+    }
+}
@@ -0,0 +1,515 @@
+use std::time::{Duration, Instant};
+
+use nalgebra_glm::Vec3;
+
+/// The traversal stack in the shader has a fixed size, so the tree must not be deeper than this.
+/// Must match `BVH_STACK_SIZE` in raytracer.wgsl.
+pub const MAX_DEPTH: u32 = 32;
+
+const NUM_BINS: usize = 16;
+
+const MAX_PRIMITIVES_IN_LEAF: usize = 4;
+
+/// Leaves larger than this are always split, even if the SAH says otherwise.
+const MAX_PRIMITIVES_IN_SAH_LEAF: usize = 16;
+
+/// The cost of traversing a node, relative to intersecting a primitive.
+const TRAVERSAL_COST: f32 = 1_f32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Self {
+            min: glm::vec3(f32::MAX, f32::MAX, f32::MAX),
+            max: glm::vec3(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn from_points(points: &[Vec3]) -> Self {
+        points
+            .iter()
+            .fold(Self::empty(), |aabb, p| aabb.union(&Self { min: *p, max: *p }))
+    }
+
+    pub fn union(
+        &self,
+        other: &Self,
+    ) -> Self {
+        Self {
+            min: glm::min2(&self.min, &other.min),
+            max: glm::max2(&self.max, &other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5_f32 * (self.min + self.max)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    #[cfg(test)]
+    pub fn contains(
+        &self,
+        other: &Self,
+    ) -> bool {
+        self.min.x <= other.min.x
+            && self.min.y <= other.min.y
+            && self.min.z <= other.min.z
+            && self.max.x >= other.max.x
+            && self.max.y >= other.max.y
+            && self.max.z >= other.max.z
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0_f32;
+        }
+
+        let d = self.max - self.min;
+
+        2_f32 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+}
+
+/// A node of the flattened tree, laid out in depth-first order. A leaf has a non-zero `count`
+/// and `offset` points to its first primitive. An interior node has a zero `count`, its first
+/// child directly follows it, and `offset` points to its second child.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+pub struct GpuBvhNode {
+    aabb_min: [f32; 3],
+    offset: u32,
+    aabb_max: [f32; 3],
+    count: u32,
+}
+
+impl GpuBvhNode {
+    fn is_leaf(&self) -> bool {
+        self.count > 0_u32
+    }
+
+    #[cfg(test)]
+    fn aabb(&self) -> Aabb {
+        Aabb {
+            min: glm::make_vec3(&self.aabb_min),
+            max: glm::make_vec3(&self.aabb_max),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+
+pub struct BvhStats {
+    pub primitive_count: u32,
+    pub node_count: u32,
+    pub leaf_count: u32,
+    pub depth: u32,
+    pub build_time: Duration,
+}
+
+pub struct Bvh {
+    pub nodes: Vec<GpuBvhNode>,
+    /// The leaves refer to ranges of this array, which contains indices into the primitive
+    /// bounds the tree was built from.
+    pub primitive_indices: Vec<u32>,
+    pub stats: BvhStats,
+}
+
+impl Bvh {
+    /// Builds the tree top-down, splitting each node with the surface area heuristic evaluated
+    /// over a fixed number of centroid bins. An empty scene results in a single zeroed node,
+    /// which the shader treats as an empty leaf.
+    pub fn build(primitive_bounds: &[Aabb]) -> Self {
+        let start = Instant::now();
+
+        let mut builder = Builder {
+            primitive_bounds,
+            centroids: primitive_bounds.iter().map(Aabb::centroid).collect(),
+            nodes: Vec::with_capacity(2 * primitive_bounds.len()),
+            depth: 0_u32,
+        };
+
+        let mut primitive_indices: Vec<u32> = (0..primitive_bounds.len() as u32).collect();
+
+        if primitive_indices.is_empty() {
+            builder.nodes.push(bytemuck::Zeroable::zeroed());
+        } else {
+            builder.build_node(&mut primitive_indices, 0_u32, 1_u32);
+        }
+
+        let nodes = builder.nodes;
+
+        let stats = BvhStats {
+            primitive_count: primitive_bounds.len() as u32,
+            node_count: nodes.len() as u32,
+            leaf_count: nodes.iter().filter(|node| node.is_leaf()).count() as u32,
+            depth: builder.depth,
+            build_time: start.elapsed(),
+        };
+
+        Self {
+            nodes,
+            primitive_indices,
+            stats,
+        }
+    }
+
+    /// Shifts the node and primitive indices for a tree which is stored after others, starting
+    /// at `node_offset` in a shared node array and at `primitive_offset` in a shared primitive
+    /// array. An empty tree's zeroed node is kept as it is, it is still an empty leaf.
+    pub fn offset_indices(
+        &mut self,
+        node_offset: u32,
+        primitive_offset: u32,
+    ) {
+        if self.primitive_indices.is_empty() {
+            return;
+        }
+
+        for node in &mut self.nodes {
+            if node.is_leaf() {
+                node.offset += primitive_offset;
+            } else {
+                node.offset += node_offset;
+            }
+        }
+    }
+
+    /// The bounds of everything in the tree.
+    pub fn bounds(&self) -> Aabb {
+        if self.primitive_indices.is_empty() {
+            return Aabb::empty();
+        }
+
+        Aabb {
+            min: glm::make_vec3(&self.nodes[0].aabb_min),
+            max: glm::make_vec3(&self.nodes[0].aabb_max),
+        }
+    }
+}
+
+struct Builder<'a> {
+    primitive_bounds: &'a [Aabb],
+    centroids: Vec<Vec3>,
+    nodes: Vec<GpuBvhNode>,
+    depth: u32,
+}
+
+#[derive(Clone, Copy)]
+
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+impl Builder<'_> {
+    /// `primitives` is the range of the primitive index array starting at `first`. Returns the
+    /// index of the created node.
+    fn build_node(
+        &mut self,
+        primitives: &mut [u32],
+        first: u32,
+        depth: u32,
+    ) -> u32 {
+        self.depth = self.depth.max(depth);
+
+        let node_idx = self.nodes.len();
+
+        let bounds = primitives
+            .iter()
+            .fold(Aabb::empty(), |aabb, &idx| {
+                aabb.union(&self.primitive_bounds[idx as usize])
+            });
+
+        self.nodes.push(GpuBvhNode {
+            aabb_min: bounds.min.into(),
+            offset: first,
+            aabb_max: bounds.max.into(),
+            count: primitives.len() as u32,
+        });
+
+        if primitives.len() <= MAX_PRIMITIVES_IN_LEAF || depth >= MAX_DEPTH {
+            return node_idx as u32;
+        }
+
+        let mid = match self.find_sah_split(primitives, &bounds) {
+            Some(mid) => mid,
+            None if primitives.len() <= MAX_PRIMITIVES_IN_SAH_LEAF => return node_idx as u32,
+            None => self.median_split(primitives),
+        };
+
+        let (left, right) = primitives.split_at_mut(mid);
+
+        self.build_node(left, first, depth + 1_u32);
+
+        let right_idx = self.build_node(right, first + mid as u32, depth + 1_u32);
+
+        let node = &mut self.nodes[node_idx];
+
+        node.offset = right_idx;
+
+        node.count = 0_u32;
+
+        node_idx as u32
+    }
+
+    /// Partitions `primitives` along the cheapest bin boundary and returns the partition point,
+    /// or `None` if no split is cheaper than making a leaf.
+    fn find_sah_split(
+        &self,
+        primitives: &mut [u32],
+        bounds: &Aabb,
+    ) -> Option<usize> {
+        let centroid_bounds = Aabb::from_points(
+            &primitives
+                .iter()
+                .map(|&idx| self.centroids[idx as usize])
+                .collect::<Vec<_>>(),
+        );
+
+        let extent = centroid_bounds.max - centroid_bounds.min;
+
+        let leaf_cost = primitives.len() as f32;
+
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        for axis in 0..3 {
+            if extent[axis] <= 0_f32 {
+                continue;
+            }
+
+            let bin_of = |centroid: &Vec3| -> usize {
+                let t = (centroid[axis] - centroid_bounds.min[axis]) / extent[axis];
+
+                ((t * NUM_BINS as f32) as usize).min(NUM_BINS - 1)
+            };
+
+            let mut bins = [Bin {
+                bounds: Aabb::empty(),
+                count: 0,
+            }; NUM_BINS];
+
+            for &idx in primitives.iter() {
+                let bin = &mut bins[bin_of(&self.centroids[idx as usize])];
+
+                bin.bounds = bin.bounds.union(&self.primitive_bounds[idx as usize]);
+
+                bin.count += 1;
+            }
+
+            // Sweep from the right to get the cost of everything right of each boundary.
+            let mut right_costs = [0_f32; NUM_BINS];
+
+            let mut right = Bin {
+                bounds: Aabb::empty(),
+                count: 0,
+            };
+
+            for split in (1..NUM_BINS).rev() {
+                right.bounds = right.bounds.union(&bins[split].bounds);
+
+                right.count += bins[split].count;
+
+                right_costs[split] = right.bounds.surface_area() * right.count as f32;
+            }
+
+            let mut left = Bin {
+                bounds: Aabb::empty(),
+                count: 0,
+            };
+
+            for split in 1..NUM_BINS {
+                left.bounds = left.bounds.union(&bins[split - 1].bounds);
+
+                left.count += bins[split - 1].count;
+
+                if left.count == 0 || left.count == primitives.len() {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + (left.bounds.surface_area() * left.count as f32 + right_costs[split])
+                        / bounds.surface_area();
+
+                if best.map_or(true, |(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, split, cost));
+                }
+            }
+        }
+
+        let (axis, split, cost) = best?;
+
+        if cost >= leaf_cost && primitives.len() <= MAX_PRIMITIVES_IN_SAH_LEAF {
+            return None;
+        }
+
+        let threshold =
+            centroid_bounds.min[axis] + extent[axis] * (split as f32 / NUM_BINS as f32);
+
+        Some(partition(primitives, |&idx| {
+            self.centroids[idx as usize][axis] < threshold
+        }))
+        .filter(|&mid| mid > 0 && mid < primitives.len())
+    }
+
+    /// Sorts the primitives along the longest axis of their centroids and splits in the middle.
+    /// Used when the binned SAH cannot separate the primitives.
+    fn median_split(
+        &self,
+        primitives: &mut [u32],
+    ) -> usize {
+        let centroid_bounds = Aabb::from_points(
+            &primitives
+                .iter()
+                .map(|&idx| self.centroids[idx as usize])
+                .collect::<Vec<_>>(),
+        );
+
+        let axis = (centroid_bounds.max - centroid_bounds.min).imax();
+
+        primitives.sort_by(|&a, &b| {
+            self.centroids[a as usize][axis].total_cmp(&self.centroids[b as usize][axis])
+        });
+
+        primitives.len() / 2
+    }
+}
+
+/// Moves the elements matching `predicate` to the front and returns how many there are.
+fn partition<T>(
+    slice: &mut [T],
+    predicate: impl Fn(&T) -> bool,
+) -> usize {
+    let mut mid = 0;
+
+    for idx in 0..slice.len() {
+        if predicate(&slice[idx]) {
+            slice.swap(idx, mid);
+
+            mid += 1;
+        }
+    }
+
+    mid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box(center: Vec3) -> Aabb {
+        let half = glm::vec3(0.5_f32, 0.5_f32, 0.5_f32);
+        Aabb {
+            min: center - half,
+            max: center + half,
+        }
+    }
+
+    fn grid_of_boxes(n: usize) -> Vec<Aabb> {
+        (0..n * n * n)
+            .map(|i| {
+                let (x, y, z) = (i % n, (i / n) % n, i / (n * n));
+                unit_box(glm::vec3(2_f32 * x as f32, 2_f32 * y as f32, 2_f32 * z as f32))
+            })
+            .collect()
+    }
+
+    fn check_node(
+        bvh: &Bvh,
+        bounds: &[Aabb],
+        node_idx: usize,
+        visited: &mut Vec<u32>,
+    ) {
+        let node = &bvh.nodes[node_idx];
+        if node.is_leaf() {
+            for i in node.offset..node.offset + node.count {
+                let primitive = bvh.primitive_indices[i as usize];
+                assert!(node.aabb().contains(&bounds[primitive as usize]));
+                visited.push(primitive);
+            }
+        } else {
+            let left = node_idx + 1;
+            let right = node.offset as usize;
+            assert!(node.aabb().contains(&bvh.nodes[left].aabb()));
+            assert!(node.aabb().contains(&bvh.nodes[right].aabb()));
+            check_node(bvh, bounds, left, visited);
+            check_node(bvh, bounds, right, visited);
+        }
+    }
+
+    #[test]
+    fn test_every_primitive_is_in_exactly_one_leaf() {
+        let bounds = grid_of_boxes(6);
+        let bvh = Bvh::build(&bounds);
+
+        let mut visited = Vec::new();
+        check_node(&bvh, &bounds, 0, &mut visited);
+        visited.sort_unstable();
+
+        assert_eq!(visited, (0..bounds.len() as u32).collect::<Vec<_>>());
+        assert_eq!(bvh.stats.node_count as usize, bvh.nodes.len());
+        assert!(bvh.stats.depth <= MAX_DEPTH);
+    }
+
+    #[test]
+    fn test_sah_splits_separated_clusters() {
+        // Two clusters far apart should end up in different subtrees of the root.
+        let mut bounds: Vec<_> = (0..8)
+            .map(|i| unit_box(glm::vec3(i as f32 * 0.1, 0_f32, 0_f32)))
+            .collect();
+        bounds.extend((0..8).map(|i| unit_box(glm::vec3(100_f32 + i as f32 * 0.1, 0_f32, 0_f32))));
+
+        let bvh = Bvh::build(&bounds);
+        let root = &bvh.nodes[0];
+        assert!(!root.is_leaf());
+        assert!(bvh.nodes[1].aabb().max.x < 50_f32);
+        assert!(bvh.nodes[root.offset as usize].aabb().min.x > 50_f32);
+    }
+
+    #[test]
+    fn test_identical_primitives_do_not_overflow_depth() {
+        let bounds = vec![unit_box(Vec3::zeros()); 1000];
+        let bvh = Bvh::build(&bounds);
+
+        let mut visited = Vec::new();
+        check_node(&bvh, &bounds, 0, &mut visited);
+        assert_eq!(visited.len(), bounds.len());
+        assert!(bvh.stats.depth <= MAX_DEPTH);
+    }
+
+    #[test]
+    fn test_offset_indices() {
+        let bounds = grid_of_boxes(3);
+        let bvh = Bvh::build(&bounds);
+        let mut moved = Bvh::build(&bounds);
+        moved.offset_indices(10, 100);
+
+        for (node, moved_node) in bvh.nodes.iter().zip(&moved.nodes) {
+            let expected_offset = if node.is_leaf() { 100 } else { 10 };
+            assert_eq!(moved_node.offset, node.offset + expected_offset);
+            assert_eq!(moved_node.count, node.count);
+        }
+
+        let mut empty = Bvh::build(&[]);
+        empty.offset_indices(10, 100);
+        assert_eq!(empty.nodes[0].offset, 0);
+        assert!(empty.bounds().is_empty());
+    }
+
+    #[test]
+    fn test_empty_scene() {
+        let bvh = Bvh::build(&[]);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 0);
+        assert_eq!(bvh.nodes[0].offset, 0);
+    }
+}
@@ -0,0 +1,130 @@
+/// How the values stored in a texture's image are encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+
+pub enum ColorSpace {
+    /// Colors with Rec.709 primaries and the sRGB transfer function, which is decoded to linear
+    /// on load. Most 8-bit color images, such as photos and albedo maps, are stored this way.
+    #[default]
+    Srgb,
+    /// Linear colors with Rec.709 primaries.
+    Linear,
+    /// Non-color values, such as roughness or normals, which are loaded as is and are not
+    /// converted to the working space.
+    Data,
+}
+
+/// The linear color space all the rendering happens in. Scene colors, textures and the sky are
+/// converted into it when loaded, and the display transform converts the accumulated radiance
+/// back to Rec.709 before tonemapping. Rendered images are saved in Rec.709.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+
+pub enum WorkingSpace {
+    /// Linear Rec.709, which has the same primaries and white point as sRGB.
+    #[default]
+    Rec709,
+    /// The ACES AP1 primaries, with a D60 white point. The wider gamut makes the multiplication
+    /// of colors, such as repeated bounces off a saturated albedo, closer to spectral rendering.
+    AcesCg,
+}
+
+impl WorkingSpace {
+    /// The matrix which converts linear Rec.709 colors to the working space, in rows.
+    pub fn from_rec709(self) -> [[f32; 3]; 3] {
+        match self {
+            WorkingSpace::Rec709 => IDENTITY,
+            // Includes the Bradford chromatic adaptation from D65 to D60.
+            WorkingSpace::AcesCg => [
+                [0.613_097_4_f32, 0.339_523_15_f32, 0.047_379_45_f32],
+                [0.070_193_72_f32, 0.916_353_9_f32, 0.013_452_4_f32],
+                [0.020_615_593_f32, 0.109_569_77_f32, 0.869_814_6_f32],
+            ],
+        }
+    }
+
+    /// The matrix which converts working space colors to linear Rec.709, in rows.
+    pub fn to_rec709(self) -> [[f32; 3]; 3] {
+        match self {
+            WorkingSpace::Rec709 => IDENTITY,
+            // The inverse of the matrix above.
+            WorkingSpace::AcesCg => [
+                [1.705_051_f32, -0.621_792_1_f32, -0.083_258_87_f32],
+                [-0.130_256_42_f32, 1.140_804_7_f32, -0.010_548_319_f32],
+                [-0.024_003_357_f32, -0.128_968_98_f32, 1.152_972_3_f32],
+            ],
+        }
+    }
+}
+
+const IDENTITY: [[f32; 3]; 3] = [
+    [1_f32, 0_f32, 0_f32],
+    [0_f32, 1_f32, 0_f32],
+    [0_f32, 0_f32, 1_f32],
+];
+
+/// Multiplies a column vector by a matrix given in rows.
+pub fn mul(
+    rows: &[[f32; 3]; 3],
+    v: [f32; 3],
+) -> [f32; 3] {
+    rows.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+/// Lays out a matrix given in rows like a WGSL `mat3x3<f32>`, which is stored in columns padded
+/// to 16 bytes.
+pub fn gpu_mat3(rows: &[[f32; 3]; 3]) -> [[f32; 4]; 3] {
+    [0_usize, 1_usize, 2_usize].map(|col| [rows[0][col], rows[1][col], rows[2][col], 0_f32])
+}
+
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.040_45_f32 {
+        x / 12.92_f32
+    } else {
+        ((x + 0.055_f32) / 1.055_f32).powf(2.4_f32)
+    }
+}
+
+/// The window's swapchain applies this encoding in hardware, since it has an sRGB format.
+pub fn linear_to_srgb(x: f32) -> f32 {
+    let x = x.clamp(0_f32, 1_f32);
+
+    if x <= 0.003_130_8_f32 {
+        12.92_f32 * x
+    } else {
+        1.055_f32 * x.powf(1_f32 / 2.4_f32) - 0.055_f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_working_space_round_trip() {
+        let color = [0.8_f32, 0.3_f32, 0.05_f32];
+
+        for space in [WorkingSpace::Rec709, WorkingSpace::AcesCg] {
+            let round_trip = mul(&space.to_rec709(), mul(&space.from_rec709(), color));
+
+            for (a, b) in round_trip.iter().zip(color) {
+                assert!((a - b).abs() < 1e-5_f32, "{space:?}");
+            }
+        }
+
+        // White stays white, the matrices adapt the white point.
+        let white = mul(&WorkingSpace::AcesCg.from_rec709(), [1_f32; 3]);
+
+        assert!(white.iter().all(|c| (c - 1_f32).abs() < 1e-4_f32));
+    }
+
+    #[test]
+    fn test_srgb_transfer_round_trip() {
+        for i in 0..=255 {
+            let x = i as f32 / 255_f32;
+
+            assert!((linear_to_srgb(srgb_to_linear(x)) - x).abs() < 1e-5_f32);
+        }
+
+        // 8-bit middle gray is much darker than 50 % in linear.
+        assert!((srgb_to_linear(0.5_f32) - 0.214_f32).abs() < 1e-3_f32);
+    }
+}
@@ -0,0 +1,166 @@
+// Automatic exposure maps the metered luminance to middle gray.
+const KEY_LUMINANCE = 0.18f;
+
+struct DisplayParams {
+    accumulatedSamplesPerPixel: u32,
+    tonemapper: u32,
+    // 2^exposure_ev, multiplied by the automatic exposure if it is enabled
+    exposure: f32,
+    whitePoint: f32,
+    autoExposure: u32,
+    // Converts the working space to Rec.709, which the tonemappers expect
+    toRec709: mat3x3<f32>,
+}
+
+// Written by histogram.wgsl
+struct ExposureState {
+    adaptedLogLuminance: f32,
+    meteredLogLuminance: f32,
+    histogram: array<u32, 64>,
+}
+
+@group(0) @binding(0) var<uniform> displayParams: DisplayParams;
+@group(0) @binding(1) var image: texture_2d<f32>;
+
+@group(1) @binding(0) var<storage, read> exposureState: ExposureState;
+
+struct VertexOutput {
+    @builtin(position) clipPosition: vec4<f32>,
+    @location(0) texCoords: vec2<f32>,
+}
+
+@vertex
+fn vsMain(@builtin(vertex_index) vertexIndex: u32) -> VertexOutput {
+    // A single triangle which covers the whole target, with texCoords (0, 0) in the top left
+    // corner and (1, 1) in the bottom right corner.
+    let texCoords = vec2(f32((vertexIndex << 1u) & 2u), f32(vertexIndex & 2u));
+
+    return VertexOutput(
+        vec4(2f * texCoords.x - 1f, 1f - 2f * texCoords.y, 0f, 1f),
+        texCoords
+    );
+}
+
+@fragment
+fn fsMain(in: VertexOutput) -> @location(0) vec4<f32> {
+    // The image can have a different resolution than the target, and is scaled to fit it.
+    let imageSize = vec2<i32>(textureDimensions(image));
+    let xy = min(vec2<i32>(in.texCoords * vec2<f32>(imageSize)), imageSize - 1);
+
+    let sum = displayParams.toRec709 * textureLoad(image, xy, 0).rgb;
+    let invN = 1f / f32(max(displayParams.accumulatedSamplesPerPixel, 1u));
+
+    var exposure = displayParams.exposure;
+    if displayParams.autoExposure == 1u {
+        exposure *= KEY_LUMINANCE / exp2(exposureState.adaptedLogLuminance);
+    }
+
+    return vec4(
+        tonemap(exposure * invN * sum),
+        1f
+    );
+}
+
+// Mirrored by tonemap.rs, which is used for saving images.
+fn tonemap(radiance: vec3<f32>) -> vec3<f32> {
+    let x = max(radiance, vec3(0f));
+    let w = displayParams.whitePoint;
+
+    var color = x;
+    switch displayParams.tonemapper {
+        // ACES fitted
+        case 0u: {
+            color = acesFitted(x);
+        }
+
+        // Reinhard extended
+        case 1u: {
+            color = reinhardExtended(x, w);
+        }
+
+        // AgX
+        case 2u: {
+            color = agx(x);
+        }
+
+        // Uncharted2
+        case 3u: {
+            color = uncharted2Curve(x) / uncharted2Curve(vec3(w));
+        }
+
+        // Linear clamp
+        default: {}
+    }
+
+    return clamp(color, vec3(0f), vec3(1f));
+}
+
+fn acesFitted(x: vec3<f32>) -> vec3<f32> {
+    // Based on Stephen Hill's fit of the ACES RRT and ODT
+    // https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+    // The matrices are written in rows, so vectors are multiplied from the left.
+    let inputMat = mat3x3<f32>(
+        0.59719, 0.35458, 0.04823,
+        0.07600, 0.90834, 0.01566,
+        0.02840, 0.13383, 0.83777
+    );
+    let outputMat = mat3x3<f32>(
+        1.60475, -0.53108, -0.07367,
+        -0.10208, 1.10813, -0.00605,
+        -0.00327, -0.07276, 1.07602
+    );
+
+    let v = x * inputMat;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return (a / b) * outputMat;
+}
+
+fn reinhardExtended(x: vec3<f32>, whitePoint: f32) -> vec3<f32> {
+    let luminance = dot(x, vec3(0.2126, 0.7152, 0.0722));
+    if luminance <= 0f {
+        return vec3(0f);
+    }
+
+    let mapped = luminance * (1f + luminance / (whitePoint * whitePoint)) / (1f + luminance);
+    return x * (mapped / luminance);
+}
+
+fn agx(x: vec3<f32>) -> vec3<f32> {
+    // Based on Benjamin Wrensch's minimal AgX implementation
+    // https://iolite-engine.com/blog_posts/minimal_agx_implementation
+    let insetMat = mat3x3<f32>(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104
+    );
+    let outsetMat = mat3x3<f32>(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116
+    );
+    let minEv = -12.47393;
+    let maxEv = 4.026069;
+
+    var v = insetMat * x;
+    v = (clamp(log2(max(v, vec3(1e-10))), vec3(minEv), vec3(maxEv)) - minEv) / (maxEv - minEv);
+
+    let v2 = v * v;
+    let v4 = v2 * v2;
+    v = 15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v - 0.00232;
+
+    // The curve's output is display encoded, decode it back to linear.
+    return pow(max(outsetMat * v, vec3(0f)), vec3(2.2));
+}
+
+fn uncharted2Curve(x: vec3<f32>) -> vec3<f32> {
+    // Based on uncharted2 tonemapping function
+    // https://dmnsgn.github.io/glsl-tone-map/
+    let a = 0.15;
+    let b = 0.50;
+    let c = 0.10;
+    let d = 0.20;
+    let e = 0.02;
+    let f = 0.30;
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
@@ -0,0 +1,211 @@
+use bytemuck::Zeroable;
+
+use super::{texture_atlas::f16_bits, Texture};
+
+/// The header of the environment map buffer, followed by the sampling distribution built by
+/// `sampling_cdf`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+pub struct GpuEnvironmentMapHeader {
+    width: u32,
+    height: u32,
+}
+
+/// Builds the contents of the environment map buffer. Without an environment map, the header is
+/// zeroed and the shader never reads the distribution.
+pub fn environment_map_data(environment_map: Option<&Texture>) -> Vec<u8> {
+    let (header, cdf) = match environment_map {
+        Some(texture) => {
+            let (width, height) = texture.dimensions();
+
+            let header = GpuEnvironmentMapHeader { width, height };
+
+            (header, sampling_cdf(texture))
+        }
+        None => (GpuEnvironmentMapHeader::zeroed(), vec![0_f32]),
+    };
+
+    let mut data = bytemuck::bytes_of(&header).to_vec();
+
+    data.extend_from_slice(bytemuck::cast_slice(&cdf));
+
+    data
+}
+
+/// Uploads the environment map's radiance as 16-bit floats, which the shader reads texel by
+/// texel. Without an environment map, the texture is a single black texel.
+pub fn environment_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    environment_map: Option<&Texture>,
+) -> wgpu::TextureView {
+    let (dimensions, texels) = match environment_map {
+        Some(texture) => (texture.dimensions(), texture.as_slice()),
+        None => ((1_u32, 1_u32), &[[0_f32; 3]][..]),
+    };
+
+    let size = wgpu::Extent3d {
+        width: dimensions.0,
+        height: dimensions.1,
+        depth_or_array_layers: 1,
+    };
+
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba16Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+        label: Some("environment texture"),
+    });
+
+    let bytes: Vec<u16> = texels
+        .iter()
+        .flat_map(|texel| [texel[0], texel[1], texel[2], 1_f32].map(f16_bits))
+        .collect();
+
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        bytemuck::cast_slice(&bytes),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(8 * dimensions.0),
+            rows_per_image: Some(dimensions.1),
+        },
+        size,
+    );
+
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+/// A piecewise constant 2D distribution over the pixels of an equirectangular image,
+/// proportional to their luminance and the solid angle they cover.
+///
+/// The marginal CDF over the rows comes first, with `height + 1` entries. It is followed by one
+/// conditional CDF over the columns for each row, with `width + 1` entries each. Every CDF starts
+/// at 0 and ends at 1.
+pub fn sampling_cdf(texture: &Texture) -> Vec<f32> {
+    let (width, height) = texture.dimensions();
+
+    let (width, height) = (width as usize, height as usize);
+
+    let texels = texture.as_slice();
+
+    let mut conditional_cdfs = Vec::with_capacity(height * (width + 1));
+
+    let mut row_weights = Vec::with_capacity(height);
+
+    for y in 0..height {
+        // The rows near the poles are stretched over a smaller solid angle.
+        let sin_theta = ((y as f32 + 0.5_f32) / height as f32 * std::f32::consts::PI).sin();
+
+        let row = &texels[y * width..(y + 1) * width];
+
+        let row_weight = append_cdf(
+            &mut conditional_cdfs,
+            row.iter().map(|texel| luminance(texel) * sin_theta),
+        );
+
+        row_weights.push(row_weight);
+    }
+
+    let mut cdf = Vec::with_capacity(height + 1 + conditional_cdfs.len());
+
+    append_cdf(&mut cdf, row_weights.into_iter());
+
+    cdf.extend_from_slice(&conditional_cdfs);
+
+    cdf
+}
+
+/// Appends the normalized CDF of the weights, and returns the sum of the weights. If all the
+/// weights are zero, the distribution is uniform instead.
+fn append_cdf(
+    cdf: &mut Vec<f32>,
+    weights: impl ExactSizeIterator<Item = f32>,
+) -> f32 {
+    let count = weights.len();
+
+    let start = cdf.len();
+
+    let mut sum = 0_f32;
+
+    cdf.push(0_f32);
+
+    for weight in weights {
+        sum += weight.max(0_f32);
+
+        cdf.push(sum);
+    }
+
+    let entries = &mut cdf[start..];
+
+    if sum > 0_f32 {
+        for c in entries.iter_mut() {
+            *c /= sum;
+        }
+    } else {
+        for (idx, c) in entries.iter_mut().enumerate() {
+            *c = idx as f32 / count as f32;
+        }
+    }
+
+    // Guard against rounding, the shader relies on the last entry being exactly one.
+    entries[count] = 1_f32;
+
+    sum
+}
+
+fn luminance(texel: &[f32; 3]) -> f32 {
+    0.2126_f32 * texel[0] + 0.7152_f32 * texel[1] + 0.0722_f32 * texel[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bright_texel_dominates_the_distribution() {
+        let (width, height) = (8_usize, 4_usize);
+
+        let mut texels = vec![[0.1_f32; 3]; width * height];
+
+        texels[width + 5] = [1000_f32; 3];
+
+        let texture = Texture::new_from_data((width as u32, height as u32), texels);
+
+        let cdf = sampling_cdf(&texture);
+
+        assert_eq!(cdf.len(), (height + 1) + height * (width + 1));
+
+        assert!(cdf.windows(2).take(height).all(|w| w[0] <= w[1]));
+
+        // Most of the probability is in row 1, and column 5 within it.
+        assert_eq!(cdf[height], 1_f32);
+
+        assert!(cdf[2] - cdf[1] > 0.9_f32);
+
+        let row = &cdf[(height + 1) + (width + 1)..(height + 1) + 2 * (width + 1)];
+
+        assert_eq!((row[0], row[width]), (0_f32, 1_f32));
+
+        assert!(row[6] - row[5] > 0.9_f32);
+    }
+
+    #[test]
+    fn test_black_map_is_sampled_uniformly() {
+        let texture = Texture::new_from_data((2_u32, 2_u32), vec![[0_f32; 3]; 4]);
+
+        let cdf = sampling_cdf(&texture);
+
+        assert_eq!(cdf, [0_f32, 0.5, 1.0, 0.0, 0.5, 1.0, 0.0, 0.5, 1.0]);
+    }
+}
@@ -0,0 +1,413 @@
+use std::sync::mpsc;
+
+use wgpu::util::DeviceExt;
+
+use super::{
+    accumulation::AccumulationImage,
+    gpu_buffer::{storage_layout, uniform_layout},
+    tonemap::{AutoExposureParams, Metering},
+};
+
+/// Must match `NUM_BINS` in histogram.wgsl.
+pub const NUM_HISTOGRAM_BINS: usize = 64;
+
+/// The log2 luminance range covered by the histogram. Darker pixels go into the first bin and are
+/// not metered, brighter pixels are counted in the last bin.
+pub const MIN_LOG_LUMINANCE: f32 = -10_f32;
+
+pub const MAX_LOG_LUMINANCE: f32 = 10_f32;
+
+/// Must match `KEY_LUMINANCE` in display.wgsl.
+const KEY_LUMINANCE: f32 = 0.18_f32;
+
+/// Must match `@workgroup_size` of `buildHistogram`.
+const WORKGROUP_SIZE: u32 = 16_u32;
+
+/// The histogram and exposure of the latest frame, read back from the GPU for the UI.
+#[derive(Clone, Copy, Debug)]
+
+pub struct ExposureStats {
+    pub histogram: [u32; NUM_HISTOGRAM_BINS],
+    pub metered_log_luminance: f32,
+    pub adapted_log_luminance: f32,
+}
+
+impl ExposureStats {
+    /// The exposure applied on top of the compensation, in stops.
+    pub fn exposure_ev(&self) -> f32 {
+        KEY_LUMINANCE.log2() - self.adapted_log_luminance
+    }
+}
+
+/// Meters the accumulated image with a luminance histogram on the GPU, and adapts the exposure the
+/// display pass uses over time.
+pub struct AutoExposure {
+    params_buffer: wgpu::Buffer,
+    state_buffer: wgpu::Buffer,
+    compute_bind_group: wgpu::BindGroup,
+    display_layout: wgpu::BindGroupLayout,
+    display_bind_group: wgpu::BindGroup,
+    build_pipeline: wgpu::ComputePipeline,
+    meter_pipeline: wgpu::ComputePipeline,
+    /// False until the first frame has been metered, which then sets the exposure directly.
+    has_adapted: bool,
+    readback_buffer: wgpu::Buffer,
+    readback: Readback,
+    stats: Option<ExposureStats>,
+}
+
+/// The state of the non-blocking copy of the exposure state to the CPU, which spans frames.
+enum Readback {
+    Idle,
+    /// The copy has been recorded, and the buffer can be mapped once it has been submitted.
+    Copied,
+    Mapping(mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>),
+}
+
+impl AutoExposure {
+    /// `image_layout` is the accumulation image's display layout, used as group 0.
+    pub fn new(
+        device: &wgpu::Device,
+        image_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<GpuExposureParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+            label: Some("exposure parameter buffer"),
+        });
+
+        let histogram_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            contents: bytemuck::bytes_of(&[0_u32; NUM_HISTOGRAM_BINS]),
+            usage: wgpu::BufferUsages::STORAGE,
+            label: Some("histogram buffer"),
+        });
+
+        let state_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            contents: bytemuck::bytes_of(&GpuExposureState {
+                adapted_log_luminance: 0_f32,
+                metered_log_luminance: 0_f32,
+                histogram: [0_u32; NUM_HISTOGRAM_BINS],
+            }),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            label: Some("exposure state buffer"),
+        });
+
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: state_buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+            label: Some("exposure readback buffer"),
+        });
+
+        let compute_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_layout(0_u32, wgpu::ShaderStages::COMPUTE),
+                storage_layout(1_u32, wgpu::ShaderStages::COMPUTE, false),
+                storage_layout(2_u32, wgpu::ShaderStages::COMPUTE, false),
+            ],
+            label: Some("exposure compute layout"),
+        });
+
+        let compute_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &compute_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0_u32,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1_u32,
+                    resource: histogram_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2_u32,
+                    resource: state_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("exposure compute bind group"),
+        });
+
+        let display_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[storage_layout(0_u32, wgpu::ShaderStages::FRAGMENT, true)],
+            label: Some("exposure display layout"),
+        });
+
+        let display_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &display_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0_u32,
+                resource: state_buffer.as_entire_binding(),
+            }],
+            label: Some("exposure display bind group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            source: wgpu::ShaderSource::Wgsl(include_str!("histogram.wgsl").into()),
+            label: Some("histogram.wgsl"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[image_layout, &compute_layout],
+            push_constant_ranges: &[],
+            label: Some("exposure layout"),
+        });
+
+        let create_pipeline = |entry_point, label| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                label: Some(label),
+            })
+        };
+
+        Self {
+            params_buffer,
+            state_buffer,
+            compute_bind_group,
+            display_layout,
+            display_bind_group,
+            build_pipeline: create_pipeline("buildHistogram", "build histogram pipeline"),
+            meter_pipeline: create_pipeline("meterHistogram", "meter histogram pipeline"),
+            has_adapted: false,
+            readback_buffer,
+            readback: Readback::Idle,
+            stats: None,
+        }
+    }
+
+    pub fn display_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.display_layout
+    }
+
+    pub fn display_bind_group(&self) -> &wgpu::BindGroup {
+        &self.display_bind_group
+    }
+
+    pub fn stats(&self) -> Option<&ExposureStats> {
+        self.stats.as_ref()
+    }
+
+    /// Makes the next update set the exposure directly, without adapting from the previous one.
+    pub fn reset(&mut self) {
+        self.has_adapted = false;
+    }
+
+    /// Records the passes which meter the current accumulated image and adapt the exposure over
+    /// `dt` seconds. Must be called at most once per submitted frame, since it also advances the
+    /// readback of the previous frame's statistics.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        image: &AccumulationImage,
+        params: &AutoExposureParams,
+        dt: f32,
+    ) {
+        self.poll_readback(device);
+
+        let adaptation = if self.has_adapted {
+            1_f32 - (-dt * params.adaptation_rate.max(0_f32)).exp()
+        } else {
+            1_f32
+        };
+
+        self.has_adapted = true;
+
+        let gpu_params = GpuExposureParams {
+            min_log_luminance: MIN_LOG_LUMINANCE,
+            log_luminance_range: MAX_LOG_LUMINANCE - MIN_LOG_LUMINANCE,
+            adaptation,
+            metering: match params.metering {
+                Metering::Average => 0_u32,
+                Metering::Percentile => 1_u32,
+            },
+            percentile: params.percentile,
+            _padding: [0_u32; 3],
+        };
+
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&gpu_params));
+
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("exposure pass"),
+            });
+
+            compute_pass.set_bind_group(0, image.display_bind_group(), &[]);
+
+            compute_pass.set_bind_group(1, &self.compute_bind_group, &[]);
+
+            let (width, height) = image.size();
+
+            compute_pass.set_pipeline(&self.build_pipeline);
+
+            compute_pass.dispatch_workgroups(
+                width.div_ceil(WORKGROUP_SIZE),
+                height.div_ceil(WORKGROUP_SIZE),
+                1,
+            );
+
+            compute_pass.set_pipeline(&self.meter_pipeline);
+
+            compute_pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        if matches!(self.readback, Readback::Idle) {
+            encoder.copy_buffer_to_buffer(
+                &self.state_buffer,
+                0,
+                &self.readback_buffer,
+                0,
+                self.state_buffer.size(),
+            );
+
+            self.readback = Readback::Copied;
+        }
+    }
+
+    /// Reads the exposure state back to the CPU, blocking until the GPU has finished all the
+    /// submitted work.
+    pub fn read_stats(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<ExposureStats, wgpu::BufferAsyncError> {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: self.state_buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+            label: Some("exposure readback buffer"),
+        });
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+        encoder.copy_buffer_to_buffer(&self.state_buffer, 0, &buffer, 0, buffer.size());
+
+        queue.submit(Some(encoder.finish()));
+
+        let (sender, receiver) = mpsc::channel();
+
+        buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                // The receiver outlives the poll below, so sending cannot fail.
+                let _ = sender.send(result);
+            });
+
+        device.poll(wgpu::Maintain::Wait);
+
+        receiver
+            .recv()
+            .expect("The map callback should have been called by the blocking poll")?;
+
+        let stats = read_mapped_stats(&buffer);
+
+        buffer.unmap();
+
+        Ok(stats)
+    }
+
+    fn poll_readback(
+        &mut self,
+        device: &wgpu::Device,
+    ) {
+        match &self.readback {
+            Readback::Idle => {}
+            // The copy was recorded into the previous frame's encoder, which has been submitted by
+            // now.
+            Readback::Copied => {
+                let (sender, receiver) = mpsc::channel();
+
+                self.readback_buffer
+                    .slice(..)
+                    .map_async(wgpu::MapMode::Read, move |result| {
+                        // The receiver is dropped if the raytracer is, nobody is interested then.
+                        let _ = sender.send(result);
+                    });
+
+                self.readback = Readback::Mapping(receiver);
+            }
+            Readback::Mapping(receiver) => {
+                device.poll(wgpu::Maintain::Poll);
+
+                match receiver.try_recv() {
+                    Ok(Ok(())) => {
+                        self.stats = Some(read_mapped_stats(&self.readback_buffer));
+
+                        self.readback_buffer.unmap();
+
+                        self.readback = Readback::Idle;
+                    }
+                    Ok(Err(_)) | Err(mpsc::TryRecvError::Disconnected) => {
+                        self.readback = Readback::Idle;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {}
+                }
+            }
+        }
+    }
+}
+
+fn read_mapped_stats(buffer: &wgpu::Buffer) -> ExposureStats {
+    let state: GpuExposureState =
+        bytemuck::pod_read_unaligned(&buffer.slice(..).get_mapped_range());
+
+    ExposureStats {
+        histogram: state.histogram,
+        metered_log_luminance: state.metered_log_luminance,
+        adapted_log_luminance: state.adapted_log_luminance,
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+struct GpuExposureParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    adaptation: f32,
+    metering: u32,
+    percentile: f32,
+    _padding: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+struct GpuExposureState {
+    adapted_log_luminance: f32,
+    metered_log_luminance: f32,
+    histogram: [u32; NUM_HISTOGRAM_BINS],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposure_maps_adapted_luminance_to_key() {
+        let stats = ExposureStats {
+            histogram: [0_u32; NUM_HISTOGRAM_BINS],
+            metered_log_luminance: 0_f32,
+            adapted_log_luminance: 2_f32,
+        };
+
+        let exposed = 4_f32 * stats.exposure_ev().exp2();
+
+        assert!((exposed - KEY_LUMINANCE).abs() < 1e-6_f32);
+    }
+
+    #[test]
+    fn test_gpu_struct_sizes() {
+        // Uniform structs are padded to 16 bytes, and the state must match ExposureState.
+        assert_eq!(std::mem::size_of::<GpuExposureParams>(), 32_usize);
+
+        assert_eq!(
+            std::mem::size_of::<GpuExposureState>(),
+            8_usize + 4_usize * NUM_HISTOGRAM_BINS
+        );
+    }
+}
@@ -47,19 +47,6 @@ impl UniformBuffer {
         &self.handle
     }
 
-    pub fn layout(&self, visibility: wgpu::ShaderStages) -> wgpu::BindGroupLayoutEntry {
-        wgpu::BindGroupLayoutEntry {
-            binding: self.binding_idx,
-            visibility,
-            ty: wgpu::BindingType::Buffer {
-                ty: wgpu::BufferBindingType::Uniform,
-                has_dynamic_offset: false,
-                min_binding_size: None,
-            },
-            count: None,
-        }
-    }
-
     pub fn binding(&self) -> wgpu::BindGroupEntry<'_> {
         wgpu::BindGroupEntry {
             binding: self.binding_idx,
@@ -74,6 +61,26 @@ pub struct StorageBuffer {
 }
 
 impl StorageBuffer {
+    /// wgpu does not allow empty bindings, so an empty slice is uploaded as a single zeroed
+    /// element. The shader must treat a zeroed element as inert, e.g. a sphere with zero radius.
+    pub fn new_from_slice<T: bytemuck::Pod>(
+        device: &wgpu::Device,
+        data: &[T],
+        binding_idx: u32,
+        label: Option<&str>,
+    ) -> Self {
+        if data.is_empty() {
+            Self::new_from_bytes(
+                device,
+                bytemuck::bytes_of(&T::zeroed()),
+                binding_idx,
+                label,
+            )
+        } else {
+            Self::new_from_bytes(device, bytemuck::cast_slice(data), binding_idx, label)
+        }
+    }
+
     pub fn new_from_bytes(
         device: &wgpu::Device,
         bytes: &[u8],
@@ -96,23 +103,6 @@ impl StorageBuffer {
         &self.handle
     }
 
-    pub fn layout(
-        &self,
-        visibility: wgpu::ShaderStages,
-        read_only: bool,
-    ) -> wgpu::BindGroupLayoutEntry {
-        wgpu::BindGroupLayoutEntry {
-            binding: self.binding_idx,
-            visibility,
-            ty: wgpu::BindingType::Buffer {
-                ty: wgpu::BufferBindingType::Storage { read_only },
-                has_dynamic_offset: false,
-                min_binding_size: None,
-            },
-            count: None,
-        }
-    }
-
     pub fn binding(&self) -> wgpu::BindGroupEntry<'_> {
         wgpu::BindGroupEntry {
             binding: self.binding_idx,
@@ -120,3 +110,38 @@ impl StorageBuffer {
         }
     }
 }
+
+/// The layout entry of a uniform buffer bound at `binding`.
+pub fn uniform_layout(
+    binding: u32,
+    visibility: wgpu::ShaderStages,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+/// The layout entry of a storage buffer bound at `binding`.
+pub fn storage_layout(
+    binding: u32,
+    visibility: wgpu::ShaderStages,
+    read_only: bool,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
@@ -0,0 +1,120 @@
+const NUM_BINS = 64u;
+
+struct DisplayParams {
+    accumulatedSamplesPerPixel: u32,
+    tonemapper: u32,
+    exposure: f32,
+    whitePoint: f32,
+    autoExposure: u32,
+    toRec709: mat3x3<f32>,
+}
+
+struct ExposureParams {
+    minLogLuminance: f32,
+    logLuminanceRange: f32,
+    // The fraction of the way from the adapted to the metered luminance covered this frame.
+    adaptation: f32,
+    // 0 for the average, 1 for the percentile
+    metering: u32,
+    percentile: f32,
+}
+
+struct ExposureState {
+    adaptedLogLuminance: f32,
+    meteredLogLuminance: f32,
+    // The histogram of the latest frame, for the UI.
+    histogram: array<u32, NUM_BINS>,
+}
+
+@group(0) @binding(0) var<uniform> displayParams: DisplayParams;
+@group(0) @binding(1) var image: texture_2d<f32>;
+
+@group(1) @binding(0) var<uniform> exposureParams: ExposureParams;
+@group(1) @binding(1) var<storage, read_write> histogram: array<atomic<u32>, NUM_BINS>;
+@group(1) @binding(2) var<storage, read_write> exposureState: ExposureState;
+
+var<workgroup> localHistogram: array<atomic<u32>, NUM_BINS>;
+
+// Bin 0 holds the pixels too dark to meter, such as black backgrounds. The other bins split the
+// log luminance range evenly.
+@compute @workgroup_size(16, 16)
+fn buildHistogram(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) localIdx: u32
+) {
+    if localIdx < NUM_BINS {
+        atomicStore(&localHistogram[localIdx], 0u);
+    }
+    workgroupBarrier();
+
+    let imageSize = textureDimensions(image);
+    if id.x < u32(imageSize.x) && id.y < u32(imageSize.y) {
+        let invN = 1f / f32(max(displayParams.accumulatedSamplesPerPixel, 1u));
+        let rgb = invN * (displayParams.toRec709 * textureLoad(image, vec2(i32(id.x), i32(id.y)), 0).rgb);
+        let luminance = dot(rgb, vec3(0.2126, 0.7152, 0.0722));
+
+        var bin = 0u;
+        let logLuminance = log2(max(luminance, 1e-10));
+        if logLuminance >= exposureParams.minLogLuminance {
+            let t = (logLuminance - exposureParams.minLogLuminance) / exposureParams.logLuminanceRange;
+            bin = 1u + min(u32(t * f32(NUM_BINS - 1u)), NUM_BINS - 2u);
+        }
+
+        atomicAdd(&localHistogram[bin], 1u);
+    }
+    workgroupBarrier();
+
+    if localIdx < NUM_BINS {
+        atomicAdd(&histogram[localIdx], atomicLoad(&localHistogram[localIdx]));
+    }
+}
+
+var<workgroup> binCounts: array<u32, NUM_BINS>;
+
+// Meters the histogram, adapts the exposure towards it, and clears the histogram for the next
+// frame. Runs as a single workgroup.
+@compute @workgroup_size(64)
+fn meterHistogram(@builtin(local_invocation_index) localIdx: u32) {
+    let count = atomicLoad(&histogram[localIdx]);
+    atomicStore(&histogram[localIdx], 0u);
+    exposureState.histogram[localIdx] = count;
+    binCounts[localIdx] = count;
+    workgroupBarrier();
+
+    if localIdx != 0u {
+        return;
+    }
+
+    var total = 0u;
+    for (var bin = 1u; bin < NUM_BINS; bin += 1u) {
+        total += binCounts[bin];
+    }
+
+    // Nothing to meter, keep the current exposure.
+    if total == 0u {
+        return;
+    }
+
+    var meteredBin = 0f;
+    if exposureParams.metering == 0u {
+        for (var bin = 1u; bin < NUM_BINS; bin += 1u) {
+            meteredBin += f32(binCounts[bin]) * (f32(bin - 1u) + 0.5);
+        }
+        meteredBin /= f32(total);
+    } else {
+        let threshold = exposureParams.percentile * f32(total);
+        var cumulative = 0u;
+        for (var bin = 1u; bin < NUM_BINS; bin += 1u) {
+            cumulative += binCounts[bin];
+            meteredBin = f32(bin - 1u) + 0.5;
+            if f32(cumulative) >= threshold {
+                break;
+            }
+        }
+    }
+
+    let metered = exposureParams.minLogLuminance + meteredBin / f32(NUM_BINS - 1u) * exposureParams.logLuminanceRange;
+
+    exposureState.meteredLogLuminance = metered;
+    exposureState.adaptedLogLuminance = mix(exposureState.adaptedLogLuminance, metered, exposureParams.adaptation);
+}
@@ -0,0 +1,486 @@
+use std::{collections::HashMap, ops::Range};
+
+use bytemuck::Zeroable;
+use nalgebra_glm::{Mat4, Quat, Vec3};
+
+use super::{
+    bvh::{Aabb, Bvh, BvhStats, GpuBvhNode},
+    mesh::GpuMeshData,
+    GpuPrimitive, Scene,
+};
+
+/// Places a copy of a sphere, a shape or a group of meshes with an affine transform from the object's
+/// own space into the world. All the copies of an object share its geometry and its BVH, and an
+/// object which is instanced is only drawn where its instances place it.
+#[derive(Clone, Debug, PartialEq)]
+
+pub struct Instance {
+    pub object: InstancedObject,
+    transform: Mat4,
+    inverse: Mat4,
+    /// The transforms at time 0 and at time 1 of an instance which moves.
+    motion: Option<[Transform; 2]>,
+}
+
+/// A scale, followed by a rotation and a translation. Moving instances interpolate between two
+/// of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Transform {
+    pub fn matrix(&self) -> Mat4 {
+        glm::translation(&self.translation)
+            * glm::quat_to_mat4(&self.rotation)
+            * glm::scaling(&self.scale)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+
+pub enum InstancedObject {
+    /// An index into `Scene::spheres`.
+    Sphere(u32),
+    /// An index into `Scene::shapes`.
+    Shape(u32),
+    /// A range of `Scene::meshes`, such as all the meshes of one OBJ file.
+    Meshes(Range<u32>),
+}
+
+impl Instance {
+    /// Returns `None` if the transform can't be inverted, such as when it scales an axis to zero.
+    pub fn new(
+        object: InstancedObject,
+        transform: Mat4,
+    ) -> Option<Self> {
+        let inverse = transform.try_inverse()?;
+
+        Some(Self {
+            object,
+            transform,
+            inverse,
+            motion: None,
+        })
+    }
+
+    /// An instance which moves from `start` at time 0 to `end` at time 1. The translation and the
+    /// scale are interpolated linearly, and the rotation along the shortest arc. Returns `None`
+    /// if either transform scales an axis to zero, or if the scale of an axis changes sign.
+    pub fn new_moving(
+        object: InstancedObject,
+        start: &Transform,
+        end: &Transform,
+    ) -> Option<Self> {
+        if start.scale.component_mul(&end.scale).min() <= 0_f32 {
+            return None;
+        }
+
+        let mut instance = Self::new(object, start.matrix())?;
+
+        instance.motion = Some([*start, *end]);
+
+        Some(instance)
+    }
+
+    /// The transform at time 0.
+    pub fn transform(&self) -> &Mat4 {
+        &self.transform
+    }
+
+    pub fn inverse(&self) -> &Mat4 {
+        &self.inverse
+    }
+
+    pub fn motion(&self) -> Option<&[Transform; 2]> {
+        self.motion.as_ref()
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+pub struct GpuInstance {
+    /// The top three rows of the transform, column by column. Each column is padded to 16 bytes,
+    /// as in WGSL's `mat4x3`.
+    object_to_world: [[f32; 4]; 4],
+    world_to_object: [[f32; 4]; 4],
+    /// The root of the object's tree in the shared node array.
+    root: u32,
+    /// 1 if the instance moves, and the transform is interpolated between the two in `motion`.
+    is_moving: u32,
+    _padding: [u32; 2],
+    motion: [GpuTransform; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+struct GpuTransform {
+    translation: Vec3,
+    _padding1: f32,
+    /// A unit quaternion, as (x, y, z, w).
+    rotation: [f32; 4],
+    scale: Vec3,
+    _padding2: f32,
+}
+
+impl GpuTransform {
+    fn new(transform: &Transform) -> Self {
+        Self {
+            translation: transform.translation,
+            _padding1: 0_f32,
+            rotation: transform.rotation.normalize().coords.into(),
+            scale: transform.scale,
+            _padding2: 0_f32,
+        }
+    }
+}
+
+/// The scene's two-level BVH. The top level holds the spheres, shapes and triangles placed in the
+/// world directly, and the instances, whose leaves lead to the tree of their object. The objects' trees
+/// follow the top level in the same node and primitive arrays, so the root is always node 0.
+pub struct SceneBvh {
+    pub nodes: Vec<GpuBvhNode>,
+    pub primitives: Vec<GpuPrimitive>,
+    pub instances: Vec<GpuInstance>,
+    /// The spheres, shapes and triangles placed in the world directly, not through instances.
+    pub world_primitives: Vec<GpuPrimitive>,
+    pub stats: BvhStats,
+}
+
+impl SceneBvh {
+    pub fn build(
+        scene: &Scene,
+        mesh_data: &GpuMeshData,
+    ) -> Self {
+        let triangle_bounds: Vec<Aabb> = mesh_data.triangle_bounds().collect();
+
+        let mut is_instanced_sphere = vec![false; scene.spheres.len()];
+
+        let mut is_instanced_shape = vec![false; scene.shapes.len()];
+
+        let mut is_instanced_mesh = vec![false; scene.meshes.len()];
+
+        // Each object's tree is built once, however many instances share it.
+        let mut object_indices: HashMap<&InstancedObject, usize> = HashMap::new();
+
+        let mut objects: Vec<(Bvh, Vec<GpuPrimitive>)> = Vec::new();
+
+        for instance in &scene.instances {
+            match &instance.object {
+                InstancedObject::Sphere(idx) => is_instanced_sphere[*idx as usize] = true,
+                InstancedObject::Shape(idx) => is_instanced_shape[*idx as usize] = true,
+                InstancedObject::Meshes(meshes) => {
+                    is_instanced_mesh[meshes.start as usize..meshes.end as usize].fill(true);
+                }
+            }
+
+            if object_indices.contains_key(&instance.object) {
+                continue;
+            }
+
+            let (primitives, bounds) =
+                object_primitives(&instance.object, scene, mesh_data, &triangle_bounds);
+
+            objects.push((Bvh::build(&bounds), primitives));
+
+            object_indices.insert(&instance.object, objects.len() - 1);
+        }
+
+        let world_primitives: Vec<GpuPrimitive> = (0..scene.spheres.len())
+            .filter(|&idx| !is_instanced_sphere[idx])
+            .map(GpuPrimitive::sphere)
+            .chain(
+                (0..scene.shapes.len())
+                    .filter(|&idx| !is_instanced_shape[idx])
+                    .map(GpuPrimitive::shape),
+            )
+            .chain(
+                mesh_data
+                    .triangle_ranges
+                    .iter()
+                    .enumerate()
+                    .filter(|&(mesh_idx, _)| !is_instanced_mesh[mesh_idx])
+                    .flat_map(|(_, triangles)| triangles.clone())
+                    .map(GpuPrimitive::triangle),
+            )
+            .collect();
+
+        let (top_level_primitives, top_level_bounds): (Vec<GpuPrimitive>, Vec<Aabb>) =
+            world_primitives
+                .iter()
+                .map(|&primitive| {
+                    let bounds = match primitive.kind {
+                        0_u32 => scene.spheres[primitive.index as usize].aabb(),
+                        4_u32 => scene.shapes[primitive.index as usize].aabb(),
+                        _ => triangle_bounds[primitive.index as usize],
+                    };
+
+                    (primitive, bounds)
+                })
+                .chain(scene.instances.iter().enumerate().map(|(idx, instance)| {
+                    let (bvh, _) = &objects[object_indices[&instance.object]];
+
+                    (
+                        GpuPrimitive::instance(idx),
+                        instance_bounds(&bvh.bounds(), instance),
+                    )
+                }))
+                .unzip();
+
+        let top_level = Bvh::build(&top_level_bounds);
+
+        let mut nodes = top_level.nodes;
+
+        let mut primitives: Vec<GpuPrimitive> = top_level
+            .primitive_indices
+            .iter()
+            .map(|&idx| top_level_primitives[idx as usize])
+            .collect();
+
+        let mut stats = top_level.stats;
+
+        let mut object_depth = 0_u32;
+
+        let mut roots = Vec::with_capacity(objects.len());
+
+        for (mut bvh, object_primitives) in objects {
+            roots.push(nodes.len() as u32);
+
+            bvh.offset_indices(nodes.len() as u32, primitives.len() as u32);
+
+            nodes.extend(bvh.nodes);
+
+            primitives.extend(
+                bvh.primitive_indices
+                    .iter()
+                    .map(|&idx| object_primitives[idx as usize]),
+            );
+
+            stats.primitive_count += bvh.stats.primitive_count;
+
+            stats.node_count += bvh.stats.node_count;
+
+            stats.leaf_count += bvh.stats.leaf_count;
+
+            stats.build_time += bvh.stats.build_time;
+
+            object_depth = object_depth.max(bvh.stats.depth);
+        }
+
+        // Rays go on from the top level's leaves into the objects' trees.
+        stats.depth += object_depth;
+
+        let instances = scene
+            .instances
+            .iter()
+            .map(|instance| {
+                let motion = instance.motion().map(|[start, end]| {
+                    let mut end = GpuTransform::new(end);
+
+                    // Quaternions q and -q are the same rotation, the one nearer to the start is
+                    // interpolated to along the shortest arc.
+                    if glm::dot(&start.rotation.coords, &end.rotation.into()) < 0_f32 {
+                        end.rotation = end.rotation.map(|x| -x);
+                    }
+
+                    [GpuTransform::new(start), end]
+                });
+
+                GpuInstance {
+                    object_to_world: gpu_mat4x3(instance.transform()),
+                    world_to_object: gpu_mat4x3(instance.inverse()),
+                    root: roots[object_indices[&instance.object]],
+                    is_moving: u32::from(motion.is_some()),
+                    _padding: [0_u32; 2],
+                    motion: motion.unwrap_or([GpuTransform::zeroed(); 2]),
+                }
+            })
+            .collect();
+
+        Self {
+            nodes,
+            primitives,
+            instances,
+            world_primitives,
+            stats,
+        }
+    }
+}
+
+/// The sphere, shape or triangles of an object, and their bounds in the object's space.
+fn object_primitives(
+    object: &InstancedObject,
+    scene: &Scene,
+    mesh_data: &GpuMeshData,
+    triangle_bounds: &[Aabb],
+) -> (Vec<GpuPrimitive>, Vec<Aabb>) {
+    match object {
+        InstancedObject::Sphere(idx) => {
+            let sphere = &scene.spheres[*idx as usize];
+
+            (
+                vec![GpuPrimitive::sphere(*idx as usize)],
+                vec![sphere.aabb()],
+            )
+        }
+        InstancedObject::Shape(idx) => (
+            vec![GpuPrimitive::shape(*idx as usize)],
+            vec![scene.shapes[*idx as usize].aabb()],
+        ),
+        InstancedObject::Meshes(meshes) => mesh_data.triangle_ranges
+            [meshes.start as usize..meshes.end as usize]
+            .iter()
+            .flat_map(Clone::clone)
+            .map(|idx| (GpuPrimitive::triangle(idx), triangle_bounds[idx]))
+            .unzip(),
+    }
+}
+
+/// The bounds of the object's box wherever the instance places it while it moves.
+fn instance_bounds(
+    bounds: &Aabb,
+    instance: &Instance,
+) -> Aabb {
+    let Some([start, end]) = instance.motion() else {
+        return transform_bounds(bounds, instance.transform());
+    };
+
+    // Without a rotation, each point of the box moves in a straight line, and stays within the
+    // bounds of its start and end.
+    if start.rotation == end.rotation || bounds.is_empty() {
+        return transform_bounds(bounds, &start.matrix())
+            .union(&transform_bounds(bounds, &end.matrix()));
+    }
+
+    // A rotating box stays within the ball around the translation its farthest scaled corner
+    // reaches. The scale is interpolated linearly, so it's the farthest at either end.
+    let radius = corners(bounds)
+        .iter()
+        .flat_map(|corner| {
+            [start.scale, end.scale].map(|scale| glm::length(&scale.component_mul(corner)))
+        })
+        .fold(0_f32, f32::max);
+
+    let extent = glm::vec3(radius, radius, radius);
+
+    Aabb {
+        min: glm::min2(&start.translation, &end.translation) - extent,
+        max: glm::max2(&start.translation, &end.translation) + extent,
+    }
+}
+
+/// The bounds of the box's eight corners after the transform.
+fn transform_bounds(
+    bounds: &Aabb,
+    transform: &Mat4,
+) -> Aabb {
+    if bounds.is_empty() {
+        return Aabb::empty();
+    }
+
+    let corners = corners(bounds).map(|p| (transform * glm::vec4(p.x, p.y, p.z, 1_f32)).xyz());
+
+    Aabb::from_points(&corners)
+}
+
+fn corners(bounds: &Aabb) -> [Vec3; 8] {
+    [0_usize, 1, 2, 3, 4, 5, 6, 7].map(|corner| {
+        // Each bit of the corner's index picks the minimum or the maximum along an axis.
+        let select = |axis: usize| {
+            if corner & (1 << axis) == 0 {
+                bounds.min[axis]
+            } else {
+                bounds.max[axis]
+            }
+        };
+
+        glm::vec3(select(0), select(1), select(2))
+    })
+}
+
+fn gpu_mat4x3(m: &Mat4) -> [[f32; 4]; 4] {
+    [0_usize, 1, 2, 3].map(|c| [m[(0, c)], m[(1, c)], m[(2, c)], 0_f32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transform_bounds() {
+        let bounds = Aabb {
+            min: glm::vec3(-1_f32, -1_f32, -1_f32),
+            max: glm::vec3(1_f32, 1_f32, 1_f32),
+        };
+
+        let transform = glm::translate(
+            &glm::scale(&Mat4::identity(), &glm::vec3(2_f32, 1_f32, 1_f32)),
+            &glm::vec3(1_f32, 0_f32, 0_f32),
+        );
+
+        let transformed = transform_bounds(&bounds, &transform);
+        assert_eq!(transformed.min, glm::vec3(0_f32, -1_f32, -1_f32));
+        assert_eq!(transformed.max, glm::vec3(4_f32, 1_f32, 1_f32));
+    }
+
+    #[test]
+    fn test_singular_transform_is_rejected() {
+        let transform = glm::scale(&Mat4::identity(), &glm::vec3(1_f32, 0_f32, 1_f32));
+        assert!(Instance::new(InstancedObject::Sphere(0), transform).is_none());
+
+        let start = Transform {
+            translation: Vec3::zeros(),
+            rotation: Quat::identity(),
+            scale: glm::vec3(1_f32, 1_f32, 1_f32),
+        };
+        let end = Transform {
+            scale: glm::vec3(1_f32, -1_f32, 1_f32),
+            ..start
+        };
+        assert!(Instance::new_moving(InstancedObject::Sphere(0), &start, &end).is_none());
+    }
+
+    #[test]
+    fn test_moving_instance_bounds() {
+        let bounds = Aabb {
+            min: glm::vec3(1_f32, 0_f32, 0_f32),
+            max: glm::vec3(2_f32, 1_f32, 1_f32),
+        };
+        let start = Transform {
+            translation: Vec3::zeros(),
+            rotation: Quat::identity(),
+            scale: glm::vec3(1_f32, 1_f32, 1_f32),
+        };
+        let end = Transform {
+            translation: glm::vec3(0_f32, 3_f32, 0_f32),
+            rotation: glm::quat_angle_axis(std::f32::consts::PI, &Vec3::y()),
+            ..start
+        };
+        let instance = Instance::new_moving(InstancedObject::Sphere(0), &start, &end).unwrap();
+        let moving_bounds = instance_bounds(&bounds, &instance);
+
+        // Halfway, the box has turned a quarter and is out of the bounds of both of its ends.
+        let halfway = Transform {
+            translation: glm::vec3(0_f32, 1.5_f32, 0_f32),
+            rotation: glm::quat_slerp(&start.rotation, &end.rotation, 0.5_f32),
+            ..start
+        };
+        let halfway_bounds = transform_bounds(&bounds, &halfway.matrix());
+        assert!(halfway_bounds.max.z.max(-halfway_bounds.min.z) > 1.9_f32);
+        assert!(moving_bounds.contains(&halfway_bounds));
+
+        let end = Transform {
+            rotation: Quat::identity(),
+            ..end
+        };
+        let instance = Instance::new_moving(InstancedObject::Sphere(0), &start, &end).unwrap();
+        let moving_bounds = instance_bounds(&bounds, &instance);
+        assert_eq!(moving_bounds.min, glm::vec3(1_f32, 0_f32, 0_f32));
+        assert_eq!(moving_bounds.max, glm::vec3(2_f32, 4_f32, 1_f32));
+    }
+}
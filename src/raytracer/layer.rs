@@ -1,8 +1,9 @@
 use std::ops::DerefMut;
 
 use super::{
-    math::*, scatter_metal, texture::*, texture_lookup, GpuCamera, GpuMaterial, Intersection,
-    Material, Metal, Ray, RenderParams, Scatterable, Scene, Sphere,
+    math::*, scatter_metal, texture::*, texture_atlas::TextureAtlasBuilder, texture_lookup,
+    GpuCamera, Intersection, Material, Metal, Ray, RenderParams, Scatterable, Scene, Sphere,
+    TextureDescriptor,
 };
 
 use image::{DynamicImage, ImageBuffer, Rgb};
@@ -41,14 +42,23 @@ pub struct Layer {
     pub camera: GpuCamera,
     pub world: Vec<Box<Sphere>>,
     pub materials: Vec<Material>,
-    global_texture_data: Vec<[f32; 3]>,
-    material_data: Vec<GpuMaterial>,
+    texture_atlas: TextureAtlasBuilder,
+    material_data: Vec<PreviewMaterial>,
+}
+
+/// The part of a material the preview samples: the texture and the parameter `GpuMaterial` puts in
+/// `desc1` and `x`.
+struct PreviewMaterial {
+    desc1: TextureDescriptor,
+    x: f32,
 }
 
 impl Layer {
     pub fn new(
         size: [f32; 2],
         render_params: &RenderParams,
+        scene: &Scene,
+        limits: &wgpu::Limits,
     ) -> Self {
         // Note: GpuCamera works in Imgui viewport
         let camera = GpuCamera::new(&render_params.camera, (size[0] as u32, size[1] as u32));
@@ -60,18 +70,16 @@ impl Layer {
         let imgbuf = Box::into_raw(Box::new(new_buffer));
 
         // Generating hittable objects
-        let scene = Self::scene();
-
         let world = scene.spheres[..]
             .into_iter()
             .map(|s| Box::new(s.clone()))
             .collect();
 
-        let materials = scene.materials;
+        let materials = scene.materials.clone();
 
-        let global_texture_data: Vec<[f32; 3]> = Vec::new();
+        let texture_atlas = TextureAtlasBuilder::new(limits);
 
-        let material_data: Vec<GpuMaterial> = Vec::with_capacity(0);
+        let material_data: Vec<PreviewMaterial> = Vec::with_capacity(0);
 
         let texture_id = TextureId::new(0);
 
@@ -82,69 +90,45 @@ impl Layer {
             camera,
             world,
             materials,
-            global_texture_data,
+            texture_atlas,
             material_data,
         }
     }
 
-    pub fn scene() -> Scene {
-        let materials = vec![
-            Material::Checkerboard {
-                even: Texture::new_from_color(glm::vec3(0.5_f32, 0.7_f32, 0.8_f32)),
-                odd: Texture::new_from_color(glm::vec3(0.9_f32, 0.9_f32, 0.9_f32)),
-            },
-            Material::Lambertian {
-                albedo: Texture::new_from_image("assets/moon.jpeg")
-                    .expect("Hardcoded path should be valid"),
-            },
-            Material::Metal {
-                albedo: Texture::new_from_color(glm::vec3(1_f32, 0.85_f32, 0.57_f32)),
-                fuzz: 0.4_f32,
-            },
-            Material::Dielectric {
-                refraction_index: 1.5_f32,
-            },
-            Material::Lambertian {
-                albedo: Texture::new_from_image("assets/earthmap.jpeg")
-                    .expect("Hardcoded path should be valid"),
-            },
-        ];
-
-        let spheres = vec![
-            Sphere::new(glm::vec3(5.0, 1.2, -1.5), 1.2, 4_u32),
-            Sphere::new(glm::vec3(0.0, -500.0, -1.0), 500.0, 0_u32),
-            Sphere::new(glm::vec3(0.0, 1.0, 0.0), 1.0, 3_u32),
-            Sphere::new(glm::vec3(-5.0, 1.0, 0.0), 1.0, 2_u32),
-            Sphere::new(glm::vec3(2.0, -1.0, 0.0), 2.0, 3_u32),
-            Sphere::new(glm::vec3(5.0, 0.8, 1.5), 0.8, 1_u32),
-        ];
-
-        Scene { spheres, materials }
-    }
-
-    pub fn set_global_data(&mut self) -> bool {
+    /// Packs only the textures the preview samples, rather than every texture of the scene.
+    pub fn set_global_data(&mut self) -> Result<bool, TextureError> {
         self.material_data = Vec::with_capacity(self.materials.len());
 
         for material in self.materials.iter() {
-            let gpu_material = match material {
-                Material::Lambertian { albedo } => {
-                    GpuMaterial::lambertian(albedo, &mut self.global_texture_data)
-                }
-                Material::Metal { albedo, fuzz } => {
-                    GpuMaterial::metal(albedo, *fuzz, &mut self.global_texture_data)
-                }
-                Material::Dielectric { refraction_index } => {
-                    GpuMaterial::dielectric(*refraction_index)
-                }
-                Material::Checkerboard { odd, even } => {
-                    GpuMaterial::checkerboard(odd, even, &mut self.global_texture_data)
-                }
+            let (texture, x) = match material {
+                Material::Lambertian { albedo, .. } => (Some(albedo), 0_f32),
+                Material::Metal { albedo, fuzz, .. } => (Some(albedo), *fuzz),
+                Material::Dielectric {
+                    refraction_index,
+                    attenuation_color,
+                    ..
+                } => (attenuation_color.as_ref(), *refraction_index),
+                Material::Checkerboard { even, .. } => (Some(even), 0_f32),
+                Material::Emissive { radiance, strength } => (Some(radiance), *strength),
+                Material::Principled {
+                    base_color,
+                    metallic,
+                    ..
+                } => (Some(base_color), *metallic),
+                Material::Volume {
+                    density, albedo, ..
+                } => (Some(albedo), *density),
+            };
+
+            let desc1 = match texture {
+                Some(texture) => self.texture_atlas.add(texture)?,
+                None => TextureDescriptor::empty(),
             };
 
-            self.material_data.push(gpu_material);
+            self.material_data.push(PreviewMaterial { desc1, x });
         }
 
-        true
+        Ok(true)
     }
 
     pub fn register_texture(
@@ -229,7 +213,7 @@ impl Layer {
             .build(|| {
                 new_imgui_region_size = Some(ui.content_region_avail());
 
-                for c in &mut self.camera.eye {
+                for c in &mut self.camera.start.eye {
                     if ui.slider("eye", -10.0, 10.0, c) {};
                 }
 
@@ -348,7 +332,7 @@ impl Layer {
 
                     let fuzzy = self.material_data[2].x;
 
-                    let albedo = texture_lookup(texture, &self.global_texture_data, uu, vv);
+                    let albedo = texture_lookup(texture, &self.texture_atlas, uu, vv);
 
                     if !scatter_metal(&ray, rec, scattered_ray) {
                         return vec3_to_rgb8(vec3(0.0, 0.0, 0.0));
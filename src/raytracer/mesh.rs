@@ -0,0 +1,166 @@
+use std::ops::Range;
+
+use nalgebra_glm::{Vec2, Vec3};
+
+use super::bvh::Aabb;
+
+/// A triangle mesh in world space, or in its own space if instances place it. All the triangles
+/// of a mesh share a single material.
+#[derive(Clone)]
+
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2>,
+    pub triangles: Vec<[u32; 3]>,
+    pub material_idx: u32,
+}
+
+impl Mesh {
+    /// Smooth vertex normals are generated if `normals` is empty, and all uvs are set to zero if
+    /// `uvs` is empty.
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<Vec2>,
+        triangles: Vec<[u32; 3]>,
+        material_idx: u32,
+    ) -> Self {
+        let normals = if normals.is_empty() {
+            smooth_normals(&positions, &triangles)
+        } else {
+            normals
+        };
+
+        let uvs = if uvs.is_empty() {
+            vec![Vec2::zeros(); positions.len()]
+        } else {
+            uvs
+        };
+
+        Self {
+            positions,
+            normals,
+            uvs,
+            triangles,
+            material_idx,
+        }
+    }
+}
+
+fn smooth_normals(
+    positions: &[Vec3],
+    triangles: &[[u32; 3]],
+) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::zeros(); positions.len()];
+
+    for [i0, i1, i2] in triangles.iter().map(|t| t.map(|i| i as usize)) {
+        // The cross product's length is twice the triangle's area, which weights the face normal
+        // by area for free.
+        let face_normal =
+            glm::cross(&(positions[i1] - positions[i0]), &(positions[i2] - positions[i0]));
+
+        normals[i0] += face_normal;
+
+        normals[i1] += face_normal;
+
+        normals[i2] += face_normal;
+    }
+
+    normals
+        .into_iter()
+        .map(|n| {
+            if n == Vec3::zeros() {
+                glm::vec3(0_f32, 1_f32, 0_f32)
+            } else {
+                glm::normalize(&n)
+            }
+        })
+        .collect()
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+pub struct GpuTriangle {
+    indices: [u32; 3],
+    material_idx: u32,
+}
+
+impl GpuTriangle {
+    pub fn material_idx(&self) -> u32 {
+        self.material_idx
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+pub struct GpuVertex {
+    position_and_u: [f32; 4],
+    normal_and_v: [f32; 4],
+}
+
+/// The vertex and index data of all the meshes in a scene, concatenated.
+pub struct GpuMeshData {
+    pub vertices: Vec<GpuVertex>,
+    pub triangles: Vec<GpuTriangle>,
+    /// The range of `triangles` each mesh was turned into.
+    pub triangle_ranges: Vec<Range<usize>>,
+}
+
+impl GpuMeshData {
+    pub fn new(meshes: &[Mesh]) -> Self {
+        let mut data = Self {
+            vertices: Vec::new(),
+            triangles: Vec::new(),
+            triangle_ranges: Vec::new(),
+        };
+
+        for mesh in meshes {
+            let vertex_offset = data.vertices.len() as u32;
+
+            let attributes = mesh.positions.iter().zip(&mesh.normals).zip(&mesh.uvs);
+
+            data.vertices
+                .extend(attributes.map(|((p, n), uv)| GpuVertex {
+                    position_and_u: [p.x, p.y, p.z, uv.x],
+                    normal_and_v: [n.x, n.y, n.z, uv.y],
+                }));
+
+            let first_triangle = data.triangles.len();
+
+            data.triangles
+                .extend(mesh.triangles.iter().map(|indices| GpuTriangle {
+                    indices: indices.map(|i| i + vertex_offset),
+                    material_idx: mesh.material_idx,
+                }));
+
+            data.triangle_ranges
+                .push(first_triangle..data.triangles.len());
+        }
+
+        data
+    }
+
+    /// The triangles followed by the vertices, which the shader reads from a single buffer of
+    /// 16-byte elements. Each triangle takes one element and each vertex two, the vertices start
+    /// at `triangles.len()`.
+    pub fn packed(&self) -> Vec<[u32; 4]> {
+        let triangles: &[[u32; 4]] = bytemuck::cast_slice(&self.triangles);
+
+        let vertices: &[[u32; 4]] = bytemuck::cast_slice(&self.vertices);
+
+        [triangles, vertices].concat()
+    }
+
+    pub fn triangle_bounds(&self) -> impl Iterator<Item = Aabb> + '_ {
+        self.triangles.iter().map(|triangle| {
+            Aabb::from_points(&triangle.indices.map(|idx| {
+                let p = self.vertices[idx as usize].position_and_u;
+
+                glm::vec3(p[0], p[1], p[2])
+            }))
+        })
+    }
+}
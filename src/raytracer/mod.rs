@@ -1,103 +1,142 @@
+use accumulation::AccumulationImage;
 pub use color::Color;
-use gpu_buffer::{StorageBuffer, UniformBuffer};
+use bvh::Aabb;
+use color_space::gpu_mat3;
+use environment::{environment_map_data, environment_texture};
+use exposure::AutoExposure;
+use gpu_buffer::{storage_layout, uniform_layout, StorageBuffer, UniformBuffer};
 use image::Rgb;
+use instance::SceneBvh;
+use mesh::GpuMeshData;
+use shape::GpuShape;
 pub use math::*;
 use nalgebra_glm::{acos, atan2, dot, vec3, Vec3};
-use wgpu::util::DeviceExt;
-pub use {angle::Angle, layer::Layer, texture::Texture, texture::WgpuTexture};
+use texture_atlas::{TextureAtlas, TextureAtlasBuilder};
+pub use {
+    angle::Angle,
+    bvh::BvhStats,
+    color_space::{ColorSpace, WorkingSpace},
+    exposure::ExposureStats,
+    instance::{Instance, InstancedObject, Transform},
+    layer::Layer,
+    mesh::Mesh,
+    rendered_image::{readback_bytes_per_row, readback_pixels, ImageExportError, RenderedImage},
+    shape::{Geometry, Shape},
+    texture::{
+        FilterMode, Pattern, PatternSpace, Procedural, Texture, TextureError, TextureSampler,
+        WgpuTexture, WrapMode,
+    },
+    tonemap::{DisplayParams, Metering, Tonemapper},
+};
 
 use thiserror::Error;
 
+mod accumulation;
 mod angle;
+mod bvh;
 mod color;
+mod color_space;
+mod environment;
+mod exposure;
 mod gpu_buffer;
+mod instance;
 mod layer;
 mod math;
+mod mesh;
+mod rendered_image;
+mod shape;
 mod texture;
+mod texture_atlas;
+mod tonemap;
 
 use std::f32::consts::*;
 
 pub struct Raytracer {
-    vertex_uniform_bind_group: wgpu::BindGroup,
-    vertex_buffer: wgpu::Buffer,
     frame_data_buffer: UniformBuffer,
-    image_bind_group: wgpu::BindGroup,
+    display_params_buffer: UniformBuffer,
+    trace_image_layout: wgpu::BindGroupLayout,
+    display_image_layout: wgpu::BindGroupLayout,
+    accumulation_image: AccumulationImage,
+    auto_exposure: AutoExposure,
     camera_buffer: UniformBuffer,
     sampling_parameter_buffer: UniformBuffer,
-    hw_sky_state_buffer: StorageBuffer,
+    hw_sky_state_buffer: UniformBuffer,
     parameter_bind_group: wgpu::BindGroup,
     scene_bind_group: wgpu::BindGroup,
-    pipeline: wgpu::RenderPipeline,
+    bvh_stats: BvhStats,
+    has_environment_map: bool,
+    working_space: WorkingSpace,
+    trace_pipeline: wgpu::ComputePipeline,
+    display_pipeline: wgpu::RenderPipeline,
     latest_render_params: RenderParams,
     render_progress: RenderProgress,
     frame_number: u32,
 }
 
+/// The path tracing shader binds the scene's spheres, materials, meshes, BVH nodes, primitives,
+/// environment map, instances and shapes as storage buffers, which fits the default limit of 8.
+/// The triangles and vertices share the mesh buffer, and the lights follow the BVH's primitives.
+const TRACE_STORAGE_BUFFERS: u32 = 8_u32;
+
+/// The scene bindings of the 8-bit atlas, the sampler and the 16-bit atlas.
+const ATLAS_BINDINGS: [u32; 3] = [2_u32, 8_u32, 7_u32];
+
 impl Raytracer {
+    /// The limits to request the device with, for an adapter with `adapter_limits`. The defaults
+    /// cover the raytracer, but downlevel adapters may support fewer storage buffers.
+    pub fn device_limits(adapter_limits: &wgpu::Limits) -> Result<wgpu::Limits, DeviceLimitsError> {
+        let max_storage_buffers = adapter_limits.max_storage_buffers_per_shader_stage;
+
+        if max_storage_buffers < TRACE_STORAGE_BUFFERS {
+            return Err(DeviceLimitsError::TooFewStorageBuffers(
+                max_storage_buffers,
+                TRACE_STORAGE_BUFFERS,
+            ));
+        }
+
+        Ok(wgpu::Limits::default())
+    }
+
+    /// The image is traced at `render_params.viewport_size` and drawn by `render_frame` to render
+    /// targets of `target_format`, scaled to their size.
     pub fn new(
         device: &wgpu::Device,
-        surface_config: &wgpu::SurfaceConfiguration,
+        queue: &wgpu::Queue,
+        target_format: wgpu::TextureFormat,
         scene: &Scene,
         render_params: &RenderParams,
-        max_viewport_resolution: u32,
-    ) -> Result<Self, RenderParamsValidationError> {
-        match render_params.validate() {
+    ) -> Result<Self, RaytracerError> {
+        let has_environment_map = scene.environment_map.is_some();
+
+        match render_params.validate(has_environment_map) {
             Ok(_) => {}
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
         }
 
-        let uniforms = VertexUniforms {
-            view_projection_matrix: unit_quad_projection_matrix(),
-            model_matrix: glm::identity(),
-        };
+        let frame_data_buffer =
+            UniformBuffer::new(device, 16_u64, 0_u32, Some("frame data buffer"));
 
-        let vertex_uniform_buffer = UniformBuffer::new_from_bytes(
+        let display_params_buffer = UniformBuffer::new(
             device,
-            bytemuck::bytes_of(&uniforms),
+            std::mem::size_of::<GpuDisplayParams>() as wgpu::BufferAddress,
             0_u32,
-            Some("uniforms"),
+            Some("display parameter buffer"),
         );
 
-        let vertex_uniform_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[vertex_uniform_buffer.layout(wgpu::ShaderStages::VERTEX)],
-                label: Some("uniforms layout"),
-            });
-
-        let vertex_uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &vertex_uniform_bind_group_layout,
-            entries: &[vertex_uniform_buffer.binding()],
-            label: Some("uniforms bind group"),
-        });
-
-        let frame_data_buffer =
-            UniformBuffer::new(device, 16_u64, 0_u32, Some("frame data buffer"));
-
-        let image_buffer = {
-            let buffer = vec![[0_f32; 3]; max_viewport_resolution as usize];
+        let trace_image_layout = AccumulationImage::trace_layout(device);
 
-            StorageBuffer::new_from_bytes(
-                device,
-                bytemuck::cast_slice(buffer.as_slice()),
-                1_u32,
-                Some("image buffer"),
-            )
-        };
+        let display_image_layout = AccumulationImage::display_layout(device);
 
-        let image_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    frame_data_buffer.layout(wgpu::ShaderStages::FRAGMENT),
-                    image_buffer.layout(wgpu::ShaderStages::FRAGMENT, false),
-                ],
-                label: Some("image layout"),
-            });
+        let accumulation_image = AccumulationImage::new(
+            device,
+            render_params.viewport_size,
+            &trace_image_layout,
+            &display_image_layout,
+            &frame_data_buffer,
+            &display_params_buffer,
+        );
 
-        let image_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &image_bind_group_layout,
-            entries: &[frame_data_buffer.binding(), image_buffer.binding()],
-            label: Some("image bind group"),
-        });
+        let auto_exposure = AutoExposure::new(device, &display_image_layout);
 
         let camera_buffer = {
             let camera = GpuCamera::new(&render_params.camera, render_params.viewport_size);
@@ -118,9 +157,12 @@ impl Raytracer {
         );
 
         let hw_sky_state_buffer = {
-            let sky_state = render_params.sky.to_sky_state()?;
+            let sky_state = render_params
+                .sky
+                .to_sky_state(scene.working_space)
+                .map_err(RenderParamsValidationError::from)?;
 
-            StorageBuffer::new_from_bytes(
+            UniformBuffer::new_from_bytes(
                 device,
                 bytemuck::bytes_of(&sky_state),
                 2_u32,
@@ -130,11 +172,7 @@ impl Raytracer {
 
         let parameter_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    camera_buffer.layout(wgpu::ShaderStages::FRAGMENT),
-                    sampling_parameter_buffer.layout(wgpu::ShaderStages::FRAGMENT),
-                    hw_sky_state_buffer.layout(wgpu::ShaderStages::FRAGMENT, true),
-                ],
+                entries: &parameter_layout_entries(),
                 label: Some("parameter layout"),
             });
 
@@ -148,58 +186,190 @@ impl Raytracer {
             label: Some("parameter bind group"),
         });
 
-        let (scene_bind_group_layout, scene_bind_group) = {
-            let sphere_buffer = StorageBuffer::new_from_bytes(
+        let (scene_bind_group_layout, scene_bind_group, bvh_stats) = {
+            let sphere_buffer = StorageBuffer::new_from_slice(
                 device,
-                bytemuck::cast_slice(scene.spheres.as_slice()),
+                scene.spheres.as_slice(),
                 0_u32,
                 Some("scene buffer"),
             );
 
-            let mut global_texture_data: Vec<[f32; 3]> = Vec::new();
+            let mut atlas_builder = TextureAtlasBuilder::new(&device.limits());
 
             let mut material_data: Vec<GpuMaterial> = Vec::with_capacity(scene.materials.len());
 
             for material in scene.materials.iter() {
                 let gpu_material = match material {
-                    Material::Lambertian { albedo } => {
-                        GpuMaterial::lambertian(albedo, &mut global_texture_data)
+                    Material::Lambertian { albedo, .. } => {
+                        GpuMaterial::lambertian(albedo, &mut atlas_builder)
                     }
-                    Material::Metal { albedo, fuzz } => {
-                        GpuMaterial::metal(albedo, *fuzz, &mut global_texture_data)
+                    Material::Metal { albedo, fuzz, .. } => {
+                        GpuMaterial::metal(albedo, *fuzz, &mut atlas_builder)
                     }
-                    Material::Dielectric { refraction_index } => {
-                        GpuMaterial::dielectric(*refraction_index)
+                    Material::Dielectric {
+                        refraction_index,
+                        attenuation_color,
+                        attenuation_distance,
+                        abbe_number,
+                        ..
+                    } => GpuMaterial::dielectric(
+                        *refraction_index,
+                        attenuation_color.as_ref(),
+                        *attenuation_distance,
+                        *abbe_number,
+                        &mut atlas_builder,
+                    ),
+                    Material::Checkerboard { odd, even, .. } => {
+                        GpuMaterial::checkerboard(odd, even, &mut atlas_builder)
                     }
-                    Material::Checkerboard { odd, even } => {
-                        GpuMaterial::checkerboard(odd, even, &mut global_texture_data)
+                    Material::Emissive { radiance, strength } => {
+                        GpuMaterial::emissive(radiance, *strength, &mut atlas_builder)
                     }
-                };
-
-                material_data.push(gpu_material);
+                    Material::Principled {
+                        base_color,
+                        metallic,
+                        roughness,
+                        metallic_roughness,
+                        ior,
+                        emission,
+                        ..
+                    } => GpuMaterial::principled(
+                        base_color,
+                        *metallic,
+                        *roughness,
+                        metallic_roughness.as_ref(),
+                        *ior,
+                        emission.as_ref(),
+                        &mut atlas_builder,
+                    ),
+                    Material::Volume {
+                        density,
+                        albedo,
+                        anisotropy,
+                        refraction_index,
+                    } => GpuMaterial::volume(
+                        *density,
+                        albedo,
+                        *anisotropy,
+                        *refraction_index,
+                        &mut atlas_builder,
+                    ),
+                }?;
+
+                material_data
+                    .push(gpu_material.with_normal_map(material.normal_map(), &mut atlas_builder)?);
             }
 
-            let material_buffer = StorageBuffer::new_from_bytes(
+            let material_buffer = StorageBuffer::new_from_slice(
                 device,
-                bytemuck::cast_slice(material_data.as_slice()),
+                material_data.as_slice(),
                 1_u32,
                 Some("materials buffer"),
             );
 
-            let texture_buffer = StorageBuffer::new_from_bytes(
+            let texture_atlas = atlas_builder.build(device, queue);
+
+            // Maps larger than a texture can be are sampled at a lower resolution.
+            let environment_map = scene
+                .environment_map
+                .clone()
+                .map(|texture| texture.fit_within(device.limits().max_texture_dimension_2d));
+
+            let environment_map_buffer = StorageBuffer::new_from_bytes(
                 device,
-                bytemuck::cast_slice(global_texture_data.as_slice()),
-                2_u32,
-                Some("textures buffer"),
+                &environment_map_data(environment_map.as_ref()),
+                6_u32,
+                Some("environment map buffer"),
+            );
+
+            let environment_texture = environment_texture(device, queue, environment_map.as_ref());
+
+            let mesh_data = GpuMeshData::new(&scene.meshes);
+
+            let mesh_buffer = StorageBuffer::new_from_slice(
+                device,
+                mesh_data.packed().as_slice(),
+                3_u32,
+                Some("mesh buffer"),
+            );
+
+            let scene_bvh = SceneBvh::build(scene, &mesh_data);
+
+            let bvh_node_buffer = StorageBuffer::new_from_slice(
+                device,
+                scene_bvh.nodes.as_slice(),
+                4_u32,
+                Some("bvh node buffer"),
+            );
+
+            let instance_buffer = StorageBuffer::new_from_slice(
+                device,
+                scene_bvh.instances.as_slice(),
+                10_u32,
+                Some("instance buffer"),
+            );
+
+            let shape_data: Vec<GpuShape> = scene.shapes.iter().map(GpuShape::new).collect();
+
+            let shape_buffer = StorageBuffer::new_from_slice(
+                device,
+                shape_data.as_slice(),
+                11_u32,
+                Some("shape buffer"),
+            );
+
+            let lights = gather_lights(scene, &mesh_data, &scene_bvh.world_primitives);
+
+            // The lights follow the BVH's primitives in the same buffer.
+            let primitive_buffer = StorageBuffer::new_from_slice(
+                device,
+                [scene_bvh.primitives.as_slice(), lights.as_slice()]
+                    .concat()
+                    .as_slice(),
+                5_u32,
+                Some("primitive buffer"),
+            );
+
+            let scene_layout = GpuSceneLayout {
+                first_vertex: mesh_data.triangles.len() as u32,
+                first_light: scene_bvh.primitives.len() as u32,
+                light_count: lights.len() as u32,
+                _padding: 0_u32,
+            };
+
+            let scene_layout_buffer = UniformBuffer::new_from_bytes(
+                device,
+                bytemuck::bytes_of(&scene_layout),
+                12_u32,
+                Some("scene layout buffer"),
             );
 
+            // The device is requested with the default limits, which larger scenes exceed.
+            let max_binding_size = u64::from(device.limits().max_storage_buffer_binding_size);
+
+            for (name, buffer) in [
+                ("spheres", &sphere_buffer),
+                ("materials", &material_buffer),
+                ("meshes", &mesh_buffer),
+                ("BVH nodes", &bvh_node_buffer),
+                ("primitives", &primitive_buffer),
+                ("environment map", &environment_map_buffer),
+                ("instances", &instance_buffer),
+                ("shapes", &shape_buffer),
+            ] {
+                let size = buffer.handle().size();
+
+                if size > max_binding_size {
+                    return Err(RaytracerError::SceneTooLarge(name, size, max_binding_size));
+                }
+            }
+
+            let [ldr_atlas_binding, sampler_binding, hdr_atlas_binding] =
+                texture_atlas.bindings(ATLAS_BINDINGS);
+
             let scene_bind_group_layout =
                 device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    entries: &[
-                        sphere_buffer.layout(wgpu::ShaderStages::FRAGMENT, true),
-                        material_buffer.layout(wgpu::ShaderStages::FRAGMENT, true),
-                        texture_buffer.layout(wgpu::ShaderStages::FRAGMENT, true),
-                    ],
+                    entries: &scene_layout_entries(),
                     label: Some("scene layout"),
                 });
 
@@ -208,113 +378,170 @@ impl Raytracer {
                 entries: &[
                     sphere_buffer.binding(),
                     material_buffer.binding(),
-                    texture_buffer.binding(),
+                    ldr_atlas_binding,
+                    mesh_buffer.binding(),
+                    bvh_node_buffer.binding(),
+                    primitive_buffer.binding(),
+                    environment_map_buffer.binding(),
+                    hdr_atlas_binding,
+                    sampler_binding,
+                    wgpu::BindGroupEntry {
+                        binding: 9_u32,
+                        resource: wgpu::BindingResource::TextureView(&environment_texture),
+                    },
+                    instance_buffer.binding(),
+                    shape_buffer.binding(),
+                    scene_layout_buffer.binding(),
                 ],
                 label: Some("scene bind group"),
             });
 
-            (scene_bind_group_layout, scene_bind_group)
+            (scene_bind_group_layout, scene_bind_group, scene_bvh.stats)
         };
 
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            source: wgpu::ShaderSource::Wgsl(include_str!("raytracer.wgsl").into()),
-            label: Some("raytracer.wgsl"),
-        });
+        let trace_pipeline = {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                source: wgpu::ShaderSource::Wgsl(include_str!("raytracer.wgsl").into()),
+                label: Some("raytracer.wgsl"),
+            });
 
-        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            bind_group_layouts: &[
-                &vertex_uniform_bind_group_layout,
-                &image_bind_group_layout,
-                &parameter_bind_group_layout,
-                &scene_bind_group_layout,
-            ],
-            push_constant_ranges: &[],
-            label: Some("raytracer layout"),
-        });
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[
+                    &trace_image_layout,
+                    &parameter_bind_group_layout,
+                    &scene_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+                label: Some("raytracer layout"),
+            });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vsMain",
-                buffers: &[Vertex::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                layout: Some(&pipeline_layout),
                 module: &shader,
-                entry_point: "fsMain",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: surface_config.format,
-                    blend: Some(wgpu::BlendState {
-                        color: wgpu::BlendComponent::REPLACE,
-                        alpha: wgpu::BlendComponent::REPLACE,
-                    }),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                cull_mode: Some(wgpu::Face::Back),
-                // Requires Features::DEPTH_CLAMPING
-                conservative: false,
-                unclipped_depth: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            label: Some("raytracer pipeline"),
-            // If the pipeline will be used with a multiview render pass, this
-            // indicates how many array layers the attachments will have.
-            multiview: None,
-        });
+                entry_point: "csMain",
+                label: Some("raytracer pipeline"),
+            })
+        };
 
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            contents: bytemuck::cast_slice(VERTICES),
-            usage: wgpu::BufferUsages::VERTEX,
-            label: Some("VertexInput buffer"),
-        });
+        let display_pipeline = {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                source: wgpu::ShaderSource::Wgsl(include_str!("display.wgsl").into()),
+                label: Some("display.wgsl"),
+            });
+
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&display_image_layout, auto_exposure.display_layout()],
+                push_constant_ranges: &[],
+                label: Some("display layout"),
+            });
+
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vsMain",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fsMain",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: target_format,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent::REPLACE,
+                            alpha: wgpu::BlendComponent::REPLACE,
+                        }),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    // The fullscreen triangle is generated in the vertex shader, in clockwise
+                    // order.
+                    cull_mode: None,
+                    conservative: false,
+                    unclipped_depth: false,
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                label: Some("display pipeline"),
+                multiview: None,
+            })
+        };
 
         let render_progress = RenderProgress::new();
 
         let frame_number = 1_u32;
 
         Ok(Self {
-            vertex_uniform_bind_group,
             frame_data_buffer,
-            image_bind_group,
+            display_params_buffer,
+            trace_image_layout,
+            display_image_layout,
+            accumulation_image,
+            auto_exposure,
             camera_buffer,
             sampling_parameter_buffer,
             hw_sky_state_buffer,
             parameter_bind_group,
             scene_bind_group,
-            vertex_buffer,
-            pipeline,
+            bvh_stats,
+            has_environment_map,
+            working_space: scene.working_space,
+            trace_pipeline,
+            display_pipeline,
             latest_render_params: *render_params,
             render_progress,
             frame_number,
         })
     }
 
-    pub fn render_frame<'a>(
-        &'a mut self,
+    /// Records the dispatches which add the next samples to the accumulated image. Does nothing
+    /// once all the samples have been taken.
+    pub fn trace_frame(
+        &mut self,
         queue: &wgpu::Queue,
-        render_pass: &mut wgpu::RenderPass<'a>,
+        encoder: &mut wgpu::CommandEncoder,
     ) {
-        {
-            let gpu_sampling_params = self
-                .render_progress
-                .next_frame(&self.latest_render_params.sampling);
+        let gpu_sampling_params = self
+            .render_progress
+            .next_frame(&self.latest_render_params.sampling);
 
+        {
             queue.write_buffer(
                 &self.sampling_parameter_buffer.handle(),
                 0,
                 bytemuck::cast_slice(&[gpu_sampling_params]),
             );
+
+            let display = &self.latest_render_params.display;
+
+            let display_params = GpuDisplayParams {
+                accumulated_samples_per_pixel: gpu_sampling_params.accumulated_samples_per_pixel,
+                tonemapper: display.tonemapper.id(),
+                exposure: display.exposure_ev.exp2(),
+                white_point: display.white_point,
+                auto_exposure: u32::from(display.auto_exposure.enabled),
+                _padding: [0_u32; 3],
+                to_rec709: gpu_mat3(&self.working_space.to_rec709()),
+            };
+
+            queue.write_buffer(
+                &self.display_params_buffer.handle(),
+                0,
+                bytemuck::bytes_of(&display_params),
+            );
+        }
+
+        if gpu_sampling_params.num_samples_per_pixel == 0_u32 {
+            return;
         }
 
         {
@@ -322,7 +549,7 @@ impl Raytracer {
 
             let frame_number = self.frame_number;
 
-            let frame_data = [viewport_size.0, viewport_size.1, frame_number];
+            let frame_data = [viewport_size.0, viewport_size.1, frame_number, 0_u32];
 
             queue.write_buffer(
                 &self.frame_data_buffer.handle(),
@@ -331,27 +558,71 @@ impl Raytracer {
             );
         }
 
-        render_pass.set_pipeline(&self.pipeline);
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("raytracer pass"),
+            });
 
-        render_pass.set_bind_group(0, &self.vertex_uniform_bind_group, &[]);
+            compute_pass.set_pipeline(&self.trace_pipeline);
 
-        render_pass.set_bind_group(1, &self.image_bind_group, &[]);
+            compute_pass.set_bind_group(1, &self.parameter_bind_group, &[]);
 
-        render_pass.set_bind_group(2, &self.parameter_bind_group, &[]);
+            compute_pass.set_bind_group(2, &self.scene_bind_group, &[]);
 
-        render_pass.set_bind_group(3, &self.scene_bind_group, &[]);
+            self.accumulation_image.dispatch(&mut compute_pass);
+        }
 
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        self.accumulation_image.swap();
 
-        let num_vertices = VERTICES.len() as u32;
+        self.frame_number += 1_u32;
+    }
 
-        render_pass.draw(0..num_vertices, 0..1);
+    /// Draws the accumulated image, tonemapped, over the whole render target.
+    pub fn render_frame<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+    ) {
+        render_pass.set_pipeline(&self.display_pipeline);
 
-        self.frame_number += 1_u32;
+        render_pass.set_bind_group(0, self.accumulation_image.display_bind_group(), &[]);
+
+        render_pass.set_bind_group(1, self.auto_exposure.display_bind_group(), &[]);
+
+        render_pass.draw(0..3, 0..1);
+    }
+
+    /// Records the passes which meter the accumulated image and adapt the automatic exposure over
+    /// `dt` seconds. Call once per frame after `trace_frame`, and submit the encoder before the
+    /// next call. Does nothing while automatic exposure is disabled.
+    pub fn update_exposure(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        dt: f32,
+    ) {
+        let params = &self.latest_render_params.display.auto_exposure;
+
+        if !params.enabled {
+            // Enabling it again exposes the image right away instead of adapting from a stale
+            // exposure.
+            self.auto_exposure.reset();
+
+            return;
+        }
+
+        self.auto_exposure
+            .update(device, queue, encoder, &self.accumulation_image, params, dt);
+    }
+
+    /// The histogram and exposure of a recent frame, lagging a few frames behind the display.
+    pub fn exposure_stats(&self) -> Option<&ExposureStats> {
+        self.auto_exposure.stats()
     }
 
     pub fn set_render_params(
         &mut self,
+        device: &wgpu::Device,
         queue: &wgpu::Queue,
         render_params: &RenderParams,
     ) -> Result<(), RenderParamsValidationError> {
@@ -359,13 +630,21 @@ impl Raytracer {
             return Ok(());
         }
 
-        match render_params.validate() {
+        match render_params.validate(self.has_environment_map) {
             Ok(_) => {}
             Err(err) => return Err(err),
         }
 
+        // The display parameters are uploaded with every frame, and don't invalidate the
+        // accumulated samples.
+        self.latest_render_params.display = render_params.display;
+
+        if *render_params == self.latest_render_params {
+            return Ok(());
+        }
+
         {
-            let sky_state = render_params.sky.to_sky_state()?;
+            let sky_state = render_params.sky.to_sky_state(self.working_space)?;
 
             queue.write_buffer(
                 &self.hw_sky_state_buffer.handle(),
@@ -380,6 +659,17 @@ impl Raytracer {
             queue.write_buffer(&self.camera_buffer.handle(), 0, bytemuck::bytes_of(&camera));
         }
 
+        if render_params.viewport_size != self.accumulation_image.size() {
+            self.accumulation_image = AccumulationImage::new(
+                device,
+                render_params.viewport_size,
+                &self.trace_image_layout,
+                &self.display_image_layout,
+                &self.frame_data_buffer,
+                &self.display_params_buffer,
+            );
+        }
+
         self.latest_render_params = *render_params;
 
         self.render_progress.reset();
@@ -391,6 +681,190 @@ impl Raytracer {
         self.render_progress.accumulated_samples() as f32
             / self.latest_render_params.sampling.max_samples_per_pixel as f32
     }
+
+    pub fn bvh_stats(&self) -> &BvhStats {
+        &self.bvh_stats
+    }
+
+    pub fn has_environment_map(&self) -> bool {
+        self.has_environment_map
+    }
+
+    /// Copies the accumulated image back to the CPU, averages the samples and converts them from
+    /// the working space to Rec.709. Blocks until the GPU has finished all the submitted work.
+    pub fn read_image(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<RenderedImage, wgpu::BufferAsyncError> {
+        let (width, height) = self.accumulation_image.size();
+
+        let bytes_per_row = readback_bytes_per_row(width);
+
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: u64::from(bytes_per_row) * u64::from(height),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+            label: Some("image readback buffer"),
+        });
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+        encoder.copy_texture_to_buffer(
+            self.accumulation_image.current_texture().as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &readback_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        queue.submit(Some(encoder.finish()));
+
+        let buffer_slice = readback_buffer.slice(..);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            // The receiver outlives the poll below, so sending cannot fail.
+            let _ = sender.send(result);
+        });
+
+        device.poll(wgpu::Maintain::Wait);
+
+        receiver
+            .recv()
+            .expect("The map callback should have been called by the blocking poll")?;
+
+        let pixels = readback_pixels(
+            &buffer_slice.get_mapped_range(),
+            width,
+            self.render_progress.accumulated_samples(),
+            &self.working_space.to_rec709(),
+        );
+
+        readback_buffer.unmap();
+
+        let mut display = self.latest_render_params.display;
+
+        // Bake the current automatic exposure into the image's exposure, which tonemaps it
+        // like the window.
+        if display.auto_exposure.enabled {
+            let stats = self.auto_exposure.read_stats(device, queue)?;
+
+            display.exposure_ev += stats.exposure_ev();
+
+            display.auto_exposure.enabled = false;
+        }
+
+        Ok(RenderedImage {
+            width,
+            height,
+            pixels,
+            display,
+        })
+    }
+}
+
+/// The lights the shader samples directly: the primitives with an emissive material, spheres
+/// first, then triangles, then the shapes which can be sampled, and the sun last. Instanced
+/// primitives are only hit by scattered rays, so only the `world_primitives` are considered.
+fn gather_lights(
+    scene: &Scene,
+    mesh_data: &GpuMeshData,
+    world_primitives: &[GpuPrimitive],
+) -> Vec<GpuPrimitive> {
+    let is_light = |primitive: &GpuPrimitive| {
+        let material_idx = match primitive.kind {
+            0_u32 => scene.spheres[primitive.index as usize].material_idx(),
+            4_u32 => {
+                let shape = &scene.shapes[primitive.index as usize];
+
+                if !shape.is_sampled_as_light() {
+                    return false;
+                }
+
+                shape.material_idx
+            }
+            _ => mesh_data.triangles[primitive.index as usize].material_idx(),
+        };
+
+        matches!(
+            scene.materials.get(material_idx as usize),
+            Some(Material::Emissive { strength, .. }) if *strength > 0_f32
+        )
+    };
+
+    [0_u32, 1_u32, 4_u32]
+        .into_iter()
+        .flat_map(|kind| {
+            world_primitives
+                .iter()
+                .filter(move |primitive| primitive.kind == kind)
+        })
+        .copied()
+        .filter(is_light)
+        .chain(std::iter::once(GpuPrimitive::sun()))
+        .collect()
+}
+
+/// The camera, the sampling parameters and the sky state.
+fn parameter_layout_entries() -> [wgpu::BindGroupLayoutEntry; 3] {
+    [0_u32, 1_u32, 2_u32].map(|binding| uniform_layout(binding, wgpu::ShaderStages::COMPUTE))
+}
+
+/// The scene's buffers, the texture atlases and the environment map, bound as `@group(2)` of the
+/// path tracing shader.
+fn scene_layout_entries() -> [wgpu::BindGroupLayoutEntry; 13] {
+    let visibility = wgpu::ShaderStages::COMPUTE;
+
+    let [ldr_atlas_layout, sampler_layout, hdr_atlas_layout] =
+        TextureAtlas::layouts(ATLAS_BINDINGS, visibility);
+
+    [
+        storage_layout(0_u32, visibility, true),
+        storage_layout(1_u32, visibility, true),
+        ldr_atlas_layout,
+        storage_layout(3_u32, visibility, true),
+        storage_layout(4_u32, visibility, true),
+        storage_layout(5_u32, visibility, true),
+        storage_layout(6_u32, visibility, true),
+        hdr_atlas_layout,
+        sampler_layout,
+        wgpu::BindGroupLayoutEntry {
+            binding: 9_u32,
+            visibility,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        },
+        storage_layout(10_u32, visibility, true),
+        storage_layout(11_u32, visibility, true),
+        uniform_layout(12_u32, visibility),
+    ]
+}
+
+#[derive(Error, Debug)]
+
+pub enum RaytracerError {
+    #[error(transparent)]
+    RenderParams(#[from] RenderParamsValidationError),
+    #[error(transparent)]
+    Texture(#[from] TextureError),
+    #[error("the scene's {0} take {1} bytes, more than the {2} bytes the device can bind")]
+    SceneTooLarge(&'static str, u64, u64),
 }
 
 #[derive(Error, Debug)]
@@ -406,19 +880,50 @@ pub enum RenderParamsValidationError {
     ApertureOutOfRange(f32),
     #[error("focus_distance must be greater than zero")]
     FocusDistanceOutOfRange(f32),
+    #[error("shutter_open ({0}) and shutter_close ({1}) must be between 0..=1, in order")]
+    ShutterOutOfRange(f32, f32),
     #[error(transparent)]
     HwSkyModelValidationError(#[from] hw_skymodel::rgb::Error),
+    #[error("the environment map sky mode requires a scene with an environment map")]
+    MissingEnvironmentMap,
+    #[error("fog density must be at least zero")]
+    FogDensityOutOfRange(f32),
+    #[error("fog height_falloff must be greater than zero")]
+    FogHeightFalloffOutOfRange(f32),
+    #[error("fog anisotropy must be between -1..1")]
+    FogAnisotropyOutOfRange(f32),
+    #[error("white_point must be greater than zero")]
+    WhitePointOutOfRange(f32),
+    #[error("auto exposure percentile must be between 0..=1")]
+    PercentileOutOfRange(f32),
+}
+
+#[derive(Error, Debug)]
+
+pub enum DeviceLimitsError {
+    #[error("the adapter supports {0} storage buffers per shader stage, the raytracer needs {1}")]
+    TooFewStorageBuffers(u32, u32),
 }
 
 pub struct Scene {
     pub spheres: Vec<Sphere>,
+    pub meshes: Vec<Mesh>,
+    /// Planes, quads, boxes, disks and cylinders.
+    pub shapes: Vec<Shape>,
+    /// Copies of spheres, meshes and shapes placed by transforms, see `Instance`.
+    pub instances: Vec<Instance>,
     pub materials: Vec<Material>,
+    /// An equirectangular image of the surroundings, used instead of the sky model in
+    /// `SkyMode::EnvironmentMap`.
+    pub environment_map: Option<Texture>,
+    /// The color space of the materials and the environment map, which the scene is rendered in.
+    pub working_space: WorkingSpace,
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 
-pub struct Sphere(glm::Vec4, f32, u32, [u32; 2]);
+pub struct Sphere(glm::Vec4, f32, u32, [u32; 2], glm::Vec4);
 
 impl Sphere {
     pub fn new(
@@ -426,15 +931,214 @@ impl Sphere {
         radius: f32,
         material_idx: u32,
     ) -> Self {
-        Self(glm::vec3_to_vec4(&center), radius, material_idx, [0_u32; 2])
+        Self::new_moving(center, center, radius, material_idx)
+    }
+
+    /// A sphere whose center moves in a straight line from `start_center` at time 0 to
+    /// `end_center` at time 1, like the moving spheres of the book.
+    pub fn new_moving(
+        start_center: glm::Vec3,
+        end_center: glm::Vec3,
+        radius: f32,
+        material_idx: u32,
+    ) -> Self {
+        Self(
+            glm::vec3_to_vec4(&start_center),
+            radius,
+            material_idx,
+            [0_u32; 2],
+            glm::vec3_to_vec4(&(end_center - start_center)),
+        )
+    }
+
+    pub fn center(
+        &self,
+        time: f32,
+    ) -> glm::Vec3 {
+        self.0.xyz() + time * self.4.xyz()
+    }
+
+    /// The bounds of the sphere along its whole path.
+    fn aabb(&self) -> Aabb {
+        let extent = glm::vec3(self.1, self.1, self.1);
+
+        let bounds_at = |time: f32| Aabb {
+            min: self.center(time) - extent,
+            max: self.center(time) + extent,
+        };
+
+        bounds_at(0_f32).union(&bounds_at(1_f32))
     }
 }
 
+#[derive(Clone)]
+
 pub enum Material {
-    Lambertian { albedo: Texture },
-    Metal { albedo: Texture, fuzz: f32 },
-    Dielectric { refraction_index: f32 },
-    Checkerboard { even: Texture, odd: Texture },
+    Lambertian {
+        albedo: Texture,
+        normal_map: Option<NormalMap>,
+    },
+    Metal {
+        albedo: Texture,
+        fuzz: f32,
+        normal_map: Option<NormalMap>,
+    },
+    /// Glass and other clear media, which reflect and refract light following the exact Fresnel
+    /// equations.
+    Dielectric {
+        refraction_index: f32,
+        /// The color white light turns after travelling `attenuation_distance` through the
+        /// medium, looked up where the light enters it. The medium is clear without it.
+        attenuation_color: Option<Texture>,
+        attenuation_distance: f32,
+        /// Makes the index of refraction depend on the wavelength. `refraction_index` is the index
+        /// at 587.6 nm, and lower Abbe numbers spread the colors further apart.
+        abbe_number: Option<f32>,
+        normal_map: Option<NormalMap>,
+    },
+    Checkerboard {
+        even: Texture,
+        odd: Texture,
+        normal_map: Option<NormalMap>,
+    },
+    /// Emits `strength * radiance` and does not scatter light.
+    Emissive { radiance: Texture, strength: f32 },
+    /// A metallic-roughness material with a GGX specular lobe over a diffuse base, which matches
+    /// the glTF material model.
+    Principled {
+        base_color: Texture,
+        metallic: f32,
+        /// The perceptual roughness, which is squared for the GGX distribution.
+        roughness: f32,
+        /// Multiplies `metallic` by its blue channel and `roughness` by its green channel.
+        metallic_roughness: Option<Texture>,
+        /// The index of refraction which sets the reflectance of the dielectric, non-metallic
+        /// part.
+        ior: f32,
+        /// Radiance emitted in addition to the scattered light. Unlike `Emissive` materials,
+        /// this surface is not sampled as a light, it is only found by rays which hit it.
+        emission: Option<Texture>,
+        normal_map: Option<NormalMap>,
+    },
+    /// Bounds a homogeneous medium, such as smoke, which scatters light inside of the primitive
+    /// with the Henyey-Greenstein phase function. The boundary is invisible, unless it has an
+    /// index of refraction, when it reflects and refracts light like a dielectric. A medium behind
+    /// such a boundary looks like wax, marble or milk. Volumes can't overlap or nest.
+    Volume {
+        /// The number of collisions per unit of distance.
+        density: f32,
+        /// The probability of light being scattered rather than absorbed at each collision,
+        /// looked up where the light enters the volume.
+        albedo: Texture,
+        /// Between -1..1, positive values scatter light forward and negative values backward.
+        anisotropy: f32,
+        refraction_index: Option<f32>,
+    },
+}
+
+/// Perturbs the shading normal of a surface, to add detail which isn't in the geometry. The
+/// tangent frame follows the direction of increasing u and v, on spheres and on meshes alike.
+#[derive(Clone)]
+
+pub enum NormalMap {
+    /// A tangent-space normal map, with x in the red, y in the green and z in the blue channel,
+    /// mapped from -1..1 to 0..1. The green channel points towards increasing v, as in OpenGL
+    /// and glTF. `scale` multiplies the x and y components.
+    Normal { texture: Texture, scale: f32 },
+    /// A height map, read from the red channel, which is the gray value of a grayscale image.
+    /// A height of 1 displaces the surface by `height` scene units.
+    Bump { texture: Texture, height: f32 },
+}
+
+impl Material {
+    /// Converts the material's colors from linear Rec.709 to the working space.
+    pub fn to_working_space(
+        self,
+        working_space: WorkingSpace,
+    ) -> Self {
+        match self {
+            Material::Lambertian { albedo, normal_map } => Material::Lambertian {
+                albedo: albedo.to_working_space(working_space),
+                normal_map,
+            },
+            Material::Metal {
+                albedo,
+                fuzz,
+                normal_map,
+            } => Material::Metal {
+                albedo: albedo.to_working_space(working_space),
+                fuzz,
+                normal_map,
+            },
+            Material::Dielectric {
+                refraction_index,
+                attenuation_color,
+                attenuation_distance,
+                abbe_number,
+                normal_map,
+            } => Material::Dielectric {
+                refraction_index,
+                attenuation_color: attenuation_color
+                    .map(|color| color.to_working_space(working_space)),
+                attenuation_distance,
+                abbe_number,
+                normal_map,
+            },
+            Material::Checkerboard {
+                even,
+                odd,
+                normal_map,
+            } => Material::Checkerboard {
+                even: even.to_working_space(working_space),
+                odd: odd.to_working_space(working_space),
+                normal_map,
+            },
+            Material::Emissive { radiance, strength } => Material::Emissive {
+                radiance: radiance.to_working_space(working_space),
+                strength,
+            },
+            Material::Principled {
+                base_color,
+                metallic,
+                roughness,
+                metallic_roughness,
+                ior,
+                emission,
+                normal_map,
+            } => Material::Principled {
+                base_color: base_color.to_working_space(working_space),
+                metallic,
+                roughness,
+                // Not a color, the values are used as they are.
+                metallic_roughness,
+                ior,
+                emission: emission.map(|emission| emission.to_working_space(working_space)),
+                normal_map,
+            },
+            Material::Volume {
+                density,
+                albedo,
+                anisotropy,
+                refraction_index,
+            } => Material::Volume {
+                density,
+                albedo: albedo.to_working_space(working_space),
+                anisotropy,
+                refraction_index,
+            },
+        }
+    }
+
+    pub fn normal_map(&self) -> Option<&NormalMap> {
+        match self {
+            Material::Lambertian { normal_map, .. }
+            | Material::Metal { normal_map, .. }
+            | Material::Dielectric { normal_map, .. }
+            | Material::Checkerboard { normal_map, .. }
+            | Material::Principled { normal_map, .. } => normal_map.as_ref(),
+            Material::Emissive { .. } | Material::Volume { .. } => None,
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -443,12 +1147,21 @@ pub struct RenderParams {
     pub camera: Camera,
     pub sky: SkyParams,
     pub sampling: SamplingParams,
+    pub display: DisplayParams,
     pub viewport_size: (u32, u32),
 }
 
 impl RenderParams {
-    fn validate(&self) -> Result<(), RenderParamsValidationError> {
-        if self.sampling.max_samples_per_pixel % self.sampling.num_samples_per_pixel != 0 {
+    pub fn validate(
+        &self,
+        has_environment_map: bool,
+    ) -> Result<(), RenderParamsValidationError> {
+        if self
+            .sampling
+            .max_samples_per_pixel
+            .checked_rem(self.sampling.num_samples_per_pixel)
+            != Some(0_u32)
+        {
             return Err(RenderParamsValidationError::MaxSampleCountNotMultiple(
                 self.sampling.max_samples_per_pixel,
                 self.sampling.num_samples_per_pixel,
@@ -480,6 +1193,52 @@ impl RenderParams {
             ));
         }
 
+        if !(0_f32..=1_f32).contains(&self.camera.shutter_open)
+            || !(self.camera.shutter_open..=1_f32).contains(&self.camera.shutter_close)
+        {
+            return Err(RenderParamsValidationError::ShutterOutOfRange(
+                self.camera.shutter_open,
+                self.camera.shutter_close,
+            ));
+        }
+
+        // The sky model checks the ranges of its own parameters.
+        hw_skymodel::rgb::SkyState::new(&self.sky.sky_model_params())?;
+
+        if self.sky.mode == SkyMode::EnvironmentMap && !has_environment_map {
+            return Err(RenderParamsValidationError::MissingEnvironmentMap);
+        }
+
+        if self.sky.fog.density < 0_f32 {
+            return Err(RenderParamsValidationError::FogDensityOutOfRange(
+                self.sky.fog.density,
+            ));
+        }
+
+        if self.sky.fog.height_falloff <= 0_f32 {
+            return Err(RenderParamsValidationError::FogHeightFalloffOutOfRange(
+                self.sky.fog.height_falloff,
+            ));
+        }
+
+        if self.sky.fog.anisotropy.abs() >= 1_f32 {
+            return Err(RenderParamsValidationError::FogAnisotropyOutOfRange(
+                self.sky.fog.anisotropy,
+            ));
+        }
+
+        if self.display.white_point <= 0_f32 {
+            return Err(RenderParamsValidationError::WhitePointOutOfRange(
+                self.display.white_point,
+            ));
+        }
+
+        if !(0_f32..=1_f32).contains(&self.display.auto_exposure.percentile) {
+            return Err(RenderParamsValidationError::PercentileOutOfRange(
+                self.display.auto_exposure.percentile,
+            ));
+        }
+
         Ok(())
     }
 }
@@ -496,6 +1255,22 @@ pub struct Camera {
     pub aperture: f32,
     /// Focus distance must be a positive number.
     pub focus_distance: f32,
+    /// The pose at time 1 of a camera which moves, the pose above is the one at time 0.
+    pub end_pose: Option<CameraPose>,
+    /// The part of the scene's time, from 0 to 1, during which the shutter is open. Each sample
+    /// is taken at a random time in it. Both must be between 0..=1, and the shutter can't close
+    /// before it opens.
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+/// Where a camera is and which way it looks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+
+pub struct CameraPose {
+    pub eye_pos: glm::Vec3,
+    pub eye_dir: glm::Vec3,
+    pub up: glm::Vec3,
 }
 
 impl Camera {
@@ -536,11 +1311,23 @@ impl Camera {
             vfov: Angle::degrees(vfov_degrees),
             aperture,
             focus_distance,
+            end_pose: None,
+            shutter_open: 0_f32,
+            shutter_close: 1_f32,
+        }
+    }
+
+    pub fn start_pose(&self) -> CameraPose {
+        CameraPose {
+            eye_pos: self.eye_pos,
+            eye_dir: self.eye_dir,
+            up: self.up,
         }
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
 
 pub struct SkyParams {
     // Azimuth must be between 0..=360 degrees
@@ -551,6 +1338,39 @@ pub struct SkyParams {
     pub turbidity: f32,
     // Albedo elements must be between 0..=1
     pub albedo: [f32; 3],
+    pub mode: SkyMode,
+    // Rotation of the environment map around the up axis
+    pub environment_rotation_degrees: f32,
+    // Scale of the environment map's radiance
+    pub environment_intensity: f32,
+    pub fog: FogParams,
+}
+
+/// A height fog which fills the whole scene. It's lit by the sky and the sun, and the rays which
+/// escape to the sky pass through it as well.
+#[derive(Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+
+pub struct FogParams {
+    // The density at height 0, in collisions per unit of distance. Zero disables the fog.
+    pub density: f32,
+    // The height over which the density falls off by a factor of e, greater than zero
+    pub height_falloff: f32,
+    // The probability of light being scattered rather than absorbed at each collision
+    pub albedo: [f32; 3],
+    // Henyey-Greenstein anisotropy between -1..1, positive values scatter light forward
+    pub anisotropy: f32,
+}
+
+impl Default for FogParams {
+    fn default() -> Self {
+        Self {
+            density: 0_f32,
+            height_falloff: 10_f32,
+            albedo: [1_f32; 3],
+            anisotropy: 0_f32,
+        }
+    }
 }
 
 impl Default for SkyParams {
@@ -560,12 +1380,37 @@ impl Default for SkyParams {
             zenith_degrees: 85_f32,
             turbidity: 4_f32,
             albedo: [1_f32; 3],
+            mode: SkyMode::HosekWilkie,
+            environment_rotation_degrees: 0_f32,
+            environment_intensity: 1_f32,
+            fog: FogParams::default(),
         }
     }
 }
 
+/// What rays which miss the scene see.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+
+pub enum SkyMode {
+    /// The Hosek-Wilkie sky model, and the sun.
+    HosekWilkie,
+    /// The scene's environment map.
+    EnvironmentMap,
+}
+
 impl SkyParams {
-    fn to_sky_state(self: &SkyParams) -> Result<GpuSkyState, hw_skymodel::rgb::Error> {
+    fn sky_model_params(&self) -> hw_skymodel::rgb::SkyParams {
+        hw_skymodel::rgb::SkyParams {
+            elevation: FRAC_PI_2 - Angle::degrees(self.zenith_degrees).as_radians(),
+            turbidity: self.turbidity,
+            albedo: self.albedo,
+        }
+    }
+
+    fn to_sky_state(
+        self: &SkyParams,
+        working_space: WorkingSpace,
+    ) -> Result<GpuSkyState, hw_skymodel::rgb::Error> {
         let azimuth = Angle::degrees(self.azimuth_degrees).as_radians();
 
         let zenith = Angle::degrees(self.zenith_degrees).as_radians();
@@ -577,24 +1422,88 @@ impl SkyParams {
             0_f32,
         ];
 
-        let state = hw_skymodel::rgb::SkyState::new(&hw_skymodel::rgb::SkyParams {
-            elevation: FRAC_PI_2 - zenith,
-            turbidity: self.turbidity,
-            albedo: self.albedo,
-        })?;
+        let state = hw_skymodel::rgb::SkyState::new(&self.sky_model_params())?;
 
         let (params_data, radiance_data) = state.raw();
 
+        let mut params = [0_f32; 28];
+
+        params[..params_data.len()].copy_from_slice(&params_data);
+
         Ok(GpuSkyState {
-            params: params_data,
+            params,
             radiances: radiance_data,
-            _padding: [0_u32, 2],
+            _padding: 0_u32,
             sun_direction,
+            sun_radiance: color_space::mul(&working_space.from_rec709(), self.sun_radiance()),
+            // 2 * pi * (1 - cos(r)), written so that it doesn't cancel out for the tiny radius.
+            sun_solid_angle: 4_f32
+                * PI
+                * (0.5_f32 * Angle::degrees(SUN_ANGULAR_RADIUS_DEGREES).as_radians())
+                    .sin()
+                    .powi(2),
+            mode: match self.mode {
+                SkyMode::HosekWilkie => 0_u32,
+                SkyMode::EnvironmentMap => 1_u32,
+            },
+            environment_rotation: Angle::degrees(self.environment_rotation_degrees).as_radians(),
+            environment_intensity: self.environment_intensity,
+            _padding2: 0_u32,
+            from_rec709: gpu_mat3(&working_space.from_rec709()),
+            fog_density: self.fog.density,
+            fog_height_falloff: self.fog.height_falloff,
+            fog_anisotropy: self.fog.anisotropy,
+            _padding3: 0_u32,
+            fog_albedo: color_space::mul(&working_space.from_rec709(), self.fog.albedo),
+            _padding4: 0_u32,
+        })
+    }
+
+    /// The radiance of the sun disk after the light has passed through the atmosphere, using the
+    /// transmittance model from the appendix of Preetham et al. 1999, "A Practical Analytic Model
+    /// for Daylight", evaluated at one wavelength per color channel. The result is in the same
+    /// units as the sky model's radiance.
+    fn sun_radiance(&self) -> [f32; 3] {
+        // (wavelength in micrometers, ozone absorption coefficient in 1/cm, extraterrestrial
+        // solar radiance)
+        const CHANNELS: [(f32, f32, f32); 3] = [
+            (0.68_f32, 0.036_f32, 2.37e6_f32),
+            (0.55_f32, 0.085_f32, 2.92e6_f32),
+            (0.44_f32, 0_f32, 2.78e6_f32),
+        ];
+
+        // Ozone layer thickness in cm.
+        const OZONE_THICKNESS: f32 = 0.35_f32;
+
+        // Ångström exponent of the aerosols.
+        const ALPHA: f32 = 1.3_f32;
+
+        let zenith_degrees = self.zenith_degrees.clamp(0_f32, 90_f32);
+
+        // Relative optical mass of the air the light passes through, compared to the zenith.
+        let optical_mass = 1_f32
+            / (Angle::degrees(zenith_degrees).as_radians().cos()
+                + 0.15_f32 * (93.885_f32 - zenith_degrees).powf(-1.253_f32));
+
+        let beta = 0.04608_f32 * self.turbidity - 0.04586_f32;
+
+        CHANNELS.map(|(wavelength, ozone_coefficient, extraterrestrial_radiance)| {
+            let rayleigh = (-0.008735_f32 * wavelength.powf(-4.08_f32) * optical_mass).exp();
+
+            let aerosol = (-beta * wavelength.powf(-ALPHA) * optical_mass).exp();
+
+            let ozone = (-ozone_coefficient * OZONE_THICKNESS * optical_mass).exp();
+
+            extraterrestrial_radiance * rayleigh * aerosol * ozone
         })
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+/// The angular radius of the sun disk as seen from the earth.
+const SUN_ANGULAR_RADIUS_DEGREES: f32 = 0.2667_f32;
+
+#[derive(Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
 
 pub struct SamplingParams {
     pub max_samples_per_pixel: u32,
@@ -682,6 +1591,20 @@ impl RenderProgress {
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 
 pub struct GpuCamera {
+    /// The camera at time 0 and at time 1, which are the same unless it moves. Rays are cast from
+    /// the frame interpolated to their time.
+    start: GpuCameraFrame,
+    end: GpuCameraFrame,
+    lens_radius: f32,
+    shutter_open: f32,
+    shutter_close: f32,
+    _padding: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+struct GpuCameraFrame {
     eye: glm::Vec3,
     _padding1: f32,
     horizontal: glm::Vec3,
@@ -691,9 +1614,9 @@ pub struct GpuCamera {
     u: glm::Vec3,
     _padding4: f32,
     v: glm::Vec3,
-    lens_radius: f32,
-    lower_left_corner: glm::Vec3,
     _padding5: f32,
+    lower_left_corner: glm::Vec3,
+    _padding6: f32,
 }
 
 impl GpuCamera {
@@ -701,8 +1624,45 @@ impl GpuCamera {
         camera: &Camera,
         viewport_size: (u32, u32),
     ) -> Self {
-        let lens_radius = 0.5_f32 * camera.aperture;
+        let start_pose = camera.start_pose();
+
+        let end_pose = camera.end_pose.unwrap_or(start_pose);
+
+        Self {
+            start: GpuCameraFrame::new(camera, &start_pose, viewport_size),
+            end: GpuCameraFrame::new(camera, &end_pose, viewport_size),
+            lens_radius: 0.5_f32 * camera.aperture,
+            shutter_open: camera.shutter_open,
+            shutter_close: camera.shutter_close,
+            _padding: 0_f32,
+        }
+    }
+
+    // NOTE: make ray fro camera
+    // https://raytracing.github.io/images/fig-1.03-cam-geom.jpg
+    pub fn make_ray(
+        &mut self,
+        u: f32,
+        v: f32,
+    ) -> Ray {
+        let time = glm::lerp_scalar(self.shutter_open, self.shutter_close, rand::random::<f32>());
+
+        let frame = self.start.lerp(&self.end, time);
+
+        Ray::new(
+            frame.eye,
+            frame.lower_left_corner + u * frame.horizontal + v * frame.vertical - frame.eye,
+            time,
+        )
+    }
+}
 
+impl GpuCameraFrame {
+    fn new(
+        camera: &Camera,
+        pose: &CameraPose,
+        viewport_size: (u32, u32),
+    ) -> Self {
         let aspect = viewport_size.0 as f32 / viewport_size.1 as f32;
 
         let theta = camera.vfov.as_radians();
@@ -711,21 +1671,21 @@ impl GpuCamera {
 
         let half_width = aspect * half_height;
 
-        let w = glm::normalize(&camera.eye_dir);
+        let w = glm::normalize(&pose.eye_dir);
 
-        let v = glm::normalize(&camera.up);
+        let v = glm::normalize(&pose.up);
 
         let u = glm::cross(&w, &v);
 
         let lower_left_corner =
-            camera.eye_pos + camera.focus_distance * w - half_width * u - half_height * v;
+            pose.eye_pos + camera.focus_distance * w - half_width * u - half_height * v;
 
         let horizontal = 2_f32 * half_width * u;
 
         let vertical = 2_f32 * half_height * v;
 
         Self {
-            eye: camera.eye_pos,
+            eye: pose.eye_pos,
             _padding1: 0_f32,
             horizontal,
             _padding2: 0_f32,
@@ -734,23 +1694,28 @@ impl GpuCamera {
             u,
             _padding4: 0_f32,
             v,
-            lens_radius,
-            lower_left_corner,
             _padding5: 0_f32,
+            lower_left_corner,
+            _padding6: 0_f32,
         }
     }
 
-    // NOTE: make ray fro camera
-    // https://raytracing.github.io/images/fig-1.03-cam-geom.jpg
-    pub fn make_ray(
-        &mut self,
-        u: f32,
-        v: f32,
-    ) -> Ray {
-        Ray::new(
-            self.eye,
-            self.lower_left_corner + u * self.horizontal + v * self.vertical - self.eye,
-        )
+    /// Interpolates each vector of the frames linearly, as the shader does. This is close to
+    /// turning the camera for the small turns it makes while the shutter is open.
+    fn lerp(
+        &self,
+        other: &Self,
+        t: f32,
+    ) -> Self {
+        Self {
+            eye: glm::lerp(&self.eye, &other.eye, t),
+            horizontal: glm::lerp(&self.horizontal, &other.horizontal, t),
+            vertical: glm::lerp(&self.vertical, &other.vertical, t),
+            u: glm::lerp(&self.u, &other.u, t),
+            v: glm::lerp(&self.v, &other.v, t),
+            lower_left_corner: glm::lerp(&self.lower_left_corner, &other.lower_left_corner, t),
+            ..*self
+        }
     }
 }
 
@@ -761,72 +1726,188 @@ struct GpuMaterial {
     id: u32,
     desc1: TextureDescriptor,
     desc2: TextureDescriptor,
+    desc3: TextureDescriptor,
     x: f32,
+    y: f32,
+    z: f32,
+    /// The normal or bump map, see `with_normal_map`.
+    desc4: TextureDescriptor,
+    /// 0 for none, 1 for a normal map and 2 for a bump map.
+    normal_map: u32,
+    /// The normal map's scale, or the bump map's height.
+    normal_map_strength: f32,
 }
 
 impl GpuMaterial {
     pub fn lambertian(
         albedo: &Texture,
-        global_texture_data: &mut Vec<[f32; 3]>,
-    ) -> Self {
-        Self {
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        Ok(Self {
             id: 0_u32,
-            desc1: Self::append_to_global_texture_data(albedo, global_texture_data),
+            desc1: atlas.add(albedo)?,
             desc2: TextureDescriptor::empty(),
+            desc3: TextureDescriptor::empty(),
             x: 0_f32,
-        }
+            y: 0_f32,
+            z: 0_f32,
+            desc4: TextureDescriptor::empty(),
+            normal_map: 0_u32,
+            normal_map_strength: 0_f32,
+        })
     }
 
     pub fn metal(
         albedo: &Texture,
         fuzz: f32,
-        global_texture_data: &mut Vec<[f32; 3]>,
-    ) -> Self {
-        Self {
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        Ok(Self {
             id: 1_u32,
-            desc1: Self::append_to_global_texture_data(albedo, global_texture_data),
+            desc1: atlas.add(albedo)?,
             desc2: TextureDescriptor::empty(),
+            desc3: TextureDescriptor::empty(),
             x: fuzz,
-        }
+            y: 0_f32,
+            z: 0_f32,
+            desc4: TextureDescriptor::empty(),
+            normal_map: 0_u32,
+            normal_map_strength: 0_f32,
+        })
     }
 
-    pub fn dielectric(refraction_index: f32) -> Self {
-        Self {
+    pub fn dielectric(
+        refraction_index: f32,
+        attenuation_color: Option<&Texture>,
+        attenuation_distance: f32,
+        abbe_number: Option<f32>,
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        Ok(Self {
             id: 2_u32,
-            desc1: TextureDescriptor::empty(),
+            desc1: match attenuation_color {
+                Some(color) => atlas.add(color)?,
+                None => TextureDescriptor::empty(),
+            },
             desc2: TextureDescriptor::empty(),
+            desc3: TextureDescriptor::empty(),
             x: refraction_index,
-        }
+            y: attenuation_distance,
+            // Zero disables dispersion.
+            z: abbe_number.unwrap_or(0_f32),
+            desc4: TextureDescriptor::empty(),
+            normal_map: 0_u32,
+            normal_map_strength: 0_f32,
+        })
     }
 
     pub fn checkerboard(
         even: &Texture,
         odd: &Texture,
-        global_texture_data: &mut Vec<[f32; 3]>,
-    ) -> Self {
-        Self {
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        Ok(Self {
             id: 3_u32,
-            desc1: Self::append_to_global_texture_data(even, global_texture_data),
-            desc2: Self::append_to_global_texture_data(odd, global_texture_data),
+            desc1: atlas.add(even)?,
+            desc2: atlas.add(odd)?,
+            desc3: TextureDescriptor::empty(),
             x: 0_f32,
-        }
+            y: 0_f32,
+            z: 0_f32,
+            desc4: TextureDescriptor::empty(),
+            normal_map: 0_u32,
+            normal_map_strength: 0_f32,
+        })
     }
 
-    fn append_to_global_texture_data(
-        texture: &Texture,
-        global_texture_data: &mut Vec<[f32; 3]>,
-    ) -> TextureDescriptor {
-        let dimensions = texture.dimensions();
+    pub fn emissive(
+        radiance: &Texture,
+        strength: f32,
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        Ok(Self {
+            id: 4_u32,
+            desc1: atlas.add(radiance)?,
+            desc2: TextureDescriptor::empty(),
+            desc3: TextureDescriptor::empty(),
+            x: strength,
+            y: 0_f32,
+            z: 0_f32,
+            desc4: TextureDescriptor::empty(),
+            normal_map: 0_u32,
+            normal_map_strength: 0_f32,
+        })
+    }
 
-        let offset = global_texture_data.len() as u32;
+    pub fn principled(
+        base_color: &Texture,
+        metallic: f32,
+        roughness: f32,
+        metallic_roughness: Option<&Texture>,
+        ior: f32,
+        emission: Option<&Texture>,
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        let desc1 = atlas.add(base_color)?;
+
+        let mut optional_texture = |texture: Option<&Texture>| match texture {
+            Some(texture) => atlas.add(texture),
+            None => Ok(TextureDescriptor::empty()),
+        };
 
-        global_texture_data.extend_from_slice(texture.as_slice());
+        Ok(Self {
+            id: 5_u32,
+            desc1,
+            desc2: optional_texture(metallic_roughness)?,
+            desc3: optional_texture(emission)?,
+            x: metallic,
+            y: roughness,
+            z: ior,
+            desc4: TextureDescriptor::empty(),
+            normal_map: 0_u32,
+            normal_map_strength: 0_f32,
+        })
+    }
 
-        TextureDescriptor {
-            width: dimensions.0,
-            height: dimensions.1,
-            offset,
-        }
+    pub fn volume(
+        density: f32,
+        albedo: &Texture,
+        anisotropy: f32,
+        refraction_index: Option<f32>,
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        Ok(Self {
+            id: 6_u32,
+            desc1: atlas.add(albedo)?,
+            desc2: TextureDescriptor::empty(),
+            desc3: TextureDescriptor::empty(),
+            x: density,
+            y: anisotropy,
+            // Zero makes the boundary invisible.
+            z: refraction_index.unwrap_or(0_f32),
+            desc4: TextureDescriptor::empty(),
+            normal_map: 0_u32,
+            normal_map_strength: 0_f32,
+        })
+    }
+
+    pub fn with_normal_map(
+        self,
+        normal_map: Option<&NormalMap>,
+        atlas: &mut TextureAtlasBuilder,
+    ) -> Result<Self, TextureError> {
+        let (texture, kind, strength) = match normal_map {
+            Some(NormalMap::Normal { texture, scale }) => (texture, 1_u32, *scale),
+            Some(NormalMap::Bump { texture, height }) => (texture, 2_u32, *height),
+            None => return Ok(self),
+        };
+
+        Ok(Self {
+            desc4: atlas.add(texture)?,
+            normal_map: kind,
+            normal_map_strength: strength,
+            ..self
+        })
     }
 
     pub fn register_texture(
@@ -872,7 +1953,22 @@ impl GpuMaterial {
 pub struct TextureDescriptor {
     width: u32,
     height: u32,
-    offset: u32,
+    /// 0 for the 8-bit sRGB atlas, 1 for the 16-bit float atlas
+    atlas: u32,
+    page: u32,
+    /// The top left corner of the texture's rectangle in the page, see `TextureAtlasBuilder`.
+    x: u32,
+    y: u32,
+    num_levels: u32,
+    wrap_mode: u32,
+    filter_mode: u32,
+    /// 0 for an image, otherwise the procedural pattern, see `Pattern`.
+    pattern: u32,
+    /// 0 for UV space, 1 for world space.
+    pattern_space: u32,
+    pattern_scale: f32,
+    octaves: u32,
+    distortion: f32,
 }
 
 impl TextureDescriptor {
@@ -880,7 +1976,18 @@ impl TextureDescriptor {
         Self {
             width: 0_u32,
             height: 0_u32,
-            offset: 0xffffffff,
+            atlas: 0_u32,
+            page: 0xffffffff,
+            x: 0_u32,
+            y: 0_u32,
+            num_levels: 0_u32,
+            wrap_mode: 0_u32,
+            filter_mode: 0_u32,
+            pattern: 0_u32,
+            pattern_space: 0_u32,
+            pattern_scale: 0_f32,
+            octaves: 0_u32,
+            distortion: 0_f32,
         }
     }
 }
@@ -889,117 +1996,116 @@ impl TextureDescriptor {
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 
 struct GpuSkyState {
-    params: [f32; 27],       // 0 byte offset, 108 byte size
-    radiances: [f32; 3],     // 108 byte offset, 12 byte size
-    _padding: [u32; 2],      // 120 byte offset, 8 byte size
-    sun_direction: [f32; 4], // 128 byte offset, 16 byte size
+    params: [f32; 28],          // 0 byte offset, 112 byte size
+    radiances: [f32; 3],        // 112 byte offset, 12 byte size
+    _padding: u32,              // 124 byte offset, 4 byte size
+    sun_direction: [f32; 4],    // 128 byte offset, 16 byte size
+    sun_radiance: [f32; 3],     // 144 byte offset, 12 byte size
+    sun_solid_angle: f32,       // 156 byte offset, 4 byte size
+    mode: u32,                  // 160 byte offset, 4 byte size
+    environment_rotation: f32,  // 164 byte offset, 4 byte size
+    environment_intensity: f32, // 168 byte offset, 4 byte size
+    _padding2: u32,             // 172 byte offset, 4 byte size
+    from_rec709: [[f32; 4]; 3], // 176 byte offset, 48 byte size
+    fog_density: f32,           // 224 byte offset, 4 byte size
+    fog_height_falloff: f32,    // 228 byte offset, 4 byte size
+    fog_anisotropy: f32,        // 232 byte offset, 4 byte size
+    _padding3: u32,             // 236 byte offset, 4 byte size
+    fog_albedo: [f32; 3],       // 240 byte offset, 12 byte size
+    _padding4: u32,             // 252 byte offset, 4 byte size
 }
 
+/// Where the shader finds the vertices in the mesh buffer, and the lights in the primitive buffer.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 
-struct GpuSamplingParams {
-    num_samples_per_pixel: u32,
-    num_bounces: u32,
-    accumulated_samples_per_pixel: u32,
-    clear_accumulated_samples: u32,
+struct GpuSceneLayout {
+    first_vertex: u32,
+    first_light: u32,
+    light_count: u32,
+    _padding: u32,
 }
 
+/// An entry in the primitive array the BVH leaves point to: which primitive buffer to look in,
+/// and the index into it.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 
-struct VertexUniforms {
-    view_projection_matrix: glm::Mat4,
-    model_matrix: glm::Mat4,
-}
-
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-
-struct Vertex {
-    position: [f32; 2],
-    tex_coords: [f32; 2],
+struct GpuPrimitive {
+    kind: u32,
+    index: u32,
 }
 
-impl Vertex {
-    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
-        wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
-            step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: &[
-                // @location(0)
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x2,
-                    offset: 0,
-                    shader_location: 0,
-                },
-                // @location(1)
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x2,
-                    offset: std::mem::size_of::<[f32; 2]>() as u64,
-                    shader_location: 1,
-                },
-            ],
+impl GpuPrimitive {
+    fn sphere(idx: usize) -> Self {
+        Self {
+            kind: 0_u32,
+            index: idx as u32,
         }
     }
-}
 
-fn unit_quad_projection_matrix() -> glm::Mat4 {
-    let sw = 0.5_f32;
+    fn triangle(idx: usize) -> Self {
+        Self {
+            kind: 1_u32,
+            index: idx as u32,
+        }
+    }
 
-    let sh = 0.5_f32;
+    fn shape(idx: usize) -> Self {
+        Self {
+            kind: 4_u32,
+            index: idx as u32,
+        }
+    }
 
-    // Our ortho camera is just centered at (0, 0)
+    /// An instance in the top level of the BVH, whose object is traversed in its own space.
+    fn instance(idx: usize) -> Self {
+        Self {
+            kind: 3_u32,
+            index: idx as u32,
+        }
+    }
 
-    let left = -sw;
+    /// The sun is not part of the scene geometry, it only appears in the light list.
+    fn sun() -> Self {
+        Self {
+            kind: 2_u32,
+            index: 0_u32,
+        }
+    }
+}
 
-    let right = sw;
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 
-    let bottom = -sh;
+struct GpuSamplingParams {
+    num_samples_per_pixel: u32,
+    num_bounces: u32,
+    accumulated_samples_per_pixel: u32,
+    clear_accumulated_samples: u32,
+}
 
-    let top = sh;
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 
-    // DirectX, Metal, wgpu share the same left-handed coordinate system
-    // for their normalized device coordinates:
-    // https://github.com/gfx-rs/gfx/tree/master/src/backend/dx12
-    glm::ortho_lh_zo(left, right, bottom, top, -1_f32, 1_f32)
+struct GpuDisplayParams {
+    accumulated_samples_per_pixel: u32,
+    tonemapper: u32,
+    exposure: f32,
+    white_point: f32,
+    auto_exposure: u32,
+    _padding: [u32; 3],
+    to_rec709: [[f32; 4]; 3],
 }
 
-const VERTICES: &[Vertex] = &[
-    Vertex {
-        position: [-0.5, 0.5],
-        tex_coords: [0.0, 0.0],
-    },
-    Vertex {
-        position: [-0.5, -0.5],
-        tex_coords: [0.0, 1.0],
-    },
-    Vertex {
-        position: [0.5, -0.5],
-        tex_coords: [1.0, 1.0],
-    },
-    Vertex {
-        position: [-0.5, 0.5],
-        tex_coords: [0.0, 0.0],
-    },
-    Vertex {
-        position: [0.5, -0.5],
-        tex_coords: [1.0, 1.0],
-    },
-    Vertex {
-        position: [0.5, 0.5],
-        tex_coords: [1.0, 0.0],
-    },
-];
-
 // from wgsl
 /**
- * lookup a per pixel texture from global textures vector
+ * lookup a per pixel texture from the texture atlas
  */
 
 pub fn texture_lookup(
     desc: TextureDescriptor,
-    textures: &[[f32; 3]],
+    atlas: &TextureAtlasBuilder,
     u: f32,
     v: f32,
 ) -> Vec3 {
@@ -1011,9 +2117,7 @@ pub fn texture_lookup(
 
     let i = (v * desc.height as f32) as u32;
 
-    let idx = i * desc.width + j;
-
-    let elem = (*textures)[desc.offset as usize + idx as usize];
+    let elem = atlas.texel(&desc, j.min(desc.width - 1), i.min(desc.height - 1));
 
     return vec3(elem[0], elem[1], elem[2]);
 }
@@ -1021,6 +2125,9 @@ pub fn texture_lookup(
 pub struct Ray {
     origin: Vec3,
     direction: Vec3,
+    /// The instant the ray is cast at, within the camera's shutter interval. Moving objects are
+    /// intersected where they are at that time.
+    time: f32,
 }
 
 impl Default for Ray {
@@ -1029,7 +2136,11 @@ impl Default for Ray {
 
         let direction = glm::vec3(0.0, 0.0, -1.0);
 
-        Self { origin, direction }
+        Self {
+            origin,
+            direction,
+            time: 0_f32,
+        }
     }
 }
 
@@ -1037,8 +2148,13 @@ impl Ray {
     pub fn new(
         origin: Vec3,
         direction: Vec3,
+        time: f32,
     ) -> Self {
-        Self { origin, direction }
+        Self {
+            origin,
+            direction,
+            time,
+        }
     }
 
     pub fn new_from_xy(
@@ -1049,7 +2165,11 @@ impl Ray {
 
         let direction = origin - glm::vec3(x, y, -1.0);
 
-        Self { origin, direction }
+        Self {
+            origin,
+            direction,
+            time: 0_f32,
+        }
     }
 }
 
@@ -1126,7 +2246,7 @@ impl Sphere {
         rec: *mut Intersection,
     ) -> (bool, Option<*mut Intersection>) {
         unsafe {
-            let oc = ray.origin - self.0.xyz();
+            let oc = ray.origin - self.center(ray.time);
 
             let a = dot(&ray.direction, &ray.direction);
 
@@ -1163,7 +2283,7 @@ impl Sphere {
         tmax: f32,
         rec: &'a mut Intersection,
     ) -> (bool, Option<&mut Intersection>) {
-        let oc = ray.origin - self.0.xyz();
+        let oc = ray.origin - self.center(ray.time);
 
         let a = dot(&ray.direction, &ray.direction);
 
@@ -1192,7 +2312,7 @@ impl Sphere {
 
         rec.p = ray.origin + ray.direction * rec.t;
 
-        let n = rec.p - self.0.xyz();
+        let n = rec.p - self.center(ray.time);
 
         rec.f = dot(&ray.direction, &n) < 0.0;
 
@@ -1230,7 +2350,7 @@ impl Sphere {
 
         // normal = P -c
         // https://raytracing.github.io/images/fig-1.05-sphere-normal.jpg
-        let n = (1.0 / self.1) * ((*hit).p - self.0.xyz());
+        let n = (1.0 / self.1) * ((*hit).p - self.center(ray.time));
         hit.set_face_normal(ray, n);
 
         // ?
@@ -1280,12 +2400,14 @@ fn scatter_lambertian(
     unsafe {
         let scatter_direction = (*rec).p - random_unit_vector();
 
-        let temp_ray = Ray::new(ray.origin, scatter_direction);
+        let temp_ray = Ray::new(ray.origin, scatter_direction, ray.time);
 
         (*ray_scattered).origin = temp_ray.origin;
 
         (*ray_scattered).direction = temp_ray.direction;
 
+        (*ray_scattered).time = temp_ray.time;
+
         true
     }
 }
@@ -1300,12 +2422,14 @@ fn scatter_metal(
     unsafe {
         let reflected = reflect(unit_vertor(ray.direction), (*rec).n);
 
-        let temp_ray = Ray::new((*rec).p, reflected);
+        let temp_ray = Ray::new((*rec).p, reflected, ray.time);
 
         (*ray_scattered).origin = temp_ray.origin;
 
         (*ray_scattered).direction = temp_ray.direction;
 
+        (*ray_scattered).time = temp_ray.time;
+
         if dot(&(*ray_scattered).direction, &(*rec).n) > 0.0 {
             return true;
         }
@@ -1333,3 +2457,191 @@ pub fn default_background(ray: &Ray) -> Rgb<u8> {
 pub fn gradient_background(ray: &Ray) -> Rgb<u8> {
     Rgb([ray.direction.y as u8, ray.direction.x as u8, 50])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sun_radiance_dims_and_reddens_towards_horizon() {
+        let sun_radiance = |zenith_degrees: f32| {
+            SkyParams {
+                zenith_degrees,
+                ..Default::default()
+            }
+            .sun_radiance()
+        };
+
+        let noon = sun_radiance(0_f32);
+
+        let sunset = sun_radiance(85_f32);
+
+        for channel in 0..3 {
+            assert!(sunset[channel] < noon[channel]);
+        }
+
+        assert!(sunset[0] / sunset[2] > noon[0] / noon[2]);
+
+        // More aerosols absorb more light.
+        let hazy = SkyParams {
+            turbidity: 8_f32,
+            ..Default::default()
+        }
+        .sun_radiance();
+
+        assert!(hazy[1] < sun_radiance(SkyParams::default().zenith_degrees)[1]);
+    }
+
+    #[test]
+    fn test_gather_lights() {
+        let emissive = |strength| Material::Emissive {
+            radiance: Texture::new_from_color(vec3(1_f32, 1_f32, 1_f32)),
+            strength,
+        };
+        let quad = Geometry::Quad {
+            corner: Vec3::zeros(),
+            u: Vec3::x(),
+            v: Vec3::z(),
+        };
+        let plane = Geometry::Plane {
+            point: Vec3::zeros(),
+            normal: Vec3::y(),
+        };
+        let mut scene = Scene {
+            spheres: vec![
+                Sphere::new(Vec3::zeros(), 1_f32, 0_u32),
+                Sphere::new(Vec3::zeros(), 1_f32, 1_u32),
+                Sphere::new(Vec3::zeros(), 1_f32, 2_u32),
+            ],
+            meshes: vec![Mesh::new(
+                vec![Vec3::zeros(), Vec3::x(), Vec3::y(), Vec3::z()],
+                Vec::new(),
+                Vec::new(),
+                vec![[0_u32, 1_u32, 2_u32], [0_u32, 2_u32, 3_u32]],
+                1_u32,
+            )],
+            shapes: vec![Shape::new(plane, 1_u32), Shape::new(quad, 1_u32)],
+            instances: Vec::new(),
+            materials: vec![
+                Material::Lambertian {
+                    albedo: Texture::new_from_color(vec3(0.5_f32, 0.5_f32, 0.5_f32)),
+                    normal_map: None,
+                },
+                emissive(1_f32),
+                // Emits nothing, so it isn't worth sampling.
+                emissive(0_f32),
+            ],
+            environment_map: None,
+            working_space: WorkingSpace::default(),
+        };
+        let lights = |scene: &Scene| {
+            let mesh_data = GpuMeshData::new(&scene.meshes);
+            let scene_bvh = SceneBvh::build(scene, &mesh_data);
+            gather_lights(scene, &mesh_data, &scene_bvh.world_primitives)
+                .iter()
+                .map(|light| (light.kind, light.index))
+                .collect::<Vec<_>>()
+        };
+
+        // The emissive sphere, both triangles, the quad but not the plane, and the sun.
+        assert_eq!(
+            lights(&scene),
+            [
+                (0_u32, 1_u32),
+                (1_u32, 0_u32),
+                (1_u32, 1_u32),
+                (4_u32, 1_u32),
+                (2_u32, 0_u32)
+            ]
+        );
+
+        scene.materials.truncate(1);
+        scene.spheres.truncate(1);
+        scene.meshes[0].material_idx = 0_u32;
+        scene.shapes.clear();
+        assert_eq!(lights(&scene), [(2_u32, 0_u32)]);
+    }
+
+    #[test]
+    fn test_trace_pipeline_layout_fits_the_default_limits() {
+        let groups = [
+            AccumulationImage::trace_layout_entries().to_vec(),
+            parameter_layout_entries().to_vec(),
+            scene_layout_entries().to_vec(),
+        ];
+        let limits = wgpu::Limits::default();
+        assert!(groups.len() as u32 <= limits.max_bind_groups);
+
+        // The layouts have an entry for every binding the shader declares, and no others.
+        let shader = include_str!("raytracer.wgsl");
+        for (group, entries) in groups.iter().enumerate() {
+            let prefix = format!("@group({group}) @binding(");
+            let declarations = shader.matches(&prefix).count();
+            assert_eq!(declarations, entries.len());
+            for entry in entries {
+                let declaration = format!("@group({group}) @binding({}) var", entry.binding);
+                assert!(shader.contains(&declaration), "{declaration}");
+            }
+        }
+
+        let count = |is_type: fn(&wgpu::BindingType) -> bool| {
+            groups
+                .iter()
+                .flatten()
+                .filter(|entry| is_type(&entry.ty))
+                .count() as u32
+        };
+        let storage_buffers = count(|ty| {
+            matches!(
+                ty,
+                wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { .. },
+                    ..
+                }
+            )
+        });
+        assert_eq!(storage_buffers, TRACE_STORAGE_BUFFERS);
+        assert!(storage_buffers <= limits.max_storage_buffers_per_shader_stage);
+        let uniform_buffers = count(|ty| {
+            matches!(
+                ty,
+                wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    ..
+                }
+            )
+        });
+        assert!(uniform_buffers <= limits.max_uniform_buffers_per_shader_stage);
+        let dynamic_uniform_buffers = count(|ty| {
+            matches!(
+                ty,
+                wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    ..
+                }
+            )
+        });
+        assert!(dynamic_uniform_buffers <= limits.max_dynamic_uniform_buffers_per_pipeline_layout);
+        let textures = count(|ty| matches!(ty, wgpu::BindingType::Texture { .. }));
+        assert!(textures <= limits.max_sampled_textures_per_shader_stage);
+        let samplers = count(|ty| matches!(ty, wgpu::BindingType::Sampler(_)));
+        assert!(samplers <= limits.max_samplers_per_shader_stage);
+        let storage_textures = count(|ty| matches!(ty, wgpu::BindingType::StorageTexture { .. }));
+        assert!(storage_textures <= limits.max_storage_textures_per_shader_stage);
+    }
+
+    #[test]
+    fn test_device_limits_are_the_defaults() {
+        let limits = Raytracer::device_limits(&wgpu::Limits::default()).unwrap();
+        assert_eq!(limits, wgpu::Limits::default());
+
+        assert!(matches!(
+            Raytracer::device_limits(&wgpu::Limits::downlevel_webgl2_defaults()),
+            Err(DeviceLimitsError::TooFewStorageBuffers(
+                0,
+                TRACE_STORAGE_BUFFERS
+            ))
+        ));
+    }
+}
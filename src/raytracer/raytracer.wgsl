@@ -7,61 +7,60 @@ const FRAC_PI_2 = 1.5707964f;
 const MIN_T = 0.001f;
 const MAX_T = 1000f;
 
-const CHANNEL_R = 0u;
-const CHANNEL_G = 1u;
-const CHANNEL_B = 2u;
-
-@group(0) @binding(0) var<uniform> vertexUniforms: VertexUniforms;
-
-@vertex
-fn vsMain(model: VertexInput) -> VertexOutput {
-    return VertexOutput(
-        vertexUniforms.viewProjectionMat * vertexUniforms.modelMat * vec4<f32>(model.position, 0.0, 1.0),
-        model.texCoords
-    );
-}
-
-struct VertexUniforms {
-    viewProjectionMat: mat4x4<f32>,
-    modelMat: mat4x4<f32>,
-}
-
-struct VertexInput {
-    @location(0) position: vec2<f32>,
-    @location(1) texCoords: vec2<f32>,
-}
-
-struct VertexOutput {
-    @builtin(position) clipPosition: vec4<f32>,
-    @location(0) texCoords: vec2<f32>,
-}
-
-@group(1) @binding(0) var<uniform> frameData: vec4<u32>;
-@group(1) @binding(1) var<storage, read_write> imageBuffer: array<array<f32, 3>>;
+// Stands for the distance to a collision which never happens, such as in a vacuum.
+const INFINITE_DISTANCE = 1e30f;
 
-@group(2) @binding(0) var<uniform> camera: Camera;
-@group(2) @binding(1) var<uniform> samplingParams: SamplingParams;
-@group(2) @binding(2) var<storage, read> skyState: SkyState;
+// Shadow rays pass through at most this many boundaries of volumes.
+const MAX_VOLUME_CROSSINGS = 8u;
 
-@group(3) @binding(0) var<storage, read> spheres: array<Sphere>;
-@group(3) @binding(1) var<storage, read> materials: array<Material>;
-@group(3) @binding(2) var<storage, read> textures: array<array<f32, 3>>;
+const BVH_STACK_SIZE = 32u;
 
-@fragment
-fn fsMain(in: VertexOutput) -> @location(0) vec4<f32> {
-    let u = in.texCoords.x;
-    let v = in.texCoords.y;
+const CHANNEL_R = 0u;
+const CHANNEL_G = 1u;
+const CHANNEL_B = 2u;
 
+@group(0) @binding(0) var<uniform> frameData: vec4<u32>;
+@group(0) @binding(1) var<uniform> tileOrigin: vec4<u32>;
+@group(0) @binding(2) var accumulatedImage: texture_2d<f32>;
+@group(0) @binding(3) var outputImage: texture_storage_2d<rgba32float, write>;
+
+@group(1) @binding(0) var<uniform> camera: Camera;
+@group(1) @binding(1) var<uniform> samplingParams: SamplingParams;
+@group(1) @binding(2) var<uniform> skyState: SkyState;
+
+@group(2) @binding(0) var<storage, read> spheres: array<Sphere>;
+@group(2) @binding(1) var<storage, read> materials: array<Material>;
+@group(2) @binding(2) var ldrAtlas: texture_2d_array<f32>;
+// The triangles, followed by the vertices at sceneLayout.firstVertex. See meshTriangle and
+// vertexPosition.
+@group(2) @binding(3) var<storage, read> meshData: array<vec4<f32>>;
+@group(2) @binding(4) var<storage, read> bvhNodes: array<BvhNode>;
+// The primitives the BVH leaves point to, followed by the lights at sceneLayout.firstLight.
+@group(2) @binding(5) var<storage, read> primitives: array<PrimitiveRef>;
+@group(2) @binding(6) var<storage, read> environmentMap: EnvironmentMap;
+@group(2) @binding(7) var hdrAtlas: texture_2d_array<f32>;
+@group(2) @binding(8) var atlasSampler: sampler;
+@group(2) @binding(9) var environmentTexture: texture_2d<f32>;
+@group(2) @binding(10) var<storage, read> instances: array<Instance>;
+@group(2) @binding(11) var<storage, read> shapes: array<Shape>;
+@group(2) @binding(12) var<uniform> sceneLayout: SceneLayout;
+
+// Each dispatch covers one tile of the image, starting at tileOrigin.
+@compute @workgroup_size(8, 8)
+fn csMain(@builtin(global_invocation_id) id: vec3<u32>) {
     let imageWidth = frameData.x;
     let imageHeight = frameData.y;
     let frameNumber = frameData.z;
 
-    let x = u32(u * f32(imageWidth));
-    let y = u32(v * f32(imageHeight));
-    let idx = imageWidth * y + x;
+    let x = tileOrigin.x + id.x;
+    let y = tileOrigin.y + id.y;
+    if x >= imageWidth || y >= imageHeight {
+        return;
+    }
 
+    // The previous sum is read from one texture and the new sum written to the other.
     var rngState = initRng(vec2(x, y), vec2(imageWidth, imageHeight), frameNumber);
-    var pixel = vec3(imageBuffer[idx][0u], imageBuffer[idx][1u], imageBuffer[idx][2u]);
+    var pixel = textureLoad(accumulatedImage, vec2(i32(x), i32(y)), 0).rgb;
     {
         if samplingParams.clearAccumulatedSamples == 1u {
             pixel = vec3(0f);
@@ -70,36 +69,7 @@ fn fsMain(in: VertexOutput) -> @location(0) vec4<f32> {
         let rgb = samplePixel(x, y, &rngState);
         pixel += rgb;
     }
-    imageBuffer[idx] = array<f32, 3>(pixel.r, pixel.g, pixel.b);
-
-    let invN = 1f / f32(samplingParams.accumulatedSamplesPerPixel);
-
-    return vec4(
-        uncharted2(invN * pixel),
-        1f
-    );
-}
-
-fn uncharted2(x: vec3<f32>) -> vec3<f32> {
-    // Based on uncharted2 tonemapping function
-    // https://dmnsgn.github.io/glsl-tone-map/
-    let exposureBias = 0.246;   // determined experimentally for the scene
-    let curr = uncharted2Tonemap(exposureBias * x);
-
-    let w = 11.2;
-    let whiteScale = 1f / uncharted2Tonemap(vec3(w));
-    return whiteScale * curr;
-}
-
-fn uncharted2Tonemap(x: vec3<f32>) -> vec3<f32> {
-    let a = 0.15;
-    let b = 0.50;
-    let c = 0.10;
-    let d = 0.20;
-    let e = 0.02;
-    let f = 0.30;
-    let w = 11.2;
-    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+    textureStore(outputImage, vec2(i32(x), i32(y)), vec4(pixel, 1f));
 }
 
 fn samplePixel(x: u32, y: u32, rngState: ptr<function, u32>) -> vec3<f32> {
@@ -127,48 +97,616 @@ fn rayColor(primaryRay: Ray, rngState: ptr<function, u32>) -> vec3<f32> {
     var color = vec3(0f);
     var throughput = vec3(1f);
 
+    // A ray cone, which grows by the angle a pixel subtends, gives the width of the surface a
+    // path's ray covers. Texture lookups use it to pick a mip level.
+    let focusPlaneCenter = camera.start.lowerLeftCorner + 0.5 * (camera.start.horizontal + camera.start.vertical);
+    let coneSpreadAngle = length(camera.start.vertical) / (f32(frameData.y) * length(focusPlaneCenter - camera.start.eye));
+    var coneWidth = 0f;
+
+    // Lights hit by a ray scattered from a surface which samples lights were also sampled directly
+    // at that surface, so their contribution is weighted with the pdf of the scattered direction.
+    var isSpecularBounce = true;
+    var bsdfPdf = 0f;
+
+    // The absorption coefficient of the dielectric the ray travels through, zero outside.
+    var mediumAbsorption = vec3(0f);
+
+    // The medium of the volume the ray travels through, which has a zero density outside. Volumes
+    // don't nest, and the camera starts outside of all of them.
+    var medium = Medium();
+
+    // Where the path last scattered. Rays pass through the invisible boundaries of volumes, and
+    // the pdfs of the lights they hit are measured from here.
+    var scatterPoint = primaryRay.origin;
+
+    // Dispersive dielectrics refract each wavelength differently. The path picks a single
+    // wavelength at the first one it hits, and follows it from then on. Zero until then.
+    var wavelength = 0f;
+
     for (var bounce = 0u; bounce < samplingParams.numBounces; bounce += 1u) {
         var intersection = Intersection();
         var materialIdx = 0u;
+        var hitPrimitive = PrimitiveRef();
+        let isHit = rayIntersectScene(ray, MIN_T, MAX_T, &intersection, &materialIdx, &hitPrimitive);
+
+        // Free-flight sampling: the ray collides with the volume's medium or with the fog before it
+        // reaches the surface with the probability of the light being scattered on the way. The
+        // media are independent, and the nearer of their collisions happens first.
+        let direction = normalize(ray.direction);
+        let surfaceDistance = select(INFINITE_DISTANCE, intersection.t * length(ray.direction), isHit);
+        let volumeDistance = sampleFreeFlight(medium.density, rngState);
+        let fogDistance = sampleFogFreeFlight(ray.origin, direction, rngState);
+        let collisionDistance = min(volumeDistance, fogDistance);
+
+        if collisionDistance < surfaceDistance {
+            var scatteringMedium = medium;
+            if fogDistance < volumeDistance {
+                scatteringMedium = fogMedium();
+            }
 
-        // Intersection test
-        var closestT = MAX_T;
+            // The probability of the collision cancels out the transmittance up to it, and the
+            // albedo is the probability of the light being scattered rather than absorbed.
+            throughput *= exp(-mediumAbsorption * collisionDistance) * scatteringMedium.albedo;
+            coneWidth += coneSpreadAngle * collisionDistance;
 
-        for (var idx = 0u; idx < arrayLength(&spheres); idx = idx + 1u) {
-            let sphere = spheres[idx];
-            var testIntersect = Intersection();
-            if rayIntersectSphere(ray, sphere, MIN_T, closestT, &testIntersect) {
-                closestT = testIntersect.t;
-                intersection = testIntersect;
-                materialIdx = sphere.materialIdx;
-            }
+            var mediumHit = Intersection();
+            mediumHit.p = ray.origin + collisionDistance * direction;
+            mediumHit.time = ray.time;
+
+            let phase = phaseMaterial(scatteringMedium.anisotropy);
+            color += throughput * sampleDirectLight(-direction, mediumHit, phase, medium, rngState);
+
+            // Sampling the phase function exactly leaves the throughput as it is.
+            ray = Ray(mediumHit.p, sampleHenyeyGreenstein(direction, scatteringMedium.anisotropy, rngState), ray.time);
+            scatterPoint = mediumHit.p;
+            isSpecularBounce = false;
+            bsdfPdf = pdfBsdf(-direction, mediumHit, phase, ray.direction);
+            continue;
         }
 
-        if closestT < MAX_T {
+        if isHit {
+            var material = materials[materialIdx];
+
+            // Beer-Lambert absorption along the path through the medium.
+            throughput *= exp(-mediumAbsorption * intersection.t * length(ray.direction));
+
+            // The cone's footprint is stretched on surfaces seen at grazing angles.
+            coneWidth += coneSpreadAngle * intersection.t * length(ray.direction);
+            let cosine = abs(dot(intersection.n, direction));
+            intersection.footprint = coneWidth / max(cosine, 0.1);
+
+            // The invisible boundaries of volumes don't scatter light, the ray carries on inside
+            // of the volume's medium or out of it.
+            if material.id == 6u && material.z == 0f {
+                medium = mediumBehindBoundary(intersection, material, ray.direction);
+                ray = Ray(intersection.p, ray.direction, ray.time);
+                continue;
+            }
+
+            // Emissive materials terminate the path, they don't scatter light.
+            if material.id == 4u {
+                var misWeight = 1f;
+                if !isSpecularBounce {
+                    let lightPdf = pdfLight(hitPrimitive, Ray(scatterPoint, ray.direction, ray.time), intersection);
+                    misWeight = powerHeuristic(bsdfPdf, lightPdf);
+                }
+
+                color += misWeight * throughput * emittedRadiance(intersection, material);
+                break;
+            }
+
+            // Principled materials emit as well as scatter, but are not sampled as lights.
+            if material.id == 5u {
+                color += throughput * principledEmission(intersection, material);
+            }
+
+            // Lambertian, checkerboard and principled
+            let samplesLights = material.id == 0u || material.id == 3u || material.id == 5u;
+            let wo = -direction;
+
+            var hit = intersection;
+            hit.n = shadingNormal(hit, material);
+
+            // Dielectrics and the refractive boundaries of volumes tell entering from leaving the
+            // surface by the normal's side, the other surfaces reflect on whichever side the ray
+            // arrives from.
+            if material.id != 2u && material.id != 6u {
+                if dot(hit.ng, ray.direction) > 0f {
+                    hit.n = -hit.n;
+                    hit.ng = -hit.ng;
+                }
+
+                hit.n = visibleNormal(hit.n, wo);
+            }
+
+            if samplesLights {
+                color += throughput * sampleDirectLight(wo, hit, material, medium, rngState);
+            }
+
+            if material.id == 2u && material.z > 0f {
+                if wavelength == 0f {
+                    wavelength = 380f + 400f * rngNextFloat(rngState);
+                    throughput *= wavelengthWeight(wavelength);
+                }
+
+                material.x = dispersiveIndex(material.x, material.z, wavelength);
+            }
+
             // Scatter the ray from the surface
-            let material = materials[materialIdx];
-            var scatter = scatterRay(ray, intersection, material, rngState);
+            var scatter = scatterRay(ray, hit, material, rngState);
             ray = scatter.ray;
             throughput *= scatter.albedo;
+
+            // Rays which leave a dielectric inwards, refracted or internally reflected, are inside
+            // of its medium.
+            if material.id == 2u {
+                if dot(ray.direction, hit.ng) < 0f {
+                    mediumAbsorption = dielectricAbsorption(hit, material);
+                } else {
+                    mediumAbsorption = vec3(0f);
+                }
+            }
+
+            if material.id == 6u {
+                medium = mediumBehindBoundary(hit, material, ray.direction);
+            }
+
+            scatterPoint = hit.p;
+            isSpecularBounce = !samplesLights;
+            bsdfPdf = pdfBsdf(wo, hit, material, ray.direction);
         } else {
             // The ray missed. Output background color.
-            let v = normalize(ray.direction);
+            let v = direction;
+
+            if skyState.mode == 1u {
+                // The environment map is a light, and was sampled directly at diffuse surfaces.
+                var misWeight = 1f;
+                if !isSpecularBounce {
+                    misWeight = powerHeuristic(bsdfPdf, pdfEnvironmentLight(v));
+                }
+
+                color += misWeight * throughput * environmentRadiance(v);
+                break;
+            }
+
             let s = skyState.sunDirection;
 
             let theta = acos(v.y);
             let gamma = acos(clamp(dot(v, s), -1f, 1f));
 
-            color = vec3(
+            color += throughput * (skyState.fromRec709 * vec3(
                 radiance(theta, gamma, CHANNEL_R),
                 radiance(theta, gamma, CHANNEL_G),
                 radiance(theta, gamma, CHANNEL_B)
-            );
+            ));
+
+            if isInSunDisk(v) {
+                var misWeight = 1f;
+                if !isSpecularBounce {
+                    misWeight = powerHeuristic(bsdfPdf, pdfSunLight());
+                }
+
+                color += misWeight * throughput * skyState.sunRadiance;
+            }
+
+            break;
+        }
+    }
+
+    return color;
+}
+
+fn emittedRadiance(hit: Intersection, material: Material) -> vec3<f32> {
+    let strength = material.x;
+    return strength * textureLookup(material.desc1, hit);
+}
+
+// Samples the light reaching a surface, or a point in a medium, through the `medium` it's in.
+fn sampleDirectLight(wo: vec3<f32>, hit: Intersection, material: Material, medium: Medium, rngState: ptr<function, u32>) -> vec3<f32> {
+    // Pick one light uniformly, pdfLight accounts for the selection probability.
+    let numLights = sceneLayout.lightCount;
+    let light = primitives[sceneLayout.firstLight + min(u32(rngNextFloat(rngState) * f32(numLights)), numLights - 1u)];
+
+    let lightSample = sampleLight(light, hit.p, hit.time, rngState);
+    if lightSample.pdf <= 0f {
+        return vec3(0f);
+    }
+
+    // Surfaces only scatter light which arrives on their side, media scatter it from everywhere.
+    if material.id != 6u && (dot(hit.n, lightSample.wi) <= 0f || dot(hit.ng, lightSample.wi) <= 0f) {
+        return vec3(0f);
+    }
+
+    var lightHit = Intersection();
+    var lightMaterialIdx = 0u;
+    var lightPrimitive = PrimitiveRef();
+    var transmittance = 1f;
+    let isOccluded = traceShadowRay(Ray(hit.p, lightSample.wi, hit.time), medium, &lightHit, &lightMaterialIdx, &lightPrimitive, &transmittance);
+
+    var radiance = vec3(0f);
+    if light.kind == 2u {
+        // The sky is only visible if the shadow ray escapes the scene.
+        if isOccluded {
+            return vec3(0f);
+        }
+
+        if skyState.mode == 1u {
+            radiance = environmentRadiance(lightSample.wi);
+        } else {
+            radiance = skyState.sunRadiance;
+        }
+    } else {
+        // The shadow ray has to reach the sampled light, and not some other surface in front of it.
+        if !isOccluded || lightPrimitive.kind != light.kind || lightPrimitive.index != light.index {
+            return vec3(0f);
+        }
+
+        radiance = emittedRadiance(lightHit, materials[lightMaterialIdx]);
+    }
+
+    let misWeight = powerHeuristic(lightSample.pdf, pdfBsdf(wo, hit, material, lightSample.wi));
+
+    return misWeight * transmittance * radiance * evalBsdf(wo, hit, material, lightSample.wi) / lightSample.pdf;
+}
+
+// Finds what a shadow ray with a unit direction hits, passing through the invisible boundaries of
+// volumes, and the fraction of the light which makes it through the media along the way.
+fn traceShadowRay(shadowRay: Ray, startMedium: Medium, hit: ptr<function, Intersection>, materialIdx: ptr<function, u32>, hitPrimitive: ptr<function, PrimitiveRef>, transmittance: ptr<function, f32>) -> bool {
+    var ray = shadowRay;
+    var medium = startMedium;
+    var distance = 0f;
+    var opticalDepth = 0f;
+    var isOccluded = false;
+
+    for (var crossing = 0u; crossing < MAX_VOLUME_CROSSINGS; crossing += 1u) {
+        isOccluded = rayIntersectScene(ray, MIN_T, MAX_T, hit, materialIdx, hitPrimitive);
+        let segmentLength = select(MAX_T, (*hit).t, isOccluded);
+        opticalDepth += medium.density * segmentLength;
+        distance += segmentLength;
+
+        if !isOccluded {
+            break;
+        }
 
+        let material = materials[*materialIdx];
+        if material.id != 6u || material.z != 0f {
             break;
         }
+
+        medium = mediumBehindBoundary(*hit, material, ray.direction);
+        ray = Ray((*hit).p, ray.direction, ray.time);
+    }
+
+    *transmittance = exp(-opticalDepth - fogOpticalDepth(shadowRay.origin, shadowRay.direction, distance));
+    return isOccluded;
+}
+
+// The BSDF times the cosine term, of the materials which sample lights, or the phase function of
+// a medium.
+fn evalBsdf(wo: vec3<f32>, hit: Intersection, material: Material, wi: vec3<f32>) -> vec3<f32> {
+    if material.id == 6u {
+        return vec3(phaseHenyeyGreenstein(dot(-wo, wi), material.y));
+    }
+
+    if material.id == 5u {
+        return evalPrincipled(wo, hit, principledSurface(hit, material), wi);
+    }
+
+    return evalLambertian(hit, diffuseTexture(hit, material), wi);
+}
+
+fn pdfBsdf(wo: vec3<f32>, hit: Intersection, material: Material, wi: vec3<f32>) -> f32 {
+    if material.id == 6u {
+        return phaseHenyeyGreenstein(dot(-wo, wi), material.y);
+    }
+
+    if material.id == 5u {
+        return pdfPrincipled(wo, hit, principledSurface(hit, material), wi);
+    }
+
+    return pdfLambertian(hit, wi);
+}
+
+fn powerHeuristic(pdf: f32, otherPdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = otherPdf * otherPdf;
+    return a / (a + b);
+}
+
+struct LightSample {
+    wi: vec3<f32>,
+    // Solid angle pdf, including the probability of selecting the light.
+    pdf: f32,
+}
+
+// Moving lights are sampled where they are at `time`.
+fn sampleLight(light: PrimitiveRef, p: vec3<f32>, time: f32, rngState: ptr<function, u32>) -> LightSample {
+    let selectionPdf = 1f / f32(sceneLayout.lightCount);
+
+    switch light.kind {
+        // Sphere
+        case 0u: {
+            let sphere = spheres[light.index];
+            let wi = sampleSphereLight(sphere, p, time, rngState);
+            return LightSample(wi, selectionPdf * pdfSphereLight(sphere, p, time));
+        }
+
+        // Triangle
+        case 1u: {
+            let triangle = meshTriangle(light.index);
+            let q = sampleTriangleLight(triangle, rngState);
+            let toLight = q - p;
+            let distanceSquared = dot(toLight, toLight);
+            let wi = toLight * inverseSqrt(distanceSquared);
+            return LightSample(wi, selectionPdf * pdfTriangleLight(triangle, distanceSquared, wi));
+        }
+
+        // Quad or disk
+        case 4u: {
+            let shape = shapes[light.index];
+            let q = sampleShapeLight(shape, rngState);
+            let toLight = q - p;
+            let distanceSquared = dot(toLight, toLight);
+            let wi = toLight * inverseSqrt(distanceSquared);
+            return LightSample(wi, selectionPdf * pdfShapeLight(shape, distanceSquared, wi));
+        }
+
+        // Sun, or the environment map
+        case 2u: {
+            if skyState.mode == 1u {
+                return sampleEnvironmentLight(rngState);
+            }
+
+            let coneFactor = skyState.sunSolidAngle / (2f * PI);
+            let wi = sampleCone(skyState.sunDirection, coneFactor, rngState);
+            return LightSample(wi, pdfSunLight());
+        }
+
+        default: {
+            return LightSample(vec3(0f), 0f);
+        }
+    }
+}
+
+fn pdfSunLight() -> f32 {
+    let selectionPdf = 1f / f32(sceneLayout.lightCount);
+    return selectionPdf / skyState.sunSolidAngle;
+}
+
+fn isInSunDisk(direction: vec3<f32>) -> bool {
+    let cosAngularRadius = 1f - skyState.sunSolidAngle / (2f * PI);
+    return dot(direction, skyState.sunDirection) >= cosAngularRadius;
+}
+
+struct EnvironmentMap {
+    width: u32,
+    height: u32,
+    // The marginal CDF over the rows, followed by the conditional CDF over the columns of each
+    // row. See environment.rs.
+    cdf: array<f32>,
+}
+
+// Equirectangular coordinates, with v = 0 at the zenith.
+fn environmentUv(direction: vec3<f32>) -> vec2<f32> {
+    let phi = atan2(direction.z, direction.x) - skyState.environmentRotation;
+    let theta = acos(clamp(direction.y, -1f, 1f));
+    return vec2(fract(phi / (2f * PI)), theta / PI);
+}
+
+fn environmentDirection(uv: vec2<f32>) -> vec3<f32> {
+    let phi = 2f * PI * uv.x + skyState.environmentRotation;
+    let theta = PI * uv.y;
+    let sinTheta = sin(theta);
+    return vec3(sinTheta * cos(phi), cos(theta), sinTheta * sin(phi));
+}
+
+fn environmentTexel(uv: vec2<f32>) -> vec2<u32> {
+    let x = min(u32(uv.x * f32(environmentMap.width)), environmentMap.width - 1u);
+    let y = min(u32(uv.y * f32(environmentMap.height)), environmentMap.height - 1u);
+    return vec2(x, y);
+}
+
+fn environmentRadiance(direction: vec3<f32>) -> vec3<f32> {
+    let texel = environmentTexel(environmentUv(direction));
+    let radiance = textureLoad(environmentTexture, vec2<i32>(texel), 0).rgb;
+    return skyState.environmentIntensity * radiance;
+}
+
+fn sampleEnvironmentLight(rngState: ptr<function, u32>) -> LightSample {
+    let width = environmentMap.width;
+    let height = environmentMap.height;
+
+    // Pick a row from the marginal distribution, then a column within it.
+    let r1 = rngNextFloat(rngState);
+    let y = sampleCdf(0u, height, r1);
+
+    let rowOffset = height + 1u + y * (width + 1u);
+    let r2 = rngNextFloat(rngState);
+    let x = sampleCdf(rowOffset, width, r2);
+
+    // Place the sample uniformly within the texel.
+    let uv = vec2(
+        (f32(x) + rngNextFloat(rngState)) / f32(width),
+        (f32(y) + rngNextFloat(rngState)) / f32(height)
+    );
+
+    let wi = environmentDirection(uv);
+
+    return LightSample(wi, pdfEnvironmentTexel(vec2(x, y), uv.y));
+}
+
+fn pdfEnvironmentLight(direction: vec3<f32>) -> f32 {
+    let uv = environmentUv(direction);
+    return pdfEnvironmentTexel(environmentTexel(uv), uv.y);
+}
+
+fn pdfEnvironmentTexel(texel: vec2<u32>, v: f32) -> f32 {
+    let width = environmentMap.width;
+    let height = environmentMap.height;
+
+    let sinTheta = sin(PI * v);
+    if sinTheta <= 0f {
+        return 0f;
+    }
+
+    let rowPdf = (environmentMap.cdf[texel.y + 1u] - environmentMap.cdf[texel.y]) * f32(height);
+
+    let rowOffset = height + 1u + texel.y * (width + 1u);
+    let columnPdf = (environmentMap.cdf[rowOffset + texel.x + 1u] - environmentMap.cdf[rowOffset + texel.x]) * f32(width);
+
+    // Convert the pdf over the image into a solid angle pdf.
+    let selectionPdf = 1f / f32(sceneLayout.lightCount);
+    return selectionPdf * rowPdf * columnPdf / (2f * PI * PI * sinTheta);
+}
+
+// Returns the index i of the interval with cdf[i] <= r < cdf[i + 1], out of the `count` intervals
+// of the CDF starting at `offset`.
+fn sampleCdf(offset: u32, count: u32, r: f32) -> u32 {
+    var lo = 0u;
+    var hi = count;
+
+    while lo + 1u < hi {
+        let mid = (lo + hi) / 2u;
+        if environmentMap.cdf[offset + mid] <= r {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    return lo;
+}
+
+// The pdf of sampling the direction of `ray` towards the light it hit, with sampleLight.
+fn pdfLight(light: PrimitiveRef, ray: Ray, hit: Intersection) -> f32 {
+    let selectionPdf = 1f / f32(sceneLayout.lightCount);
+
+    switch light.kind {
+        // Sphere
+        case 0u: {
+            return selectionPdf * pdfSphereLight(spheres[light.index], ray.origin, ray.time);
+        }
+
+        // Triangle
+        case 1u: {
+            let toLight = hit.p - ray.origin;
+            let distanceSquared = dot(toLight, toLight);
+            let wi = toLight * inverseSqrt(distanceSquared);
+            return selectionPdf * pdfTriangleLight(meshTriangle(light.index), distanceSquared, wi);
+        }
+
+        // Quad or disk. The other shapes are never sampled as lights, so the rays which hit them
+        // keep all of their emission.
+        case 4u: {
+            let shape = shapes[light.index];
+            if shape.kind != 1u && shape.kind != 3u {
+                return 0f;
+            }
+
+            let toLight = hit.p - ray.origin;
+            let distanceSquared = dot(toLight, toLight);
+            let wi = toLight * inverseSqrt(distanceSquared);
+            return selectionPdf * pdfShapeLight(shape, distanceSquared, wi);
+        }
+
+        default: {
+            return 0f;
+        }
+    }
+}
+
+fn sampleSphereLight(sphere: Sphere, p: vec3<f32>, time: f32, rngState: ptr<function, u32>) -> vec3<f32> {
+    // Uniformly sample the cone of directions in which the sphere is visible from p.
+    let toCenter = sphereCenter(sphere, time) - p;
+    let sinThetaMaxSquared = sphere.radius * sphere.radius / dot(toCenter, toCenter);
+
+    // 1 - cosThetaMax, without the cancellation for small or distant spheres.
+    let coneFactor = sinThetaMaxSquared / (1f + sqrt(max(0f, 1f - sinThetaMaxSquared)));
+
+    return sampleCone(normalize(toCenter), coneFactor, rngState);
+}
+
+// Uniformly samples the directions within a cone around `axis`, where coneFactor is
+// 1 - cos(half angle of the cone).
+fn sampleCone(axis: vec3<f32>, coneFactor: f32, rngState: ptr<function, u32>) -> vec3<f32> {
+    let cosTheta = 1f - rngNextFloat(rngState) * coneFactor;
+    let sinTheta = sqrt(max(0f, 1f - cosTheta * cosTheta));
+    let phi = 2f * PI * rngNextFloat(rngState);
+
+    let onb = pixarOnb(axis);
+
+    return onb * vec3(cos(phi) * sinTheta, sin(phi) * sinTheta, cosTheta);
+}
+
+fn pdfSphereLight(sphere: Sphere, p: vec3<f32>, time: f32) -> f32 {
+    let toCenter = sphereCenter(sphere, time) - p;
+    let sinThetaMaxSquared = sphere.radius * sphere.radius / dot(toCenter, toCenter);
+
+    // Points inside the sphere cannot sample it.
+    if sinThetaMaxSquared >= 1f {
+        return 0f;
+    }
+
+    let coneFactor = sinThetaMaxSquared / (1f + sqrt(1f - sinThetaMaxSquared));
+
+    return 1f / (2f * PI * coneFactor);
+}
+
+fn sampleTriangleLight(triangle: Triangle, rngState: ptr<function, u32>) -> vec3<f32> {
+    // Uniformly sample a point on the triangle's area.
+    let r1 = sqrt(rngNextFloat(rngState));
+    let r2 = rngNextFloat(rngState);
+
+    let p0 = vertexPosition(triangle.v0);
+    let p1 = vertexPosition(triangle.v1);
+    let p2 = vertexPosition(triangle.v2);
+
+    return (1f - r1) * p0 + r1 * (1f - r2) * p1 + r1 * r2 * p2;
+}
+
+fn pdfTriangleLight(triangle: Triangle, distanceSquared: f32, wi: vec3<f32>) -> f32 {
+    let p0 = vertexPosition(triangle.v0);
+    let areaNormal = cross(vertexPosition(triangle.v1) - p0, vertexPosition(triangle.v2) - p0);
+    let doubleArea = length(areaNormal);
+
+    // Triangles emit on both sides.
+    let cosLight = abs(dot(areaNormal, wi));
+    if cosLight < 1e-8f * doubleArea {
+        return 0f;
+    }
+
+    // Convert the area pdf 1 / area into a solid angle pdf.
+    return 2f * distanceSquared / cosLight;
+}
+
+// Uniformly samples a point on the area of a quad or a disk.
+fn sampleShapeLight(shape: Shape, rngState: ptr<function, u32>) -> vec3<f32> {
+    let r1 = rngNextFloat(rngState);
+    let r2 = rngNextFloat(rngState);
+
+    if shape.kind == 1u {
+        return shape.origin + r1 * shape.axis1 + r2 * shape.axis2;
+    }
+
+    let radius = shape.radius * sqrt(r1);
+    let phi = 2f * PI * r2;
+    return shape.origin + radius * (cos(phi) * shape.axis1 + sin(phi) * shape.axis2);
+}
+
+fn pdfShapeLight(shape: Shape, distanceSquared: f32, wi: vec3<f32>) -> f32 {
+    var area = PI * shape.radius * shape.radius;
+    if shape.kind == 1u {
+        area = length(cross(shape.axis1, shape.axis2));
+    }
+
+    // Quads and disks emit on both sides.
+    let cosLight = abs(dot(shape.axis3, wi));
+    if cosLight < 1e-8f {
+        return 0f;
     }
 
-    return throughput * color;
+    // Convert the area pdf 1 / area into a solid angle pdf.
+    return distanceSquared / (cosLight * area);
 }
 
 fn scatterRay(wo: Ray, hit: Intersection, material: Material, rngState: ptr<function, u32>) -> Scatter {
@@ -195,6 +733,16 @@ fn scatterRay(wo: Ray, hit: Intersection, material: Material, rngState: ptr<func
             return scatterCheckerboard(hit, texture1, texture2, rngState);
         }
 
+        case 5u: {
+            return scatterPrincipled(-normalize(wo.direction), hit, principledSurface(hit, material), rngState);
+        }
+
+        // The refractive boundary of a volume
+        case 6u: {
+            let refractionIndex = material.z;
+            return scatterDielectric(wo, hit, refractionIndex, rngState);
+        }
+
         default: {
             return scatterMissingMaterial(hit, rngState);
         }
@@ -204,11 +752,16 @@ fn scatterRay(wo: Ray, hit: Intersection, material: Material, rngState: ptr<func
 fn scatterLambertian(hit: Intersection, texture: TextureDescriptor, rngState: ptr<function, u32>) -> Scatter {
     let wi = sampleLambertian(hit, rngState);
     let throughput = evalLambertian(hit, texture, wi) / pdfLambertian(hit, wi);
-    return Scatter(Ray(hit.p, wi), throughput);
+    return Scatter(Ray(hit.p, wi, hit.time), throughput);
 }
 
 fn evalLambertian(hit: Intersection, texture: TextureDescriptor, wi: vec3<f32>) -> vec3<f32> {
-    return textureLookup(texture, hit.u, hit.v) * FRAC_1_PI * max(EPSILON, dot(hit.n, wi));
+    // Light doesn't leak through the geometric surface, whichever way the shading normal leans.
+    if dot(hit.ng, wi) <= 0f {
+        return vec3(0f);
+    }
+
+    return textureLookup(texture, hit) * FRAC_1_PI * max(EPSILON, dot(hit.n, wi));
 }
 
 fn sampleLambertian(hit: Intersection, seed: ptr<function, u32>) -> vec3<f32> {
@@ -230,6 +783,85 @@ fn pdfLambertian(hit: Intersection, wi: vec3<f32>) -> f32 {
     return max(EPSILON, dot(hit.n, wi) * FRAC_1_PI);
 }
 
+// The normal perturbed by the material's normal or bump map.
+fn shadingNormal(hit: Intersection, material: Material) -> vec3<f32> {
+    switch material.normalMap {
+        // Tangent-space normal map
+        case 1u: {
+            let texel = textureLookup(material.desc4, hit);
+            let xy = material.normalMapStrength * (2f * texel.xy - 1f);
+            let z = max(2f * texel.z - 1f, EPSILON);
+            return normalize(tangentFrame(hit) * vec3(xy, z));
+        }
+
+        // Bump map
+        case 2u: {
+            return bumpNormal(hit, material.desc4, material.normalMapStrength);
+        }
+
+        default: {
+            return hit.n;
+        }
+    }
+}
+
+// An orthonormal frame around the normal, with the tangent along dpdu and the bitangent on the
+// side of dpdv. Surfaces without uvs have no derivatives, and get an arbitrary frame.
+fn tangentFrame(hit: Intersection) -> mat3x3<f32> {
+    let t = hit.dpdu - dot(hit.dpdu, hit.n) * hit.n;
+    let lengthSquared = dot(t, t);
+    if lengthSquared < 1e-12f {
+        return pixarOnb(hit.n);
+    }
+
+    let tangent = t * inverseSqrt(lengthSquared);
+    var bitangent = cross(hit.n, tangent);
+    if dot(bitangent, hit.dpdv) < 0f {
+        bitangent = -bitangent;
+    }
+
+    return mat3x3<f32>(tangent, bitangent, hit.n);
+}
+
+// The normal of the surface displaced by `height` times the texture's red channel along the
+// normal. The height's derivatives are finite differences one texel apart.
+fn bumpNormal(hit: Intersection, desc: TextureDescriptor, height: f32) -> vec3<f32> {
+    let du = 1f / f32(desc.width);
+    let dv = 1f / f32(desc.height);
+
+    var hitU = hit;
+    hitU.u += du;
+    var hitV = hit;
+    hitV.v += dv;
+
+    let h = textureLookup(desc, hit).r;
+    let dhdu = height * (textureLookup(desc, hitU).r - h) / du;
+    let dhdv = height * (textureLookup(desc, hitV).r - h) / dv;
+
+    let c = cross(hit.dpdu, hit.dpdv);
+    let area = length(c);
+    if area < 1e-12f {
+        return hit.n;
+    }
+
+    // cross(dpdu + dhdu * n, dpdv + dhdv * n), neglecting the change of the normal, with
+    // cross(dpdu, dpdv) replaced by the interpolated normal so that meshes stay smooth.
+    let handedness = select(-1f, 1f, dot(c, hit.n) >= 0f);
+    let offset = dhdu * cross(hit.n, hit.dpdv) + dhdv * cross(hit.dpdu, hit.n);
+    return normalize(hit.n + handedness * offset / area);
+}
+
+// A shading normal which faces away from the viewer would reflect light from behind the surface.
+// It is bent towards the viewer, until wo is just above its horizon.
+fn visibleNormal(n: vec3<f32>, wo: vec3<f32>) -> vec3<f32> {
+    let cosO = dot(n, wo);
+    if cosO >= EPSILON {
+        return n;
+    }
+
+    return normalize(n + (EPSILON - cosO) * wo);
+}
+
 fn pixarOnb(n: vec3<f32>) -> mat3x3<f32> {
     // https://www.jcgt.org/published/0006/01/01/paper-lowres.pdf
     let s = select(-1f, 1f, n.z >= 0f);
@@ -243,88 +875,436 @@ fn pixarOnb(n: vec3<f32>) -> mat3x3<f32> {
 
 fn scatterMetal(wo: Ray, hit: Intersection, texture: TextureDescriptor, fuzz: f32, rngState: ptr<function, u32>) -> Scatter {
     let scatterDirection = reflect(wo.direction, hit.n) + fuzz * rngNextVec3InUnitSphere(rngState);
-    let albedo = textureLookup(texture, hit.u, hit.v);
-    return Scatter(Ray(hit.p, scatterDirection), albedo);
-}
 
-fn scatterDielectric(rayIn: Ray, hit: Intersection, refractionIndex: f32, rngState: ptr<function, u32>) -> Scatter {
-    let wo = rayIn.direction;
-    var outwardNormal = vec3(0f);
-    var niOverNt = 0f;
-    var cosine = 0f;
-    if dot(wo, hit.n) > 0f {
-        outwardNormal = -hit.n;
-        niOverNt = refractionIndex;
-        cosine = refractionIndex * dot(normalize(wo), hit.n);
-    } else {
-        outwardNormal = hit.n;
-        niOverNt = 1f / refractionIndex;
-        cosine = dot(normalize(-wo), hit.n);
-    };
+    // Reflections which end up below the geometric surface are absorbed.
+    if dot(scatterDirection, hit.ng) <= 0f {
+        return Scatter(Ray(hit.p, scatterDirection, hit.time), vec3(0f));
+    }
 
-    var refractedDirection = vec3(0f);
-    if refract(wo, outwardNormal, niOverNt, &refractedDirection) {
-        let reflectionProb = schlick(cosine, refractionIndex);
-        var wi = refractedDirection;
-        if rngNextFloat(rngState) < reflectionProb {
-            reflect(wo, hit.n);
+    let albedo = textureLookup(texture, hit);
+    return Scatter(Ray(hit.p, scatterDirection, hit.time), albedo);
+}
+
+fn scatterDielectric(rayIn: Ray, hit: Intersection, refractionIndex: f32, rngState: ptr<function, u32>) -> Scatter {
+    let wo = normalize(rayIn.direction);
+
+    // The ray enters the medium when it arrives on the outside of the geometric surface. The
+    // normal is turned to face the ray, and eta is the ratio ni / nt of the indices.
+    let isEntering = dot(wo, hit.ng) < 0f;
+    let n = select(-hit.n, hit.n, isEntering);
+    let eta = select(refractionIndex, 1f / refractionIndex, isEntering);
+    let cosI = clamp(-dot(wo, n), 0f, 1f);
+
+    // Total internal reflection has a reflectance of one, so it always reflects.
+    if rngNextFloat(rngState) < fresnelDielectric(cosI, eta) {
+        var wi = reflect(wo, n);
+
+        // A reflected ray has to stay on the side of the geometric surface it arrived from.
+        if dot(wi, hit.ng) * dot(wo, hit.ng) > 0f {
+            wi = mirrorAcross(wi, hit.ng);
         }
 
-        return Scatter(Ray(hit.p, wi), vec3(1f));
+        return Scatter(Ray(hit.p, wi, hit.time), vec3(1f));
     }
 
-    let wi = reflect(wo, hit.n);
-    return Scatter(Ray(hit.p, wi), vec3(1f));
+    var wi = refract(wo, n, eta);
+
+    // A refracted ray has to cross the geometric surface.
+    if dot(wi, hit.ng) * dot(wo, hit.ng) < 0f {
+        wi = mirrorAcross(wi, hit.ng);
+    }
+
+    return Scatter(Ray(hit.p, wi, hit.time), vec3(1f));
 }
 
-fn refract(v: vec3<f32>, n: vec3<f32>, niOverNt: f32, refractDirection: ptr<function, vec3<f32>>) -> bool {
-    // ni * sin(i) = nt * sin(t)
-    // sin(t) = sin(i) * (ni / nt)
-    let uv = normalize(v);
-    let dt = dot(uv, n);
-    let discriminant = 1f - niOverNt * niOverNt * (1f - dt * dt);
-    if discriminant > 0f {
-        *refractDirection = normalize(niOverNt * (uv - dt * n) - sqrt(discriminant) * n);
-        return true;
+// Mirrors a direction which a shading normal bent to the wrong side of the geometric surface.
+fn mirrorAcross(direction: vec3<f32>, ng: vec3<f32>) -> vec3<f32> {
+    return direction - 2f * dot(direction, ng) * ng;
+}
+
+// The reflectance of a smooth boundary between two dielectrics, for unpolarized light. cosI is
+// the cosine of the angle of incidence, eta the ratio ni / nt of the indices of refraction.
+fn fresnelDielectric(cosI: f32, eta: f32) -> f32 {
+    let sin2T = eta * eta * max(0f, 1f - cosI * cosI);
+    if sin2T >= 1f {
+        return 1f;
     }
 
-    return false;
+    let cosT = sqrt(1f - sin2T);
+    let rs = (eta * cosI - cosT) / (eta * cosI + cosT);
+    let rp = (cosI - eta * cosT) / (cosI + eta * cosT);
+    return 0.5f * (rs * rs + rp * rp);
+}
+
+// The absorption coefficient inside a dielectric, from the color white light turns after the
+// attenuation distance.
+fn dielectricAbsorption(hit: Intersection, material: Material) -> vec3<f32> {
+    if material.desc1.numLevels == 0u {
+        return vec3(0f);
+    }
+
+    let attenuationColor = max(textureLookup(material.desc1, hit), vec3(1e-4f));
+    return -log(attenuationColor) / material.y;
+}
+
+// Cauchy's equation n = A + B / λ², fitted to the index at the Fraunhofer d line and to the
+// Abbe number (nd - 1) / (nF - nC).
+fn dispersiveIndex(nd: f32, abbeNumber: f32, wavelength: f32) -> f32 {
+    // In micrometers
+    let lambdaD = 0.58756f;
+    let lambdaF = 0.48613f;
+    let lambdaC = 0.65627f;
+
+    let b = (nd - 1f) / (abbeNumber * (1f / (lambdaF * lambdaF) - 1f / (lambdaC * lambdaC)));
+    let a = nd - b / (lambdaD * lambdaD);
+    let lambda = 0.001f * wavelength;
+    return a + b / (lambda * lambda);
+}
+
+// The working space color of a wavelength in nanometers, from 380 to 780, scaled so that the
+// average over uniformly sampled wavelengths is white.
+fn wavelengthWeight(wavelength: f32) -> vec3<f32> {
+    // A multi-lobe Gaussian fit of the CIE 1931 color matching functions.
+    // https://jcgt.org/published/0002/02/01/paper.pdf
+    let x = 1.056f * asymmetricGaussian(wavelength, 599.8f, 37.9f, 31.0f)
+        + 0.362f * asymmetricGaussian(wavelength, 442.0f, 16.0f, 26.7f)
+        - 0.065f * asymmetricGaussian(wavelength, 501.1f, 20.4f, 26.2f);
+    let y = 0.821f * asymmetricGaussian(wavelength, 568.8f, 46.9f, 40.5f)
+        + 0.286f * asymmetricGaussian(wavelength, 530.9f, 16.3f, 31.1f);
+    let z = 1.217f * asymmetricGaussian(wavelength, 437.0f, 11.8f, 36.0f)
+        + 0.681f * asymmetricGaussian(wavelength, 459.0f, 26.0f, 13.8f);
+
+    // XYZ to linear Rec.709, in columns. Spectral colors are outside of the gamut, the negative
+    // components are clipped.
+    let xyzToRec709 = mat3x3<f32>(
+        vec3(3.2404542f, -0.9692660f, 0.0556434f),
+        vec3(-1.5371385f, 1.8760108f, -0.2040259f),
+        vec3(-0.4985314f, 0.0415560f, 1.0572252f)
+    );
+    let rec709 = max(xyzToRec709 * vec3(x, y, z), vec3(0f));
+
+    // The reciprocals of the clipped colors' averages.
+    let normalization = vec3(2.2704398f, 3.4666380f, 3.6590345f);
+
+    return skyState.fromRec709 * (normalization * rec709);
 }
 
-fn schlick(cosine: f32, refractionIndex: f32) -> f32 {
-    var r0 = (1f - refractionIndex) / (1f + refractionIndex);
-    r0 = r0 * r0;
-    return r0 + pow((1f - r0) * (1f - cosine), 5f);
+fn asymmetricGaussian(x: f32, mu: f32, sigmaLeft: f32, sigmaRight: f32) -> f32 {
+    let t = (x - mu) / select(sigmaRight, sigmaLeft, x < mu);
+    return exp(-0.5f * t * t);
+}
+
+// A homogeneous medium, in which light collides `density` times per unit of distance on average.
+// At each collision it's scattered with the probability `albedo`, and absorbed otherwise.
+struct Medium {
+    density: f32,
+    albedo: vec3<f32>,
+    // The Henyey-Greenstein anisotropy
+    anisotropy: f32,
+}
+
+// The medium a ray is in after it crosses or reflects off the boundary of a volume, travelling
+// along `direction`. The albedo is looked up where the light enters the volume.
+fn mediumBehindBoundary(hit: Intersection, material: Material, direction: vec3<f32>) -> Medium {
+    if dot(direction, hit.ng) < 0f {
+        return Medium(material.x, textureLookup(material.desc1, hit), material.y);
+    }
+
+    return Medium();
+}
+
+// The fog's density changes with the height, only its albedo and anisotropy are used.
+fn fogMedium() -> Medium {
+    return Medium(skyState.fogDensity, skyState.fogAlbedo, skyState.fogAnisotropy);
+}
+
+// Media scatter light like the volume material, whose BSDF is the phase function.
+fn phaseMaterial(anisotropy: f32) -> Material {
+    var material = Material();
+    material.id = 6u;
+    material.y = anisotropy;
+    return material;
+}
+
+// Samples the distance to the next collision in a homogeneous medium.
+fn sampleFreeFlight(density: f32, rngState: ptr<function, u32>) -> f32 {
+    let r = rngNextFloat(rngState);
+    if density <= 0f {
+        return INFINITE_DISTANCE;
+    }
+
+    return -log(1f - r) / density;
+}
+
+// Samples the distance to the next collision in the height fog along a unit direction. The fog's
+// density falls off exponentially with the height, so the optical depth along the ray has a
+// closed form, which is inverted.
+fn sampleFogFreeFlight(origin: vec3<f32>, direction: vec3<f32>, rngState: ptr<function, u32>) -> f32 {
+    let r = rngNextFloat(rngState);
+    let density = fogDensityAt(origin.y);
+    if density <= 0f {
+        return INFINITE_DISTANCE;
+    }
+
+    let opticalDepth = -log(1f - r);
+    let k = direction.y / skyState.fogHeightFalloff;
+    if abs(k) < 1e-6f {
+        return opticalDepth / density;
+    }
+
+    // Rays which climb out of the fog before reaching the optical depth don't collide.
+    let x = 1f - opticalDepth * k / density;
+    if x <= 0f {
+        return INFINITE_DISTANCE;
+    }
+
+    return -log(x) / k;
+}
+
+// The integral of the fog's density along a unit direction, up to `distance`.
+fn fogOpticalDepth(origin: vec3<f32>, direction: vec3<f32>, distance: f32) -> f32 {
+    let density = fogDensityAt(origin.y);
+    if density <= 0f {
+        return 0f;
+    }
+
+    let k = direction.y / skyState.fogHeightFalloff;
+
+    // The exponential is clamped where the optical depth has converged, or is far too deep to let
+    // any light through, before it overflows.
+    let kd = clamp(k * distance, -80f, 80f);
+    if abs(kd) < 1e-4f {
+        return density * distance * (1f - 0.5f * kd);
+    }
+
+    return density * (1f - exp(-kd)) / k;
+}
+
+fn fogDensityAt(height: f32) -> f32 {
+    if skyState.fogDensity <= 0f {
+        return 0f;
+    }
+
+    return skyState.fogDensity * exp(-height / skyState.fogHeightFalloff);
+}
+
+// The Henyey-Greenstein phase function of the angle between the directions light travels in
+// before and after scattering. Positive anisotropies scatter light forward.
+fn phaseHenyeyGreenstein(cosTheta: f32, g: f32) -> f32 {
+    let denominator = max(1f + g * g - 2f * g * cosTheta, 1e-6f);
+    return (1f - g * g) / (4f * PI * denominator * sqrt(denominator));
+}
+
+// Samples the direction light travelling along `direction` is scattered in, proportionally to the
+// Henyey-Greenstein phase function.
+fn sampleHenyeyGreenstein(direction: vec3<f32>, g: f32, rngState: ptr<function, u32>) -> vec3<f32> {
+    let r = rngNextFloat(rngState);
+    var cosTheta = 1f - 2f * r;
+    if abs(g) > 1e-3f {
+        let s = (1f - g * g) / (1f + g - 2f * g * r);
+        cosTheta = clamp((1f + g * g - s * s) / (2f * g), -1f, 1f);
+    }
+
+    let sinTheta = sqrt(max(0f, 1f - cosTheta * cosTheta));
+    let phi = 2f * PI * rngNextFloat(rngState);
+
+    return pixarOnb(direction) * vec3(cos(phi) * sinTheta, sin(phi) * sinTheta, cosTheta);
 }
 
 fn scatterCheckerboard(hit: Intersection, texture1: TextureDescriptor, texture2: TextureDescriptor, rngState: ptr<function, u32>) -> Scatter {
+    return scatterLambertian(hit, checkerboardTexture(hit, texture1, texture2), rngState);
+}
+
+fn checkerboardTexture(hit: Intersection, texture1: TextureDescriptor, texture2: TextureDescriptor) -> TextureDescriptor {
     let sines = sin(5f * hit.p.x) * sin(5f * hit.p.y) * sin(5f * hit.p.z);
     if sines < 0f {
-        return scatterLambertian(hit, texture1, rngState);
+        return texture1;
     } else {
-        return scatterLambertian(hit, texture2, rngState);
+        return texture2;
+    }
+}
+
+// The albedo texture of the diffuse materials, lambertian and checkerboard.
+fn diffuseTexture(hit: Intersection, material: Material) -> TextureDescriptor {
+    if material.id == 3u {
+        return checkerboardTexture(hit, material.desc1, material.desc2);
+    }
+
+    return material.desc1;
+}
+
+// The textured parameters of a principled material at a hit point.
+struct PrincipledSurface {
+    // The albedo of the diffuse lobe, zero for metals.
+    diffuseColor: vec3<f32>,
+    // The reflectance of the specular lobe at normal incidence.
+    f0: vec3<f32>,
+    // The GGX roughness, which is the square of the perceptual roughness.
+    alpha: f32,
+}
+
+fn principledSurface(hit: Intersection, material: Material) -> PrincipledSurface {
+    let baseColor = textureLookup(material.desc1, hit);
+    var metallic = material.x;
+    var roughness = material.y;
+    if material.desc2.numLevels > 0u {
+        let metallicRoughness = textureLookup(material.desc2, hit);
+        metallic *= metallicRoughness.b;
+        roughness *= metallicRoughness.g;
+    }
+
+    let ior = material.z;
+    let dielectricF0 = pow((ior - 1f) / (ior + 1f), 2f);
+
+    // Perfectly smooth surfaces are a delta distribution, which can't be evaluated.
+    let alpha = max(roughness * roughness, 0.001f);
+
+    return PrincipledSurface((1f - metallic) * baseColor, mix(vec3(dielectricF0), baseColor, metallic), alpha);
+}
+
+fn principledEmission(hit: Intersection, material: Material) -> vec3<f32> {
+    if material.desc3.numLevels == 0u {
+        return vec3(0f);
+    }
+
+    return textureLookup(material.desc3, hit);
+}
+
+// Picks the specular or the diffuse lobe, in proportion to the light they reflect, and samples
+// it. The pdf is that of picking the direction with either lobe.
+fn scatterPrincipled(wo: vec3<f32>, hit: Intersection, surface: PrincipledSurface, rngState: ptr<function, u32>) -> Scatter {
+    let cosO = max(dot(hit.n, wo), EPSILON);
+
+    var wi = vec3(0f);
+    if rngNextFloat(rngState) < specularProbability(surface, cosO) {
+        let onb = pixarOnb(hit.n);
+        let u = vec2(rngNextFloat(rngState), rngNextFloat(rngState));
+        let h = onb * sampleGgxVisibleNormal(transpose(onb) * wo, surface.alpha, u);
+        wi = reflect(-wo, h);
+    } else {
+        wi = sampleLambertian(hit, rngState);
+    }
+
+    let pdf = pdfPrincipled(wo, hit, surface, wi);
+    if pdf <= 0f {
+        return Scatter(Ray(hit.p, wi, hit.time), vec3(0f));
+    }
+
+    return Scatter(Ray(hit.p, wi, hit.time), evalPrincipled(wo, hit, surface, wi) / pdf);
+}
+
+// The BSDF times the cosine term: a GGX specular lobe with height-correlated Smith masking and
+// shadowing, over a Lambertian lobe which receives the light the specular lobe doesn't reflect.
+fn evalPrincipled(wo: vec3<f32>, hit: Intersection, surface: PrincipledSurface, wi: vec3<f32>) -> vec3<f32> {
+    let cosI = dot(hit.n, wi);
+    if cosI <= 0f || dot(hit.ng, wi) <= 0f {
+        return vec3(0f);
+    }
+
+    let cosO = max(dot(hit.n, wo), EPSILON);
+    let h = normalize(wo + wi);
+    let d = ggxDistribution(dot(hit.n, h), surface.alpha);
+    let g = 1f / (1f + smithLambda(cosO, surface.alpha) + smithLambda(cosI, surface.alpha));
+    let specular = fresnelSchlick(surface.f0, dot(wo, h)) * d * g / (4f * cosO * cosI);
+
+    let diffuse = surface.diffuseColor * (1f - fresnelSchlick(surface.f0, cosO)) * FRAC_1_PI;
+
+    return (diffuse + specular) * cosI;
+}
+
+fn pdfPrincipled(wo: vec3<f32>, hit: Intersection, surface: PrincipledSurface, wi: vec3<f32>) -> f32 {
+    let cosI = dot(hit.n, wi);
+    if cosI <= 0f {
+        return 0f;
     }
+
+    let cosO = max(dot(hit.n, wo), EPSILON);
+    let h = normalize(wo + wi);
+
+    // The visible normal pdf, D * G1(wo) * dot(wo, h) / cosO, divided by the Jacobian
+    // 4 * dot(wo, h) of the reflection.
+    let g1 = 1f / (1f + smithLambda(cosO, surface.alpha));
+    let specularPdf = ggxDistribution(dot(hit.n, h), surface.alpha) * g1 / (4f * cosO);
+
+    return mix(cosI * FRAC_1_PI, specularPdf, specularProbability(surface, cosO));
+}
+
+fn specularProbability(surface: PrincipledSurface, cosO: f32) -> f32 {
+    let fresnel = fresnelSchlick(surface.f0, cosO);
+    let specular = luminance(fresnel);
+    let diffuse = luminance(surface.diffuseColor * (1f - fresnel));
+    return specular / max(specular + diffuse, EPSILON);
+}
+
+fn ggxDistribution(cosH: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = cosH * cosH * (a2 - 1f) + 1f;
+    return a2 / (PI * d * d);
+}
+
+fn smithLambda(cosTheta: f32, alpha: f32) -> f32 {
+    let cos2 = cosTheta * cosTheta;
+    let tan2 = max(1f - cos2, 0f) / max(cos2, EPSILON);
+    return 0.5f * (sqrt(1f + alpha * alpha * tan2) - 1f);
+}
+
+fn fresnelSchlick(f0: vec3<f32>, cosTheta: f32) -> vec3<f32> {
+    return f0 + (1f - f0) * pow(1f - clamp(cosTheta, 0f, 1f), 5f);
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3(0.2126f, 0.7152f, 0.0722f));
+}
+
+// Samples a microfacet normal from the distribution of normals visible from `wo`, given in the
+// local frame where the surface normal is +z.
+// https://jcgt.org/published/0007/04/01/paper.pdf
+fn sampleGgxVisibleNormal(wo: vec3<f32>, alpha: f32, u: vec2<f32>) -> vec3<f32> {
+    // Stretch the view direction to the hemisphere configuration.
+    let v = normalize(vec3(alpha * wo.x, alpha * wo.y, wo.z));
+
+    let lengthSquared = v.x * v.x + v.y * v.y;
+    var t1 = vec3(1f, 0f, 0f);
+    if lengthSquared > 0f {
+        t1 = vec3(-v.y, v.x, 0f) * inverseSqrt(lengthSquared);
+    }
+    let t2 = cross(v, t1);
+
+    // Sample the projected area of the visible hemisphere.
+    let r = sqrt(u.x);
+    let phi = 2f * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5f * (1f + v.z);
+    let p2 = (1f - s) * sqrt(1f - p1 * p1) + s * r * sin(phi);
+    let n = p1 * t1 + p2 * t2 + sqrt(max(0f, 1f - p1 * p1 - p2 * p2)) * v;
+
+    // Unstretch.
+    return normalize(vec3(alpha * n.x, alpha * n.y, max(0f, n.z)));
 }
 
 fn scatterMissingMaterial(hit: Intersection, rngState: ptr<function, u32>) -> Scatter {
     let scatterDirection = hit.n + rngNextVec3InUnitSphere(rngState);
     // An aggressive pink color to indicate an error
     let albedo = vec3(0.9921f, 0.24705f, 0.57254f);
-    return Scatter(Ray(hit.p, scatterDirection), albedo);
+    return Scatter(Ray(hit.p, scatterDirection, hit.time), albedo);
+}
+
+fn skyParam(idx: u32) -> f32 {
+    return skyState.params[idx / 4u][idx % 4u];
 }
 
 fn radiance(theta: f32, gamma: f32, channel: u32) -> f32 {
     let r = skyState.radiances[channel];
     let idx = 9u * channel;
-    let p0 = skyState.params[idx + 0u];
-    let p1 = skyState.params[idx + 1u];
-    let p2 = skyState.params[idx + 2u];
-    let p3 = skyState.params[idx + 3u];
-    let p4 = skyState.params[idx + 4u];
-    let p5 = skyState.params[idx + 5u];
-    let p6 = skyState.params[idx + 6u];
-    let p7 = skyState.params[idx + 7u];
-    let p8 = skyState.params[idx + 8u];
+    let p0 = skyParam(idx + 0u);
+    let p1 = skyParam(idx + 1u);
+    let p2 = skyParam(idx + 2u);
+    let p3 = skyParam(idx + 3u);
+    let p4 = skyParam(idx + 4u);
+    let p5 = skyParam(idx + 5u);
+    let p6 = skyParam(idx + 6u);
+    let p7 = skyParam(idx + 7u);
+    let p8 = skyParam(idx + 8u);
 
     let cosGamma = cos(gamma);
     let cosGamma2 = cosGamma * cosGamma;
@@ -343,9 +1323,24 @@ fn radiance(theta: f32, gamma: f32, channel: u32) -> f32 {
 }
 
 struct SkyState {
-    params: array<f32, 27>,
-    radiances: array<f32, 3>,
+    // The 27 parameters of the sky model, padded to whole vectors as uniform arrays need to be.
+    params: array<vec4<f32>, 7>,
+    radiances: vec3<f32>,
     sunDirection: vec3<f32>,
+    sunRadiance: vec3<f32>,
+    sunSolidAngle: f32,
+    // 0 for the Hosek-Wilkie sky, 1 for the environment map
+    mode: u32,
+    environmentRotation: f32,
+    environmentIntensity: f32,
+    // Converts the sky model's Rec.709 radiance to the working space
+    fromRec709: mat3x3<f32>,
+    // The height fog's density at height 0, zero without fog
+    fogDensity: f32,
+    // The height over which the fog's density falls off by a factor of e
+    fogHeightFalloff: f32,
+    fogAnisotropy: f32,
+    fogAlbedo: vec3<f32>,
 };
 
 struct SamplingParams {
@@ -356,39 +1351,400 @@ struct SamplingParams {
 }
 
 struct Sphere {
+    // The center at time 0.
     centerAndPad: vec4<f32>,
     radius: f32,
     materialIdx: u32,
+    // How far the center moves from time 0 to time 1.
+    motionAndPad: vec4<f32>,
+}
+
+fn sphereCenter(sphere: Sphere, time: f32) -> vec3<f32> {
+    return sphere.centerAndPad.xyz + time * sphere.motionAndPad.xyz;
+}
+
+struct BvhNode {
+    aabbMin: vec3<f32>,
+    // Leaf: index of the first primitive. Interior node: index of the second child, the first
+    // child directly follows the node.
+    offset: u32,
+    aabbMax: vec3<f32>,
+    // The number of primitives in a leaf, zero for interior nodes.
+    count: u32,
+}
+
+struct PrimitiveRef {
+    // 0 for a sphere, 1 for a triangle, 2 for the sun and 3 for an instance
+    kind: u32,
+    index: u32,
+}
+
+struct Instance {
+    objectToWorld: mat4x3<f32>,
+    worldToObject: mat4x3<f32>,
+    // The root node of the object's BVH, which is in the object's own space.
+    root: u32,
+    // Moving instances interpolate between the transforms of `motion` at time 0 and 1, static
+    // ones only use the matrices.
+    isMoving: u32,
+    motion: array<InstanceTransform, 2>,
+}
+
+// A scale, followed by a rotation and a translation.
+struct InstanceTransform {
+    translation: vec3<f32>,
+    // A unit quaternion, as (x, y, z, w).
+    rotation: vec4<f32>,
+    scale: vec3<f32>,
+}
+
+// See GpuShape for what the axes hold for each kind of shape.
+struct Shape {
+    // 0 for a plane, 1 for a quad, 2 for a box, 3 for a disk, 4 for a cylinder
+    kind: u32,
+    materialIdx: u32,
+    radius: f32,
+    origin: vec3<f32>,
+    axis1: vec3<f32>,
+    axis2: vec3<f32>,
+    axis3: vec3<f32>,
+}
+
+struct Triangle {
+    v0: u32,
+    v1: u32,
+    v2: u32,
+    materialIdx: u32,
+}
+
+struct SceneLayout {
+    // Where the vertices start in meshData. Each vertex takes two elements, the position with the
+    // u coordinate and the normal with the v coordinate.
+    firstVertex: u32,
+    // Where the lights start in primitives, the sun is the last one.
+    firstLight: u32,
+    lightCount: u32,
+}
+
+fn meshTriangle(idx: u32) -> Triangle {
+    let data = bitcast<vec4<u32>>(meshData[idx]);
+    return Triangle(data.x, data.y, data.z, data.w);
+}
+
+fn vertexPosition(idx: u32) -> vec3<f32> {
+    return meshData[sceneLayout.firstVertex + 2u * idx].xyz;
+}
+
+fn vertexNormal(idx: u32) -> vec3<f32> {
+    return meshData[sceneLayout.firstVertex + 2u * idx + 1u].xyz;
+}
+
+fn vertexUv(idx: u32) -> vec2<f32> {
+    let offset = sceneLayout.firstVertex + 2u * idx;
+    return vec2(meshData[offset].w, meshData[offset + 1u].w);
 }
 
 struct Material {
     id: u32,
     desc1: TextureDescriptor,
     desc2: TextureDescriptor,
+    desc3: TextureDescriptor,
     x: f32,
+    y: f32,
+    z: f32,
+    // The normal or bump map
+    desc4: TextureDescriptor,
+    // 0 for none, 1 for a tangent-space normal map, 2 for a bump map
+    normalMap: u32,
+    // The normal map's scale, or the bump map's height
+    normalMapStrength: f32,
 }
 
 struct TextureDescriptor {
     width: u32,
     height: u32,
-    offset: u32,
+    // 0 for the 8-bit sRGB atlas, 1 for the 16-bit float atlas
+    atlas: u32,
+    page: u32,
+    // The top left corner of the texture's rectangle in the page. The full resolution image is
+    // on the left, the smaller mip levels are stacked on its right. Each level has a one texel
+    // border. See texture_atlas.rs.
+    x: u32,
+    y: u32,
+    numLevels: u32,
+    // 0 for repeat, 1 for clamp, 2 for mirror
+    wrapMode: u32,
+    // 0 for nearest, 1 for bilinear
+    filterMode: u32,
+    // 0 for an image, 1 for noise, 2 for fBm, 3 for turbulence, 4 for marble, 5 for wood and 6
+    // for a checker. Procedural textures keep their two colors in their first two texels.
+    pattern: u32,
+    // 0 for UV space, 1 for world space
+    patternSpace: u32,
+    patternScale: f32,
+    octaves: u32,
+    distortion: f32,
+}
+
+fn textureLookup(desc: TextureDescriptor, hit: Intersection) -> vec3<f32> {
+    if desc.pattern != 0u {
+        return proceduralLookup(desc, hit);
+    }
+
+    // The mip level whose texels are about as wide as the ray's footprint.
+    let texelDensity = hit.uvDensity * f32(desc.width) * f32(desc.height);
+    let lod = clamp(log2(max(hit.footprint * sqrt(texelDensity), 1e-8)), 0f, f32(desc.numLevels - 1u));
+
+    // The uv origin is in the bottom left corner, the texels start in the top left corner.
+    let uv = vec2(hit.u, 1f - hit.v);
+
+    if desc.filterMode == 0u {
+        return textureLevelNearest(desc, u32(round(lod)), uv);
+    }
+
+    // Trilinear filtering, blending the two closest levels.
+    let level = u32(lod);
+    let t = lod - f32(level);
+    let color = textureLevelBilinear(desc, level, uv);
+    if t == 0f {
+        return color;
+    }
+
+    return mix(color, textureLevelBilinear(desc, level + 1u, uv), t);
+}
+
+fn proceduralLookup(desc: TextureDescriptor, hit: Intersection) -> vec3<f32> {
+    // The pattern's coordinates, and the width of the ray's footprint in them.
+    var p = hit.p * desc.patternScale;
+    var width = hit.footprint * desc.patternScale;
+    if desc.patternSpace == 0u {
+        // Halfway between the checker's cubes, so that UV space gets squares.
+        p = vec3(vec2(hit.u, hit.v) * desc.patternScale, 0.5f);
+        width *= sqrt(hit.uvDensity);
+    }
+
+    let t = saturate(patternValue(desc, p, width));
+    let color0 = textureLevelNearest(desc, 0u, vec2(0.25f, 0.5f));
+    let color1 = textureLevelNearest(desc, 0u, vec2(0.75f, 0.5f));
+    return mix(color0, color1, t);
+}
+
+// The value of a procedural pattern at p, roughly within 0..1.
+fn patternValue(desc: TextureDescriptor, p: vec3<f32>, width: f32) -> f32 {
+    switch desc.pattern {
+        // Noise
+        case 1u: {
+            return 0.5f + 0.5f * gradientNoise(p);
+        }
+        // fBm
+        case 2u: {
+            return 0.5f + 0.5f * fbm(p, desc.octaves, width);
+        }
+        // Turbulence
+        case 3u: {
+            return turbulence(p, desc.octaves, width);
+        }
+        // Marble, veins across the x axis which the turbulence bends.
+        case 4u: {
+            let phase = PI * p.x + 5f * desc.distortion * turbulence(p, desc.octaves, width);
+            return 0.5f + 0.5f * sin(phase);
+        }
+        // Wood, rings around the y axis which the fBm warps.
+        case 5u: {
+            let radius = length(p.xz) + 0.5f * desc.distortion * fbm(p, desc.octaves, width);
+            return fract(radius);
+        }
+        // Checker
+        case 6u: {
+            return filteredChecker(p, width);
+        }
+        default: {
+            return 0f;
+        }
+    }
+}
+
+// The checker of unit cubes, box filtered over the footprint so that it fades to the average
+// instead of aliasing in the distance.
+// Based on https://iquilezles.org/articles/checkerfiltering/
+fn filteredChecker(p: vec3<f32>, width: f32) -> f32 {
+    let w = max(width, 1e-4f);
+    let i = 2f * (abs(fract((p - 0.5f * w) * 0.5f) - 0.5f) - abs(fract((p + 0.5f * w) * 0.5f) - 0.5f)) / w;
+    return 0.5f - 0.5f * i.x * i.y * i.z;
+}
+
+// Octaves finer than the footprint would alias, they are faded out.
+fn octaveWeight(frequency: f32, width: f32) -> f32 {
+    return saturate(2f - 2f * frequency * width);
+}
+
+// Fractal Brownian motion, roughly within -1..1.
+fn fbm(p: vec3<f32>, octaves: u32, width: f32) -> f32 {
+    var sum = 0f;
+    var total = 0f;
+    var amplitude = 1f;
+    var frequency = 1f;
+    for (var i = 0u; i < octaves; i += 1u) {
+        sum += octaveWeight(frequency, width) * amplitude * gradientNoise(frequency * p);
+        total += amplitude;
+        amplitude *= 0.5f;
+        frequency *= 2f;
+    }
+
+    return sum / total;
+}
+
+// Like fBm, but summing the absolute values of the octaves, within 0..1.
+fn turbulence(p: vec3<f32>, octaves: u32, width: f32) -> f32 {
+    var sum = 0f;
+    var total = 0f;
+    var amplitude = 1f;
+    var frequency = 1f;
+    for (var i = 0u; i < octaves; i += 1u) {
+        sum += octaveWeight(frequency, width) * amplitude * abs(gradientNoise(frequency * p));
+        total += amplitude;
+        amplitude *= 0.5f;
+        frequency *= 2f;
+    }
+
+    return sum / total;
+}
+
+// Perlin's gradient noise with quintic interpolation, roughly within -1..1 and zero at the
+// lattice points.
+fn gradientNoise(p: vec3<f32>) -> f32 {
+    let cell = floor(p);
+    let f = p - cell;
+    let c = vec3<i32>(cell);
+    let s = f * f * f * (f * (f * 6f - 15f) + 10f);
+
+    let n000 = dot(latticeGradient(c), f);
+    let n100 = dot(latticeGradient(c + vec3(1, 0, 0)), f - vec3(1f, 0f, 0f));
+    let n010 = dot(latticeGradient(c + vec3(0, 1, 0)), f - vec3(0f, 1f, 0f));
+    let n110 = dot(latticeGradient(c + vec3(1, 1, 0)), f - vec3(1f, 1f, 0f));
+    let n001 = dot(latticeGradient(c + vec3(0, 0, 1)), f - vec3(0f, 0f, 1f));
+    let n101 = dot(latticeGradient(c + vec3(1, 0, 1)), f - vec3(1f, 0f, 1f));
+    let n011 = dot(latticeGradient(c + vec3(0, 1, 1)), f - vec3(0f, 1f, 1f));
+    let n111 = dot(latticeGradient(c + vec3(1, 1, 1)), f - vec3(1f, 1f, 1f));
+
+    let nx00 = mix(n000, n100, s.x);
+    let nx10 = mix(n010, n110, s.x);
+    let nx01 = mix(n001, n101, s.x);
+    let nx11 = mix(n011, n111, s.x);
+    let nxy0 = mix(nx00, nx10, s.y);
+    let nxy1 = mix(nx01, nx11, s.y);
+    return mix(nxy0, nxy1, s.z);
+}
+
+// One of Perlin's twelve gradients, the directions to the edges of a cube, hashed from the
+// lattice point.
+fn latticeGradient(c: vec3<i32>) -> vec3<f32> {
+    let h = jenkinsHash(bitcast<u32>(c.x) ^ jenkinsHash(bitcast<u32>(c.y) ^ jenkinsHash(bitcast<u32>(c.z)))) % 12u;
+    let u = select(-1f, 1f, (h & 1u) == 0u);
+    let v = select(-1f, 1f, (h & 2u) == 0u);
+    if h < 4u {
+        return vec3(u, v, 0f);
+    }
+    if h < 8u {
+        return vec3(u, 0f, v);
+    }
+    return vec3(0f, u, v);
+}
+
+fn textureLevelNearest(desc: TextureDescriptor, level: u32, uv: vec2<f32>) -> vec3<f32> {
+    let size = textureLevelSize(desc, level);
+    let xy = vec2<i32>(floor(uv * vec2<f32>(size)));
+    let x = wrapTexelCoordinate(xy.x, i32(size.x), desc.wrapMode);
+    let y = wrapTexelCoordinate(xy.y, i32(size.y), desc.wrapMode);
+    let texel = vec2<i32>(textureLevelOrigin(desc, level) + vec2(x, y));
+
+    if desc.atlas == 1u {
+        return textureLoad(hdrAtlas, texel, i32(desc.page), 0).rgb;
+    }
+
+    return textureLoad(ldrAtlas, texel, i32(desc.page), 0).rgb;
+}
+
+// The sampler's bilinear filter reads the level's border texels at the edges, which hold the
+// texels the wrap mode maps to.
+fn textureLevelBilinear(desc: TextureDescriptor, level: u32, uv: vec2<f32>) -> vec3<f32> {
+    let size = vec2<f32>(textureLevelSize(desc, level));
+    let st = vec2<f32>(textureLevelOrigin(desc, level)) + wrapUv(uv, desc.wrapMode) * size;
+
+    if desc.atlas == 1u {
+        let coords = st / vec2<f32>(textureDimensions(hdrAtlas));
+        return textureSampleLevel(hdrAtlas, atlasSampler, coords, i32(desc.page), 0f).rgb;
+    }
+
+    let coords = st / vec2<f32>(textureDimensions(ldrAtlas));
+    return textureSampleLevel(ldrAtlas, atlasSampler, coords, i32(desc.page), 0f).rgb;
+}
+
+fn textureLevelSize(desc: TextureDescriptor, level: u32) -> vec2<u32> {
+    return max(vec2(desc.width >> level, desc.height >> level), vec2(1u));
+}
+
+// The position of the level's first texel in the atlas page, inside its border.
+fn textureLevelOrigin(desc: TextureDescriptor, level: u32) -> vec2<u32> {
+    if level == 0u {
+        return vec2(desc.x + 1u, desc.y + 1u);
+    }
+
+    var y = desc.y + 1u;
+    for (var i = 1u; i < level; i += 1u) {
+        y += textureLevelSize(desc, i).y + 2u;
+    }
+
+    return vec2(desc.x + desc.width + 3u, y);
+}
+
+fn wrapUv(uv: vec2<f32>, wrapMode: u32) -> vec2<f32> {
+    switch wrapMode {
+        // Clamp
+        case 1u: {
+            return clamp(uv, vec2(0f), vec2(1f));
+        }
+
+        // Mirror, every other repetition is flipped.
+        case 2u: {
+            let m = uv - 2f * floor(0.5 * uv);
+            return select(m, 2f - m, m > vec2(1f));
+        }
+
+        // Repeat
+        default: {
+            return fract(uv);
+        }
+    }
 }
 
-fn textureLookup(desc: TextureDescriptor, u: f32, v: f32) -> vec3<f32> {
-    let u = clamp(u, 0f, 1f);
-    let v = 1f - clamp(v, 0f, 1f);
+fn wrapTexelCoordinate(x: i32, size: i32, wrapMode: u32) -> u32 {
+    switch wrapMode {
+        // Clamp
+        case 1u: {
+            return u32(clamp(x, 0, size - 1));
+        }
 
-    let j = u32(u * f32(desc.width));
-    let i = u32(v * f32(desc.height));
-    let idx = i * desc.width + j;
+        // Mirror, every other repetition is flipped.
+        case 2u: {
+            let period = 2 * size;
+            let m = ((x % period) + period) % period;
+            return u32(select(m, period - 1 - m, m >= size));
+        }
 
-    let elem = textures[desc.offset + idx];
-    return vec3(elem[0u], elem[1u], elem[2u]);
+        // Repeat
+        default: {
+            return u32(((x % size) + size) % size);
+        }
+    }
 }
 
 struct Ray {
     origin: vec3<f32>,
-    direction: vec3<f32>
+    direction: vec3<f32>,
+    // The instant within the shutter interval the ray is cast at, which moving objects are
+    // intersected at. The rays scattered along a path keep the time of its camera ray.
+    time: f32,
 }
 
 struct Scatter {
@@ -398,14 +1754,473 @@ struct Scatter {
 
 struct Intersection {
     p: vec3<f32>,
+    // The shading normal, which materials perturb with their normal maps.
     n: vec3<f32>,
+    // The normal of the actual surface, on the same side as n.
+    ng: vec3<f32>,
+    // The derivatives of the position along u and v, which give the tangent frame.
+    dpdu: vec3<f32>,
+    dpdv: vec3<f32>,
     u: f32,
     v: f32,
     t: f32,
+    // The time of the ray which hit the surface.
+    time: f32,
+    // The area of uv space per unit of surface area.
+    uvDensity: f32,
+    // The width of the surface covered by the ray, zero for an infinitely thin ray.
+    footprint: f32,
+}
+
+// Instanced hits are reported as the instance, which isn't a light.
+fn rayIntersectScene(ray: Ray, tmin: f32, tmax: f32, hit: ptr<function, Intersection>, materialIdx: ptr<function, u32>, hitPrimitive: ptr<function, PrimitiveRef>) -> bool {
+    let invDirection = 1f / ray.direction;
+    var closestT = tmax;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stackSize = 0u;
+    var nodeIdx = 0u;
+
+    loop {
+        let node = bvhNodes[nodeIdx];
+
+        if rayIntersectAabb(ray, invDirection, node.aabbMin, node.aabbMax, tmin, closestT) {
+            // A zeroed node is an empty scene's root: treat it as a leaf without primitives.
+            let isLeaf = node.count > 0u || node.offset == 0u;
+
+            if isLeaf {
+                for (var idx = 0u; idx < node.count; idx += 1u) {
+                    let primitive = primitives[node.offset + idx];
+                    var isHit = false;
+                    if primitive.kind == 3u {
+                        isHit = rayIntersectInstance(ray, instances[primitive.index], tmin, closestT, hit, materialIdx);
+                    } else {
+                        isHit = rayIntersectPrimitive(ray, primitive, tmin, closestT, hit, materialIdx);
+                    }
+
+                    if isHit {
+                        closestT = (*hit).t;
+                        *hitPrimitive = primitive;
+                    }
+                }
+            } else {
+                stack[stackSize] = node.offset;
+                stackSize += 1u;
+                nodeIdx += 1u;
+                continue;
+            }
+        }
+
+        if stackSize == 0u {
+            break;
+        }
+
+        stackSize -= 1u;
+        nodeIdx = stack[stackSize];
+    }
+
+    return closestT < tmax;
+}
+
+// Intersects the instance's object in its own space, and moves the hit into the world. The ray's
+// direction isn't normalized after the transform, so that t is the same in both spaces.
+fn rayIntersectInstance(ray: Ray, instance: Instance, tmin: f32, tmax: f32, hit: ptr<function, Intersection>, materialIdx: ptr<function, u32>) -> bool {
+    var objectToWorld = instance.objectToWorld;
+    var worldToObject = instance.worldToObject;
+
+    // The transform of a moving instance at the ray's time. Its inverse undoes the translation,
+    // then the rotation with its transpose, then the scale.
+    if instance.isMoving == 1u {
+        let start = instance.motion[0];
+        let end = instance.motion[1];
+
+        let translation = mix(start.translation, end.translation, ray.time);
+        let rotation = quaternionToMatrix(quaternionSlerp(start.rotation, end.rotation, ray.time));
+        let scale = mix(start.scale, end.scale, ray.time);
+
+        let linear = rotation * diagonalMatrix(scale);
+        let inverseLinear = diagonalMatrix(1f / scale) * transpose(rotation);
+
+        objectToWorld = mat4x3(linear[0], linear[1], linear[2], translation);
+        worldToObject = mat4x3(inverseLinear[0], inverseLinear[1], inverseLinear[2], -(inverseLinear * translation));
+    }
+
+    let objectRay = Ray(
+        worldToObject * vec4(ray.origin, 1f),
+        worldToObject * vec4(ray.direction, 0f),
+        ray.time
+    );
+
+    var objectHit = Intersection();
+    if !rayIntersectObject(objectRay, instance.root, tmin, tmax, &objectHit, materialIdx) {
+        return false;
+    }
+
+    let linear = mat3x3(objectToWorld[0], objectToWorld[1], objectToWorld[2]);
+    let inverseLinear = mat3x3(worldToObject[0], worldToObject[1], worldToObject[2]);
+
+    // Normals transform with the inverse transpose.
+    let ng = objectHit.ng * inverseLinear;
+
+    var worldHit = objectHit;
+    worldHit.p = rayPointAtParameter(ray, objectHit.t);
+    worldHit.n = normalize(objectHit.n * inverseLinear);
+    worldHit.ng = normalize(ng);
+    worldHit.dpdu = linear * objectHit.dpdu;
+    worldHit.dpdv = linear * objectHit.dpdv;
+
+    // The transform scales the areas on the surface by the determinant times the length of the
+    // transformed unit normal.
+    worldHit.uvDensity = objectHit.uvDensity / max(abs(determinant(linear)) * length(ng), 1e-12f);
+
+    *hit = worldHit;
+    return true;
+}
+
+// Spherical linear interpolation between unit quaternions, which the CPU puts in the same
+// hemisphere so that it follows the shortest arc.
+fn quaternionSlerp(q0: vec4<f32>, q1: vec4<f32>, t: f32) -> vec4<f32> {
+    let cosTheta = dot(q0, q1);
+
+    // The sine below vanishes for nearly equal rotations, which are close to a straight line.
+    if cosTheta > 0.9995f {
+        return normalize(mix(q0, q1, t));
+    }
+
+    let theta = acos(cosTheta);
+    return (sin((1f - t) * theta) * q0 + sin(t * theta) * q1) / sin(theta);
+}
+
+fn quaternionToMatrix(q: vec4<f32>) -> mat3x3<f32> {
+    let x = q.x;
+    let y = q.y;
+    let z = q.z;
+    let w = q.w;
+
+    return mat3x3(
+        vec3(1f - 2f * (y * y + z * z), 2f * (x * y + w * z), 2f * (x * z - w * y)),
+        vec3(2f * (x * y - w * z), 1f - 2f * (x * x + z * z), 2f * (y * z + w * x)),
+        vec3(2f * (x * z + w * y), 2f * (y * z - w * x), 1f - 2f * (x * x + y * y))
+    );
+}
+
+fn diagonalMatrix(d: vec3<f32>) -> mat3x3<f32> {
+    return mat3x3(vec3(d.x, 0f, 0f), vec3(0f, d.y, 0f), vec3(0f, 0f, d.z));
+}
+
+// The traversal of an object's BVH, whose leaves only hold spheres and triangles.
+fn rayIntersectObject(ray: Ray, root: u32, tmin: f32, tmax: f32, hit: ptr<function, Intersection>, materialIdx: ptr<function, u32>) -> bool {
+    let invDirection = 1f / ray.direction;
+    var closestT = tmax;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stackSize = 0u;
+    var nodeIdx = root;
+
+    loop {
+        let node = bvhNodes[nodeIdx];
+
+        if rayIntersectAabb(ray, invDirection, node.aabbMin, node.aabbMax, tmin, closestT) {
+            // A zeroed node is an empty object's root: treat it as a leaf without primitives.
+            let isLeaf = node.count > 0u || node.offset == 0u;
+
+            if isLeaf {
+                for (var idx = 0u; idx < node.count; idx += 1u) {
+                    let primitive = primitives[node.offset + idx];
+                    if rayIntersectPrimitive(ray, primitive, tmin, closestT, hit, materialIdx) {
+                        closestT = (*hit).t;
+                    }
+                }
+            } else {
+                stack[stackSize] = node.offset;
+                stackSize += 1u;
+                nodeIdx += 1u;
+                continue;
+            }
+        }
+
+        if stackSize == 0u {
+            break;
+        }
+
+        stackSize -= 1u;
+        nodeIdx = stack[stackSize];
+    }
+
+    return closestT < tmax;
+}
+
+fn rayIntersectAabb(ray: Ray, invDirection: vec3<f32>, aabbMin: vec3<f32>, aabbMax: vec3<f32>, tmin: f32, tmax: f32) -> bool {
+    let t0 = (aabbMin - ray.origin) * invDirection;
+    let t1 = (aabbMax - ray.origin) * invDirection;
+    let tsmaller = min(t0, t1);
+    let tbigger = max(t0, t1);
+
+    let tnear = max(tmin, max(tsmaller.x, max(tsmaller.y, tsmaller.z)));
+    let tfar = min(tmax, min(tbigger.x, min(tbigger.y, tbigger.z)));
+
+    return tnear <= tfar;
+}
+
+fn rayIntersectPrimitive(ray: Ray, primitive: PrimitiveRef, tmin: f32, tmax: f32, hit: ptr<function, Intersection>, materialIdx: ptr<function, u32>) -> bool {
+    switch primitive.kind {
+        // Sphere
+        case 0u: {
+            let sphere = spheres[primitive.index];
+            if rayIntersectSphere(ray, sphere, tmin, tmax, hit) {
+                *materialIdx = sphere.materialIdx;
+                return true;
+            }
+        }
+
+        // Triangle
+        case 1u: {
+            let triangle = meshTriangle(primitive.index);
+            if rayIntersectTriangle(ray, triangle, tmin, tmax, hit) {
+                *materialIdx = triangle.materialIdx;
+                return true;
+            }
+        }
+
+        // Plane, quad, box, disk or cylinder
+        case 4u: {
+            let shape = shapes[primitive.index];
+            if rayIntersectShape(ray, shape, tmin, tmax, hit) {
+                *materialIdx = shape.materialIdx;
+                return true;
+            }
+        }
+
+        default: {}
+    }
+
+    return false;
+}
+
+fn rayIntersectShape(ray: Ray, shape: Shape, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
+    switch shape.kind {
+        // Plane
+        case 0u: {
+            return rayIntersectPlane(ray, shape, tmin, tmax, hit);
+        }
+
+        // Quad
+        case 1u: {
+            return rayIntersectQuad(ray, shape, tmin, tmax, hit);
+        }
+
+        // Box
+        case 2u: {
+            return rayIntersectBox(ray, shape, tmin, tmax, hit);
+        }
+
+        // Disk
+        case 3u: {
+            return rayIntersectDisk(ray, shape, tmin, tmax, hit);
+        }
+
+        // Cylinder
+        case 4u: {
+            return rayIntersectCylinder(ray, shape, tmin, tmax, hit);
+        }
+
+        default: {
+            return false;
+        }
+    }
+}
+
+// The parameter at which the ray crosses the plane through `origin` facing `n`, or -1 if the ray
+// runs parallel to it.
+fn rayPlaneParameter(ray: Ray, origin: vec3<f32>, n: vec3<f32>) -> f32 {
+    let denominator = dot(ray.direction, n);
+    if abs(denominator) < 1e-12f {
+        return -1f;
+    }
+
+    return dot(origin - ray.origin, n) / denominator;
+}
+
+fn rayIntersectPlane(ray: Ray, shape: Shape, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
+    let t = rayPlaneParameter(ray, shape.origin, shape.axis3);
+    if t <= tmin || t >= tmax {
+        return false;
+    }
+
+    // The texture repeats every scene unit along the tangents.
+    let p = rayPointAtParameter(ray, t);
+    let d = p - shape.origin;
+    let n = shape.axis3;
+    *hit = Intersection(p, n, n, shape.axis1, shape.axis2, dot(d, shape.axis1), dot(d, shape.axis2), t, ray.time, 1f, 0f);
+    return true;
+}
+
+fn rayIntersectQuad(ray: Ray, shape: Shape, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
+    let t = rayPlaneParameter(ray, shape.origin, shape.axis3);
+    if t <= tmin || t >= tmax {
+        return false;
+    }
+
+    // The coordinates of the point along the two edges, which are its uvs.
+    let p = rayPointAtParameter(ray, t);
+    let d = p - shape.origin;
+    let w = cross(shape.axis1, shape.axis2);
+    let invAreaSquared = 1f / dot(w, w);
+    let u = dot(cross(d, shape.axis2), w) * invAreaSquared;
+    let v = dot(cross(shape.axis1, d), w) * invAreaSquared;
+    if u < 0f || u > 1f || v < 0f || v > 1f {
+        return false;
+    }
+
+    let n = shape.axis3;
+    *hit = Intersection(p, n, n, shape.axis1, shape.axis2, u, v, t, ray.time, sqrt(invAreaSquared), 0f);
+    return true;
+}
+
+fn rayIntersectDisk(ray: Ray, shape: Shape, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
+    let t = rayPlaneParameter(ray, shape.origin, shape.axis3);
+    if t <= tmin || t >= tmax {
+        return false;
+    }
+
+    let p = rayPointAtParameter(ray, t);
+    let d = (p - shape.origin) / shape.radius;
+    if dot(d, d) > 1f {
+        return false;
+    }
+
+    // The texture's square is fitted around the disk.
+    let u = 0.5f + 0.5f * dot(d, shape.axis1);
+    let v = 0.5f + 0.5f * dot(d, shape.axis2);
+    let dpdu = 2f * shape.radius * shape.axis1;
+    let dpdv = 2f * shape.radius * shape.axis2;
+    let uvDensity = 0.25f / (shape.radius * shape.radius);
+
+    let n = shape.axis3;
+    *hit = Intersection(p, n, n, dpdu, dpdv, u, v, t, ray.time, uvDensity, 0f);
+    return true;
+}
+
+fn rayIntersectBox(ray: Ray, shape: Shape, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
+    // The ray in the box's frame, where the box spans -halfSize..halfSize.
+    let halfSize = vec3(length(shape.axis1), length(shape.axis2), length(shape.axis3));
+    let frame = mat3x3<f32>(shape.axis1 / halfSize.x, shape.axis2 / halfSize.y, shape.axis3 / halfSize.z);
+    let origin = (ray.origin - shape.origin) * frame;
+    let direction = ray.direction * frame;
+
+    let invDirection = 1f / direction;
+    let t0 = (-halfSize - origin) * invDirection;
+    let t1 = (halfSize - origin) * invDirection;
+    let tsmaller = min(t0, t1);
+    let tbigger = max(t0, t1);
+    let tnear = max(tsmaller.x, max(tsmaller.y, tsmaller.z));
+    let tfar = min(tbigger.x, min(tbigger.y, tbigger.z));
+
+    // Rays from inside of the box hit it where they leave.
+    let t = select(tnear, tfar, tnear <= tmin);
+    if tnear > tfar || t <= tmin || t >= tmax {
+        return false;
+    }
+
+    // The point is on the face of the axis along which it's the farthest out. The face's texture
+    // axes are the next two axes in order, with u flipped on the negative side so that
+    // dpdu x dpdv faces out.
+    let localP = origin + t * direction;
+    let distance = abs(localP) / halfSize;
+    var faceAxis = vec3(1f, 0f, 0f);
+    if distance.y > distance.x && distance.y >= distance.z {
+        faceAxis = vec3(0f, 1f, 0f);
+    } else if distance.z > distance.x && distance.z > distance.y {
+        faceAxis = vec3(0f, 0f, 1f);
+    }
+
+    let uAxis = faceAxis.zxy;
+    let vAxis = faceAxis.yzx;
+    let side = select(-1f, 1f, dot(faceAxis, localP) >= 0f);
+    let u = 0.5f + 0.5f * side * dot(uAxis, localP / halfSize);
+    let v = 0.5f + 0.5f * dot(vAxis, localP / halfSize);
+    let dpdu = 2f * side * (frame * (uAxis * halfSize));
+    let dpdv = 2f * (frame * (vAxis * halfSize));
+    let uvDensity = 0.25f / (dot(uAxis, halfSize) * dot(vAxis, halfSize));
+
+    let n = side * (frame * faceAxis);
+    *hit = Intersection(rayPointAtParameter(ray, t), n, n, dpdu, dpdv, u, v, t, ray.time, uvDensity, 0f);
+    return true;
+}
+
+fn rayIntersectCylinder(ray: Ray, shape: Shape, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
+    // The ray in the cylinder's frame, where its axis runs along z from 0 to the height.
+    let height = length(shape.axis1);
+    let frame = mat3x3<f32>(shape.axis2, shape.axis3, shape.axis1 / height);
+    let origin = (ray.origin - shape.origin) * frame;
+    let direction = ray.direction * frame;
+    let radiusSquared = shape.radius * shape.radius;
+
+    var closestT = tmax;
+    // 0 for the side, 1 for the bottom cap, 2 for the top cap
+    var surface = 3u;
+
+    let a = dot(direction.xy, direction.xy);
+    let b = dot(origin.xy, direction.xy);
+    let c = dot(origin.xy, origin.xy) - radiusSquared;
+    let discriminant = b * b - a * c;
+    if a > 0f && discriminant > 0f {
+        for (var root = 0u; root < 2u; root += 1u) {
+            let t = (-b + select(-1f, 1f, root == 1u) * sqrt(discriminant)) / a;
+            let z = origin.z + t * direction.z;
+            if t > tmin && t < closestT && z >= 0f && z <= height {
+                closestT = t;
+                surface = 0u;
+            }
+        }
+    }
+
+    if direction.z != 0f {
+        for (var cap = 1u; cap <= 2u; cap += 1u) {
+            let t = (select(0f, height, cap == 2u) - origin.z) / direction.z;
+            let xy = origin.xy + t * direction.xy;
+            if t > tmin && t < closestT && dot(xy, xy) <= radiusSquared {
+                closestT = t;
+                surface = cap;
+            }
+        }
+    }
+
+    if surface == 3u {
+        return false;
+    }
+
+    let p = rayPointAtParameter(ray, closestT);
+    let localP = origin + closestT * direction;
+
+    if surface == 0u {
+        // u goes around the axis and v from the base to the top.
+        let phi = atan2(localP.y, localP.x);
+        let u = 0.5f + 0.5f * FRAC_1_PI * phi;
+        let v = localP.z / height;
+        let n = frame * vec3(localP.xy / shape.radius, 0f);
+        let dpdu = 2f * PI * (frame * vec3(-localP.y, localP.x, 0f));
+        let uvDensity = 1f / (2f * PI * shape.radius * height);
+        *hit = Intersection(p, n, n, dpdu, shape.axis1, u, v, closestT, ray.time, uvDensity, 0f);
+        return true;
+    }
+
+    // The caps are textured like disks, with u flipped on the bottom so that dpdu x dpdv faces
+    // out.
+    let side = select(-1f, 1f, surface == 2u);
+    let d = localP.xy / shape.radius;
+    let u = 0.5f + 0.5f * side * d.x;
+    let v = 0.5f + 0.5f * d.y;
+    let dpdu = 2f * side * shape.radius * shape.axis2;
+    let dpdv = 2f * shape.radius * shape.axis3;
+    let uvDensity = 0.25f / radiusSquared;
+    let n = side * (shape.axis1 / height);
+    *hit = Intersection(p, n, n, dpdu, dpdv, u, v, closestT, ray.time, uvDensity, 0f);
+    return true;
 }
 
 fn rayIntersectSphere(ray: Ray, sphere: Sphere, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
-    let oc = ray.origin - sphere.centerAndPad.xyz;
+    let oc = ray.origin - sphereCenter(sphere, ray.time);
     let a = dot(ray.direction, ray.direction);
     let b = dot(oc, ray.direction);
     let c = dot(oc, oc) - sphere.radius * sphere.radius;
@@ -430,13 +2245,99 @@ fn rayIntersectSphere(ray: Ray, sphere: Sphere, tmin: f32, tmax: f32, hit: ptr<f
 
 fn sphereIntersection(ray: Ray, sphere: Sphere, t: f32) -> Intersection {
     let p = rayPointAtParameter(ray, t);
-    let n = (1f / sphere.radius) * (p - sphere.centerAndPad.xyz);
+    let n = (1f / sphere.radius) * (p - sphereCenter(sphere, ray.time));
     let theta = acos(-n.y);
     let phi = atan2(-n.z, n.x) + PI;
     let u = 0.5 * FRAC_1_PI * phi;
     let v = FRAC_1_PI * theta;
 
-    return Intersection(p, n, u, v, t);
+    // u goes around the equator and v from the bottom pole to the top one.
+    let sinTheta = sqrt(max(0f, 1f - n.y * n.y));
+    let dpdu = 2f * PI * sphere.radius * vec3(n.z, 0f, -n.x);
+    let dpdv = PI * sphere.radius * vec3(n.y * cos(phi), sinTheta, -n.y * sin(phi));
+
+    // The texture covers the sphere's surface area once.
+    let uvDensity = 1f / (4f * PI * sphere.radius * sphere.radius);
+
+    return Intersection(p, n, n, dpdu, dpdv, u, v, t, ray.time, uvDensity, 0f);
+}
+
+fn rayIntersectTriangle(ray: Ray, triangle: Triangle, tmin: f32, tmax: f32, hit: ptr<function, Intersection>) -> bool {
+    // Möller-Trumbore intersection
+    // https://www.graphics.cornell.edu/pubs/1997/MT97.pdf
+    let p0 = vertexPosition(triangle.v0);
+    let e1 = vertexPosition(triangle.v1) - p0;
+    let e2 = vertexPosition(triangle.v2) - p0;
+
+    let pvec = cross(ray.direction, e2);
+    let det = dot(e1, pvec);
+
+    // The ray is parallel to the triangle, or the triangle is degenerate.
+    if abs(det) < 1e-8f {
+        return false;
+    }
+
+    let invDet = 1f / det;
+    let tvec = ray.origin - p0;
+    let b1 = invDet * dot(tvec, pvec);
+    if b1 < 0f || b1 > 1f {
+        return false;
+    }
+
+    let qvec = cross(tvec, e1);
+    let b2 = invDet * dot(ray.direction, qvec);
+    if b2 < 0f || b1 + b2 > 1f {
+        return false;
+    }
+
+    let t = invDet * dot(e2, qvec);
+    if t < tmax && t > tmin {
+        *hit = triangleIntersection(ray, triangle, t, b1, b2);
+        return true;
+    }
+
+    return false;
+}
+
+fn triangleIntersection(ray: Ray, triangle: Triangle, t: f32, b1: f32, b2: f32) -> Intersection {
+    let p = rayPointAtParameter(ray, t);
+    let b0 = 1f - b1 - b2;
+
+    let n = normalize(
+        b0 * vertexNormal(triangle.v0) + b1 * vertexNormal(triangle.v1) + b2 * vertexNormal(triangle.v2)
+    );
+    let uv0 = vertexUv(triangle.v0);
+    let uv1 = vertexUv(triangle.v1);
+    let uv2 = vertexUv(triangle.v2);
+    let uv = b0 * uv0 + b1 * uv1 + b2 * uv2;
+
+    let p0 = vertexPosition(triangle.v0);
+    let e1 = vertexPosition(triangle.v1) - p0;
+    let e2 = vertexPosition(triangle.v2) - p0;
+    let faceNormal = cross(e1, e2);
+    let worldArea = length(faceNormal);
+
+    // Meshes aren't wound consistently, the face normal is put on the side of the vertex normals.
+    var ng = faceNormal / max(worldArea, 1e-12);
+    if dot(ng, n) < 0f {
+        ng = -ng;
+    }
+
+    // Solve e1 = duv1.x * dpdu + duv1.y * dpdv and e2 = duv2.x * dpdu + duv2.y * dpdv.
+    let duv1 = uv1 - uv0;
+    let duv2 = uv2 - uv0;
+    let uvDet = duv1.x * duv2.y - duv1.y * duv2.x;
+    var dpdu = vec3(0f);
+    var dpdv = vec3(0f);
+    if abs(uvDet) > 1e-12f {
+        dpdu = (duv2.y * e1 - duv1.y * e2) / uvDet;
+        dpdv = (duv1.x * e2 - duv2.x * e1) / uvDet;
+    }
+
+    let uvArea = abs(uvDet);
+    let uvDensity = uvArea / max(worldArea, 1e-12);
+
+    return Intersection(p, n, ng, dpdu, dpdv, uv.x, uv.y, t, ray.time, uvDensity, 0f);
 }
 
 fn rayPointAtParameter(ray: Ray, t: f32) -> vec3<f32> {
@@ -444,23 +2345,49 @@ fn rayPointAtParameter(ray: Ray, t: f32) -> vec3<f32> {
 }
 
 struct Camera {
+    // The camera at time 0 and at time 1, which are the same unless it moves.
+    start: CameraFrame,
+    end: CameraFrame,
+    lensRadius: f32,
+    shutterOpen: f32,
+    shutterClose: f32,
+}
+
+struct CameraFrame {
     eye: vec3<f32>,
     horizontal: vec3<f32>,
     vertical: vec3<f32>,
     u: vec3<f32>,
     v: vec3<f32>,
-    lensRadius: f32,
     lowerLeftCorner: vec3<f32>,
 }
 
 fn cameraMakeRay(camera: Camera, rngState: ptr<function, u32>, u: f32, v: f32) -> Ray {
+    // Each sample is taken at a random instant while the shutter is open, from where the camera
+    // is at that instant.
+    let time = mix(camera.shutterOpen, camera.shutterClose, rngNextFloat(rngState));
+    let frame = cameraFrameAt(camera, time);
+
     let randomPointInLens = camera.lensRadius * rngNextVec3InUnitDisk(rngState);
-    let lensOffset = randomPointInLens.x * camera.u + randomPointInLens.y * camera.v;
+    let lensOffset = randomPointInLens.x * frame.u + randomPointInLens.y * frame.v;
 
-    let origin = camera.eye + lensOffset;
-    let direction = camera.lowerLeftCorner + u * camera.horizontal + v * camera.vertical - origin;
+    let origin = frame.eye + lensOffset;
+    let direction = frame.lowerLeftCorner + u * frame.horizontal + v * frame.vertical - origin;
 
-    return Ray(origin, direction);
+    return Ray(origin, direction, time);
+}
+
+// The vectors of the frame are interpolated linearly, which is close to turning the camera for
+// the small turns it makes while the shutter is open.
+fn cameraFrameAt(camera: Camera, time: f32) -> CameraFrame {
+    return CameraFrame(
+        mix(camera.start.eye, camera.end.eye, time),
+        mix(camera.start.horizontal, camera.end.horizontal, time),
+        mix(camera.start.vertical, camera.end.vertical, time),
+        mix(camera.start.u, camera.end.u, time),
+        mix(camera.start.v, camera.end.v, time),
+        mix(camera.start.lowerLeftCorner, camera.end.lowerLeftCorner, time)
+    );
 }
 
 fn rngNextVec3InUnitDisk(state: ptr<function, u32>) -> vec3<f32> {
@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use image::{codecs::hdr::HdrEncoder, Rgb, Rgb32FImage, RgbImage};
+use thiserror::Error;
+
+use super::{
+    color_space::{self, linear_to_srgb},
+    tonemap::{tonemap, DisplayParams},
+};
+
+/// The accumulated image read back from the GPU, as the average linear Rec.709 radiance of each
+/// pixel in row-major order, starting from the top left corner.
+pub struct RenderedImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 3]>,
+    /// The display settings at the time of the readback, applied to PNG files.
+    pub display: DisplayParams,
+}
+
+impl RenderedImage {
+    /// The format is chosen by the file extension. PNG files get the same tonemapping as the
+    /// window, EXR and HDR files store the linear radiance.
+    pub fn save(
+        &self,
+        path: &Path,
+    ) -> Result<(), ImageExportError> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+
+        let result = match extension.as_str() {
+            "png" => self.to_tonemapped_rgb8().save(path),
+            "exr" => self.to_rgb32f().save(path),
+            "hdr" => std::fs::File::create(path)
+                .map_err(image::ImageError::IoError)
+                .and_then(|file| {
+                    let pixels: Vec<Rgb<f32>> = self.pixels.iter().map(|p| Rgb(*p)).collect();
+
+                    HdrEncoder::new(std::io::BufWriter::new(file)).encode(
+                        &pixels,
+                        self.width as usize,
+                        self.height as usize,
+                    )
+                }),
+            _ => {
+                return Err(ImageExportError::UnsupportedFormat(path.to_owned()));
+            }
+        };
+
+        result.map_err(|source| ImageExportError::Write {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    pub fn to_tonemapped_rgb8(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let pixel = self.pixels[(self.width * y + x) as usize];
+
+            Rgb(tonemap(&self.display, pixel).map(|c| (linear_to_srgb(c) * 255_f32).round() as u8))
+        })
+    }
+
+    fn to_rgb32f(&self) -> Rgb32FImage {
+        Rgb32FImage::from_raw(
+            self.width,
+            self.height,
+            self.pixels.iter().flatten().copied().collect(),
+        )
+        .expect("The pixel count should match the image size")
+    }
+}
+
+/// The distance between the rows of an image `width` pixels wide copied from the accumulation
+/// texture, as the rows of a texture copy have to be aligned.
+pub fn readback_bytes_per_row(width: u32) -> u32 {
+    (width * std::mem::size_of::<[f32; 4]>() as u32)
+        .next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+}
+
+/// Averages the sums copied from the accumulation texture, dropping the padding at the end of
+/// each row, and converts them from the working space with `to_rec709`.
+pub fn readback_pixels(
+    data: &[u8],
+    width: u32,
+    accumulated_samples: u32,
+    to_rec709: &[[f32; 3]; 3],
+) -> Vec<[f32; 3]> {
+    // Before the first frame, the sums are all zero.
+    let inv_samples = 1_f32 / accumulated_samples.max(1_u32) as f32;
+
+    data.chunks_exact(readback_bytes_per_row(width) as usize)
+        .flat_map(|row| {
+            bytemuck::cast_slice::<u8, [f32; 4]>(row)
+                .iter()
+                .take(width as usize)
+                .map(|pixel| {
+                    let radiance = [pixel[0], pixel[1], pixel[2]].map(|c| c * inv_samples);
+
+                    color_space::mul(to_rec709, radiance)
+                })
+        })
+        .collect()
+}
+
+#[derive(Error, Debug)]
+
+pub enum ImageExportError {
+    #[error("{}: unsupported image format, expected .png, .exr or .hdr", .0.display())]
+    UnsupportedFormat(PathBuf),
+    #[error("{}: {source}", .path.display())]
+    Write {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::raytracer::{color_space::WorkingSpace, tonemap::Tonemapper};
+
+    /// The rows of a readback of sums, each pixel's sum set by `sum(x, y)`.
+    fn readback(
+        width: u32,
+        height: u32,
+        sum: impl Fn(u32, u32) -> f32,
+    ) -> Vec<f32> {
+        let row_len = readback_bytes_per_row(width) as usize / std::mem::size_of::<f32>();
+
+        let mut data = vec![-1_f32; row_len * height as usize];
+
+        for y in 0..height {
+            for x in 0..width {
+                let idx = y as usize * row_len + 4 * x as usize;
+                data[idx..idx + 4].copy_from_slice(&[sum(x, y), sum(x, y), sum(x, y), 0_f32]);
+            }
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_readback_rows_are_aligned() {
+        assert_eq!(readback_bytes_per_row(16), 256);
+        assert_eq!(readback_bytes_per_row(17), 512);
+        // Not a multiple of 64 pixels, nor of the 16 pixels in 256 bytes.
+        assert_eq!(readback_bytes_per_row(100), 1792);
+    }
+
+    #[test]
+    fn test_readback_pixels_are_averaged_without_the_padding() {
+        let (width, height) = (100_u32, 3_u32);
+        let data = readback(width, height, |x, y| (y * width + x) as f32 * 4_f32);
+        let to_rec709 = WorkingSpace::Rec709.to_rec709();
+
+        let pixels = readback_pixels(bytemuck::cast_slice(&data), width, 4_u32, &to_rec709);
+        assert_eq!(pixels.len(), (width * height) as usize);
+        // The padding was filled with -1, which would show up in the pixels if it wasn't dropped.
+        for (idx, pixel) in pixels.iter().enumerate() {
+            assert_eq!(*pixel, [idx as f32; 3]);
+        }
+    }
+
+    #[test]
+    fn test_readback_without_samples_is_black() {
+        let data = readback(5_u32, 2_u32, |_, _| 0_f32);
+        let to_rec709 = WorkingSpace::Rec709.to_rec709();
+
+        let image = RenderedImage {
+            width: 5_u32,
+            height: 2_u32,
+            pixels: readback_pixels(bytemuck::cast_slice(&data), 5_u32, 0_u32, &to_rec709),
+            display: DisplayParams::default(),
+        };
+        assert!(image.to_rgb32f().iter().all(|c| *c == 0_f32));
+        assert!(image.to_tonemapped_rgb8().iter().all(|c| *c == 0_u8));
+    }
+
+    #[test]
+    fn test_tonemapped_rgb8_applies_the_exposure() {
+        let image = RenderedImage {
+            width: 2_u32,
+            height: 1_u32,
+            pixels: vec![[0.25_f32; 3], [4_f32; 3]],
+            display: DisplayParams {
+                tonemapper: Tonemapper::LinearClamp,
+                exposure_ev: 1_f32,
+                ..Default::default()
+            },
+        };
+        let rgb8 = image.to_tonemapped_rgb8();
+        let half = (linear_to_srgb(0.5_f32) * 255_f32).round() as u8;
+        assert_eq!(rgb8.get_pixel(0, 0).0, [half; 3]);
+        // Colors brighter than white are clamped.
+        assert_eq!(rgb8.get_pixel(1, 0).0, [u8::MAX; 3]);
+    }
+}
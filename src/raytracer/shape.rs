@@ -0,0 +1,347 @@
+use nalgebra_glm::{Quat, Vec3};
+
+use super::bvh::Aabb;
+
+/// Infinite planes are bounded this far out from the origin, well beyond where rays reach, so
+/// that they fit into the BVH.
+const PLANE_EXTENT: f32 = 1e5_f32;
+
+/// A planar or quadric primitive, which is intersected analytically like the spheres.
+#[derive(Clone, Copy, Debug, PartialEq)]
+
+pub struct Shape {
+    pub geometry: Geometry,
+    pub material_idx: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+
+pub enum Geometry {
+    /// An infinite plane through `point`. Textures repeat every scene unit along it.
+    Plane { point: Vec3, normal: Vec3 },
+    /// The parallelogram spanned by the edges `u` and `v` from `corner`, like the quads of the
+    /// book. Its normal is `u × v`, and the texture covers it once.
+    Quad { corner: Vec3, u: Vec3, v: Vec3 },
+    /// A box with the half extents `half_size` along its axes, turned by `rotation` about its
+    /// center. Each face is covered by the texture once.
+    Box {
+        center: Vec3,
+        half_size: Vec3,
+        rotation: Quat,
+    },
+    /// A flat disk facing `normal`, covered by the texture's inscribed circle.
+    Disk {
+        center: Vec3,
+        normal: Vec3,
+        radius: f32,
+    },
+    /// A cylinder around the segment from `base` to `top`, closed by disks at both ends. The
+    /// texture wraps around its side once.
+    Cylinder { base: Vec3, top: Vec3, radius: f32 },
+}
+
+impl Shape {
+    pub fn new(
+        geometry: Geometry,
+        material_idx: u32,
+    ) -> Self {
+        Self {
+            geometry,
+            material_idx,
+        }
+    }
+
+    /// Only emissive quads and disks are sampled as lights, the other shapes light the scene
+    /// through the rays which hit them.
+    pub fn is_sampled_as_light(&self) -> bool {
+        matches!(self.geometry, Geometry::Quad { .. } | Geometry::Disk { .. })
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        match self.geometry {
+            Geometry::Plane { point, normal } => {
+                // Planes at a right angle to an axis are flat along it.
+                let is_flat_along =
+                    |axis: usize| (0..3).all(|other| other == axis || normal[other] == 0_f32);
+
+                let extent = glm::vec3(PLANE_EXTENT, PLANE_EXTENT, PLANE_EXTENT);
+
+                let mut bounds = Aabb {
+                    min: -extent,
+                    max: extent,
+                };
+
+                for axis in (0..3).filter(|&axis| is_flat_along(axis)) {
+                    bounds.min[axis] = point[axis];
+
+                    bounds.max[axis] = point[axis];
+                }
+
+                bounds
+            }
+            Geometry::Quad { corner, u, v } => {
+                Aabb::from_points(&[corner, corner + u, corner + v, corner + u + v])
+            }
+            Geometry::Box {
+                center,
+                half_size,
+                rotation,
+            } => {
+                let axes = glm::quat_to_mat3(&rotation);
+
+                let extent = glm::abs(&axes) * half_size;
+
+                Aabb {
+                    min: center - extent,
+                    max: center + extent,
+                }
+            }
+            Geometry::Disk {
+                center,
+                normal,
+                radius,
+            } => disk_bounds(center, &glm::normalize(&normal), radius),
+            Geometry::Cylinder { base, top, radius } => {
+                let axis = glm::normalize(&(top - base));
+
+                disk_bounds(base, &axis, radius).union(&disk_bounds(top, &axis, radius))
+            }
+        }
+    }
+}
+
+/// A disk reaches `radius * sin(angle to the axis)` out along each axis.
+fn disk_bounds(
+    center: Vec3,
+    normal: &Vec3,
+    radius: f32,
+) -> Aabb {
+    let extent = normal.map(|n| radius * (1_f32 - n * n).max(0_f32).sqrt());
+
+    Aabb {
+        min: center - extent,
+        max: center + extent,
+    }
+}
+
+/// A shape as the shader intersects it, with a frame of three axes, whose meaning depends on the
+/// kind of shape:
+///
+/// - plane: two unit tangents and the unit normal,
+/// - quad: the two edges and the unit normal,
+/// - box: the half extents along the box's axes,
+/// - disk: two unit tangents and the unit normal,
+/// - cylinder: the axis from the base to the top, and two unit vectors at a right angle to it,
+///   whose cross product points along it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+
+pub struct GpuShape {
+    /// 0 for a plane, 1 for a quad, 2 for a box, 3 for a disk and 4 for a cylinder.
+    kind: u32,
+    material_idx: u32,
+    radius: f32,
+    _padding: u32,
+    /// The plane's point, the quad's corner, the center of the box or of the disk, or the base
+    /// of the cylinder.
+    origin: Vec3,
+    _padding1: f32,
+    axis1: Vec3,
+    _padding2: f32,
+    axis2: Vec3,
+    _padding3: f32,
+    axis3: Vec3,
+    _padding4: f32,
+}
+
+impl GpuShape {
+    pub fn new(shape: &Shape) -> Self {
+        let (kind, origin, axes, radius) = match shape.geometry {
+            Geometry::Plane { point, normal } => {
+                let normal = glm::normalize(&normal);
+
+                let (tangent, bitangent) = orthonormal_basis(&normal);
+
+                (0_u32, point, [tangent, bitangent, normal], 0_f32)
+            }
+            Geometry::Quad { corner, u, v } => (
+                1_u32,
+                corner,
+                [u, v, glm::normalize(&glm::cross(&u, &v))],
+                0_f32,
+            ),
+            Geometry::Box {
+                center,
+                half_size,
+                rotation,
+            } => {
+                let axes = glm::quat_to_mat3(&rotation);
+
+                (
+                    2_u32,
+                    center,
+                    [0_usize, 1, 2].map(|axis| half_size[axis] * axes.column(axis)),
+                    0_f32,
+                )
+            }
+            Geometry::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                let normal = glm::normalize(&normal);
+
+                let (tangent, bitangent) = orthonormal_basis(&normal);
+
+                (3_u32, center, [tangent, bitangent, normal], radius)
+            }
+            Geometry::Cylinder { base, top, radius } => {
+                let (tangent, bitangent) = orthonormal_basis(&glm::normalize(&(top - base)));
+
+                (4_u32, base, [top - base, tangent, bitangent], radius)
+            }
+        };
+
+        Self {
+            kind,
+            material_idx: shape.material_idx,
+            radius,
+            _padding: 0_u32,
+            origin,
+            _padding1: 0_f32,
+            axis1: axes[0],
+            _padding2: 0_f32,
+            axis2: axes[1],
+            _padding3: 0_f32,
+            axis3: axes[2],
+            _padding4: 0_f32,
+        }
+    }
+}
+
+/// Two unit vectors which make a right-handed frame with the unit vector `n`, from Duff et al.
+/// 2017, "Building an Orthonormal Basis, Revisited". The shader's `pixarOnb` is the same.
+fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let s = if n.z >= 0_f32 { 1_f32 } else { -1_f32 };
+
+    let a = -1_f32 / (s + n.z);
+
+    let b = n.x * n.y * a;
+
+    (
+        glm::vec3(1_f32 + s * n.x * n.x * a, s * b, -s * n.x),
+        glm::vec3(b, s + n.y * n.y * a, -n.y),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shape_bounds() {
+        let plane = Shape::new(
+            Geometry::Plane {
+                point: glm::vec3(0_f32, -1_f32, 0_f32),
+                normal: glm::vec3(0_f32, 2_f32, 0_f32),
+            },
+            0,
+        );
+        let bounds = plane.aabb();
+        assert_eq!((bounds.min.y, bounds.max.y), (-1_f32, -1_f32));
+        assert_eq!(bounds.max.x, PLANE_EXTENT);
+
+        let cylinder = Shape::new(
+            Geometry::Cylinder {
+                base: glm::vec3(0_f32, 0_f32, 0_f32),
+                top: glm::vec3(0_f32, 2_f32, 0_f32),
+                radius: 0.5_f32,
+            },
+            0,
+        );
+        let bounds = cylinder.aabb();
+        assert_eq!(bounds.min, glm::vec3(-0.5_f32, 0_f32, -0.5_f32));
+        assert_eq!(bounds.max, glm::vec3(0.5_f32, 2_f32, 0.5_f32));
+
+        let cube = Shape::new(
+            Geometry::Box {
+                center: Vec3::zeros(),
+                half_size: glm::vec3(1_f32, 1_f32, 1_f32),
+                rotation: glm::quat_angle_axis(std::f32::consts::FRAC_PI_4, &Vec3::y()),
+            },
+            0,
+        );
+        let bounds = cube.aabb();
+        assert!((bounds.max.x - std::f32::consts::SQRT_2).abs() < 1e-5_f32);
+        assert!((bounds.max.y - 1_f32).abs() < 1e-5_f32);
+    }
+
+    #[test]
+    fn test_only_quads_and_disks_are_sampled_as_lights() {
+        let geometries = [
+            Geometry::Plane {
+                point: Vec3::zeros(),
+                normal: Vec3::y(),
+            },
+            Geometry::Quad {
+                corner: Vec3::zeros(),
+                u: Vec3::x(),
+                v: Vec3::z(),
+            },
+            Geometry::Box {
+                center: Vec3::zeros(),
+                half_size: glm::vec3(1_f32, 1_f32, 1_f32),
+                rotation: Quat::identity(),
+            },
+            Geometry::Disk {
+                center: Vec3::zeros(),
+                normal: Vec3::y(),
+                radius: 1_f32,
+            },
+            Geometry::Cylinder {
+                base: Vec3::zeros(),
+                top: Vec3::y(),
+                radius: 1_f32,
+            },
+        ];
+
+        // The shader's light pdf is zero for every kind but quads (1) and disks (3), which must
+        // be exactly the shapes in the light list.
+        for geometry in geometries {
+            let shape = Shape::new(geometry, 0);
+            let kind = GpuShape::new(&shape).kind;
+            assert_eq!(shape.is_sampled_as_light(), kind == 1 || kind == 3);
+        }
+    }
+
+    #[test]
+    fn test_gpu_frames_are_right_handed() {
+        let shapes = [
+            Geometry::Disk {
+                center: Vec3::zeros(),
+                normal: glm::vec3(0_f32, 0_f32, -3_f32),
+                radius: 1_f32,
+            },
+            Geometry::Cylinder {
+                base: Vec3::zeros(),
+                top: glm::vec3(1_f32, 1_f32, 0_f32),
+                radius: 1_f32,
+            },
+        ];
+
+        for geometry in shapes {
+            let gpu_shape = GpuShape::new(&Shape::new(geometry, 0));
+            let (tangent_cross, normal) = match geometry {
+                Geometry::Disk { .. } => (
+                    glm::cross(&gpu_shape.axis1, &gpu_shape.axis2),
+                    gpu_shape.axis3,
+                ),
+                _ => (
+                    glm::cross(&gpu_shape.axis2, &gpu_shape.axis3),
+                    glm::normalize(&gpu_shape.axis1),
+                ),
+            };
+            assert!(glm::distance(&tangent_cross, &normal) < 1e-5_f32);
+        }
+    }
+}
@@ -2,13 +2,111 @@ use imgui::TextureId;
 
 use thiserror::Error;
 
-use image::{GenericImageView, ImageBuffer, Rgb, RgbaImage};
+use std::path::PathBuf;
+
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb};
+
+use super::color_space::{mul, srgb_to_linear, ColorSpace, WorkingSpace};
 
 pub type XImageBuffer = ImageBuffer<Rgb<u8>, Vec<u8>>;
 
+#[derive(Clone)]
+
 pub struct Texture {
     dimensions: (u32, u32),
     data: Vec<[f32; 3]>,
+    /// The alpha channel of images which have one, kept for alpha cutouts.
+    #[allow(dead_code)]
+    alpha: Option<Vec<f32>>,
+    sampler: TextureSampler,
+    /// Whether the source had more than 8 bits per channel, in which case the texture is kept in
+    /// 16-bit floats on the GPU.
+    high_precision: bool,
+    /// The pattern of a procedural texture, whose texels are the two colors it blends between.
+    procedural: Option<Procedural>,
+}
+
+/// How a texture is read between and outside of its texels, and from afar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+
+pub struct TextureSampler {
+    /// `Clamp` by default, which is how textures were always read.
+    pub wrap: WrapMode,
+    pub filter: FilterMode,
+    /// Whether a mip chain is generated, which is read from when the texture is seen from afar
+    /// so that it doesn't alias.
+    pub mipmaps: bool,
+}
+
+impl Default for TextureSampler {
+    fn default() -> Self {
+        Self {
+            wrap: WrapMode::Clamp,
+            filter: FilterMode::Bilinear,
+            mipmaps: true,
+        }
+    }
+}
+
+/// What is read for texture coordinates outside of 0..=1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    /// Repeats the texture, flipping every other repetition.
+    Mirror,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+
+pub enum FilterMode {
+    Nearest,
+    /// Interpolates between the four closest texels, and between the two closest mip levels.
+    Bilinear,
+}
+
+/// A pattern which is evaluated wherever the texture is looked up, rather than read from texels.
+/// Its value blends from the texture's first color to its second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+
+pub struct Procedural {
+    pub pattern: Pattern,
+    pub space: PatternSpace,
+    /// The frequency of the pattern, such as the number of checker squares per unit.
+    pub scale: f32,
+    /// The number of noise octaves summed by every pattern except `Noise` and `Checker`.
+    pub octaves: u32,
+    /// How strongly noise displaces the veins of `Marble` and the rings of `Wood`.
+    pub distortion: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+
+pub enum Pattern {
+    /// Smooth gradient noise.
+    Noise,
+    /// Fractal Brownian motion, octaves of noise at doubling frequencies and halving amplitudes.
+    Fbm,
+    /// Like `Fbm`, but summing the absolute value of each octave, which gives creases.
+    Turbulence,
+    /// Veins along the x axis, displaced by turbulence.
+    Marble,
+    /// Rings around the y axis, displaced by fBm.
+    Wood,
+    /// Alternating unit cubes, or squares in UV space.
+    Checker,
+}
+
+/// The coordinates a procedural pattern is evaluated at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+
+pub enum PatternSpace {
+    /// The texture coordinates, so that the pattern follows the surface.
+    Uv,
+    /// The position of the hit, so that the pattern is carved out of a solid.
+    World,
 }
 
 pub struct WgpuTexture {
@@ -18,31 +116,96 @@ pub struct WgpuTexture {
 }
 
 impl Texture {
-    pub fn new_from_image(path: &str) -> Result<Self, TextureError> {
-        use std::fs::*;
-        use std::io::BufReader;
+    /// Loads an image of any format the `image` crate supports, detected from the file's contents
+    /// or else its extension. 8 and 16-bit values are decoded according to `color_space`, floating
+    /// point values, such as those of Radiance `.hdr` and `OpenEXR` files, are always linear and
+    /// are not clamped. The result is still in Rec.709, see `to_working_space`.
+    pub fn new_from_image(
+        path: impl AsRef<std::path::Path>,
+        color_space: ColorSpace,
+    ) -> Result<Self, TextureError> {
+        let path = path.as_ref();
+
+        let image = image::io::Reader::open(path)
+            .and_then(image::io::Reader::with_guessed_format)
+            .map_err(|source| TextureError::Io {
+                path: path.to_owned(),
+                source,
+            })?
+            .decode()
+            .map_err(|source| TextureError::Decode {
+                path: path.to_owned(),
+                source,
+            })?;
+
+        Ok(Self::new_from_dynamic_image(image, color_space))
+    }
 
-        let file = File::open(path)?;
+    /// Decodes an image file which is already in memory, such as one embedded in a glTF file.
+    /// The format is detected from the contents, see `new_from_image`.
+    pub fn new_from_memory(
+        bytes: &[u8],
+        color_space: ColorSpace,
+    ) -> Result<Self, TextureError> {
+        let image = image::load_from_memory(bytes)?;
+
+        Ok(Self::new_from_dynamic_image(image, color_space))
+    }
+
+    fn new_from_dynamic_image(
+        image: DynamicImage,
+        color_space: ColorSpace,
+    ) -> Self {
+        let has_alpha = image.color().has_alpha();
+
+        let high_precision = image.color().bytes_per_pixel() > image.color().channel_count();
+
+        let is_float = matches!(
+            image,
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+        );
 
-        let pixels: RgbaImage =
-            image::load(BufReader::new(file), image::ImageFormat::Jpeg)?.into_rgba8();
+        let decode = |x: f32| match color_space {
+            ColorSpace::Srgb if !is_float => srgb_to_linear(x),
+            _ => x,
+        };
 
-        let inv_255 = 1_f32 / 255_f32;
+        // Integer values are normalized to 0..=1, float values are kept as they are.
+        let pixels = image.into_rgba32f();
 
         let dimensions = pixels.dimensions();
 
-        let data: Vec<_> = pixels
+        let data = pixels
             .pixels()
-            .map(|p| -> [f32; 3] {
-                [
-                    inv_255 * (p[0] as f32),
-                    inv_255 * (p[1] as f32),
-                    inv_255 * (p[2] as f32),
-                ]
-            })
+            .map(|p| [decode(p[0]), decode(p[1]), decode(p[2])])
             .collect();
 
-        Ok(Self { dimensions, data })
+        let alpha = has_alpha.then(|| pixels.pixels().map(|p| p[3]).collect());
+
+        Self {
+            dimensions,
+            data,
+            alpha,
+            sampler: TextureSampler::default(),
+            high_precision,
+            procedural: None,
+        }
+    }
+
+    pub fn new_from_data(
+        dimensions: (u32, u32),
+        data: Vec<[f32; 3]>,
+    ) -> Self {
+        assert_eq!(data.len(), (dimensions.0 * dimensions.1) as usize);
+
+        Self {
+            dimensions,
+            data,
+            alpha: None,
+            sampler: TextureSampler::default(),
+            high_precision: true,
+            procedural: None,
+        }
     }
 
     pub fn new_from_color(color: glm::Vec3) -> Self {
@@ -50,7 +213,133 @@ impl Texture {
 
         let dimensions = (1_u32, 1_u32);
 
-        Self { dimensions, data }
+        Self {
+            dimensions,
+            data,
+            alpha: None,
+            sampler: TextureSampler::default(),
+            high_precision: false,
+            procedural: None,
+        }
+    }
+
+    /// A procedural texture blending between `colors`, which are linear Rec.709 like the texels
+    /// of any other texture.
+    pub fn new_procedural(
+        procedural: Procedural,
+        colors: [glm::Vec3; 2],
+    ) -> Self {
+        let data = colors.map(|c| [c.x, c.y, c.z]).to_vec();
+
+        // The colors are read texel by texel, never filtered together.
+        let sampler = TextureSampler {
+            wrap: WrapMode::Clamp,
+            filter: FilterMode::Nearest,
+            mipmaps: false,
+        };
+
+        Self {
+            dimensions: (2_u32, 1_u32),
+            data,
+            alpha: None,
+            sampler,
+            high_precision: false,
+            procedural: Some(procedural),
+        }
+    }
+
+    pub fn with_sampler(
+        mut self,
+        sampler: TextureSampler,
+    ) -> Self {
+        self.sampler = sampler;
+
+        self
+    }
+
+    pub fn sampler(&self) -> &TextureSampler {
+        &self.sampler
+    }
+
+    pub fn procedural(&self) -> Option<&Procedural> {
+        self.procedural.as_ref()
+    }
+
+    /// Multiplies every texel by `factor`, such as the color factor of a glTF material.
+    pub fn scaled(
+        mut self,
+        factor: [f32; 3],
+    ) -> Self {
+        for texel in &mut self.data {
+            *texel = [0_usize, 1_usize, 2_usize].map(|c| texel[c] * factor[c]);
+        }
+
+        self
+    }
+
+    pub fn is_high_precision(&self) -> bool {
+        self.high_precision
+    }
+
+    /// Halves the texture until neither side is larger than `max_dimension`.
+    pub fn fit_within(
+        mut self,
+        max_dimension: u32,
+    ) -> Self {
+        while self.dimensions.0 > max_dimension || self.dimensions.1 > max_dimension {
+            let (width, height) = self.dimensions;
+
+            self.data = downsample(&self.data, width, height);
+
+            self.dimensions = ((width / 2).max(1), (height / 2).max(1));
+
+            // Nothing reads the alpha channel yet, it is dropped rather than downsampled.
+            self.alpha = None;
+        }
+
+        self
+    }
+
+    /// The texels of every mip level, starting with the texture itself. Each level halves the
+    /// size of the previous one, rounding down, until a single texel is left. Only the texture
+    /// itself is returned if it has no mipmaps.
+    pub fn mip_levels(&self) -> Vec<Vec<[f32; 3]>> {
+        let mut levels = vec![self.data.clone()];
+
+        if !self.sampler.mipmaps {
+            return levels;
+        }
+
+        let (mut width, mut height) = self.dimensions;
+
+        while width > 1 || height > 1 {
+            let previous = levels.last().expect("There is always the first level");
+
+            levels.push(downsample(previous, width, height));
+
+            width = (width / 2).max(1);
+
+            height = (height / 2).max(1);
+        }
+
+        levels
+    }
+
+    /// Converts the linear Rec.709 colors of a color texture into the working space. Data
+    /// textures must not be converted.
+    pub fn to_working_space(
+        mut self,
+        working_space: WorkingSpace,
+    ) -> Self {
+        if working_space != WorkingSpace::Rec709 {
+            let matrix = working_space.from_rec709();
+
+            for texel in &mut self.data {
+                *texel = mul(&matrix, *texel);
+            }
+        }
+
+        self
     }
 
     pub fn as_slice(&self) -> &[[f32; 3]] {
@@ -77,6 +366,43 @@ impl Texture {
     }
 }
 
+/// Box filters the texels down to half the size, rounding down, for the next mip level.
+pub fn downsample(
+    texels: &[[f32; 3]],
+    width: u32,
+    height: u32,
+) -> Vec<[f32; 3]> {
+    let next_width = (width / 2).max(1);
+
+    let next_height = (height / 2).max(1);
+
+    let mut level = Vec::with_capacity((next_width * next_height) as usize);
+
+    for y in 0..next_height {
+        for x in 0..next_width {
+            // The box filter of the 2x2 texels this texel covers, which are clamped for odd sizes
+            // and for a side that is already one texel wide.
+            let x0 = (2 * x).min(width - 1);
+
+            let x1 = (2 * x + 1).min(width - 1);
+
+            let y0 = (2 * y).min(height - 1);
+
+            let y1 = (2 * y + 1).min(height - 1);
+
+            let corners = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)]
+                .map(|(tx, ty)| texels[(ty * width + tx) as usize]);
+
+            level.push(
+                [0_usize, 1_usize, 2_usize]
+                    .map(|c| 0.25_f32 * corners.iter().map(|texel| texel[c]).sum::<f32>()),
+            );
+        }
+    }
+
+    level
+}
+
 impl WgpuTexture {
     pub fn new_imgui_texture<'a>(
         device: &wgpu::Device,
@@ -169,8 +495,111 @@ impl WgpuTexture {
 #[derive(Error, Debug)]
 
 pub enum TextureError {
-    #[error(transparent)]
-    FileIoError(#[from] std::io::Error),
+    #[error("{}: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{}: {source}", .path.display())]
+    Decode {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    /// An in-memory image failed to decode, there is no path to report.
     #[error(transparent)]
     ImageLoadError(#[from] image::ImageError),
+    #[error("the textures need more than the {0} atlas pages the device supports")]
+    AtlasFull(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb32FImage, Rgba};
+
+    /// A temporary directory for one test of this process, which parallel runs of the tests
+    /// don't write into.
+    fn test_dir(test_name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("texture_test_{}_{test_name}", std::process::id()));
+
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn test_load_16_bit_png_with_alpha() {
+        let path = test_dir("16_bit_png").join("texture.png");
+
+        ImageBuffer::<Rgba<u16>, _>::from_pixel(2, 1, Rgba([65535, 0, 32768, 16384]))
+            .save(&path)
+            .unwrap();
+
+        let texture = Texture::new_from_image(&path, ColorSpace::Linear).unwrap();
+
+        assert_eq!(texture.dimensions(), (2, 1));
+
+        assert_eq!(texture.as_slice()[0][0], 1_f32);
+
+        assert!((texture.as_slice()[0][2] - 0.5_f32).abs() < 1e-4_f32);
+
+        assert!((texture.alpha.as_ref().unwrap()[1] - 0.25_f32).abs() < 1e-4_f32);
+
+        assert!(texture.is_high_precision());
+    }
+
+    #[test]
+    fn test_float_image_is_not_clamped_or_decoded() {
+        // The extension says nothing, the format is detected from the contents.
+        let path = test_dir("float_image").join("texture.bin");
+
+        let mut file = std::fs::File::create(&path).unwrap();
+
+        Rgb32FImage::from_pixel(1, 1, Rgb([4_f32, 0.5_f32, 0_f32]))
+            .write_to(&mut file, image::ImageOutputFormat::OpenExr)
+            .unwrap();
+
+        let texture = Texture::new_from_image(&path, ColorSpace::Srgb).unwrap();
+
+        assert_eq!(texture.as_slice()[0], [4_f32, 0.5_f32, 0_f32]);
+
+        assert!(texture.alpha.is_none());
+    }
+
+    #[test]
+    fn test_mip_levels_average_down_to_one_texel() {
+        let row = [1_f32, 0_f32, 2_f32, 2_f32];
+
+        let texture =
+            Texture::new_from_data((4, 2), row.iter().chain(&row).map(|&v| [v; 3]).collect());
+
+        let levels = texture.mip_levels();
+
+        assert_eq!(
+            levels.iter().map(Vec::len).collect::<Vec<_>>(),
+            [8_usize, 2_usize, 1_usize]
+        );
+
+        assert_eq!(levels[1], [[0.5_f32; 3], [2_f32; 3]]);
+
+        assert_eq!(levels[2], [[1.25_f32; 3]]);
+
+        let sampler = TextureSampler {
+            mipmaps: false,
+            ..Default::default()
+        };
+
+        assert_eq!(texture.with_sampler(sampler).mip_levels().len(), 1_usize);
+    }
+
+    #[test]
+    fn test_missing_file_reports_path() {
+        let Err(err) = Texture::new_from_image("assets/missing.png", ColorSpace::Srgb) else {
+            panic!("expected an error");
+        };
+
+        assert!(err.to_string().starts_with("assets/missing.png: "), "{err}");
+    }
 }
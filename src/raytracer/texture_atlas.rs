@@ -0,0 +1,587 @@
+use super::{
+    color_space::linear_to_srgb,
+    texture::{downsample, FilterMode, Pattern, PatternSpace, Texture, TextureError, WrapMode},
+    TextureDescriptor,
+};
+
+/// Packs textures and their mip levels into the pages of two texture arrays: colors within
+/// 0..=1 are stored as 8-bit sRGB, anything brighter or more precise as 16-bit floats.
+///
+/// Each texture gets a rectangle with the full resolution image on the left and the smaller mip
+/// levels stacked on its right. Every level is surrounded by a one texel border, filled according
+/// to the texture's wrap mode, so that the hardware's bilinear filter reads the right texels at
+/// the edges.
+pub struct TextureAtlasBuilder {
+    page_size: u32,
+    max_pages: u32,
+    ldr: AtlasPages,
+    hdr: AtlasPages,
+}
+
+#[derive(Default)]
+struct AtlasPages {
+    placements: Vec<Placement>,
+    num_pages: u32,
+    /// The shelf the next rectangle is placed on.
+    cursor_x: u32,
+    cursor_y: u32,
+    shelf_height: u32,
+    /// The width and height of the area used on any page.
+    used_size: (u32, u32),
+}
+
+struct Placement {
+    page: u32,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    texels: Vec<[f32; 3]>,
+}
+
+impl TextureAtlasBuilder {
+    pub fn new(limits: &wgpu::Limits) -> Self {
+        Self {
+            page_size: limits.max_texture_dimension_2d,
+            max_pages: limits.max_texture_array_layers,
+            ldr: AtlasPages::default(),
+            hdr: AtlasPages::default(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        texture: &Texture,
+    ) -> Result<TextureDescriptor, TextureError> {
+        let mut levels = texture.mip_levels();
+
+        let mut dimensions = texture.dimensions();
+
+        // Textures which don't fit on a page lose their finest levels.
+        loop {
+            let (width, height) = rectangle_size(dimensions, levels.len());
+
+            if width <= self.page_size && height <= self.page_size {
+                break;
+            }
+
+            if levels.len() > 1 {
+                levels.remove(0);
+            } else {
+                levels[0] = downsample(&levels[0], dimensions.0, dimensions.1);
+            }
+
+            dimensions = mip_size(dimensions, 1);
+        }
+
+        let is_hdr = levels[0]
+            .iter()
+            .flatten()
+            .any(|c| !(0_f32..=1_f32).contains(c))
+            || texture.is_high_precision();
+
+        let (width, height) = rectangle_size(dimensions, levels.len());
+
+        let mut texels = vec![[0_f32; 3]; (width * height) as usize];
+
+        let sampler = texture.sampler();
+
+        for (level, data) in levels.iter().enumerate() {
+            let (level_width, level_height) = mip_size(dimensions, level as u32);
+
+            let (origin_x, origin_y) = level_origin(dimensions, level as u32);
+
+            // The border texels are copied from the texels they stand in for.
+            for y in -1..=level_height as i32 {
+                for x in -1..=level_width as i32 {
+                    let src_x = wrap(x, level_width, sampler.wrap);
+
+                    let src_y = wrap(y, level_height, sampler.wrap);
+
+                    let dst_x = (origin_x as i32 + x) as u32;
+
+                    let dst_y = (origin_y as i32 + y) as u32;
+
+                    texels[(dst_y * width + dst_x) as usize] =
+                        data[(src_y * level_width + src_x) as usize];
+                }
+            }
+        }
+
+        let (atlas, pages) = if is_hdr {
+            (1_u32, &mut self.hdr)
+        } else {
+            (0_u32, &mut self.ldr)
+        };
+
+        let (page, x, y) = pages.place(width, height, self.page_size);
+
+        if page >= self.max_pages {
+            return Err(TextureError::AtlasFull(self.max_pages));
+        }
+
+        pages.placements.push(Placement {
+            page,
+            x,
+            y,
+            width,
+            height,
+            texels,
+        });
+
+        let procedural = texture.procedural();
+
+        Ok(TextureDescriptor {
+            width: dimensions.0,
+            height: dimensions.1,
+            atlas,
+            page,
+            x,
+            y,
+            num_levels: levels.len() as u32,
+            wrap_mode: match sampler.wrap {
+                WrapMode::Repeat => 0_u32,
+                WrapMode::Clamp => 1_u32,
+                WrapMode::Mirror => 2_u32,
+            },
+            filter_mode: match sampler.filter {
+                FilterMode::Nearest => 0_u32,
+                FilterMode::Bilinear => 1_u32,
+            },
+            pattern: match procedural.map(|p| p.pattern) {
+                None => 0_u32,
+                Some(Pattern::Noise) => 1_u32,
+                Some(Pattern::Fbm) => 2_u32,
+                Some(Pattern::Turbulence) => 3_u32,
+                Some(Pattern::Marble) => 4_u32,
+                Some(Pattern::Wood) => 5_u32,
+                Some(Pattern::Checker) => 6_u32,
+            },
+            pattern_space: match procedural.map(|p| p.space) {
+                Some(PatternSpace::World) => 1_u32,
+                _ => 0_u32,
+            },
+            pattern_scale: procedural.map_or(0_f32, |p| p.scale),
+            octaves: procedural.map_or(0_u32, |p| p.octaves),
+            distortion: procedural.map_or(0_f32, |p| p.distortion),
+        })
+    }
+
+    /// Reads a texel of a texture's full resolution level on the CPU.
+    pub fn texel(
+        &self,
+        desc: &TextureDescriptor,
+        x: u32,
+        y: u32,
+    ) -> [f32; 3] {
+        let pages = if desc.atlas == 1_u32 {
+            &self.hdr
+        } else {
+            &self.ldr
+        };
+
+        let placement = pages
+            .placements
+            .iter()
+            .find(|p| p.page == desc.page && p.x == desc.x && p.y == desc.y)
+            .expect("The descriptor was returned by this builder");
+
+        placement.texels[((y + 1) * placement.width + x + 1) as usize]
+    }
+
+    /// Creates the texture arrays and uploads the placed textures.
+    pub fn build(
+        self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> TextureAtlas {
+        let ldr = self.ldr.upload(
+            device,
+            queue,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            |texel| {
+                let [r, g, b] = texel.map(|c| (linear_to_srgb(c) * 255_f32).round() as u8);
+
+                vec![r, g, b, u8::MAX]
+            },
+            Some("ldr texture atlas"),
+        );
+
+        let hdr = self.hdr.upload(
+            device,
+            queue,
+            wgpu::TextureFormat::Rgba16Float,
+            |texel| {
+                let [r, g, b, a] = [texel[0], texel[1], texel[2], 1_f32].map(f16_bits);
+
+                bytemuck::cast_slice(&[r, g, b, a]).to_vec()
+            },
+            Some("hdr texture atlas"),
+        );
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            label: Some("texture atlas sampler"),
+            ..Default::default()
+        });
+
+        TextureAtlas { ldr, hdr, sampler }
+    }
+}
+
+impl AtlasPages {
+    /// Places a rectangle on the current shelf, or on a new shelf or page if it doesn't fit.
+    fn place(
+        &mut self,
+        width: u32,
+        height: u32,
+        page_size: u32,
+    ) -> (u32, u32, u32) {
+        if self.num_pages == 0 {
+            self.num_pages = 1;
+        }
+
+        if self.cursor_x + width > page_size {
+            self.cursor_x = 0;
+
+            self.cursor_y += self.shelf_height;
+
+            self.shelf_height = 0;
+        }
+
+        if self.cursor_y + height > page_size {
+            self.num_pages += 1;
+
+            self.cursor_x = 0;
+
+            self.cursor_y = 0;
+
+            self.shelf_height = 0;
+        }
+
+        let position = (self.num_pages - 1, self.cursor_x, self.cursor_y);
+
+        self.cursor_x += width;
+
+        self.shelf_height = self.shelf_height.max(height);
+
+        self.used_size = (
+            self.used_size.0.max(self.cursor_x),
+            self.used_size.1.max(self.cursor_y + height),
+        );
+
+        position
+    }
+
+    fn upload(
+        self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        encode: impl Fn(&[f32; 3]) -> Vec<u8>,
+        label: Option<&str>,
+    ) -> wgpu::TextureView {
+        // wgpu does not allow empty textures, an unused atlas is a single texel.
+        let size = wgpu::Extent3d {
+            width: self.used_size.0.max(1_u32),
+            height: self.used_size.1.max(1_u32),
+            depth_or_array_layers: self.num_pages.max(1_u32),
+        };
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+            label,
+        });
+
+        let bytes_per_texel = format
+            .block_size(None)
+            .expect("The atlas formats are uncompressed color formats");
+
+        for placement in &self.placements {
+            let bytes: Vec<u8> = placement.texels.iter().flat_map(&encode).collect();
+
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: placement.x,
+                        y: placement.y,
+                        z: placement.page,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &bytes,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(placement.width * bytes_per_texel),
+                    rows_per_image: Some(placement.height),
+                },
+                wgpu::Extent3d {
+                    width: placement.width,
+                    height: placement.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        })
+    }
+}
+
+/// The scene's textures on the GPU, see `TextureAtlasBuilder`.
+pub struct TextureAtlas {
+    ldr: wgpu::TextureView,
+    hdr: wgpu::TextureView,
+    sampler: wgpu::Sampler,
+}
+
+impl TextureAtlas {
+    /// The layout entries of the 8-bit atlas, the sampler and the 16-bit atlas, in this order.
+    pub fn layouts(
+        bindings: [u32; 3],
+        visibility: wgpu::ShaderStages,
+    ) -> [wgpu::BindGroupLayoutEntry; 3] {
+        let texture_layout = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        [
+            texture_layout(bindings[0]),
+            wgpu::BindGroupLayoutEntry {
+                binding: bindings[1],
+                visibility,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            texture_layout(bindings[2]),
+        ]
+    }
+
+    pub fn bindings(
+        &self,
+        bindings: [u32; 3],
+    ) -> [wgpu::BindGroupEntry<'_>; 3] {
+        [
+            wgpu::BindGroupEntry {
+                binding: bindings[0],
+                resource: wgpu::BindingResource::TextureView(&self.ldr),
+            },
+            wgpu::BindGroupEntry {
+                binding: bindings[1],
+                resource: wgpu::BindingResource::Sampler(&self.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: bindings[2],
+                resource: wgpu::BindingResource::TextureView(&self.hdr),
+            },
+        ]
+    }
+}
+
+/// Must match `textureLevelSize` in raytracer.wgsl.
+fn mip_size(
+    dimensions: (u32, u32),
+    level: u32,
+) -> (u32, u32) {
+    (
+        (dimensions.0 >> level).max(1_u32),
+        (dimensions.1 >> level).max(1_u32),
+    )
+}
+
+/// The position of a level's first texel in the texture's rectangle, inside the border. Must
+/// match `textureLevelOrigin` in raytracer.wgsl.
+fn level_origin(
+    dimensions: (u32, u32),
+    level: u32,
+) -> (u32, u32) {
+    if level == 0 {
+        return (1_u32, 1_u32);
+    }
+
+    let y = (1..level)
+        .map(|idx| mip_size(dimensions, idx).1 + 2_u32)
+        .sum::<u32>();
+
+    (dimensions.0 + 3_u32, y + 1_u32)
+}
+
+fn rectangle_size(
+    dimensions: (u32, u32),
+    num_levels: usize,
+) -> (u32, u32) {
+    let (width, height) = (dimensions.0 + 2_u32, dimensions.1 + 2_u32);
+
+    if num_levels <= 1 {
+        return (width, height);
+    }
+
+    let column_height = (1..num_levels as u32)
+        .map(|level| mip_size(dimensions, level).1 + 2_u32)
+        .sum::<u32>();
+
+    (
+        width + mip_size(dimensions, 1).0 + 2_u32,
+        height.max(column_height),
+    )
+}
+
+/// Maps a texel coordinate outside of 0..size to the texel it stands in for. Must match
+/// `wrapTexelCoordinate` in raytracer.wgsl.
+fn wrap(
+    x: i32,
+    size: u32,
+    wrap_mode: WrapMode,
+) -> u32 {
+    let size = size as i32;
+
+    let x = match wrap_mode {
+        WrapMode::Repeat => x.rem_euclid(size),
+        WrapMode::Clamp => x.clamp(0, size - 1),
+        WrapMode::Mirror => {
+            let m = x.rem_euclid(2 * size);
+
+            if m >= size {
+                2 * size - 1 - m
+            } else {
+                m
+            }
+        }
+    };
+
+    x as u32
+}
+
+/// Converts to the bits of the closest half precision float. Values beyond the half range are
+/// clamped to the largest finite half, and NaNs become zero.
+pub fn f16_bits(value: f32) -> u16 {
+    let value = if value.is_nan() {
+        0_f32
+    } else {
+        value.clamp(-65504_f32, 65504_f32)
+    };
+
+    let bits = value.to_bits();
+
+    let sign = ((bits >> 16) & 0x8000) as u16;
+
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+
+    let mantissa = bits & 0x007f_ffff;
+
+    if exponent <= 0 {
+        // Subnormal halves, or zero if the value is too small.
+        if exponent < -10 {
+            return sign;
+        }
+
+        let mantissa = mantissa | 0x0080_0000;
+
+        let shift = (14 - exponent) as u32;
+
+        let round = (mantissa >> (shift - 1)) & 1;
+
+        return sign | ((mantissa >> shift) + round) as u16;
+    }
+
+    // A carry from rounding correctly moves into the exponent.
+    let round = (mantissa >> 12) & 1;
+
+    sign | (((exponent as u32) << 10 | mantissa >> 13) + round) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f16_bits() {
+        assert_eq!(f16_bits(0_f32), 0x0000);
+
+        assert_eq!(f16_bits(1_f32), 0x3c00);
+
+        assert_eq!(f16_bits(-2_f32), 0xc000);
+
+        assert_eq!(f16_bits(0.5_f32), 0x3800);
+
+        assert_eq!(f16_bits(65504_f32), 0x7bff);
+
+        assert_eq!(f16_bits(1e9_f32), 0x7bff);
+
+        assert_eq!(f16_bits(2_f32.powi(-24)), 0x0001);
+
+        assert_eq!(f16_bits(f32::NAN), 0x0000);
+    }
+
+    #[test]
+    fn test_levels_and_borders_fit_in_the_rectangle() {
+        let dimensions = (5_u32, 3_u32);
+
+        let (width, height) = rectangle_size(dimensions, 3);
+
+        for level in 0..3_u32 {
+            let origin = level_origin(dimensions, level);
+
+            let size = mip_size(dimensions, level);
+
+            assert!(origin.0 >= 1 && origin.1 >= 1);
+
+            assert!(origin.0 + size.0 + 1 <= width, "level {level}");
+
+            assert!(origin.1 + size.1 + 1 <= height, "level {level}");
+        }
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap(-1, 4, WrapMode::Repeat), 3);
+
+        assert_eq!(wrap(4, 4, WrapMode::Repeat), 0);
+
+        assert_eq!(wrap(-1, 4, WrapMode::Clamp), 0);
+
+        assert_eq!(wrap(4, 4, WrapMode::Mirror), 3);
+
+        assert_eq!(wrap(-1, 4, WrapMode::Mirror), 0);
+    }
+
+    #[test]
+    fn test_full_atlas_is_an_error() {
+        let mut builder = TextureAtlasBuilder::new(&wgpu::Limits {
+            max_texture_dimension_2d: 4_u32,
+            max_texture_array_layers: 1_u32,
+            ..wgpu::Limits::default()
+        });
+
+        let texture = Texture::new_from_color(glm::vec3(1_f32, 0_f32, 0_f32));
+
+        assert!(builder.add(&texture).is_ok());
+
+        assert!(matches!(
+            builder.add(&texture),
+            Err(TextureError::AtlasFull(1_u32))
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+use super::color_space::mul;
+
+/// How the accumulated radiance is turned into displayable colors. Only affects the display, so
+/// changing it keeps the accumulated samples.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+
+pub struct DisplayParams {
+    pub tonemapper: Tonemapper,
+    // Exposure in stops, the radiance is scaled by 2^exposure_ev before tonemapping. With automatic
+    // exposure, this is the compensation added on top of the metered exposure.
+    pub exposure_ev: f32,
+    // The radiance which is mapped to white by Reinhard and Uncharted2, must be positive
+    pub white_point: f32,
+    pub auto_exposure: AutoExposureParams,
+}
+
+impl Default for DisplayParams {
+    fn default() -> Self {
+        Self {
+            tonemapper: Tonemapper::AcesFitted,
+            exposure_ev: 0_f32,
+            white_point: 11.2_f32,
+            auto_exposure: AutoExposureParams::default(),
+        }
+    }
+}
+
+/// Exposes the image so that its metered luminance maps to middle gray, measured from a
+/// histogram of the accumulated image every frame.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+
+pub struct AutoExposureParams {
+    pub enabled: bool,
+    pub metering: Metering,
+    // The fraction of pixels darker than the metered luminance with Metering::Percentile, must be
+    // between 0..=1
+    pub percentile: f32,
+    // How quickly the exposure follows the metered luminance, in 1/s
+    pub adaptation_rate: f32,
+}
+
+impl Default for AutoExposureParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            metering: Metering::Average,
+            percentile: 0.5_f32,
+            adaptation_rate: 2_f32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+
+pub enum Metering {
+    /// The average log luminance, the geometric mean of the luminance.
+    Average,
+    /// The luminance below which `percentile` of the pixels are.
+    Percentile,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+
+pub enum Tonemapper {
+    /// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms.
+    AcesFitted,
+    /// Reinhard's operator on the luminance, extended with a white point.
+    ReinhardExtended,
+    /// Troy Sobotka's AgX, with the polynomial approximation of its default contrast curve.
+    Agx,
+    /// John Hable's filmic curve from Uncharted 2.
+    Uncharted2,
+    /// Clamps the exposed radiance to 0..=1.
+    LinearClamp,
+}
+
+impl Tonemapper {
+    pub const ALL: [Tonemapper; 5] = [
+        Tonemapper::AcesFitted,
+        Tonemapper::ReinhardExtended,
+        Tonemapper::Agx,
+        Tonemapper::Uncharted2,
+        Tonemapper::LinearClamp,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tonemapper::AcesFitted => "ACES fitted",
+            Tonemapper::ReinhardExtended => "Reinhard extended",
+            Tonemapper::Agx => "AgX",
+            Tonemapper::Uncharted2 => "Uncharted2",
+            Tonemapper::LinearClamp => "Linear clamp",
+        }
+    }
+
+    /// Must match the switch in display.wgsl.
+    pub fn id(self) -> u32 {
+        match self {
+            Tonemapper::AcesFitted => 0_u32,
+            Tonemapper::ReinhardExtended => 1_u32,
+            Tonemapper::Agx => 2_u32,
+            Tonemapper::Uncharted2 => 3_u32,
+            Tonemapper::LinearClamp => 4_u32,
+        }
+    }
+}
+
+/// Mirrors `tonemap` in display.wgsl, so that saved images look like the window. Returns linear
+/// colors in 0..=1.
+pub fn tonemap(
+    params: &DisplayParams,
+    radiance: [f32; 3],
+) -> [f32; 3] {
+    let exposure = params.exposure_ev.exp2();
+
+    let x = radiance.map(|c| (c * exposure).max(0_f32));
+
+    let w = params.white_point;
+
+    let color = match params.tonemapper {
+        Tonemapper::AcesFitted => aces_fitted(x),
+        Tonemapper::ReinhardExtended => reinhard_extended(x, w),
+        Tonemapper::Agx => agx(x),
+        Tonemapper::Uncharted2 => {
+            let white_scale = 1_f32 / uncharted2_curve(w);
+
+            x.map(|c| uncharted2_curve(c) * white_scale)
+        }
+        Tonemapper::LinearClamp => x,
+    };
+
+    color.map(|c| c.clamp(0_f32, 1_f32))
+}
+
+fn aces_fitted(x: [f32; 3]) -> [f32; 3] {
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719_f32, 0.35458_f32, 0.04823_f32],
+        [0.07600_f32, 0.90834_f32, 0.01566_f32],
+        [0.02840_f32, 0.13383_f32, 0.83777_f32],
+    ];
+
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475_f32, -0.53108_f32, -0.07367_f32],
+        [-0.10208_f32, 1.10813_f32, -0.00605_f32],
+        [-0.00327_f32, -0.07276_f32, 1.07602_f32],
+    ];
+
+    let v = mul(&INPUT, x).map(|v| {
+        let a = v * (v + 0.024_578_6_f32) - 0.000_090_537_f32;
+
+        let b = v * (0.983_729_f32 * v + 0.432_951_f32) + 0.238_081_f32;
+
+        a / b
+    });
+
+    mul(&OUTPUT, v)
+}
+
+fn reinhard_extended(
+    x: [f32; 3],
+    white_point: f32,
+) -> [f32; 3] {
+    let luminance = 0.2126_f32 * x[0] + 0.7152_f32 * x[1] + 0.0722_f32 * x[2];
+
+    if luminance <= 0_f32 {
+        return [0_f32; 3];
+    }
+
+    let mapped =
+        luminance * (1_f32 + luminance / (white_point * white_point)) / (1_f32 + luminance);
+
+    x.map(|c| c * mapped / luminance)
+}
+
+fn agx(x: [f32; 3]) -> [f32; 3] {
+    const INSET: [[f32; 3]; 3] = [
+        [0.842_479_06_f32, 0.078_433_6_f32, 0.079_223_745_f32],
+        [0.042_328_242_f32, 0.878_468_6_f32, 0.079_166_13_f32],
+        [0.042_375_655_f32, 0.078_433_6_f32, 0.879_142_97_f32],
+    ];
+
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196_879_f32, -0.098_020_88_f32, -0.099_029_74_f32],
+        [-0.052_896_85_f32, 1.151_903_1_f32, -0.098_961_18_f32],
+        [-0.052_971_635_f32, -0.098_043_45_f32, 1.151_073_7_f32],
+    ];
+
+    const MIN_EV: f32 = -12.473_93_f32;
+
+    const MAX_EV: f32 = 4.026_069_f32;
+
+    let encoded = mul(&INSET, x).map(|v| {
+        let v = (v.max(1e-10_f32).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+
+        let v2 = v * v;
+
+        let v4 = v2 * v2;
+
+        15.5_f32 * v4 * v2 - 40.14_f32 * v4 * v + 31.96_f32 * v4 - 6.868_f32 * v2 * v
+            + 0.4298_f32 * v2
+            + 0.1191_f32 * v
+            - 0.002_32_f32
+    });
+
+    // The curve's output is display encoded, decode it back to linear.
+    mul(&OUTSET, encoded).map(|v| v.max(0_f32).powf(2.2_f32))
+}
+
+fn uncharted2_curve(x: f32) -> f32 {
+    let a = 0.15_f32;
+
+    let b = 0.50_f32;
+
+    let c = 0.10_f32;
+
+    let d = 0.20_f32;
+
+    let e = 0.02_f32;
+
+    let f = 0.30_f32;
+
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_white_point_maps_to_white() {
+        for tonemapper in [Tonemapper::ReinhardExtended, Tonemapper::Uncharted2] {
+            let params = DisplayParams {
+                tonemapper,
+                exposure_ev: 0_f32,
+                white_point: 4_f32,
+                ..Default::default()
+            };
+
+            let white = tonemap(&params, [4_f32; 3]);
+
+            assert!(
+                white.iter().all(|c| (c - 1_f32).abs() < 1e-4_f32),
+                "{tonemapper:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tonemappers_are_monotonic_and_bounded() {
+        for tonemapper in Tonemapper::ALL {
+            let params = DisplayParams {
+                tonemapper,
+                ..Default::default()
+            };
+
+            let mut previous = 0_f32;
+
+            for stop in -10..=10 {
+                let gray = tonemap(&params, [(stop as f32).exp2(); 3])[1];
+
+                assert!((0_f32..=1_f32).contains(&gray), "{tonemapper:?}");
+
+                assert!(gray >= previous - 1e-4_f32, "{tonemapper:?} at {stop}");
+
+                previous = gray;
+            }
+
+            // A stop of exposure brightens the image like twice the radiance.
+            let brighter = DisplayParams {
+                exposure_ev: 1_f32,
+                ..params
+            };
+
+            assert_eq!(
+                tonemap(&brighter, [0.1_f32; 3]),
+                tonemap(&params, [0.2_f32; 3])
+            );
+        }
+    }
+}
@@ -0,0 +1,1741 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    fly_camera::{CameraMotion, FlyCameraController},
+    gltf_import::{self, GltfImportError},
+    obj_import::{self, ObjImportError},
+    raytracer::{
+        Angle, ColorSpace, DisplayParams, Geometry, Instance, InstancedObject, Material, NormalMap,
+        Pattern, PatternSpace, Procedural, RenderParams, RenderParamsValidationError,
+        SamplingParams, Scene, Shape, SkyMode, SkyParams, Sphere, Texture, TextureError,
+        TextureSampler, Transform, WorkingSpace,
+    },
+};
+
+/// The scene file version understood by this build. Bump it whenever the format changes in a
+/// way that old files can no longer be read.
+pub const SCENE_FILE_VERSION: u32 = 1;
+
+/// Everything a scene file describes: the geometry and materials, and the initial values of the
+/// parameters which can later be tweaked in the UI.
+pub struct LoadedScene {
+    pub scene: Scene,
+    pub camera: FlyCameraController,
+    pub sky: SkyParams,
+    pub sampling: SamplingParams,
+    pub display: DisplayParams,
+}
+
+/// Loads a RON scene file, or a glTF file when the extension is `.gltf` or `.glb`. Relative
+/// texture paths are resolved against the directory the scene file is in.
+pub fn load(path: impl AsRef<Path>) -> Result<LoadedScene, SceneFileError> {
+    let path = path.as_ref();
+
+    let is_gltf = path.extension().map_or(false, |ext| {
+        ext.eq_ignore_ascii_case("gltf") || ext.eq_ignore_ascii_case("glb")
+    });
+
+    if is_gltf {
+        return load_gltf(path);
+    }
+
+    let source = std::fs::read_to_string(path).map_err(|source| SceneFileError::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    parse(&source, base_dir).map_err(|err| err.with_path(path))
+}
+
+/// Parses the contents of a scene file. Errors are reported without a file path, `load` fills it
+/// in.
+pub fn parse(
+    source: &str,
+    base_dir: &Path,
+) -> Result<LoadedScene, SceneFileError> {
+    // Check the version before anything else, so that files written for a newer format get a
+    // useful error instead of a complaint about some unknown field.
+    let header: SceneFileHeader = ron::de::from_str(source).map_err(SceneFileError::from_ron)?;
+
+    if header.version != SCENE_FILE_VERSION {
+        return Err(SceneFileError::UnsupportedVersion {
+            path: PathBuf::new(),
+            found: header.version,
+            expected: SCENE_FILE_VERSION,
+        });
+    }
+
+    let file: SceneFile = ron::de::from_str(source).map_err(SceneFileError::from_ron)?;
+
+    let mut materials = Vec::with_capacity(file.materials.len());
+
+    for (idx, material) in file.materials.iter().enumerate() {
+        materials.push(material.load(base_dir, &format!("materials[{idx}]"))?);
+    }
+
+    let mut spheres = Vec::with_capacity(file.spheres.len());
+
+    for (idx, sphere) in file.spheres.iter().enumerate() {
+        if sphere.radius <= 0_f32 {
+            return Err(SceneFileError::invalid_value(
+                format!("spheres[{idx}].radius"),
+                format!("radius must be greater than zero, got {}", sphere.radius),
+            ));
+        }
+
+        if sphere.material as usize >= materials.len() {
+            return Err(SceneFileError::invalid_value(
+                format!("spheres[{idx}].material"),
+                format!(
+                    "material index {} is out of range, the scene has {} materials",
+                    sphere.material,
+                    materials.len()
+                ),
+            ));
+        }
+
+        let center = glm::make_vec3(&sphere.center);
+
+        spheres.push(Sphere::new_moving(
+            center,
+            sphere.end_center.map_or(center, |end| glm::make_vec3(&end)),
+            sphere.radius,
+            sphere.material,
+        ));
+    }
+
+    let mut shapes = Vec::with_capacity(file.shapes.len());
+
+    for (idx, shape) in file.shapes.iter().enumerate() {
+        let shape = shape.load(&format!("shapes[{idx}]"))?;
+
+        if shape.material_idx as usize >= materials.len() {
+            return Err(SceneFileError::invalid_value(
+                format!("shapes[{idx}].material"),
+                format!(
+                    "material index {} is out of range, the scene has {} materials",
+                    shape.material_idx,
+                    materials.len()
+                ),
+            ));
+        }
+
+        shapes.push(shape);
+    }
+
+    let num_scene_materials = materials.len();
+
+    let mut meshes = Vec::new();
+
+    // The meshes each entry was loaded into, an OBJ file has one per material.
+    let mut mesh_ranges = Vec::with_capacity(file.meshes.len());
+
+    for (idx, mesh) in file.meshes.iter().enumerate() {
+        if let Some(material) = mesh.material {
+            if material as usize >= num_scene_materials {
+                return Err(SceneFileError::invalid_value(
+                    format!("meshes[{idx}].material"),
+                    format!(
+                        "material index {material} is out of range, the scene has {num_scene_materials} materials"
+                    ),
+                ));
+            }
+        }
+
+        let mesh_path = base_dir.join(&mesh.path);
+
+        let mut loaded = obj_import::load_obj(&mesh_path, mesh.material, &mut materials)
+            .map_err(|source| SceneFileError::Mesh {
+                path: PathBuf::new(),
+                field: format!("meshes[{idx}]"),
+                source,
+            })?;
+
+        let first_mesh = meshes.len() as u32;
+
+        meshes.append(&mut loaded);
+
+        mesh_ranges.push(first_mesh..meshes.len() as u32);
+    }
+
+    let mut instances = Vec::with_capacity(file.instances.len());
+
+    for (idx, instance) in file.instances.iter().enumerate() {
+        let object = match instance.object {
+            ObjectDesc::Sphere(sphere) if (sphere as usize) < spheres.len() => {
+                InstancedObject::Sphere(sphere)
+            }
+            ObjectDesc::Shape(shape) if (shape as usize) < shapes.len() => {
+                InstancedObject::Shape(shape)
+            }
+            ObjectDesc::Mesh(mesh) if (mesh as usize) < mesh_ranges.len() => {
+                InstancedObject::Meshes(mesh_ranges[mesh as usize].clone())
+            }
+            ObjectDesc::Sphere(sphere) => {
+                return Err(SceneFileError::invalid_value(
+                    format!("instances[{idx}].object"),
+                    format!(
+                        "sphere index {sphere} is out of range, the scene has {} spheres",
+                        spheres.len()
+                    ),
+                ));
+            }
+            ObjectDesc::Shape(shape) => {
+                return Err(SceneFileError::invalid_value(
+                    format!("instances[{idx}].object"),
+                    format!(
+                        "shape index {shape} is out of range, the scene has {} shapes",
+                        shapes.len()
+                    ),
+                ));
+            }
+            ObjectDesc::Mesh(mesh) => {
+                return Err(SceneFileError::invalid_value(
+                    format!("instances[{idx}].object"),
+                    format!(
+                        "mesh index {mesh} is out of range, the scene has {} meshes",
+                        mesh_ranges.len()
+                    ),
+                ));
+            }
+        };
+
+        let start = instance.start_transform();
+
+        let static_instance = Instance::new(object.clone(), start.matrix()).ok_or_else(|| {
+            SceneFileError::invalid_value(
+                format!("instances[{idx}].scale"),
+                "must not be zero along any axis".to_owned(),
+            )
+        })?;
+
+        let Some(end) = instance.end_transform() else {
+            instances.push(static_instance);
+
+            continue;
+        };
+
+        let moving_instance = Instance::new_moving(object, &start, &end).ok_or_else(|| {
+            SceneFileError::invalid_value(
+                format!("instances[{idx}].end_scale"),
+                "must not be zero along any axis, or have another sign than scale".to_owned(),
+            )
+        })?;
+
+        instances.push(moving_instance);
+    }
+
+    let environment_map = match &file.environment_map {
+        Some(path) => {
+            let texture = Texture::new_from_image(base_dir.join(path), ColorSpace::Linear)
+                .map_err(|source| SceneFileError::Texture {
+                    path: PathBuf::new(),
+                    field: "environment_map".to_owned(),
+                    source,
+                })?;
+
+            Some(texture.to_working_space(file.working_space))
+        }
+        None => None,
+    };
+
+    let camera = file.camera.into_controller();
+
+    // The scene file has no viewport, the window or the command line sets its size.
+    let render_params = RenderParams {
+        camera: camera.renderer_camera(),
+        sky: file.sky,
+        sampling: file.sampling,
+        display: file.display,
+        viewport_size: (1_u32, 1_u32),
+    };
+
+    render_params
+        .validate(environment_map.is_some())
+        .map_err(|err| {
+            SceneFileError::invalid_value(invalid_field(&err).to_owned(), err.to_string())
+        })?;
+
+    // The materials from the meshes are included, they were appended while loading them.
+    let materials = materials
+        .into_iter()
+        .map(|material| material.to_working_space(file.working_space))
+        .collect();
+
+    Ok(LoadedScene {
+        scene: Scene {
+            spheres,
+            meshes,
+            shapes,
+            instances,
+            materials,
+            environment_map,
+            working_space: file.working_space,
+        },
+        camera,
+        sky: file.sky,
+        sampling: file.sampling,
+        display: file.display,
+    })
+}
+
+/// The scene file field holding the value `RenderParams::validate` rejected.
+fn invalid_field(err: &RenderParamsValidationError) -> &'static str {
+    match err {
+        RenderParamsValidationError::MaxSampleCountNotMultiple(_, 0_u32) => {
+            "sampling.num_samples_per_pixel"
+        }
+        RenderParamsValidationError::MaxSampleCountNotMultiple(..) => {
+            "sampling.max_samples_per_pixel"
+        }
+        RenderParamsValidationError::ViewportSize(..) => "viewport_size",
+        RenderParamsValidationError::VfovOutOfRange(_) => "camera.vfov_degrees",
+        RenderParamsValidationError::ApertureOutOfRange(_) => "camera.aperture",
+        RenderParamsValidationError::FocusDistanceOutOfRange(_) => "camera.focus_distance",
+        RenderParamsValidationError::ShutterOutOfRange(open, _)
+            if !(0_f32..=1_f32).contains(open) =>
+        {
+            "camera.shutter_open"
+        }
+        RenderParamsValidationError::ShutterOutOfRange(..) => "camera.shutter_close",
+        RenderParamsValidationError::HwSkyModelValidationError(err) => match err {
+            hw_skymodel::rgb::Error::ElevationOutOfRange(_) => "sky.zenith_degrees",
+            hw_skymodel::rgb::Error::TurbidityOutOfRange(_) => "sky.turbidity",
+            hw_skymodel::rgb::Error::AlbedoOutOfRange(_) => "sky.albedo",
+        },
+        RenderParamsValidationError::MissingEnvironmentMap => "sky.mode",
+        RenderParamsValidationError::FogDensityOutOfRange(_) => "sky.fog.density",
+        RenderParamsValidationError::FogHeightFalloffOutOfRange(_) => "sky.fog.height_falloff",
+        RenderParamsValidationError::FogAnisotropyOutOfRange(_) => "sky.fog.anisotropy",
+        RenderParamsValidationError::WhitePointOutOfRange(_) => "display.white_point",
+        RenderParamsValidationError::PercentileOutOfRange(_) => "display.auto_exposure.percentile",
+    }
+}
+
+/// A glTF file has no sampling or display settings, those keep their defaults. The sun is placed
+/// in the direction of the file's first directional light.
+fn load_gltf(path: &Path) -> Result<LoadedScene, SceneFileError> {
+    let gltf = gltf_import::load_gltf(path).map_err(|source| SceneFileError::Gltf {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    let mut sky = SkyParams::default();
+
+    if let Some(sun) = gltf.sun_direction {
+        sky.azimuth_degrees = sun.z.atan2(sun.x).to_degrees().rem_euclid(360_f32);
+
+        // The sky model has no sun below the horizon.
+        sky.zenith_degrees = sun.y.clamp(-1_f32, 1_f32).acos().to_degrees().min(90_f32);
+    }
+
+    let working_space = WorkingSpace::default();
+
+    let materials = gltf
+        .materials
+        .into_iter()
+        .map(|material| material.to_working_space(working_space))
+        .collect();
+
+    Ok(LoadedScene {
+        scene: Scene {
+            spheres: gltf.spheres,
+            meshes: gltf.meshes,
+            shapes: Vec::new(),
+            instances: gltf.instances,
+            materials,
+            environment_map: None,
+            working_space,
+        },
+        camera: gltf.camera,
+        sky,
+        sampling: SamplingParams::default(),
+        display: DisplayParams::default(),
+    })
+}
+
+#[derive(Error, Debug)]
+
+pub enum SceneFileError {
+    #[error("{}: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{}:{line}:{column}: {message}", .path.display())]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("{}: unsupported scene file version {found}, expected {expected}", .path.display())]
+    UnsupportedVersion {
+        path: PathBuf,
+        found: u32,
+        expected: u32,
+    },
+    #[error("{}: `{field}`: {message}", .path.display())]
+    InvalidValue {
+        path: PathBuf,
+        field: String,
+        message: String,
+    },
+    #[error("{}: `{field}`: failed to load texture {source}", .path.display())]
+    Texture {
+        path: PathBuf,
+        field: String,
+        source: TextureError,
+    },
+    #[error("{}: `{field}`: {source}", .path.display())]
+    Mesh {
+        path: PathBuf,
+        field: String,
+        source: ObjImportError,
+    },
+    #[error("{}: {source}", .path.display())]
+    Gltf {
+        path: PathBuf,
+        source: GltfImportError,
+    },
+}
+
+impl SceneFileError {
+    fn from_ron(err: ron::error::SpannedError) -> Self {
+        Self::Parse {
+            path: PathBuf::new(),
+            line: err.position.line,
+            column: err.position.col,
+            message: err.code.to_string(),
+        }
+    }
+
+    fn invalid_value(
+        field: String,
+        message: String,
+    ) -> Self {
+        Self::InvalidValue {
+            path: PathBuf::new(),
+            field,
+            message,
+        }
+    }
+
+    fn with_path(
+        mut self,
+        file_path: &Path,
+    ) -> Self {
+        match &mut self {
+            Self::Io { path, .. }
+            | Self::Parse { path, .. }
+            | Self::UnsupportedVersion { path, .. }
+            | Self::InvalidValue { path, .. }
+            | Self::Texture { path, .. }
+            | Self::Mesh { path, .. }
+            | Self::Gltf { path, .. } => *path = file_path.to_owned(),
+        }
+
+        self
+    }
+}
+
+#[derive(Deserialize)]
+
+struct SceneFileHeader {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Scene", deny_unknown_fields)]
+
+struct SceneFile {
+    #[allow(dead_code)]
+    version: u32,
+    #[serde(default)]
+    camera: CameraDesc,
+    #[serde(default)]
+    sky: SkyParams,
+    #[serde(default)]
+    sampling: SamplingParams,
+    #[serde(default)]
+    display: DisplayParams,
+    #[serde(default)]
+    materials: Vec<MaterialDesc>,
+    #[serde(default)]
+    spheres: Vec<SphereDesc>,
+    #[serde(default)]
+    shapes: Vec<ShapeDesc>,
+    #[serde(default)]
+    meshes: Vec<MeshDesc>,
+    #[serde(default)]
+    instances: Vec<InstanceDesc>,
+    /// An equirectangular `.hdr` or `.exr` image.
+    #[serde(default)]
+    environment_map: Option<PathBuf>,
+    /// The colors above are given in linear Rec.709, and converted to this space for rendering.
+    #[serde(default)]
+    working_space: WorkingSpace,
+}
+
+/// The start pose and lens of the fly camera. A camera which gives any of the `end_` values
+/// moves from its pose at time 0 to the end pose at time 1, the others default to the start.
+#[derive(Deserialize)]
+#[serde(rename = "Camera", default, deny_unknown_fields)]
+
+struct CameraDesc {
+    position: [f32; 3],
+    yaw_degrees: f32,
+    pitch_degrees: f32,
+    vfov_degrees: f32,
+    aperture: f32,
+    focus_distance: f32,
+    end_position: Option<[f32; 3]>,
+    end_yaw_degrees: Option<f32>,
+    end_pitch_degrees: Option<f32>,
+    /// The part of the time from 0 to 1 during which the shutter is open.
+    shutter_open: f32,
+    shutter_close: f32,
+}
+
+impl Default for CameraDesc {
+    fn default() -> Self {
+        let controller = FlyCameraController::default();
+
+        Self {
+            position: controller.position.into(),
+            yaw_degrees: controller.yaw.as_degrees(),
+            pitch_degrees: controller.pitch.as_degrees(),
+            vfov_degrees: controller.vfov_degrees,
+            aperture: controller.aperture,
+            focus_distance: controller.focus_distance,
+            end_position: None,
+            end_yaw_degrees: None,
+            end_pitch_degrees: None,
+            shutter_open: controller.shutter_open,
+            shutter_close: controller.shutter_close,
+        }
+    }
+}
+
+impl CameraDesc {
+    fn into_controller(self) -> FlyCameraController {
+        let is_moving = self.end_position.is_some()
+            || self.end_yaw_degrees.is_some()
+            || self.end_pitch_degrees.is_some();
+
+        let motion = is_moving.then(|| CameraMotion {
+            translation: glm::make_vec3(&self.end_position.unwrap_or(self.position))
+                - glm::make_vec3(&self.position),
+            yaw: Angle::degrees(
+                self.end_yaw_degrees.unwrap_or(self.yaw_degrees) - self.yaw_degrees,
+            ),
+            pitch: Angle::degrees(
+                self.end_pitch_degrees.unwrap_or(self.pitch_degrees) - self.pitch_degrees,
+            ),
+        });
+
+        FlyCameraController {
+            position: glm::make_vec3(&self.position),
+            yaw: Angle::degrees(self.yaw_degrees),
+            pitch: Angle::degrees(self.pitch_degrees),
+            vfov_degrees: self.vfov_degrees,
+            aperture: self.aperture,
+            focus_distance: self.focus_distance,
+            motion,
+            shutter_open: self.shutter_open,
+            shutter_close: self.shutter_close,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Texture")]
+
+enum TextureDesc {
+    /// A linear Rec.709 color.
+    Color([f32; 3]),
+    /// An image with the default sampler. Color images are sRGB encoded, images of other values
+    /// such as roughness are loaded as they are.
+    Image(PathBuf),
+    /// An image in the given color space, with the default sampler.
+    ImageWithColorSpace(PathBuf, ColorSpace),
+    ImageFile {
+        path: PathBuf,
+        /// Defaults to `Srgb` for color images and to `Data` for the others.
+        #[serde(default, deserialize_with = "deserialize_some")]
+        color_space: Option<ColorSpace>,
+        #[serde(default)]
+        sampler: TextureSampler,
+    },
+    /// A pattern evaluated where the texture is looked up, blending from the first of the linear
+    /// Rec.709 `colors` to the second.
+    Procedural {
+        pattern: Pattern,
+        colors: ([f32; 3], [f32; 3]),
+        #[serde(default = "default_pattern_space")]
+        space: PatternSpace,
+        #[serde(default = "default_pattern_scale")]
+        scale: f32,
+        /// Between 1 and 8.
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default = "default_distortion")]
+        distortion: f32,
+    },
+}
+
+fn default_pattern_space() -> PatternSpace {
+    PatternSpace::World
+}
+
+fn default_pattern_scale() -> f32 {
+    1_f32
+}
+
+fn default_octaves() -> u32 {
+    5_u32
+}
+
+fn default_distortion() -> f32 {
+    1_f32
+}
+
+impl TextureDesc {
+    fn load(
+        &self,
+        base_dir: &Path,
+        field: &str,
+    ) -> Result<Texture, SceneFileError> {
+        match self {
+            TextureDesc::Color(color) => Ok(Texture::new_from_color(glm::make_vec3(color))),
+            TextureDesc::Image(path) => load_image(base_dir, field, path, ColorSpace::Srgb),
+            TextureDesc::ImageWithColorSpace(path, color_space) => {
+                TextureDesc::image_file(path, *color_space).load(base_dir, field)
+            }
+            TextureDesc::ImageFile {
+                path,
+                color_space,
+                sampler,
+            } => {
+                // Color textures are converted to the working space, which data can't be.
+                if *color_space == Some(ColorSpace::Data) {
+                    return Err(SceneFileError::invalid_value(
+                        field.to_owned(),
+                        "Data is only for non-color textures, use Linear or Srgb".to_owned(),
+                    ));
+                }
+
+                let color_space = color_space.unwrap_or(ColorSpace::Srgb);
+
+                Ok(load_image(base_dir, field, path, color_space)?.with_sampler(*sampler))
+            }
+            TextureDesc::Procedural {
+                pattern,
+                colors,
+                space,
+                scale,
+                octaves,
+                distortion,
+            } => procedural_texture(
+                field,
+                *pattern,
+                colors,
+                *space,
+                *scale,
+                *octaves,
+                *distortion,
+            ),
+        }
+    }
+
+    /// Loads a texture of values other than colors, which default to being stored as they are.
+    fn load_data(
+        &self,
+        base_dir: &Path,
+        field: &str,
+    ) -> Result<Texture, SceneFileError> {
+        match self {
+            TextureDesc::Color(values) => Ok(Texture::new_from_color(glm::make_vec3(values))),
+            TextureDesc::Image(path) => load_image(base_dir, field, path, ColorSpace::Data),
+            TextureDesc::ImageWithColorSpace(path, color_space) => {
+                TextureDesc::image_file(path, *color_space).load_data(base_dir, field)
+            }
+            TextureDesc::ImageFile {
+                path,
+                color_space,
+                sampler,
+            } => {
+                let color_space = color_space.unwrap_or(ColorSpace::Data);
+
+                Ok(load_image(base_dir, field, path, color_space)?.with_sampler(*sampler))
+            }
+            // Procedural values aren't encoded, data is loaded like colors.
+            TextureDesc::Procedural { .. } => self.load(base_dir, field),
+        }
+    }
+
+    /// The `ImageFile` which `ImageWithColorSpace` is the short form of.
+    fn image_file(
+        path: &Path,
+        color_space: ColorSpace,
+    ) -> TextureDesc {
+        TextureDesc::ImageFile {
+            path: path.to_owned(),
+            color_space: Some(color_space),
+            sampler: TextureSampler::default(),
+        }
+    }
+}
+
+fn procedural_texture(
+    field: &str,
+    pattern: Pattern,
+    colors: &([f32; 3], [f32; 3]),
+    space: PatternSpace,
+    scale: f32,
+    octaves: u32,
+    distortion: f32,
+) -> Result<Texture, SceneFileError> {
+    if scale <= 0_f32 {
+        return Err(SceneFileError::invalid_value(
+            format!("{field}.scale"),
+            format!("must be greater than zero, got {scale}"),
+        ));
+    }
+
+    if !(1_u32..=8_u32).contains(&octaves) {
+        return Err(SceneFileError::invalid_value(
+            format!("{field}.octaves"),
+            format!("must be between 1 and 8, got {octaves}"),
+        ));
+    }
+
+    let procedural = Procedural {
+        pattern,
+        space,
+        scale,
+        octaves,
+        distortion,
+    };
+
+    Ok(Texture::new_procedural(
+        procedural,
+        [glm::make_vec3(&colors.0), glm::make_vec3(&colors.1)],
+    ))
+}
+
+/// Reads an optional field without RON's `Some(...)`, a missing field is `None`.
+fn deserialize_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+fn load_image(
+    base_dir: &Path,
+    field: &str,
+    path: &Path,
+    color_space: ColorSpace,
+) -> Result<Texture, SceneFileError> {
+    Texture::new_from_image(base_dir.join(path), color_space).map_err(|source| {
+        SceneFileError::Texture {
+            path: PathBuf::new(),
+            field: field.to_owned(),
+            source,
+        }
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Material", deny_unknown_fields)]
+
+enum MaterialDesc {
+    Lambertian {
+        albedo: TextureDesc,
+        #[serde(default)]
+        normal_map: Option<NormalMapDesc>,
+    },
+    Metal {
+        albedo: TextureDesc,
+        fuzz: f32,
+        #[serde(default)]
+        normal_map: Option<NormalMapDesc>,
+    },
+    Dielectric {
+        refraction_index: f32,
+        /// The color of white light after `attenuation_distance`, the glass is clear without it.
+        #[serde(default)]
+        attenuation_color: Option<TextureDesc>,
+        #[serde(default = "default_attenuation_distance")]
+        attenuation_distance: f32,
+        #[serde(default)]
+        abbe_number: Option<f32>,
+        #[serde(default)]
+        normal_map: Option<NormalMapDesc>,
+    },
+    Checkerboard {
+        even: TextureDesc,
+        odd: TextureDesc,
+        #[serde(default)]
+        normal_map: Option<NormalMapDesc>,
+    },
+    Emissive {
+        radiance: TextureDesc,
+        strength: f32,
+    },
+    Principled {
+        base_color: TextureDesc,
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_roughness")]
+        roughness: f32,
+        /// Metallic in the blue channel and roughness in the green channel, as in glTF.
+        #[serde(default)]
+        metallic_roughness: Option<TextureDesc>,
+        #[serde(default = "default_ior")]
+        ior: f32,
+        #[serde(default)]
+        emission: Option<TextureDesc>,
+        #[serde(default)]
+        normal_map: Option<NormalMapDesc>,
+    },
+    /// Fills the primitives which use it with a medium. Their boundary is invisible without a
+    /// `refraction_index`.
+    Volume {
+        density: f32,
+        albedo: TextureDesc,
+        #[serde(default)]
+        anisotropy: f32,
+        #[serde(default)]
+        refraction_index: Option<f32>,
+    },
+}
+
+fn default_roughness() -> f32 {
+    0.5_f32
+}
+
+fn default_ior() -> f32 {
+    1.5_f32
+}
+
+fn default_attenuation_distance() -> f32 {
+    1_f32
+}
+
+fn default_normal_scale() -> f32 {
+    1_f32
+}
+
+impl MaterialDesc {
+    fn load(
+        &self,
+        base_dir: &Path,
+        field: &str,
+    ) -> Result<Material, SceneFileError> {
+        let material = match self {
+            MaterialDesc::Lambertian { albedo, normal_map } => Material::Lambertian {
+                albedo: albedo.load(base_dir, &format!("{field}.albedo"))?,
+                normal_map: NormalMapDesc::load_optional(normal_map, base_dir, field)?,
+            },
+            MaterialDesc::Metal {
+                albedo,
+                fuzz,
+                normal_map,
+            } => Material::Metal {
+                albedo: albedo.load(base_dir, &format!("{field}.albedo"))?,
+                fuzz: *fuzz,
+                normal_map: NormalMapDesc::load_optional(normal_map, base_dir, field)?,
+            },
+            MaterialDesc::Dielectric {
+                refraction_index,
+                attenuation_color,
+                attenuation_distance,
+                abbe_number,
+                normal_map,
+            } => {
+                if *attenuation_distance <= 0_f32 {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.attenuation_distance"),
+                        format!("must be greater than zero, got {attenuation_distance}"),
+                    ));
+                }
+
+                if let Some(abbe_number) = abbe_number.filter(|abbe| *abbe <= 0_f32) {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.abbe_number"),
+                        format!("must be greater than zero, got {abbe_number}"),
+                    ));
+                }
+
+                let attenuation_color = match attenuation_color {
+                    Some(texture) => {
+                        Some(texture.load(base_dir, &format!("{field}.attenuation_color"))?)
+                    }
+                    None => None,
+                };
+
+                Material::Dielectric {
+                    refraction_index: *refraction_index,
+                    attenuation_color,
+                    attenuation_distance: *attenuation_distance,
+                    abbe_number: *abbe_number,
+                    normal_map: NormalMapDesc::load_optional(normal_map, base_dir, field)?,
+                }
+            }
+            MaterialDesc::Checkerboard {
+                even,
+                odd,
+                normal_map,
+            } => Material::Checkerboard {
+                even: even.load(base_dir, &format!("{field}.even"))?,
+                odd: odd.load(base_dir, &format!("{field}.odd"))?,
+                normal_map: NormalMapDesc::load_optional(normal_map, base_dir, field)?,
+            },
+            MaterialDesc::Emissive { radiance, strength } => {
+                if *strength < 0_f32 {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.strength"),
+                        format!("must not be negative, got {strength}"),
+                    ));
+                }
+
+                Material::Emissive {
+                    radiance: radiance.load(base_dir, &format!("{field}.radiance"))?,
+                    strength: *strength,
+                }
+            }
+            MaterialDesc::Principled {
+                base_color,
+                metallic,
+                roughness,
+                metallic_roughness,
+                ior,
+                emission,
+                normal_map,
+            } => {
+                for (name, value) in [("metallic", metallic), ("roughness", roughness)] {
+                    if !(0_f32..=1_f32).contains(value) {
+                        return Err(SceneFileError::invalid_value(
+                            format!("{field}.{name}"),
+                            format!("must be between 0..=1, got {value}"),
+                        ));
+                    }
+                }
+
+                if *ior < 1_f32 {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.ior"),
+                        format!("must be at least 1, got {ior}"),
+                    ));
+                }
+
+                let metallic_roughness = match metallic_roughness {
+                    Some(texture) => {
+                        Some(texture.load_data(base_dir, &format!("{field}.metallic_roughness"))?)
+                    }
+                    None => None,
+                };
+
+                let emission = match emission {
+                    Some(texture) => Some(texture.load(base_dir, &format!("{field}.emission"))?),
+                    None => None,
+                };
+
+                Material::Principled {
+                    base_color: base_color.load(base_dir, &format!("{field}.base_color"))?,
+                    metallic: *metallic,
+                    roughness: *roughness,
+                    metallic_roughness,
+                    ior: *ior,
+                    emission,
+                    normal_map: NormalMapDesc::load_optional(normal_map, base_dir, field)?,
+                }
+            }
+            MaterialDesc::Volume {
+                density,
+                albedo,
+                anisotropy,
+                refraction_index,
+            } => {
+                if *density < 0_f32 {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.density"),
+                        format!("must be at least zero, got {density}"),
+                    ));
+                }
+
+                if anisotropy.abs() >= 1_f32 {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.anisotropy"),
+                        format!("must be between -1..1, got {anisotropy}"),
+                    ));
+                }
+
+                if let Some(refraction_index) = refraction_index.filter(|ior| *ior < 1_f32) {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.refraction_index"),
+                        format!("must be at least 1, got {refraction_index}"),
+                    ));
+                }
+
+                Material::Volume {
+                    density: *density,
+                    albedo: albedo.load(base_dir, &format!("{field}.albedo"))?,
+                    anisotropy: *anisotropy,
+                    refraction_index: *refraction_index,
+                }
+            }
+        };
+
+        Ok(material)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "NormalMap", deny_unknown_fields)]
+
+enum NormalMapDesc {
+    /// A tangent-space normal map, with the green channel pointing towards increasing v.
+    Normal {
+        texture: TextureDesc,
+        #[serde(default = "default_normal_scale")]
+        scale: f32,
+    },
+    /// A height map, `height` is the displacement of a white texel in scene units.
+    Bump { texture: TextureDesc, height: f32 },
+}
+
+impl NormalMapDesc {
+    /// Loads the `normal_map` field of the material at `field`.
+    fn load_optional(
+        desc: &Option<NormalMapDesc>,
+        base_dir: &Path,
+        field: &str,
+    ) -> Result<Option<NormalMap>, SceneFileError> {
+        let field = format!("{field}.normal_map");
+
+        let normal_map = match desc {
+            Some(NormalMapDesc::Normal { texture, scale }) => NormalMap::Normal {
+                texture: texture.load_data(base_dir, &format!("{field}.texture"))?,
+                scale: *scale,
+            },
+            Some(NormalMapDesc::Bump { texture, height }) => NormalMap::Bump {
+                texture: texture.load_data(base_dir, &format!("{field}.texture"))?,
+                height: *height,
+            },
+            None => return Ok(None),
+        };
+
+        Ok(Some(normal_map))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Sphere", deny_unknown_fields)]
+
+struct SphereDesc {
+    center: [f32; 3],
+    radius: f32,
+    material: u32,
+    /// Where the center has moved to at time 1, in a straight line.
+    #[serde(default)]
+    end_center: Option<[f32; 3]>,
+}
+
+/// A plane, quad, box, disk or cylinder, intersected analytically like the spheres.
+#[derive(Deserialize)]
+#[serde(rename = "Shape", deny_unknown_fields)]
+
+enum ShapeDesc {
+    Plane {
+        point: [f32; 3],
+        normal: [f32; 3],
+        material: u32,
+    },
+    /// The parallelogram spanned by the edges `u` and `v` from `corner`, facing `u × v`.
+    Quad {
+        corner: [f32; 3],
+        u: [f32; 3],
+        v: [f32; 3],
+        material: u32,
+    },
+    /// The box from `min` to `max`, rotated about its center like the instances are.
+    Box {
+        min: [f32; 3],
+        max: [f32; 3],
+        #[serde(default)]
+        rotation_degrees: [f32; 3],
+        material: u32,
+    },
+    Disk {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+        material: u32,
+    },
+    /// A cylinder from the center of its `base` to the center of its `top`, closed at both ends.
+    Cylinder {
+        base: [f32; 3],
+        top: [f32; 3],
+        radius: f32,
+        material: u32,
+    },
+}
+
+impl ShapeDesc {
+    fn load(
+        &self,
+        field: &str,
+    ) -> Result<Shape, SceneFileError> {
+        let nonzero = |name: &str, vector: glm::Vec3| {
+            if vector == glm::Vec3::zeros() {
+                return Err(SceneFileError::invalid_value(
+                    format!("{field}.{name}"),
+                    "must not be zero".to_owned(),
+                ));
+            }
+
+            Ok(vector)
+        };
+
+        let positive = |name: &str, value: f32| {
+            if value <= 0_f32 {
+                return Err(SceneFileError::invalid_value(
+                    format!("{field}.{name}"),
+                    format!("must be greater than zero, got {value}"),
+                ));
+            }
+
+            Ok(value)
+        };
+
+        let (geometry, material) = match self {
+            ShapeDesc::Plane {
+                point,
+                normal,
+                material,
+            } => (
+                Geometry::Plane {
+                    point: glm::make_vec3(point),
+                    normal: nonzero("normal", glm::make_vec3(normal))?,
+                },
+                material,
+            ),
+            ShapeDesc::Quad {
+                corner,
+                u,
+                v,
+                material,
+            } => {
+                let (u, v) = (glm::make_vec3(u), glm::make_vec3(v));
+
+                nonzero("v", glm::cross(&u, &v)).map_err(|_| {
+                    SceneFileError::invalid_value(
+                        format!("{field}.v"),
+                        "must not be zero or parallel to u".to_owned(),
+                    )
+                })?;
+
+                (
+                    Geometry::Quad {
+                        corner: glm::make_vec3(corner),
+                        u,
+                        v,
+                    },
+                    material,
+                )
+            }
+            ShapeDesc::Box {
+                min,
+                max,
+                rotation_degrees,
+                material,
+            } => {
+                let (min, max) = (glm::make_vec3(min), glm::make_vec3(max));
+
+                if (0..3).any(|axis| min[axis] >= max[axis]) {
+                    return Err(SceneFileError::invalid_value(
+                        format!("{field}.max"),
+                        "must be greater than min along every axis".to_owned(),
+                    ));
+                }
+
+                (
+                    Geometry::Box {
+                        center: 0.5_f32 * (min + max),
+                        half_size: 0.5_f32 * (max - min),
+                        rotation: rotation_from_degrees(*rotation_degrees),
+                    },
+                    material,
+                )
+            }
+            ShapeDesc::Disk {
+                center,
+                normal,
+                radius,
+                material,
+            } => (
+                Geometry::Disk {
+                    center: glm::make_vec3(center),
+                    normal: nonzero("normal", glm::make_vec3(normal))?,
+                    radius: positive("radius", *radius)?,
+                },
+                material,
+            ),
+            ShapeDesc::Cylinder {
+                base,
+                top,
+                radius,
+                material,
+            } => {
+                let base = glm::make_vec3(base);
+
+                let top = glm::make_vec3(top);
+
+                nonzero("top", top - base).map_err(|_| {
+                    SceneFileError::invalid_value(
+                        format!("{field}.top"),
+                        "must not be the same as base".to_owned(),
+                    )
+                })?;
+
+                (
+                    Geometry::Cylinder {
+                        base,
+                        top,
+                        radius: positive("radius", *radius)?,
+                    },
+                    material,
+                )
+            }
+        };
+
+        Ok(Shape::new(geometry, *material))
+    }
+}
+
+/// A Wavefront OBJ file. The materials of its MTL file are used unless `material` is given.
+#[derive(Deserialize)]
+#[serde(rename = "Mesh", deny_unknown_fields)]
+
+struct MeshDesc {
+    path: PathBuf,
+    #[serde(default)]
+    material: Option<u32>,
+}
+
+/// A copy of a sphere or of a mesh entry, which is scaled, then rotated about the x, y and z axes
+/// in that order, then translated. The sphere or mesh is only drawn where its instances place it.
+/// An instance which gives any of the `end_` values moves from its transform at time 0 to the end
+/// transform at time 1, the others default to the start.
+#[derive(Deserialize)]
+#[serde(rename = "Instance", deny_unknown_fields)]
+
+struct InstanceDesc {
+    object: ObjectDesc,
+    #[serde(default)]
+    translation: [f32; 3],
+    #[serde(default)]
+    rotation_degrees: [f32; 3],
+    #[serde(default = "default_instance_scale")]
+    scale: [f32; 3],
+    #[serde(default)]
+    end_translation: Option<[f32; 3]>,
+    #[serde(default)]
+    end_rotation_degrees: Option<[f32; 3]>,
+    #[serde(default)]
+    end_scale: Option<[f32; 3]>,
+}
+
+fn default_instance_scale() -> [f32; 3] {
+    [1_f32; 3]
+}
+
+impl InstanceDesc {
+    fn start_transform(&self) -> Transform {
+        instance_transform(self.translation, self.rotation_degrees, self.scale)
+    }
+
+    fn end_transform(&self) -> Option<Transform> {
+        if self.end_translation.is_none()
+            && self.end_rotation_degrees.is_none()
+            && self.end_scale.is_none()
+        {
+            return None;
+        }
+
+        Some(instance_transform(
+            self.end_translation.unwrap_or(self.translation),
+            self.end_rotation_degrees.unwrap_or(self.rotation_degrees),
+            self.end_scale.unwrap_or(self.scale),
+        ))
+    }
+}
+
+fn instance_transform(
+    translation: [f32; 3],
+    rotation_degrees: [f32; 3],
+    scale: [f32; 3],
+) -> Transform {
+    Transform {
+        translation: glm::make_vec3(&translation),
+        rotation: rotation_from_degrees(rotation_degrees),
+        scale: glm::make_vec3(&scale),
+    }
+}
+
+/// Rotates about the x, y and z axes in that order.
+fn rotation_from_degrees(rotation_degrees: [f32; 3]) -> glm::Quat {
+    let [x, y, z] = rotation_degrees.map(f32::to_radians);
+
+    glm::quat_angle_axis(z, &glm::Vec3::z())
+        * glm::quat_angle_axis(y, &glm::Vec3::y())
+        * glm::quat_angle_axis(x, &glm::Vec3::x())
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Object")]
+
+enum ObjectDesc {
+    /// An index into the scene's spheres.
+    Sphere(u32),
+    /// An index into the scene's shapes.
+    Shape(u32),
+    /// An index into the scene's meshes, all the meshes the file was loaded into.
+    Mesh(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL_SCENE: &str = r#"(
+        version: 1,
+        materials: [
+            Lambertian(albedo: Color((0.5, 0.5, 0.5))),
+        ],
+        spheres: [
+            (center: (0.0, 1.0, 0.0), radius: 1.0, material: 0),
+        ],
+    )"#;
+
+    /// Parses `source` and checks that it is rejected for the value of `field`.
+    fn assert_invalid_field(
+        source: &str,
+        field: &str,
+    ) {
+        match parse(source, Path::new("")) {
+            Err(SceneFileError::InvalidValue { field: invalid, .. }) => assert_eq!(invalid, field),
+            Err(err) => panic!("expected an invalid value error for `{field}`, got {err}"),
+            Ok(_) => panic!("expected an invalid value error for `{field}`"),
+        }
+    }
+
+    #[test]
+    fn test_parse_minimal_scene() {
+        let loaded = parse(MINIMAL_SCENE, Path::new("")).unwrap();
+        assert_eq!(loaded.scene.spheres.len(), 1);
+        assert_eq!(loaded.scene.materials.len(), 1);
+        assert!(loaded.sampling == SamplingParams::default());
+    }
+
+    #[test]
+    fn test_all_scenes_load() {
+        let mut num_scenes = 0;
+        for entry in std::fs::read_dir("assets/scenes").unwrap() {
+            let path = entry.unwrap().path();
+            if let Err(err) = load(&path) {
+                panic!("{err}");
+            }
+            num_scenes += 1;
+        }
+        assert!(num_scenes > 0);
+    }
+
+    #[test]
+    fn test_load_mesh_scene() {
+        let loaded = load("assets/scenes/mesh.ron").unwrap();
+        // The cube's MTL material is appended after the scene's own materials.
+        assert_eq!(loaded.scene.meshes[0].material_idx, 2);
+    }
+
+    #[test]
+    fn test_load_principled_scene() {
+        let loaded = load("assets/scenes/principled.ron").unwrap();
+        assert!(loaded
+            .scene
+            .materials
+            .iter()
+            .all(|material| matches!(material, Material::Principled { .. })));
+    }
+
+    #[test]
+    fn test_load_normal_map_scene() {
+        let loaded = load("assets/scenes/normal_maps.ron").unwrap();
+        let materials = &loaded.scene.materials;
+        assert!(materials[0].normal_map().is_none());
+        assert!(matches!(
+            materials[1].normal_map(),
+            Some(NormalMap::Bump { height, .. }) if *height == 0.02_f32
+        ));
+        assert!(matches!(
+            materials[2].normal_map(),
+            Some(NormalMap::Normal { scale, .. }) if *scale == 1_f32
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_reports_line_and_field() {
+        let source = MINIMAL_SCENE.replace("radius: 1.0", "radios: 1.0");
+        match parse(&source, Path::new("")) {
+            Err(SceneFileError::Parse { line, message, .. }) => {
+                assert_eq!(line, 7);
+                assert!(message.contains("radios"), "{message}");
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let source = MINIMAL_SCENE.replace("version: 1", "version: 99");
+        assert!(matches!(
+            parse(&source, Path::new("")),
+            Err(SceneFileError::UnsupportedVersion { found: 99, .. })
+        ));
+    }
+
+    #[test]
+    fn test_negative_emission_strength() {
+        let source = MINIMAL_SCENE.replace(
+            "Lambertian(albedo: Color((0.5, 0.5, 0.5)))",
+            "Emissive(radiance: Color((1.0, 1.0, 1.0)), strength: -1.0)",
+        );
+        assert_invalid_field(&source, "materials[0].strength");
+    }
+
+    #[test]
+    fn test_data_color_space_for_color_texture() {
+        let source = MINIMAL_SCENE.replace(
+            "Color((0.5, 0.5, 0.5))",
+            "ImageWithColorSpace(\"roughness.png\", Data)",
+        );
+        assert_invalid_field(&source, "materials[0].albedo");
+
+        let source = MINIMAL_SCENE.replace(
+            "Color((0.5, 0.5, 0.5))",
+            "ImageFile(path: \"roughness.png\", color_space: Data)",
+        );
+        assert_invalid_field(&source, "materials[0].albedo");
+    }
+
+    #[test]
+    fn test_principled_defaults_and_range() {
+        let source = MINIMAL_SCENE.replace(
+            "Lambertian(albedo: Color((0.5, 0.5, 0.5)))",
+            "Principled(base_color: Color((0.5, 0.5, 0.5)), metallic: 1.0)",
+        );
+        let loaded = parse(&source, Path::new("")).unwrap();
+        match &loaded.scene.materials[0] {
+            Material::Principled {
+                metallic,
+                roughness,
+                ior,
+                emission,
+                ..
+            } => {
+                assert_eq!((*metallic, *roughness, *ior), (1_f32, 0.5_f32, 1.5_f32));
+                assert!(emission.is_none());
+            }
+            _ => panic!("expected a principled material"),
+        }
+
+        let source = source.replace("metallic: 1.0", "roughness: 1.5");
+        assert_invalid_field(&source, "materials[0].roughness");
+    }
+
+    #[test]
+    fn test_load_glass_scene() {
+        let loaded = load("assets/scenes/glass.ron").unwrap();
+        let materials = &loaded.scene.materials;
+        assert!(matches!(
+            &materials[2],
+            Material::Dielectric {
+                attenuation_color: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            materials[3],
+            Material::Dielectric { abbe_number: Some(abbe_number), .. } if abbe_number == 10_f32
+        ));
+    }
+
+    #[test]
+    fn test_dielectric_defaults_and_range() {
+        let source = MINIMAL_SCENE.replace(
+            "Lambertian(albedo: Color((0.5, 0.5, 0.5)))",
+            "Dielectric(refraction_index: 1.5, abbe_number: Some(40.0))",
+        );
+        let loaded = parse(&source, Path::new("")).unwrap();
+        match &loaded.scene.materials[0] {
+            Material::Dielectric {
+                attenuation_color,
+                attenuation_distance,
+                abbe_number,
+                ..
+            } => {
+                assert!(attenuation_color.is_none());
+                assert_eq!(*attenuation_distance, 1_f32);
+                assert_eq!(*abbe_number, Some(40_f32));
+            }
+            _ => panic!("expected a dielectric material"),
+        }
+
+        let source = source.replace("40.0", "0.0");
+        assert_invalid_field(&source, "materials[0].abbe_number");
+    }
+
+    #[test]
+    fn test_load_procedural_scene() {
+        let loaded = load("assets/scenes/procedural.ron").unwrap();
+        match &loaded.scene.materials[2] {
+            Material::Lambertian { albedo, .. } => {
+                let procedural = albedo.procedural().unwrap();
+                assert_eq!(procedural.pattern, Pattern::Wood);
+                assert_eq!(procedural.space, PatternSpace::World);
+                assert_eq!(procedural.octaves, 5_u32);
+            }
+            _ => panic!("expected a lambertian material"),
+        }
+    }
+
+    #[test]
+    fn test_procedural_octaves_out_of_range() {
+        let source = MINIMAL_SCENE.replace(
+            "Color((0.5, 0.5, 0.5))",
+            "Procedural(pattern: Fbm, colors: ((0.0, 0.0, 0.0), (1.0, 1.0, 1.0)), octaves: 0)",
+        );
+        assert_invalid_field(&source, "materials[0].albedo.octaves");
+    }
+
+    #[test]
+    fn test_load_instances_scene() {
+        let loaded = load("assets/scenes/instances.ron").unwrap();
+        // The tree's bark and leaves are one mesh each, which the tree instances share.
+        assert_eq!(loaded.scene.meshes.len(), 2);
+        let trees = loaded
+            .scene
+            .instances
+            .iter()
+            .filter(|instance| instance.object == InstancedObject::Meshes(0..2))
+            .count();
+        assert!(trees > 1);
+    }
+
+    #[test]
+    fn test_instance_transform_and_range() {
+        let source = MINIMAL_SCENE.replace(
+            "],\n    )",
+            "],
+        instances: [
+            (object: Sphere(0), translation: (1.0, 2.0, 3.0), scale: (2.0, 1.0, 1.0)),
+        ],
+    )",
+        );
+        let loaded = parse(&source, Path::new("")).unwrap();
+        let transform = loaded.scene.instances[0].transform();
+        let p = transform * glm::vec4(1_f32, 0_f32, 0_f32, 1_f32);
+        assert_eq!(p, glm::vec4(3_f32, 2_f32, 3_f32, 1_f32));
+
+        let source = source.replace("(2.0, 1.0, 1.0)", "(2.0, 0.0, 1.0)");
+        assert_invalid_field(&source, "instances[0].scale");
+
+        let source = source.replace("Sphere(0)", "Mesh(0)");
+        assert_invalid_field(&source, "instances[0].object");
+    }
+
+    #[test]
+    fn test_load_motion_blur_scene() {
+        let loaded = load("assets/scenes/motion_blur.ron").unwrap();
+        let sphere = &loaded.scene.spheres[1];
+        assert_eq!(sphere.center(0_f32), glm::vec3(-2.5_f32, 0.5_f32, -2_f32));
+        assert_eq!(sphere.center(1_f32), glm::vec3(-2.5_f32, 1.2_f32, -2_f32));
+        let [start, end] = loaded.scene.instances[1].motion().unwrap();
+        assert_eq!(start.translation, glm::vec3(-3_f32, 0_f32, 3_f32));
+        assert_eq!(end.translation, glm::vec3(-1.5_f32, 0_f32, 3_f32));
+        assert_eq!(end.scale, start.scale);
+        assert!(loaded.camera.motion.is_none());
+    }
+
+    #[test]
+    fn test_camera_motion_and_shutter() {
+        let source = MINIMAL_SCENE.replace(
+            "version: 1,",
+            "version: 1,
+        camera: (position: (0.0, 1.0, -5.0), end_position: Some((1.0, 1.0, -5.0)), shutter_open: 0.25),",
+        );
+        let loaded = parse(&source, Path::new("")).unwrap();
+        let motion = loaded.camera.motion.unwrap();
+        assert_eq!(motion.translation, glm::vec3(1_f32, 0_f32, 0_f32));
+        assert_eq!(motion.yaw.as_degrees(), 0_f32);
+        assert_eq!(loaded.camera.shutter_open, 0.25_f32);
+
+        let source = source.replace(
+            "shutter_open: 0.25",
+            "shutter_open: 0.5, shutter_close: 0.25",
+        );
+        assert_invalid_field(&source, "camera.shutter_close");
+    }
+
+    #[test]
+    fn test_load_volumes_scene() {
+        let loaded = load("assets/scenes/volumes.ron").unwrap();
+        // The wax has a refractive boundary, the smoke in the cubes has none.
+        assert!(matches!(
+            loaded.scene.materials[3],
+            Material::Volume { refraction_index: Some(ior), .. } if ior == 1.45_f32
+        ));
+        assert_eq!(loaded.scene.meshes[1].material_idx, 2);
+        assert_eq!(loaded.sky.fog.density, 0.04_f32);
+    }
+
+    #[test]
+    fn test_volume_defaults_and_range() {
+        let source = MINIMAL_SCENE.replace(
+            "Lambertian(albedo: Color((0.5, 0.5, 0.5)))",
+            "Volume(density: 2.0, albedo: Color((0.5, 0.5, 0.5)))",
+        );
+        let loaded = parse(&source, Path::new("")).unwrap();
+        match &loaded.scene.materials[0] {
+            Material::Volume {
+                anisotropy,
+                refraction_index,
+                ..
+            } => {
+                assert_eq!(*anisotropy, 0_f32);
+                assert!(refraction_index.is_none());
+            }
+            _ => panic!("expected a volume material"),
+        }
+        assert_eq!(loaded.sky.fog.density, 0_f32);
+
+        let invalid = source.replace("density: 2.0,", "density: 2.0, anisotropy: -1.0,");
+        assert_invalid_field(&invalid, "materials[0].anisotropy");
+
+        let invalid = source.replace(
+            "version: 1,",
+            "version: 1,
+        sky: (fog: (density: 0.1, height_falloff: 0.0)),",
+        );
+        assert_invalid_field(&invalid, "sky.fog.height_falloff");
+    }
+
+    #[test]
+    fn test_load_cornell_box_scene() {
+        let loaded = load("assets/scenes/cornell_box.ron").unwrap();
+        // Nothing but the ceiling light shines into the box.
+        assert!(loaded.sky.mode == SkyMode::EnvironmentMap);
+        assert_eq!(loaded.sky.environment_intensity, 0_f32);
+        match loaded.scene.shapes[6].geometry {
+            Geometry::Box {
+                center, half_size, ..
+            } => {
+                assert_eq!(center, glm::vec3(347.5_f32, 165_f32, 377.5_f32));
+                assert_eq!(half_size, glm::vec3(82.5_f32, 165_f32, 82.5_f32));
+            }
+            _ => panic!("expected a box"),
+        }
+    }
+
+    #[test]
+    fn test_load_shapes_scene() {
+        let loaded = load("assets/scenes/shapes.ron").unwrap();
+        let is_emissive = |shape: &Shape| {
+            matches!(
+                loaded.scene.materials[shape.material_idx as usize],
+                Material::Emissive { .. }
+            )
+        };
+        let sampled_lights = loaded
+            .scene
+            .shapes
+            .iter()
+            .filter(|shape| is_emissive(shape) && shape.is_sampled_as_light())
+            .count();
+        let hit_lights = loaded
+            .scene
+            .shapes
+            .iter()
+            .filter(|shape| is_emissive(shape) && !shape.is_sampled_as_light())
+            .count();
+        assert_eq!((sampled_lights, hit_lights), (1, 2));
+    }
+
+    #[test]
+    fn test_invalid_camera_sampling_and_sky() {
+        let invalid_values = [
+            ("camera: (vfov_degrees: 120.0)", "camera.vfov_degrees"),
+            ("camera: (aperture: 2.0)", "camera.aperture"),
+            (
+                "sampling: (num_samples_per_pixel: 0)",
+                "sampling.num_samples_per_pixel",
+            ),
+            (
+                "sampling: (max_samples_per_pixel: 9, num_samples_per_pixel: 2)",
+                "sampling.max_samples_per_pixel",
+            ),
+            ("sky: (zenith_degrees: 95.0)", "sky.zenith_degrees"),
+            ("sky: (turbidity: 0.5)", "sky.turbidity"),
+            ("sky: (albedo: (0.5, 1.5, 0.5))", "sky.albedo"),
+        ];
+        for (value, expected_field) in invalid_values {
+            let source = MINIMAL_SCENE.replace("version: 1,", &format!("version: 1, {value},"));
+            assert_invalid_field(&source, expected_field);
+        }
+    }
+
+    #[test]
+    fn test_invalid_shapes() {
+        let invalid_shapes = [
+            (
+                "Disk(center: (0.0, 0.0, 0.0), normal: (0.0, 1.0, 0.0), radius: 0.0, material: 0)",
+                "shapes[0].radius",
+            ),
+            (
+                "Quad(corner: (0.0, 0.0, 0.0), u: (1.0, 0.0, 0.0), v: (2.0, 0.0, 0.0), material: 0)",
+                "shapes[0].v",
+            ),
+            (
+                "Box(min: (0.0, 0.0, 0.0), max: (1.0, 0.0, 1.0), material: 0)",
+                "shapes[0].max",
+            ),
+            (
+                "Plane(point: (0.0, 0.0, 0.0), normal: (0.0, 1.0, 0.0), material: 1)",
+                "shapes[0].material",
+            ),
+        ];
+        for (shape, expected_field) in invalid_shapes {
+            let source = MINIMAL_SCENE.replace(
+                "spheres: [",
+                &format!("shapes: [{shape}],\n        spheres: ["),
+            );
+            assert_invalid_field(&source, expected_field);
+        }
+    }
+
+    #[test]
+    fn test_material_index_out_of_range() {
+        let source = MINIMAL_SCENE.replace("material: 0", "material: 3");
+        assert_invalid_field(&source, "spheres[0].material");
+    }
+}